@@ -1,20 +1,100 @@
 mod types;
 
+use std::borrow::Cow;
+use std::cell::Cell;
+
 pub use types::*;
 
+/// A column constraint, only the ones that matter when reading a table are kept.
+enum Constraint<'a> {
+    PrimaryKey { desc: bool },
+    Default(Option<Value<'a>>),
+    Generated(Generated<'a>),
+    Collate(&'a str),
+    Other,
+}
+
+/// How deeply expressions and selects can be nested, SQLite's default limit on the depth of an expression.
+pub const MAX_DEPTH: usize = 1000;
+
+/// The error of a statement nesting expressions deeper than [`MAX_DEPTH`].
+pub const TOO_DEEP: &str = "Expression tree is too large (maximum depth 1000)";
+
+/// Stack the statements are parsed on, the parser taking tens of kilobytes of it for each level of nesting in debug
+/// builds.
+const PARSER_STACK: usize = 64 << 20;
+
+thread_local! {
+    /// How deeply the expressions and selects being parsed are nested.
+    static DEPTH: Cell<usize> = const { Cell::new(0) };
+    /// Whether the parse went past [`MAX_DEPTH`] levels of nesting.
+    static TOO_DEEP_SEEN: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Parses a statement on a thread with a stack large enough for the most deeply nested ones. Like SQLite, it fails
+/// when an expression is more than [`MAX_DEPTH`] levels deep, or when expressions and selects are nested deeper.
+pub fn parse_statement(sql: &str) -> Result<Statement<'_>, String> {
+    let parse = move || {
+        let statement = sql::statement(sql).map_err(|e| match TOO_DEEP_SEEN.get() {
+            true => TOO_DEEP.to_string(),
+            false => e.to_string(),
+        })?;
+        let select = match &statement {
+            Statement::Select(s) | Statement::Explain(s) | Statement::ExplainQueryPlan(s) => Some(s),
+            Statement::Analyze(_) => None,
+        };
+        // A statement too deep is dropped on this thread, which takes as much stack as it is deep.
+        match select.is_some_and(|s| s.deeper_than(MAX_DEPTH)) {
+            true => Err(TOO_DEEP.to_string()),
+            false => Ok(statement),
+        }
+    };
+    std::thread::scope(|scope| {
+        let thread = std::thread::Builder::new()
+            .stack_size(PARSER_STACK)
+            .spawn_scoped(scope, parse);
+        match thread.map_err(|e| e.to_string())?.join() {
+            Ok(result) => result,
+            Err(panic) => std::panic::resume_unwind(panic),
+        }
+    })
+}
+
 peg::parser! {
     pub grammar sql() for str {
-        rule _ = quiet! { [' '|'\t'|'\n'] }
+        rule _ = quiet! { [' '|'\t'|'\n'|'\r'] }
+
+        rule string() -> Cow<'input, str>
+            = s:$(("''" / [^'\''])*) {
+                if s.contains("''") { Cow::Owned(s.replace("''", "'")) } else { Cow::Borrowed(s) }
+            }
 
-        rule string() -> &'input str
-            = s:$([c if c.is_ascii() && c != '\'']*) { s }
+        rule blob() -> Vec<u8>
+            = ['x'|'X'] "'" h:$(['0'..='9'|'a'..='f'|'A'..='F']*) "'" {?
+                if h.len() % 2 != 0 {
+                    return Err("blob literal with an even number of hex digits");
+                }
+                Ok((0..h.len()).step_by(2).map(|i| u8::from_str_radix(&h[i..i + 2], 16).unwrap()).collect())
+            }
 
         rule integer() -> i64
-            = quiet! { ("true") { 1 } / ("false") { 0 } }
-            / quiet! { n:$("-"? ['0'..='9']+) {? n.parse().or(Err("i64")) } }
+            = quiet! { kw("true") { 1 } / kw("false") { 0 } }
+            / quiet! { "0" ['x'|'X'] n:$(['0'..='9'|'a'..='f'|'A'..='F']+) {?
+                u64::from_str_radix(n, 16).map(|n| n as i64).or(Err("hex integer"))
+            } }
+            / quiet! { n:$("-"? ['0'..='9']+) !['.'|'e'|'E'] {? n.parse().or(Err("i64")) } }
+
+        rule exponent() = ['e'|'E'] ['+'|'-']? ['0'..='9']+
 
         rule float() -> f64
-            = quiet! { n:$("-"? ['0'..='9']+ "." ['0'..='9']*) {? n.parse().or(Err("f64")) } }
+            = quiet! {
+                n:$("-"? (['0'..='9']+ "." ['0'..='9']* / "." ['0'..='9']+) exponent()? / "-"? ['0'..='9']+ exponent())
+                    {? n.parse().or(Err("f64")) }
+            }
+
+        /// Integer literals too large for an i64 are read as reals.
+        rule big_integer() -> f64
+            = quiet! { n:$("-"? ['0'..='9']+) {? n.parse().or(Err("f64")) } }
 
 
         rule i(literal: &'static str)
@@ -25,84 +105,478 @@ peg::parser! {
             = l:$("\""['a'..='z'|'A'..='Z'|'_'|' ']['a'..='z'|'A'..='Z'|'_'|'0'..='9'|' ']* "\""?) { l }
             / l:$(['a'..='z'|'A'..='Z'|'_']['a'..='z'|'A'..='Z'|'_'|'0'..='9']*) { l }
 
+        rule kw(literal: &'static str)
+            = i(literal) !['a'..='z'|'A'..='Z'|'_'|'0'..='9']
+
+        rule constraint_start()
+            = kw("constraint") / kw("primary") / kw("not") / kw("null") / kw("unique") / kw("check")
+            / kw("default") / kw("collate") / kw("references") / kw("generated") / kw("as")
+
+        rule type_word()
+            = !constraint_start() ['a'..='z'|'A'..='Z'|'_']['a'..='z'|'A'..='Z'|'_'|'0'..='9']*
+
+        rule type_arg()
+            = ['+'|'-']? ['0'..='9']+ ("." ['0'..='9']*)?
+
+        /// A declared column type, e.g. `UNSIGNED BIG INT` or `VARCHAR(255)`.
+        rule type_name() -> &'input str
+            = $((type_word() ++ (_+)) (_* "(" _* type_arg() _* ("," _* type_arg() _*)? ")")?)
+
+        /// A declared column type reduced to its affinity.
         pub rule ty() -> SqlType
-            = (i("integer")/i("int"))               { SqlType::Integer }
-            / (i("varchar")/i("text"))              { SqlType::Text }
-            / (i("double")/i("real"))               { SqlType::Real }
-            / (i("numeric")/i("boolean")/i("date")) { SqlType::Numeric }
-            / i("blob")                             { SqlType::Blob }
+            = t:type_name() { SqlType::from_decl(t) }
 
         pub rule value() -> Value<'input>
             = "'" s:string() "'" { Value::String(s) }
+            / b:blob()           { Value::Blob(b.into()) }
             / f:float()          { Value::Float(f) }
             / n:integer()        { Value::Int(n) }
-            / i("null")          { Value::Null }
-
-        rule columns() -> SelectCols<'input>
-            = l:(identifier() ++ (_* "," _*)) { SelectCols::List(l) }
-            / "*"                          { SelectCols::All }
-
-        rule constraint() -> &'input str
-            = !i("primary") s:identifier() { s }
-
-        pub rule select_column_stmt() ->  SelectColStmt<'input>
-            = i("count") _* "(" _* c:columns() _* ")" { SelectColStmt::Count(c) }
-            / i("avg") _* "(" _* s:identifier() _* ")"{ SelectColStmt::Avg(s)   }
-            / c:columns()                             { SelectColStmt::List(c)  }
-
-        pub rule operator() -> &'input str
-            = o:$("="/"!="/"<="/"<"/">="/">") { o }
-
-        pub rule where_atom() -> WhereExpr<'input>
-            = l:identifier() _+ o:operator() _+ v:value() {
-                match o {
-                    "="  => WhereExpr::Eq(l, v),
-                    "!=" => WhereExpr::Neq(l, v),
-                    "<=" => WhereExpr::Leq(l, v),
-                    "<"  => WhereExpr::Le(l, v),
-                    ">=" => WhereExpr::Geq(l, v),
-                    ">"  => WhereExpr::Ge(l, v),
-                    _    => unreachable!(),
+            / f:big_integer()    { Value::Float(f) }
+            / kw("null")         { Value::Null }
+
+        rule balanced()
+            = ("(" balanced() ")" / "'" string() "'" / [^'(' | ')' | '\''])*
+
+        rule default_value() -> Value<'input>
+            = "(" _* v:default_value() _* ")" { v }
+            / "+" _* v:value()                { v }
+            / v:value()                       { v }
+
+        rule column_constraint() -> Constraint<'input>
+            = kw("primary") _+ kw("key") o:(_+ o:(kw("asc") { false } / kw("desc") { true }) { o })?
+                { Constraint::PrimaryKey { desc: o.unwrap_or(false) } }
+            / kw("default") _* v:default_value() { Constraint::Default(Some(v)) }
+            // Defaults that are not constant, like CURRENT_TIMESTAMP, can't be used by rows written before an
+            // ALTER TABLE ADD COLUMN, so there's no need to keep them around.
+            / kw("default") _* ("(" balanced() ")" / identifier()) { Constraint::Default(None) }
+            / (kw("generated") _+ kw("always") _+)? kw("as") _* "(" _* e:expr() _* ")"
+              s:(_+ s:(kw("stored") { true } / kw("virtual") { false }) { s })?
+                { Constraint::Generated(Generated { expr: e, stored: s.unwrap_or(false) }) }
+            / kw("check") _* "(" balanced() ")" { Constraint::Other }
+            / kw("collate") _+ c:identifier() { Constraint::Collate(c) }
+            / "(" balanced() ")" { Constraint::Other }
+            / !(kw("primary") / kw("default") / kw("collate")) identifier() { Constraint::Other }
+
+        /// Keywords that can't be used as an alias without `AS`.
+        rule reserved()
+            = kw("from") / kw("where") / kw("group") / kw("having") / kw("order") / kw("limit") / kw("offset")
+            / kw("union") / kw("intersect") / kw("except") / kw("on") / kw("using") / kw("join") / kw("inner")
+            / kw("left") / kw("cross") / kw("natural") / kw("window")
+
+        rule alias() -> &'input str
+            = _+ kw("as") _+ a:identifier() { a }
+            / _+ !reserved() a:identifier() { a }
+
+        /// The input from `start` up to the current position.
+        rule text_since(start: usize) -> &'input str
+            = #{|input, pos| peg::RuleResult::Matched(pos, &input[start..pos])}
+
+        rule result_column() -> ResultColumn<'input>
+            = "*"                            { ResultColumn::All }
+            / t:identifier() _* "." _* "*"   { ResultColumn::TableAll(t) }
+            / p:position!() e:expr() t:text_since(p) a:alias()? {
+                ResultColumn::Expr { expr: e, alias: a, text: t }
+            }
+
+        rule not() -> bool
+            = n:(kw("not") _*)? { n.is_some() }
+
+        /// Parses `r` one level of nesting deeper, failing past [`MAX_DEPTH`] levels rather than running out of
+        /// stack.
+        rule nested<T>(r: rule<T>) -> T
+            = enter() x:r()? leave() y:quiet! { {? x.ok_or("") } } { y }
+
+        rule enter()
+            = {?
+                let depth = DEPTH.get() + 1;
+                if depth > MAX_DEPTH {
+                    TOO_DEEP_SEEN.set(true);
+                    Err(TOO_DEEP)
+                } else {
+                    DEPTH.set(depth);
+                    Ok(())
                 }
             }
 
-        pub rule where_expr() -> WhereExpr<'input> = precedence! {
-                x:(@) _+ i("or") _+ y:@  { WhereExpr::Or(Box::new(x), Box::new(y)) }
-                --
-                x:(@) _+ i("and") _+ y:@ { WhereExpr::And(Box::new(x), Box::new(y)) }
-                --
-                e:where_atom()   { e }
-                "(" e:where_expr() ")" { e }
+        rule leave()
+            = { DEPTH.set(DEPTH.get() - 1) }
+
+        /// An expression, operators follow SQLite's precedence, see <https://sqlite.org/lang_expr.html#operators>.
+        pub rule expr() -> Expr<'input> = precedence! {
+            x:(@) _* kw("or") _* y:@  { Expr::binary(x, BinaryOp::Or, y) }
+            --
+            x:(@) _* kw("and") _* y:@ { Expr::binary(x, BinaryOp::And, y) }
+            --
+            n:negation() { n }
+        }
+
+        /// `NOT`, which binds looser than the comparisons.
+        rule negation() -> Expr<'input>
+            = kw("not") _* x:nested(<negation()>) { Expr::Unary(UnaryOp::Not, Box::new(x)) }
+            / equality()
+
+        /// `=`, `IS`, `BETWEEN`, `IN`, `LIKE` and friends.
+        rule equality() -> Expr<'input> = precedence! {
+            x:(@) _* ("==" / "=") _* y:@                   { Expr::binary(x, BinaryOp::Eq, y) }
+            x:(@) _* ("!=" / "<>") _* y:@                  { Expr::binary(x, BinaryOp::Ne, y) }
+            x:(@) _* kw("is") _* kw("not") _* y:@          { Expr::binary(x, BinaryOp::IsNot, y) }
+            x:(@) _* kw("is") _* y:@                       { Expr::binary(x, BinaryOp::Is, y) }
+            x:(@) _* kw("isnull")                          { Expr::binary(x, BinaryOp::Is, Expr::Literal(Value::Null)) }
+            x:(@) _* (kw("notnull") / kw("not") _* kw("null")) {
+                Expr::binary(x, BinaryOp::IsNot, Expr::Literal(Value::Null))
+            }
+            x:(@) _* n:not() kw("between") _* low:comparison() _* kw("and") _* high:comparison() {
+                Expr::Between { expr: Box::new(x), low: Box::new(low), high: Box::new(high), negated: n }
+            }
+            x:(@) _* n:not() kw("in") _* "(" _* s:select_stmt() _* ")" {
+                Expr::InSelect { expr: Box::new(x), select: Box::new(s), negated: n }
+            }
+            x:(@) _* n:not() kw("in") _* "(" _* l:(expr() ** (_* "," _*)) _* ")" {
+                Expr::InList { expr: Box::new(x), list: l, negated: n }
+            }
+            x:(@) _* n:not() g:(kw("like") { false } / kw("glob") { true }) _* p:comparison()
+                e:(_* kw("escape") _* e:comparison() { Box::new(e) })? {
+                Expr::Like { expr: Box::new(x), pattern: Box::new(p), escape: e, glob: g, negated: n }
+            }
+            --
+            c:comparison() { c }
+        }
+
+        /// Operators that bind tighter than `=`, `IN`, `LIKE` and friends.
+        rule comparison() -> Expr<'input> = precedence! {
+            x:(@) _* "<=" _* y:@                 { Expr::binary(x, BinaryOp::Le, y) }
+            x:(@) _* ">=" _* y:@                 { Expr::binary(x, BinaryOp::Ge, y) }
+            x:(@) _* "<" !['<'|'>'] _* y:@       { Expr::binary(x, BinaryOp::Lt, y) }
+            x:(@) _* ">" !">" _* y:@             { Expr::binary(x, BinaryOp::Gt, y) }
+            --
+            x:(@) _* "&" _* y:@                  { Expr::binary(x, BinaryOp::BitAnd, y) }
+            x:(@) _* "|" !"|" _* y:@             { Expr::binary(x, BinaryOp::BitOr, y) }
+            x:(@) _* "<<" _* y:@                 { Expr::binary(x, BinaryOp::ShiftLeft, y) }
+            x:(@) _* ">>" _* y:@                 { Expr::binary(x, BinaryOp::ShiftRight, y) }
+            --
+            x:(@) _* "+" _* y:@                  { Expr::binary(x, BinaryOp::Add, y) }
+            x:(@) _* "-" !">" _* y:@             { Expr::binary(x, BinaryOp::Sub, y) }
+            --
+            x:(@) _* "*" _* y:@                  { Expr::binary(x, BinaryOp::Mul, y) }
+            x:(@) _* "/" _* y:@                  { Expr::binary(x, BinaryOp::Div, y) }
+            x:(@) _* "%" _* y:@                  { Expr::binary(x, BinaryOp::Rem, y) }
+            --
+            x:(@) _* "||" _* y:@                 { Expr::binary(x, BinaryOp::Concat, y) }
+            x:(@) _* "->>" _* y:@                { Expr::binary(x, BinaryOp::LongArrow, y) }
+            x:(@) _* "->" _* y:@                 { Expr::binary(x, BinaryOp::Arrow, y) }
+            --
+            u:unary() { u }
+        }
+
+        /// The prefix operators, which bind looser than `COLLATE`.
+        rule unary() -> Expr<'input>
+            // The one integer too large to be written without its minus sign, like SQLite reads it.
+            = "-" _* "9223372036854775808" !['0'..='9'|'.'|'e'|'E'] { Expr::Literal(Value::Int(i64::MIN)) }
+            / "-" _* x:nested(<unary()>) { Expr::Unary(UnaryOp::Neg, Box::new(x)) }
+            / "+" _* x:nested(<unary()>) { Expr::Unary(UnaryOp::Plus, Box::new(x)) }
+            / "~" _* x:nested(<unary()>) { Expr::Unary(UnaryOp::BitNot, Box::new(x)) }
+            / a:nested(<atom()>) c:(_* kw("collate") _* c:identifier() { c })* {
+                c.into_iter().fold(a, |x, c| Expr::Collate(Box::new(x), c))
+            }
+
+        rule atom() -> Expr<'input>
+            = "(" _* s:select_stmt() _* ")" { Expr::Subquery(Box::new(s)) }
+            / "(" _* e:expr() _* ")" { e }
+            / kw("exists") _* "(" _* s:select_stmt() _* ")" { Expr::Exists(Box::new(s)) }
+            / kw("cast") _* "(" _* e:expr() _+ kw("as") _+ t:type_name() _* ")" {
+                Expr::Cast(Box::new(e), SqlType::from_decl(t))
+            }
+            / kw("case") o:(_+ !kw("when") o:expr() { Box::new(o) })?
+              b:(_+ kw("when") _+ w:expr() _+ kw("then") _+ t:expr() { (w, t) })+
+              e:(_+ kw("else") _+ e:expr() { Box::new(e) })? _+ kw("end") {
+                Expr::Case { operand: o, branches: b, otherwise: e }
+            }
+            / v:value() { Expr::Literal(v) }
+            / p:position!() n:parameter() { Expr::Parameter { name: n, offset: p } }
+            / n:$(kw("current_timestamp") / kw("current_date") / kw("current_time")) {
+                Expr::Function { name: n, args: vec![], distinct: false, function: None }
+            }
+            / n:identifier() _* "(" _* "*" _* ")" w:over()? { call(n, vec![], false, w) }
+            / n:identifier() _* "(" _* d:(kw("distinct") _+)? a:(expr() ** (_* "," _*)) _* ")" w:over()? {
+                call(n, a, d.is_some(), w)
+            }
+            / t:identifier() _* "." _* n:identifier() { Expr::Column { table: Some(t), name: n } }
+            / n:identifier() { Expr::column(n) }
+
+        /// A placeholder for a value bound when the statement runs: `?`, `?NNN`, `:name`, `@name` or `$name`.
+        rule parameter() -> &'input str
+            = $("?" ['0'..='9']*)
+            / $([':'|'@'|'$'] ['a'..='z'|'A'..='Z'|'_'|'0'..='9']+)
+
+        /// The parameters of a statement in the order they're written, with their offsets in the text. Strings and
+        /// quoted identifiers are skipped, nothing else can have the characters parameters start with.
+        pub rule parameters() -> Vec<(usize, &'input str)>
+            = p:(
+                p:position!() n:parameter() { Some((p, n)) }
+                / "'" string() "'" { None }
+                / "\"" [^'"']* "\""? { None }
+                / [_] { None }
+            )* { p.into_iter().flatten().collect() }
+
+        rule ordering_term() -> OrderingTerm<'input>
+            = e:expr() d:(_+ d:(kw("asc") { false } / kw("desc") { true }) { d })?
+              n:(_+ kw("nulls") _+ n:(kw("first") { true } / kw("last") { false }) { n })? {
+                let desc = d.unwrap_or(false);
+                OrderingTerm { expr: e, desc, nulls_first: n.unwrap_or(!desc) }
+            }
+
+        /// The window of a window function call, `OVER name` or `OVER (window)`.
+        rule over() -> WindowDef<'input>
+            = _* kw("over") _* w:("(" _* w:window_def() _* ")" { w } / b:identifier() {
+                WindowDef { base: Some(b), ..Default::default() }
+            }) { w }
+
+        rule window_def() -> WindowDef<'input>
+            = b:(!(kw("partition") / kw("order") / frame_units()) b:identifier() { b })?
+              p:(_* kw("partition") _+ kw("by") _+ p:(expr() ++ (_* "," _*)) { p })?
+              o:(_* kw("order") _+ kw("by") _+ o:(ordering_term() ++ (_* "," _*)) { o })?
+              f:(_* f:frame() { f })? {
+                WindowDef { base: b, partition_by: p.unwrap_or_default(), order_by: o.unwrap_or_default(), frame: f }
+            }
+
+        rule named_window() -> (&'input str, WindowDef<'input>)
+            = n:identifier() _+ kw("as") _* "(" _* d:window_def() _* ")" { (n, d) }
+
+        rule frame() -> Frame<'input>
+            = u:frame_units() _+ kw("between") _+ s:frame_bound() _+ kw("and") _+ e:frame_bound() x:frame_exclude()? {
+                Frame { units: u, start: s, end: e, exclude: x.unwrap_or_default() }
+            }
+            / u:frame_units() _+ s:frame_bound() x:frame_exclude()? {
+                Frame { units: u, start: s, end: FrameBound::CurrentRow, exclude: x.unwrap_or_default() }
             }
 
+        rule frame_units() -> FrameUnits
+            = kw("rows") { FrameUnits::Rows } / kw("range") { FrameUnits::Range } / kw("groups") { FrameUnits::Groups }
+
+        rule frame_bound() -> FrameBound<'input>
+            = kw("unbounded") _+ kw("preceding") { FrameBound::UnboundedPreceding }
+            / kw("unbounded") _+ kw("following") { FrameBound::UnboundedFollowing }
+            / kw("current") _+ kw("row") { FrameBound::CurrentRow }
+            / e:expr() _+ kw("preceding") { FrameBound::Preceding(e) }
+            / e:expr() _+ kw("following") { FrameBound::Following(e) }
+
+        rule frame_exclude() -> FrameExclude
+            = _+ kw("exclude") _+ x:(
+                kw("no") _+ kw("others") { FrameExclude::NoOthers }
+                / kw("current") _+ kw("row") { FrameExclude::CurrentRow }
+                / kw("group") { FrameExclude::Group }
+                / kw("ties") { FrameExclude::Ties }
+            ) { x }
+
+        rule limit() -> Limit<'input>
+            = kw("limit") _+ o:expr() _* "," _* c:expr() { Limit { count: c, offset: Some(o) } }
+            / kw("limit") _+ c:expr() o:(_+ kw("offset") _+ o:expr() { o })? { Limit { count: c, offset: o } }
+
+        rule table_ref() -> TableRef<'input>
+            = "(" _* s:nested(<select_stmt()>) _* ")" a:alias()? {
+                TableRef { source: TableSource::Subquery(Box::new(s)), alias: a }
+            }
+            / n:identifier() _* "(" _* args:(expr() ** (_* "," _*)) _* ")" a:alias()? {
+                TableRef { source: TableSource::Function { name: n, args }, alias: a }
+            }
+            / n:identifier() a:alias()? { TableRef { source: TableSource::Table(n), alias: a } }
+
+        /// Returns whether the join is a LEFT JOIN.
+        rule join_operator() -> bool
+            = _* "," _* { false }
+            / _+ kw("left") (_+ kw("outer"))? _+ kw("join") _+ { true }
+            / _+ ((kw("inner") / kw("cross")) _+)? kw("join") _+ { false }
+
+        rule join_constraint() -> JoinConstraint<'input>
+            = _+ kw("on") _+ e:expr() { JoinConstraint::On(e) }
+            / _+ kw("using") _* "(" _* c:(identifier() ++ (_* "," _*)) _* ")" { JoinConstraint::Using(c) }
+
+        rule join() -> Join<'input>
+            = l:join_operator() t:table_ref() c:join_constraint()? { Join { table: t, left: l, constraint: c } }
+
         pub rule select() -> Select<'input>
-            = i("select") _+ c:select_column_stmt() _+ i("from") _+ t:identifier() _+ i("where") _+ w:where_expr()
-                { Select { columns: c, table: t, expr: Some(w) }}
-            / i("select") _+ c:select_column_stmt() _+ i("from") _+ t:identifier()
-                { Select { columns: c, table: t, expr: None }}
+            = s:select_stmt() _* ";"? _* { s }
+
+        pub rule statement() -> Statement<'input>
+            = kw("explain") _+ kw("query") _+ kw("plan") _+ s:select() { Statement::ExplainQueryPlan(s) }
+            / kw("explain") _+ s:select() { Statement::Explain(s) }
+            / s:select() { Statement::Select(s) }
+            / kw("analyze") n:(_+ n:identifier() { n })? _* ";"? _* { Statement::Analyze(n) }
+
+        rule select_stmt() -> Select<'input>
+            = w:with()? s:select_core() c:(_+ o:compound_op() _+ s:select_core() { (o, s) })*
+              o:(_+ kw("order") _+ kw("by") _+ o:(ordering_term() ++ (_* "," _*)) { o })?
+              l:(_+ l:limit() { l })?
+            {
+                Select {
+                    with: w.unwrap_or_default(),
+                    compound: c,
+                    order_by: o.unwrap_or_default(),
+                    limit: l,
+                    ..s
+                }
+            }
+
+        /// The RECURSIVE keyword is optional, like in SQLite a table is recursive when its select reads it.
+        rule with() -> Vec<Cte<'input>>
+            = kw("with") _+ (kw("recursive") _+)? c:(cte() ++ (_* "," _*)) _+ { c }
+
+        rule cte() -> Cte<'input>
+            = n:identifier() c:(_* "(" _* c:(identifier() ++ (_* "," _*)) _* ")" { c })?
+              _+ kw("as") _* "(" _* s:nested(<select_stmt()>) _* ")" {
+                Cte { name: n, columns: c.unwrap_or_default(), select: s }
+            }
+
+        rule compound_op() -> CompoundOp
+            = kw("union") _+ kw("all") { CompoundOp::UnionAll }
+            / kw("union")               { CompoundOp::Union }
+            / kw("intersect")           { CompoundOp::Intersect }
+            / kw("except")              { CompoundOp::Except }
+
+        /// A select without the WITH, ORDER BY and LIMIT clauses, which belong to the compound it's part of.
+        rule select_core() -> Select<'input>
+            = kw("select") _+ d:(kw("distinct") _+)? (kw("all") _+)? c:(result_column() ++ (_* "," _*)) t:(_+ kw("from") _+ t:table_ref() { t })?
+              j:(j:join()* {? if t.is_some() || j.is_empty() { Ok(j) } else { Err("FROM") } })
+              w:(_+ kw("where") _+ w:expr() { w })?
+              g:(_+ kw("group") _+ kw("by") _+ g:(expr() ++ (_* "," _*)) { g })?
+              h:(_+ kw("having") _+ h:expr() { h })?
+              n:(_+ kw("window") _+ n:(named_window() ++ (_* "," _*)) { n })?
+            {
+                Select {
+                    with: vec![],
+                    distinct: d.is_some(),
+                    columns: c,
+                    from: t,
+                    joins: j,
+                    expr: w,
+                    group_by: g.unwrap_or_default(),
+                    having: h,
+                    windows: n.unwrap_or_default(),
+                    compound: vec![],
+                    order_by: vec![],
+                    limit: None,
+                }
+            }
 
         pub rule column_def() -> ColumnDef<'input>
-            = n:identifier() _+ t:ty() _+ (constraint() _+)* i("primary") _+ i("key") (_+ constraint())*
-                { ColumnDef { sql_type: t, name: n, primary_key: true } }
-            / n:identifier() _+ t:ty() (_+ constraint())*
-                { ColumnDef { sql_type: t, name: n, primary_key: false } }
-            / n:identifier() { ColumnDef { sql_type: SqlType::Text, name: n, primary_key: false }}
+            = c:column_def_with_order() { c.0 }
+
+        /// Also returns whether the column was declared `PRIMARY KEY DESC`, which keeps an INTEGER column from
+        /// becoming a rowid alias.
+        rule column_def_with_order() -> (ColumnDef<'input>, bool)
+            = n:identifier() t:(_+ t:type_name() { t })? cs:(_* c:column_constraint() { c })* {
+                let pk_desc = cs.iter().find_map(|c| match c {
+                    Constraint::PrimaryKey { desc } => Some(*desc),
+                    _ => None,
+                });
+                let column = ColumnDef {
+                    sql_type: SqlType::from_decl(t.unwrap_or("")),
+                    decl_type: t.unwrap_or(""),
+                    name: n,
+                    primary_key: pk_desc.is_some(),
+                    default: cs.iter().find_map(|c| match c {
+                        Constraint::Default(v) => v.clone(),
+                        _ => None,
+                    }),
+                    collation: cs.iter().find_map(|c| match c {
+                        Constraint::Collate(c) => Some(*c),
+                        _ => None,
+                    }),
+                    generated: cs.into_iter().find_map(|c| match c {
+                        Constraint::Generated(g) => Some(g),
+                        _ => None,
+                    }),
+                };
+                (column, pk_desc == Some(true))
+            }
+
+        rule indexed_column() -> IndexedColumn<'input>
+            = n:identifier() c:(_+ kw("collate") _+ c:identifier() { c })?
+              d:(_+ d:(kw("asc") { false } / kw("desc") { true }) { d })?
+                { IndexedColumn { name: n, collation: c, desc: d.unwrap_or(false) } }
+
+        rule table_constraint_start()
+            = kw("constraint") / kw("primary") / kw("unique") / kw("check") / kw("foreign")
+
+        /// Table constraints, yields the key columns of a `PRIMARY KEY (...)` constraint.
+        rule table_constraint() -> Option<Vec<IndexedColumn<'input>>>
+            = (kw("constraint") _+ identifier() _*)? kw("primary") _+ kw("key") _*
+              "(" _* cols:(indexed_column() ++ (_* "," _*)) _* ")" (_* !table_constraint_start() identifier())*
+                { Some(cols) }
+            / (kw("constraint") _+ identifier() _*)? (kw("unique") / kw("check") / kw("foreign") _+ kw("key"))
+              (_* ("(" balanced() ")" / !table_constraint_start() identifier()))*
+                { None }
+
+        /// Returns whether the table was declared `WITHOUT ROWID`.
+        rule table_option() -> bool
+            = kw("without") _+ kw("rowid") { true }
+            / kw("strict")                 { false }
 
         pub rule create_table() -> CreateTable<'input>
-            = i("create") _+ i("table") _+ t:identifier() _* "(" _* c:(column_def() ++ (_* "," _*)) _* ")" {
-                // Select first column as primary key if no one was specified.
-                let mut primary_key = 0;
-                for (i, c) in c[1..].iter().enumerate() {
-                    if c.primary_key {
-                        primary_key = i + 1;
-                    }
+            = i("create") _+ i("table") _+ (kw("if") _+ kw("not") _+ kw("exists") _+)? t:identifier() _* "(" _*
+              c:((!table_constraint_start() c:column_def_with_order() { c }) ++ (_* "," _*))
+              tc:(_* "," _* tc:table_constraint() { tc })* _* ")"
+              opts:((_* o:table_option() { o }) ** (_* ","))
+            {
+                let (mut columns, pk_desc): (Vec<ColumnDef>, Vec<bool>) = c.into_iter().unzip();
+                let key_columns: Vec<IndexedColumn> = match tc.into_iter().flatten().next() {
+                    Some(key) => key,
+                    None => columns
+                        .iter()
+                        .zip(&pk_desc)
+                        .filter(|(c, _)| c.primary_key)
+                        .map(|(c, desc)| IndexedColumn { name: c.name, collation: None, desc: *desc })
+                        .collect(),
+                };
+                let primary_key: Vec<usize> =
+                    key_columns.iter().filter_map(|n| columns.iter().position(|c| c.name.eq_ignore_ascii_case(n.name))).collect();
+                for &i in &primary_key {
+                    columns[i].primary_key = true;
                 }
+                let without_rowid = opts.into_iter().any(|o| o);
+                // See https://sqlite.org/lang_createtable.html#rowid, "INTEGER PRIMARY KEY DESC" is a documented
+                // quirk that doesn't alias the rowid, while "PRIMARY KEY (x DESC)" does.
+                let rowid_alias = match primary_key[..] {
+                    [pk] if !without_rowid && columns[pk].decl_type.eq_ignore_ascii_case("integer") && !pk_desc[pk] => {
+                        Some(pk)
+                    }
+                    _ => None,
+                };
                 CreateTable {
                     table_name: t,
-                    columns: c,
+                    columns,
                     primary_key,
+                    key_columns,
+                    rowid_alias,
+                    without_rowid,
                 }
             }
+
+        pub rule create_index() -> CreateIndex<'input>
+            = kw("create") _+ u:(kw("unique") _+)? kw("index") _+ (kw("if") _+ kw("not") _+ kw("exists") _+)?
+              n:identifier() _+ kw("on") _+ t:identifier() _* "(" _* c:(indexed_column() ++ (_* "," _*)) _* ")"
+              w:(_+ kw("where") _+ w:expr() { w })? _* ";"? _*
+            {
+                CreateIndex { index_name: n, table_name: t, unique: u.is_some(), columns: c, partial: w }
+            }
+    }
+}
+
+/// A function call, of a window function when it has a window.
+fn call<'a>(name: &'a str, args: Vec<Expr<'a>>, distinct: bool, window: Option<WindowDef<'a>>) -> Expr<'a> {
+    match window {
+        Some(window) => Expr::Window {
+            name,
+            args,
+            distinct,
+            window: Box::new(window),
+        },
+        None => Expr::Function {
+            name,
+            args,
+            distinct,
+            function: None,
+        },
     }
 }
 
@@ -118,43 +592,456 @@ mod tests {
     #[test]
     fn ty() {
         assert_eq!(sql::ty("VARCHAR"), Ok(SqlType::Text));
+        assert_eq!(sql::ty("VARCHAR(255)"), Ok(SqlType::Text));
+        assert_eq!(sql::ty("UNSIGNED BIG INT"), Ok(SqlType::Integer));
+        assert_eq!(sql::ty("DOUBLE PRECISION"), Ok(SqlType::Real));
+        assert_eq!(sql::ty("DECIMAL(10, 5)"), Ok(SqlType::Numeric));
+        assert_eq!(sql::ty("datetime"), Ok(SqlType::Numeric));
+        assert_eq!(sql::ty("blob"), Ok(SqlType::Blob));
+        // "POINT" contains "INT", so SQLite gives it integer affinity.
+        assert_eq!(sql::ty("FLOATING POINT"), Ok(SqlType::Integer));
+    }
+
+    #[test]
+    fn value_ordering() {
+        use Value::*;
+        use std::cmp::Ordering::*;
+        assert_eq!(Int(12).sql_cmp(&Float(12.0)), Equal);
+        assert_eq!(Float(12.5).sql_cmp(&Int(10)), Greater);
+        assert_eq!(Int(i64::MAX).sql_cmp(&Float(9223372036854775807.0)), Less);
+        assert_eq!(Null.sql_cmp(&Int(-1)), Less);
+        assert_eq!(Int(1000).sql_cmp(&String("1".into())), Less);
+        assert_eq!(String("z".into()).sql_cmp(&Blob(vec![0].into())), Less);
+    }
+
+    #[test]
+    fn affinity() {
+        use Value::*;
+        assert_eq!(String(" 42 ".into()).with_affinity(SqlType::Integer), Int(42));
+        assert!(matches!(
+            String("3.0e+5".into()).with_affinity(SqlType::Numeric),
+            Int(300000)
+        ));
+        assert!(matches!(
+            String("2.5".into()).with_affinity(SqlType::Integer),
+            Float(2.5)
+        ));
+        assert!(matches!(Int(12).with_affinity(SqlType::Real), Float(12.0)));
+        assert!(matches!(
+            String("12abc".into()).with_affinity(SqlType::Integer),
+            String(_)
+        ));
+        assert!(matches!(Float(1.5).with_affinity(SqlType::Text), String(s) if s == "1.5"));
+        assert_eq!(Float(12.0).to_string(), "12.0");
+        assert_eq!(Float(1e20).to_string(), "1.0e+20");
+        assert_eq!(Float(0.00001).to_string(), "1.0e-05");
+        assert_eq!(Float(0.1 + 0.2).to_string(), "0.3");
     }
 
     #[test]
     fn value() {
-        assert_eq!(sql::value("'name'"), Ok(Value::String("name")));
+        assert_eq!(sql::value("'name'"), Ok(Value::String("name".into())));
+    }
+
+    #[test]
+    fn group_by() {
+        let select = sql::select("SELECT g, COUNT(*) FROM t GROUP BY g, 2 HAVING avg(x) > 1").unwrap();
+        assert_eq!(
+            select.columns[1],
+            ResultColumn::Expr {
+                expr: Expr::Function {
+                    name: "COUNT",
+                    args: vec![],
+                    distinct: false,
+                    function: None
+                },
+                alias: None,
+                text: "COUNT(*)"
+            }
+        );
+        assert_eq!(select.group_by, vec![Expr::column("g"), Expr::Literal(Value::Int(2))]);
+        assert!(select.having.is_some());
+        let select = sql::select("SELECT count(*) FROM t HAVING count(*) > 1").unwrap();
+        assert!(select.group_by.is_empty() && select.having.is_some());
+    }
+
+    #[test]
+    fn joins() {
+        let select = sql::select(
+            "SELECT o.*, c.name FROM orders AS o JOIN customers c ON o.customer = c.id, items \
+             LEFT OUTER JOIN notes USING (id, kind) CROSS JOIN tags WHERE c.id > 1",
+        )
+        .unwrap();
+        assert_eq!(select.columns[0], ResultColumn::TableAll("o"));
+        assert_eq!(
+            select.from,
+            Some(TableRef {
+                source: TableSource::Table("orders"),
+                alias: Some("o")
+            })
+        );
+        let joins: Vec<_> = select
+            .joins
+            .iter()
+            .map(|j| (&j.table.source, j.table.alias, j.left, j.constraint.is_some()))
+            .collect();
+        use TableSource::Table;
+        assert_eq!(
+            joins,
+            vec![
+                (&Table("customers"), Some("c"), false, true),
+                (&Table("items"), None, false, false),
+                (&Table("notes"), None, true, true),
+                (&Table("tags"), None, false, false),
+            ]
+        );
+        assert_eq!(
+            select.joins[2].constraint,
+            Some(JoinConstraint::Using(vec!["id", "kind"]))
+        );
+        assert!(select.expr.is_some());
+    }
+
+    #[test]
+    fn subqueries() {
+        let select = sql::select(
+            "SELECT (SELECT max(x) FROM u) m FROM (SELECT * FROM t) AS s \
+             WHERE NOT EXISTS (SELECT 1 FROM v) AND s.id NOT IN (SELECT id FROM w LIMIT 2)",
+        )
+        .unwrap();
+        let ResultColumn::Expr { expr, alias, text } = &select.columns[0] else {
+            panic!("expected an expression")
+        };
+        assert!(matches!(expr, Expr::Subquery(s) if s.limit.is_none()));
+        assert_eq!((*alias, *text), (Some("m"), "(SELECT max(x) FROM u)"));
+        let from = select.from.unwrap();
+        assert!(matches!(&from.source, TableSource::Subquery(s) if s.columns == [ResultColumn::All]));
+        assert_eq!(from.alias, Some("s"));
+        let Some(Expr::Binary(l, BinaryOp::And, r)) = select.expr else {
+            panic!("expected a conjunction")
+        };
+        assert!(matches!(*l, Expr::Unary(UnaryOp::Not, e) if matches!(*e, Expr::Exists(_))));
+        assert!(matches!(*r, Expr::InSelect { negated: true, select, .. } if select.limit.is_some()));
+        assert!(matches!(sql::expr("(1)"), Ok(Expr::Literal(Value::Int(1)))));
+    }
+
+    #[test]
+    fn table_functions() {
+        let select = sql::select("SELECT key FROM t, json_each(t.doc, '$.a') AS j").unwrap();
+        assert_eq!(
+            select.joins[0].table,
+            TableRef {
+                source: TableSource::Function {
+                    name: "json_each",
+                    args: vec![
+                        Expr::Column {
+                            table: Some("t"),
+                            name: "doc"
+                        },
+                        Expr::Literal(Value::String("$.a".into()))
+                    ]
+                },
+                alias: Some("j")
+            }
+        );
+    }
+
+    #[test]
+    fn windows() {
+        let select = sql::select(
+            "SELECT rank() OVER (PARTITION BY a ORDER BY b DESC ROWS BETWEEN 1 PRECEDING AND UNBOUNDED FOLLOWING \
+             EXCLUDE TIES), count(*) OVER w, sum(x) over (w groups current row) FROM t WINDOW w AS (ORDER BY a)",
+        )
+        .unwrap();
+        let windows: Vec<_> = select
+            .columns
+            .iter()
+            .map(|c| match c {
+                ResultColumn::Expr {
+                    expr: Expr::Window { window, .. },
+                    ..
+                } => window.as_ref(),
+                _ => panic!("expected a window function call"),
+            })
+            .collect();
+        assert_eq!(windows[0].partition_by, [Expr::column("a")]);
+        assert!(windows[0].order_by[0].desc);
+        assert_eq!(
+            windows[0].frame,
+            Some(Frame {
+                units: FrameUnits::Rows,
+                start: FrameBound::Preceding(Expr::Literal(Value::Int(1))),
+                end: FrameBound::UnboundedFollowing,
+                exclude: FrameExclude::Ties,
+            })
+        );
+        assert_eq!(windows[1].base, Some("w"));
+        assert!(
+            matches!(&windows[2].frame, Some(f) if f.units == FrameUnits::Groups && f.end == FrameBound::CurrentRow)
+        );
+        assert_eq!(select.windows[0].0, "w");
+        assert_eq!(
+            sql::expr("ntile(2) OVER (w range 2 preceding)").unwrap().to_string(),
+            "ntile(2) OVER (w RANGE BETWEEN 2 PRECEDING AND CURRENT ROW)"
+        );
+        assert_eq!(sql::expr("count(*) over w").unwrap().to_string(), "count(*) OVER w");
     }
 
     #[test]
-    fn select_column_stmt() {
+    fn with() {
+        let select = sql::select(
+            "WITH RECURSIVE c(n, m) AS (SELECT 1, 2 UNION ALL SELECT n + 1, m FROM c WHERE n < 5), d AS (SELECT 1) \
+             SELECT n FROM c UNION SELECT * FROM d ORDER BY 1 LIMIT 3",
+        )
+        .unwrap();
+        assert_eq!(select.with.iter().map(|c| c.name).collect::<Vec<_>>(), ["c", "d"]);
+        let c = &select.with[0];
+        assert_eq!(c.columns, ["n", "m"]);
+        assert!(c.select.from.is_none() && c.select.columns.len() == 2);
+        assert!(matches!(&c.select.compound[..], [(CompoundOp::UnionAll, s)] if s.expr.is_some()));
+        assert!(select.with[1].columns.is_empty());
+        assert!(matches!(&select.compound[..], [(CompoundOp::Union, s)] if s.order_by.is_empty()));
+        assert!(select.order_by.len() == 1 && select.limit.is_some());
+        assert!(sql::select("SELECT 1 JOIN t").is_err());
+    }
+
+    #[test]
+    fn parameters() {
+        let parameter = |name, offset| Expr::Parameter { name, offset };
         assert_eq!(
-            sql::select_column_stmt("COUNT( id,name,   created_at )"),
-            Ok(SelectColStmt::Count(SelectCols::List(vec!["id", "name", "created_at"])))
+            sql::expr("?2 + :a * @b_1 || $c"),
+            Ok(Expr::binary(
+                parameter("?2", 0),
+                BinaryOp::Add,
+                Expr::binary(
+                    parameter(":a", 5),
+                    BinaryOp::Mul,
+                    Expr::binary(parameter("@b_1", 10), BinaryOp::Concat, parameter("$c", 18))
+                )
+            ))
         );
+        let query = "SELECT '?', \"a\", x'3f', ? FROM t WHERE a = :a AND b IN (SELECT ?3) LIMIT ?";
         assert_eq!(
-            sql::select_column_stmt("COUNT(*)"),
-            Ok(SelectColStmt::Count(SelectCols::All))
+            sql::parameters(query),
+            Ok(vec![(24, "?"), (43, ":a"), (63, "?3"), (73, "?")])
         );
+        assert!(sql::select(query).is_ok());
+    }
+
+    #[test]
+    fn compound() {
+        let select = sql::select("SELECT a FROM t INTERSECT SELECT b FROM u EXCEPT SELECT 1 ORDER BY 1").unwrap();
+        let ops: Vec<_> = select.compound.iter().map(|(op, _)| *op).collect();
+        assert_eq!(ops, [CompoundOp::Intersect, CompoundOp::Except]);
+        assert!(select.order_by.len() == 1);
+    }
+
+    #[test]
+    fn explain() {
+        let select = sql::select("SELECT a FROM t").unwrap();
+        assert_eq!(
+            sql::statement("EXPLAIN SELECT a FROM t"),
+            Ok(Statement::Explain(select.clone()))
+        );
+        assert_eq!(
+            sql::statement("explain query plan SELECT a FROM t"),
+            Ok(Statement::ExplainQueryPlan(select.clone()))
+        );
+        assert_eq!(sql::statement("SELECT a FROM t;"), Ok(Statement::Select(select)));
+        assert!(sql::statement("EXPLAINSELECT a FROM t").is_err());
+        assert!(sql::statement("EXPLAIN QUERY SELECT a FROM t").is_err());
+    }
+
+    #[test]
+    fn analyze() {
+        assert_eq!(sql::statement("ANALYZE"), Ok(Statement::Analyze(None)));
+        assert_eq!(sql::statement("analyze users;"), Ok(Statement::Analyze(Some("users"))));
+        assert!(sql::statement("ANALYZEusers").is_err());
     }
 
     #[test]
     fn select() {
+        let column = |name| ResultColumn::Expr {
+            expr: Expr::column(name),
+            alias: None,
+            text: name,
+        };
         assert_eq!(
             sql::select("SELECT name FROM users"),
             Ok(Select {
-                columns: SelectColStmt::List(SelectCols::List(vec!["name"])),
-                table: "users",
-                expr: None
+                with: vec![],
+                distinct: false,
+                columns: vec![column("name")],
+                from: Some(TableRef {
+                    source: TableSource::Table("users"),
+                    alias: None
+                }),
+                joins: vec![],
+                expr: None,
+                group_by: vec![],
+                having: None,
+                windows: vec![],
+                compound: vec![],
+                order_by: vec![],
+                limit: None
+            })
+        );
+        assert_eq!(
+            sql::select("SELECT rowid, * FROM users"),
+            Ok(Select {
+                with: vec![],
+                distinct: false,
+                columns: vec![column("rowid"), ResultColumn::All],
+                from: Some(TableRef {
+                    source: TableSource::Table("users"),
+                    alias: None
+                }),
+                joins: vec![],
+                expr: None,
+                group_by: vec![],
+                having: None,
+                windows: vec![],
+                compound: vec![],
+                order_by: vec![],
+                limit: None
             })
         );
         assert_eq!(
             sql::select("SELECT id,   name, \tcreated_at FROM users"),
             Ok(Select {
-                columns: SelectColStmt::List(SelectCols::List(vec!["id", "name", "created_at"])),
-                table: "users",
-                expr: None
+                with: vec![],
+                distinct: false,
+                columns: vec![column("id"), column("name"), column("created_at")],
+                from: Some(TableRef {
+                    source: TableSource::Table("users"),
+                    alias: None
+                }),
+                joins: vec![],
+                expr: None,
+                group_by: vec![],
+                having: None,
+                windows: vec![],
+                compound: vec![],
+                order_by: vec![],
+                limit: None
             })
         );
+        assert_eq!(
+            sql::select("SELECT price * qty AS total, name n FROM items;"),
+            Ok(Select {
+                with: vec![],
+                distinct: false,
+                columns: vec![
+                    ResultColumn::Expr {
+                        expr: Expr::binary(Expr::column("price"), BinaryOp::Mul, Expr::column("qty")),
+                        alias: Some("total"),
+                        text: "price * qty"
+                    },
+                    ResultColumn::Expr {
+                        expr: Expr::column("name"),
+                        alias: Some("n"),
+                        text: "name"
+                    }
+                ],
+                from: Some(TableRef {
+                    source: TableSource::Table("items"),
+                    alias: None
+                }),
+                joins: vec![],
+                expr: None,
+                group_by: vec![],
+                having: None,
+                windows: vec![],
+                compound: vec![],
+                order_by: vec![],
+                limit: None
+            })
+        );
+        assert!(sql::select("SELECT DISTINCT a, b FROM t").unwrap().distinct);
+        let select = sql::select("SELECT ALL alls FROM t").unwrap();
+        assert!(!select.distinct && select.columns == [column("alls")]);
+        let order_by = sql::select("SELECT * FROM t WHERE a > 1 ORDER BY a, b DESC, 2 ASC NULLS LAST, c nulls first")
+            .unwrap()
+            .order_by;
+        assert_eq!(
+            order_by,
+            vec![
+                OrderingTerm {
+                    expr: Expr::column("a"),
+                    desc: false,
+                    nulls_first: true
+                },
+                OrderingTerm {
+                    expr: Expr::column("b"),
+                    desc: true,
+                    nulls_first: false
+                },
+                OrderingTerm {
+                    expr: Expr::Literal(Value::Int(2)),
+                    desc: false,
+                    nulls_first: false
+                },
+                OrderingTerm {
+                    expr: Expr::column("c"),
+                    desc: false,
+                    nulls_first: true
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn limit() {
+        use Expr::Literal;
+        use Value::Int;
+        let limit = |sql| sql::select(sql).unwrap().limit;
+        assert_eq!(limit("SELECT * FROM t"), None);
+        assert_eq!(
+            limit("SELECT * FROM t ORDER BY a LIMIT 10"),
+            Some(Limit {
+                count: Literal(Int(10)),
+                offset: None
+            })
+        );
+        assert_eq!(
+            limit("SELECT * FROM t LIMIT 10 OFFSET 20;"),
+            Some(Limit {
+                count: Literal(Int(10)),
+                offset: Some(Literal(Int(20)))
+            })
+        );
+        assert_eq!(
+            limit("select * from t limit 20, 10"),
+            Some(Limit {
+                count: Literal(Int(10)),
+                offset: Some(Literal(Int(20)))
+            })
+        );
+    }
+
+    #[test]
+    fn create_index() {
+        let ci = sql::create_index("CREATE UNIQUE INDEX idx_name ON users (name COLLATE NOCASE, age DESC)").unwrap();
+        assert_eq!(ci.index_name, "idx_name");
+        assert_eq!(ci.table_name, "users");
+        assert!(ci.unique);
+        assert_eq!(
+            ci.columns,
+            vec![
+                IndexedColumn {
+                    name: "name",
+                    collation: Some("NOCASE"),
+                    desc: false
+                },
+                IndexedColumn {
+                    name: "age",
+                    collation: None,
+                    desc: true
+                },
+            ]
+        );
+        let ci = sql::create_index("create index if not exists i on t(a) where a is not null").unwrap();
+        assert!(!ci.unique && ci.partial.is_some());
     }
 
     #[test]
@@ -165,10 +1052,17 @@ mod tests {
                 table_name: "users",
                 columns: vec![ColumnDef {
                     sql_type: SqlType::Integer,
+                    decl_type: "INTEGER",
                     name: "id",
-                    primary_key: false
+                    primary_key: false,
+                    default: None,
+                    collation: None,
+                    generated: None,
                 }],
-                primary_key: 0
+                primary_key: vec![],
+                key_columns: vec![],
+                rowid_alias: None,
+                without_rowid: false,
             })
         );
         assert_eq!(
@@ -177,17 +1071,28 @@ mod tests {
                 table_name: "users",
                 columns: vec![
                     ColumnDef {
-                        sql_type: SqlType::Text,
+                        sql_type: SqlType::Blob,
+                        decl_type: "",
                         name: "id",
-                        primary_key: false
+                        primary_key: false,
+                        default: None,
+                        collation: None,
+                        generated: None,
                     },
                     ColumnDef {
-                        sql_type: SqlType::Text,
+                        sql_type: SqlType::Blob,
+                        decl_type: "",
                         name: "name",
-                        primary_key: false
+                        primary_key: false,
+                        default: None,
+                        collation: None,
+                        generated: None,
                     }
                 ],
-                primary_key: 0,
+                primary_key: vec![],
+                key_columns: vec![],
+                rowid_alias: None,
+                without_rowid: false,
             })
         );
         assert_eq!(
@@ -199,40 +1104,329 @@ mod tests {
                 columns: vec![
                     ColumnDef {
                         sql_type: SqlType::Integer,
+                        decl_type: "integer",
                         name: "id",
-                        primary_key: true
+                        primary_key: true,
+                        default: None,
+                        collation: None,
+                        generated: None,
                     },
                     ColumnDef {
                         sql_type: SqlType::Text,
+                        decl_type: "text",
                         name: "nome",
                         primary_key: false,
+                        default: None,
+                        collation: None,
+                        generated: None,
                     },
                     ColumnDef {
                         sql_type: SqlType::Text,
+                        decl_type: "text",
                         name: "preco",
-                        primary_key: false
+                        primary_key: false,
+                        default: None,
+                        collation: None,
+                        generated: None,
                     }
                 ],
-                primary_key: 0
+                primary_key: vec![0],
+                key_columns: vec![IndexedColumn {
+                    name: "id",
+                    collation: None,
+                    desc: false
+                }],
+                rowid_alias: Some(0),
+                without_rowid: false,
             })
         );
     }
 
+    #[test]
+    fn rowid_alias() {
+        let alias = |sql| sql::create_table(sql).unwrap().rowid_alias;
+        assert_eq!(alias("CREATE TABLE t (x INTEGER PRIMARY KEY, y)"), Some(0));
+        assert_eq!(alias("CREATE TABLE t (y, x integer primary key asc)"), Some(1));
+        assert_eq!(alias("CREATE TABLE t (x INTEGER, y, PRIMARY KEY (x DESC))"), Some(0));
+        assert_eq!(alias("CREATE TABLE t (x INTEGER PRIMARY KEY DESC, y)"), None);
+        assert_eq!(alias("CREATE TABLE t (x INT PRIMARY KEY, y)"), None);
+        assert_eq!(alias("CREATE TABLE t (x INTEGER(10) PRIMARY KEY, y)"), None);
+        assert_eq!(alias("CREATE TABLE t (x INTEGER, y INTEGER, PRIMARY KEY (x, y))"), None);
+        assert_eq!(alias("CREATE TABLE t (x INTEGER PRIMARY KEY, y) WITHOUT ROWID"), None);
+    }
+
+    #[test]
+    fn without_rowid() {
+        let ct = sql::create_table(
+            "CREATE TABLE IF NOT EXISTS kv (k TEXT, g INT, v TEXT NOT NULL, \
+             CONSTRAINT pk PRIMARY KEY (g DESC, k), UNIQUE (v) ON CONFLICT IGNORE) WITHOUT ROWID",
+        )
+        .unwrap();
+        assert!(ct.without_rowid);
+        assert_eq!(ct.primary_key, vec![1, 0]);
+        assert!(ct.columns[0].primary_key && ct.columns[1].primary_key && !ct.columns[2].primary_key);
+        assert_eq!(ct.storage_order(), vec![1, 0, 2]);
+        assert!(!sql::create_table("create table t (a, b) strict").unwrap().without_rowid);
+    }
+
+    #[test]
+    fn generated_columns() {
+        let ct = sql::create_table(
+            "CREATE TABLE g (a INT, b INT GENERATED ALWAYS AS (a * 2) VIRTUAL, c TEXT AS (upper(d)) STORED, \
+             d TEXT, e AS (a + 1) NOT NULL)",
+        )
+        .unwrap();
+        let generated: Vec<_> = ct
+            .columns
+            .iter()
+            .map(|c| c.generated.as_ref().map(|g| g.stored))
+            .collect();
+        assert_eq!(generated, vec![None, Some(false), Some(true), None, Some(false)]);
+        assert_eq!(
+            ct.columns[1].generated.as_ref().unwrap().expr,
+            sql::expr("a * 2").unwrap()
+        );
+        assert_eq!(ct.storage_order(), vec![0, 2, 3]);
+    }
+
+    #[test]
+    fn column_collations() {
+        let ct = sql::create_table(
+            "CREATE TABLE t (a TEXT COLLATE NOCASE NOT NULL, b COLLATE rtrim, c TEXT CHECK (c COLLATE nocase > 'a'))",
+        )
+        .unwrap();
+        let collations: Vec<_> = ct.columns.iter().map(|c| c.collation).collect();
+        assert_eq!(collations, vec![Some("NOCASE"), Some("rtrim"), None]);
+        assert_eq!(ct.columns[1].decl_type, "");
+    }
+
+    #[test]
+    fn column_defaults() {
+        let ct = sql::create_table(
+            "CREATE TABLE t (a TEXT NOT NULL DEFAULT 'it''s', b INT DEFAULT -5, c REAL DEFAULT (+1.5), \
+             d BLOB DEFAULT x'CAFE', e TEXT DEFAULT CURRENT_TIMESTAMP, f REFERENCES other(id) CHECK (f > 0))",
+        )
+        .unwrap();
+        let defaults: Vec<_> = ct.columns.into_iter().map(|c| c.default).collect();
+        assert_eq!(
+            defaults,
+            vec![
+                Some(Value::String("it's".into())),
+                Some(Value::Int(-5)),
+                Some(Value::Float(1.5)),
+                Some(Value::Blob(vec![0xca, 0xfe].into())),
+                None,
+                None,
+            ]
+        );
+    }
+
     #[test]
     fn where_expression() {
+        use BinaryOp::*;
+        use Expr::Literal;
         use Value::*;
-        use WhereExpr::*;
-        assert!(sql::where_atom("col up 70").is_err());
-        assert_eq!(sql::where_expr("coluna = 90"), Ok(WhereExpr::Eq("coluna", Int(90))));
-        assert_eq!(
-            sql::where_expr("coluna = 90 AND (comprimento >= 20 OR preco < 20)"),
-            Ok(And(
-                Box::new(Eq("coluna", Int(90))),
-                Box::new(Or(
-                    Box::new(Geq("comprimento", Int(20))),
-                    Box::new(Le("preco", Int(20)))
-                ))
+        let col = Expr::column;
+        let bin = Expr::binary;
+        assert!(sql::expr("col up 70").is_err());
+        assert_eq!(sql::expr("coluna = 90"), Ok(bin(col("coluna"), Eq, Literal(Int(90)))));
+        assert_eq!(
+            sql::expr("coluna = 90 AND (comprimento >= 20 OR preco < 20)"),
+            Ok(bin(
+                bin(col("coluna"), Eq, Literal(Int(90))),
+                And,
+                bin(
+                    bin(col("comprimento"), Ge, Literal(Int(20))),
+                    Or,
+                    bin(col("preco"), Lt, Literal(Int(20)))
+                )
             )),
         );
+        assert_eq!(sql::expr("notes IS NULL"), Ok(bin(col("notes"), Is, Literal(Null))));
+        assert_eq!(
+            sql::expr("notes is not null"),
+            Ok(bin(col("notes"), IsNot, Literal(Null)))
+        );
+        assert_eq!(
+            sql::expr("NOT price > 3 AND nothing IS 2"),
+            Ok(bin(
+                Expr::Unary(UnaryOp::Not, Box::new(bin(col("price"), Gt, Literal(Int(3))))),
+                And,
+                bin(col("nothing"), Is, Literal(Int(2)))
+            ))
+        );
+    }
+
+    #[test]
+    fn expression_precedence() {
+        use BinaryOp::*;
+        use Expr::Literal;
+        use Value::*;
+        let col = Expr::column;
+        let bin = Expr::binary;
+        assert_eq!(
+            sql::expr("a + b * -c || 'x'"),
+            Ok(bin(
+                col("a"),
+                Add,
+                bin(
+                    col("b"),
+                    Mul,
+                    bin(
+                        Expr::Unary(UnaryOp::Neg, Box::new(col("c"))),
+                        Concat,
+                        Literal(String("x".into()))
+                    )
+                )
+            ))
+        );
+        assert_eq!(
+            sql::expr("a < b = c <> d"),
+            Ok(bin(bin(bin(col("a"), Lt, col("b")), Eq, col("c")), Ne, col("d")))
+        );
+        assert_eq!(
+            sql::expr("t.price-1"),
+            Ok(bin(
+                Expr::Column {
+                    table: Some("t"),
+                    name: "price"
+                },
+                Sub,
+                Literal(Int(1))
+            ))
+        );
+        assert_eq!(
+            sql::expr("j->'a'->>0 * 2"),
+            Ok(bin(
+                bin(
+                    bin(col("j"), Arrow, Literal(String("a".into()))),
+                    LongArrow,
+                    Literal(Int(0))
+                ),
+                Mul,
+                Literal(Int(2))
+            ))
+        );
+        assert_eq!(
+            sql::expr("a->-1"),
+            Ok(bin(
+                col("a"),
+                Arrow,
+                Expr::Unary(UnaryOp::Neg, Box::new(Literal(Int(1))))
+            ))
+        );
+        assert_eq!(sql::expr("-9223372036854775808"), Ok(Literal(Int(i64::MIN))));
+        assert_eq!(
+            sql::expr("- 9223372036854775808.0"),
+            Ok(Expr::Unary(
+                UnaryOp::Neg,
+                Box::new(Literal(Float(9223372036854775808.0)))
+            ))
+        );
+        assert_eq!(
+            sql::expr("x - -1"),
+            Ok(bin(col("x"), Sub, Expr::Unary(UnaryOp::Neg, Box::new(Literal(Int(1))))))
+        );
+        assert_eq!(
+            sql::expr("x NOT BETWEEN 1 AND 2 AND y"),
+            Ok(bin(
+                Expr::Between {
+                    expr: Box::new(col("x")),
+                    low: Box::new(Literal(Int(1))),
+                    high: Box::new(Literal(Int(2))),
+                    negated: true
+                },
+                And,
+                col("y")
+            ))
+        );
+        assert_eq!(
+            sql::expr("name NOT LIKE 'a\\_%' ESCAPE '\\'"),
+            Ok(Expr::Like {
+                expr: Box::new(col("name")),
+                pattern: Box::new(Literal(String("a\\_%".into()))),
+                escape: Some(Box::new(Literal(String("\\".into())))),
+                glob: false,
+                negated: true
+            })
+        );
+        assert_eq!(
+            sql::expr("id in (1, 2.5e1)"),
+            Ok(Expr::InList {
+                expr: Box::new(col("id")),
+                list: vec![Literal(Int(1)), Literal(Float(25.0))],
+                negated: false
+            })
+        );
+        assert_eq!(
+            sql::expr("CASE WHEN a THEN CAST(b AS VARCHAR(10)) ELSE count(*) END"),
+            Ok(Expr::Case {
+                operand: None,
+                branches: vec![(col("a"), Expr::Cast(Box::new(col("b")), SqlType::Text))],
+                otherwise: Some(Box::new(Expr::Function {
+                    name: "count",
+                    args: vec![],
+                    distinct: false,
+                    function: None
+                }))
+            })
+        );
+        assert_eq!(
+            sql::expr("0x10 + 1e2"),
+            Ok(bin(Literal(Int(16)), Add, Literal(Float(100.0))))
+        );
+    }
+
+    #[test]
+    fn display() {
+        for (sql, written) in [
+            ("a + b * -c || 'x''y'", "a + (b * (-c || 'x''y'))"),
+            (
+                "t.x NOT BETWEEN 1 AND 2.5 OR y IS NOT NULL",
+                "(t.x NOT BETWEEN 1 AND 2.5) OR (y IS NOT NULL)",
+            ),
+            (
+                "count(*) > 1 AND sum(DISTINCT x) < 2",
+                "(count(*) > 1) AND (sum(DISTINCT x) < 2)",
+            ),
+            (
+                "CASE a WHEN 1 THEN x'0aff' ELSE NULL END",
+                "CASE a WHEN 1 THEN x'0AFF' ELSE NULL END",
+            ),
+            (
+                "x IN (SELECT 1) AND CAST(y AS text) LIKE 'a%'",
+                "(x IN (SELECT ...)) AND (CAST(y AS TEXT) LIKE 'a%')",
+            ),
+            ("current_date < date(x, '+1 day')", "current_date() < date(x, '+1 day')"),
+            (
+                "a COLLATE nocase = 'A' || (b || c) COLLATE rtrim",
+                "a COLLATE nocase = ('A' || (b || c) COLLATE rtrim)",
+            ),
+        ] {
+            assert_eq!(sql::expr(sql).unwrap().to_string(), written);
+        }
+    }
+
+    #[test]
+    fn depth() {
+        let parse = |sql: String| parse_statement(&sql).map(|_| ()).map_err(|e| e.to_string());
+        let nested = |open: &str, n: usize, close: &str| format!("SELECT {}1{}", open.repeat(n), close.repeat(n));
+        let chain = |op: &str, n: usize| vec!["x"; n].join(op);
+        let too_deep = Err(TOO_DEEP.to_string());
+        // Parentheses don't add to the depth of an expression, but they nest as deep as anything else.
+        assert_eq!(parse(nested("(", 400, ")")), Ok(()));
+        assert_eq!(parse(nested("(", 5000, ")")), too_deep);
+        assert_eq!(parse(nested("abs(", 999, ")")), Ok(()));
+        assert_eq!(parse(nested("abs(", 1000, ")")), too_deep);
+        assert_eq!(parse(nested("- ", 5000, "")), too_deep);
+        assert_eq!(parse(format!("SELECT {}", chain(" + ", 1000))), Ok(()));
+        assert_eq!(parse(format!("SELECT {}", chain(" + ", 1001))), too_deep);
+        assert_eq!(parse(format!("SELECT {}", chain(" OR ", 3000))), too_deep);
+        // Subqueries are as deep as their expressions, the tables of the FROM clause only nest.
+        assert_eq!(parse(format!("SELECT (SELECT {})", chain("+", 999))), Ok(()));
+        assert_eq!(parse(format!("SELECT (SELECT {})", chain("+", 1000))), too_deep);
+        assert_eq!(parse(format!("SELECT * FROM (SELECT {})", chain("+", 1000))), Ok(()));
+        assert_eq!(parse(format!("SELECT * FROM (SELECT {})", chain("+", 1001))), too_deep);
+        assert!(parse("SELECT (1".to_string()).unwrap_err().starts_with("error at 1:10"));
     }
 }
@@ -1,37 +1,324 @@
+use std::borrow::Cow;
+use std::cmp::Ordering;
 use std::fmt::Display;
 
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Debug, Clone)]
 pub enum Value<'a> {
-    String(&'a str),
+    String(Cow<'a, str>),
     Float(f64),
     Int(i64),
+    Blob(Cow<'a, [u8]>),
     Null,
 }
 
-impl PartialOrd for Value<'_> {
-    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+impl Value<'_> {
+    /// Position of the value's storage class in SQLite's cross-type ordering: NULL < numbers < text < blob.
+    fn class_rank(&self) -> u8 {
+        match self {
+            Self::Null => 0,
+            Self::Int(_) | Self::Float(_) => 1,
+            Self::String(_) => 2,
+            Self::Blob(_) => 3,
+        }
+    }
+
+    /// Total ordering following SQLite's rules, integers and floats are compared by numeric value and text is
+    /// compared with the BINARY collation.
+    pub fn sql_cmp(&self, other: &Self) -> Ordering {
         use Value::*;
         match (self, other) {
-            (String(s), String(o)) => s.partial_cmp(o),
-            (Float(s), Float(o)) => s.partial_cmp(o),
-            (Int(s), Int(o)) => s.partial_cmp(o),
-            (Null, Null) => Some(std::cmp::Ordering::Equal),
-            _ => None,
+            (Int(s), Int(o)) => s.cmp(o),
+            (Float(s), Float(o)) => s.total_cmp(o),
+            (Int(s), Float(o)) => int_float_cmp(*s, *o),
+            (Float(s), Int(o)) => int_float_cmp(*o, *s).reverse(),
+            (String(s), String(o)) => s.as_bytes().cmp(o.as_bytes()),
+            (Blob(s), Blob(o)) => s.cmp(o),
+            _ => self.class_rank().cmp(&other.class_rank()),
+        }
+    }
+
+    /// Converts the value into one with the given affinity, as SQLite does when storing it into a column.
+    pub fn with_affinity(self, affinity: SqlType) -> Self {
+        match affinity {
+            SqlType::Integer | SqlType::Numeric => self.numeric_affinity(),
+            SqlType::Real => match self.numeric_affinity() {
+                Self::Int(i) => Self::Float(i as f64),
+                v => v,
+            },
+            SqlType::Text => self.text_affinity(),
+            SqlType::Blob => self,
+        }
+    }
+
+    fn numeric_affinity(self) -> Self {
+        match &self {
+            Self::String(s) => parse_numeric(s).unwrap_or(self),
+            Self::Float(f) if f.fract() == 0.0 && (i64::MIN as f64..i64::MAX as f64).contains(f) => {
+                Self::Int(*f as i64)
+            }
+            _ => self,
+        }
+    }
+
+    fn text_affinity(self) -> Self {
+        match self {
+            Self::Int(i) => Self::String(i.to_string().into()),
+            Self::Float(f) => Self::String(format_real(f).into()),
+            v => v,
+        }
+    }
+
+    /// Converts the value like `CAST(value AS type)` does.
+    pub fn cast(self, ty: SqlType) -> Self {
+        match (ty, self) {
+            (_, Self::Null) => Self::Null,
+            (SqlType::Integer, v) => match v.to_number() {
+                Value::Float(f) => Self::Int(f as i64),
+                Value::Int(i) => Self::Int(i),
+                _ => unreachable!(),
+            },
+            (SqlType::Real, v) => match v.to_number() {
+                Value::Int(i) => Self::Float(i as f64),
+                Value::Float(f) => Self::Float(f),
+                _ => unreachable!(),
+            },
+            (SqlType::Numeric, v @ (Self::String(_) | Self::Blob(_))) => v.to_number().numeric_affinity(),
+            (SqlType::Numeric, v) => v,
+            (SqlType::Text, Self::Blob(b)) => Self::String(String::from_utf8_lossy(&b).into_owned().into()),
+            (SqlType::Text, v) => v.text_affinity(),
+            (SqlType::Blob, Self::String(s)) => Self::Blob(s.into_owned().into_bytes().into()),
+            (SqlType::Blob, v @ (Self::Int(_) | Self::Float(_))) => Self::Blob(v.to_string().into_bytes().into()),
+            (SqlType::Blob, v) => v,
+        }
+    }
+
+    /// Converts the value into an integer or a float for arithmetic, text and blobs use their longest numeric prefix.
+    pub fn to_number(&self) -> Value<'static> {
+        match self {
+            Self::Null => Value::Null,
+            Self::Int(i) => Value::Int(*i),
+            Self::Float(f) => Value::Float(*f),
+            Self::String(s) => numeric_prefix(s),
+            Self::Blob(b) => numeric_prefix(&String::from_utf8_lossy(b)),
+        }
+    }
+
+    /// The name of the type of the value, the one `typeof()` gives.
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Self::Null => "null",
+            Self::Int(_) => "integer",
+            Self::Float(_) => "real",
+            Self::String(_) => "text",
+            Self::Blob(_) => "blob",
         }
     }
+
+    pub fn into_owned(self) -> Value<'static> {
+        match self {
+            Self::String(s) => Value::String(Cow::Owned(s.into_owned())),
+            Self::Float(f) => Value::Float(f),
+            Self::Int(i) => Value::Int(i),
+            Self::Blob(b) => Value::Blob(Cow::Owned(b.into_owned())),
+            Self::Null => Value::Null,
+        }
+    }
+}
+
+/// Compares an integer with a float without losing precision for integers above 2^53.
+fn int_float_cmp(i: i64, f: f64) -> Ordering {
+    if f.is_nan() || f < -9223372036854775808.0 {
+        return Ordering::Greater;
+    }
+    if f >= 9223372036854775808.0 {
+        return Ordering::Less;
+    }
+    let truncated = f as i64;
+    i.cmp(&truncated)
+        .then_with(|| 0.0.partial_cmp(&(f - truncated as f64)).unwrap())
+}
+
+/// Parses text as an integer or real literal, with optional surrounding whitespace, the way SQLite does when
+/// applying NUMERIC affinity. Reals that can be represented exactly as integers become integers.
+fn parse_numeric<'b>(s: &str) -> Option<Value<'b>> {
+    let all_digits = |s: &str| s.bytes().all(|b| b.is_ascii_digit());
+    let t = s.trim_matches(|c: char| c.is_ascii_whitespace());
+    let body = t.strip_prefix(['+', '-']).unwrap_or(t);
+    let (mantissa, exp) = match body.split_once(['e', 'E']) {
+        Some((m, e)) => (m, Some(e.strip_prefix(['+', '-']).unwrap_or(e))),
+        None => (body, None),
+    };
+    let (int, frac) = mantissa.split_once('.').map_or((mantissa, None), |(i, f)| (i, Some(f)));
+    if (int.is_empty() && frac.is_none_or(str::is_empty)) || !all_digits(int) || !frac.is_none_or(all_digits) {
+        return None;
+    }
+    if exp.is_some_and(|e| e.is_empty() || !all_digits(e)) {
+        return None;
+    }
+    if frac.is_none()
+        && exp.is_none()
+        && let Ok(i) = t.parse::<i64>()
+    {
+        return Some(Value::Int(i));
+    }
+    t.parse::<f64>().ok().map(|f| Value::Float(f).numeric_affinity())
+}
+
+/// Converts the longest prefix of the text that looks like a number, text without one converts to 0.
+fn numeric_prefix<'b>(s: &str) -> Value<'b> {
+    let t = s.trim_start_matches(|c: char| c.is_ascii_whitespace());
+    let b = t.as_bytes();
+    let digits_from = |mut i: usize| {
+        while i < b.len() && b[i].is_ascii_digit() {
+            i += 1;
+        }
+        i
+    };
+    let start = if matches!(b.first(), Some(b'+' | b'-')) { 1 } else { 0 };
+    let mut end = digits_from(start);
+    let mut mantissa_digits = end - start;
+    let mut is_int = true;
+    if b.get(end) == Some(&b'.') {
+        let frac_end = digits_from(end + 1);
+        mantissa_digits += frac_end - end - 1;
+        end = frac_end;
+        is_int = false;
+    }
+    if mantissa_digits == 0 {
+        return Value::Int(0);
+    }
+    if matches!(b.get(end), Some(b'e' | b'E')) {
+        let sign = if matches!(b.get(end + 1), Some(b'+' | b'-')) {
+            1
+        } else {
+            0
+        };
+        let exp_end = digits_from(end + 1 + sign);
+        if exp_end > end + 1 + sign {
+            end = exp_end;
+            is_int = false;
+        }
+    }
+    let num = &t[..end];
+    if is_int && let Ok(i) = num.parse::<i64>() {
+        return Value::Int(i);
+    }
+    Value::Float(num.parse().unwrap_or(0.0))
+}
+
+/// Formats a float like SQLite's `%!.15g`, always leaving a decimal point or exponent in the output.
+pub fn format_real(f: f64) -> String {
+    if f.is_nan() {
+        return String::new();
+    }
+    if f.is_infinite() {
+        return if f > 0.0 { "Inf".into() } else { "-Inf".into() };
+    }
+    if f == 0.0 {
+        return "0.0".into();
+    }
+    let sci = format!("{:.14e}", f);
+    let (mantissa, exp) = sci.split_once('e').unwrap();
+    let exp: i32 = exp.parse().unwrap();
+    let (sign, mantissa) = mantissa.strip_prefix('-').map_or(("", mantissa), |m| ("-", m));
+    let digits: String = mantissa.chars().filter(|c| *c != '.').collect();
+    let digits = digits.trim_end_matches('0');
+    let digits = if digits.is_empty() { "0" } else { digits };
+    if !(-4..15).contains(&exp) {
+        let (first, rest) = digits.split_at(1);
+        let rest = if rest.is_empty() { "0" } else { rest };
+        let exp_sign = if exp < 0 { '-' } else { '+' };
+        return format!("{sign}{first}.{rest}e{exp_sign}{:02}", exp.abs());
+    }
+    if exp < 0 {
+        let zeros = "0".repeat((-exp - 1) as usize);
+        return format!("{sign}0.{zeros}{digits}");
+    }
+    let int_len = exp as usize + 1;
+    if digits.len() <= int_len {
+        let zeros = "0".repeat(int_len - digits.len());
+        format!("{sign}{digits}{zeros}.0")
+    } else {
+        let (int, frac) = digits.split_at(int_len);
+        format!("{sign}{int}.{frac}")
+    }
+}
+
+impl PartialEq for Value<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        self.sql_cmp(other) == Ordering::Equal
+    }
+}
+
+impl PartialOrd for Value<'_> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.sql_cmp(other))
+    }
 }
 
 impl Display for Value<'_> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Self::String(v) => write!(f, "{v}"),
-            Self::Float(v) => write!(f, "{v}"),
+            Self::Float(v) => write!(f, "{}", format_real(*v)),
             Self::Int(v) => write!(f, "{v}"),
+            Self::Blob(v) => write!(f, "{}", String::from_utf8_lossy(v)),
             Self::Null => write!(f, ""),
         }
     }
 }
 
+/// How text values are compared, see <https://sqlite.org/datatype3.html#collation>. Values of other types compare
+/// the same whatever the collation.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum Collation {
+    /// Byte by byte.
+    #[default]
+    Binary,
+    /// Like `Binary`, with the 26 upper case ASCII letters folded to lower case.
+    NoCase,
+    /// Like `Binary`, ignoring trailing spaces.
+    RTrim,
+}
+
+impl Collation {
+    /// The built-in collation by that name, case insensitively.
+    pub fn from_name(name: &str) -> Option<Self> {
+        [
+            ("binary", Self::Binary),
+            ("nocase", Self::NoCase),
+            ("rtrim", Self::RTrim),
+        ]
+        .into_iter()
+        .find_map(|(n, c)| n.eq_ignore_ascii_case(name).then_some(c))
+    }
+
+    /// Compares values like [`Value::sql_cmp`], with text compared by the collation.
+    pub fn compare(self, l: &Value, r: &Value) -> Ordering {
+        match (self, l, r) {
+            (Self::NoCase, Value::String(a), Value::String(b)) => {
+                let fold = |s: &str| s.bytes().map(|b| b.to_ascii_lowercase()).collect::<Vec<_>>();
+                fold(a).cmp(&fold(b))
+            }
+            (Self::RTrim, Value::String(a), Value::String(b)) => a
+                .trim_end_matches(' ')
+                .as_bytes()
+                .cmp(b.trim_end_matches(' ').as_bytes()),
+            _ => l.sql_cmp(r),
+        }
+    }
+
+    /// The value with its text changed so that values the collation finds equal are equal, to hash them.
+    pub fn fold(self, value: Value<'_>) -> Value<'_> {
+        match (self, value) {
+            (Self::NoCase, Value::String(s)) => Value::String(s.to_ascii_lowercase().into()),
+            (Self::RTrim, Value::String(s)) => Value::String(s.trim_end_matches(' ').to_owned().into()),
+            (_, value) => value,
+        }
+    }
+}
+
+/// Column type affinity, derived from the declared type of a column.
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub enum SqlType {
     Integer,
@@ -41,48 +328,795 @@ pub enum SqlType {
     Blob,
 }
 
-#[derive(Debug, PartialEq, Eq)]
-pub enum SelectCols<'a> {
-    List(Vec<&'a str>),
+impl SqlType {
+    /// Determines the affinity of a declared column type, using the rules from
+    /// <https://sqlite.org/datatype3.html#determination_of_column_affinity>.
+    pub fn from_decl(decl: &str) -> Self {
+        let decl = decl.to_ascii_uppercase();
+        if decl.contains("INT") {
+            Self::Integer
+        } else if decl.contains("CHAR") || decl.contains("CLOB") || decl.contains("TEXT") {
+            Self::Text
+        } else if decl.contains("BLOB") || decl.trim().is_empty() {
+            Self::Blob
+        } else if decl.contains("REAL") || decl.contains("FLOA") || decl.contains("DOUB") {
+            Self::Real
+        } else {
+            Self::Numeric
+        }
+    }
+
+    /// Whether values compared against this affinity should be converted to numbers.
+    pub fn is_numeric(self) -> bool {
+        matches!(self, Self::Integer | Self::Real | Self::Numeric)
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum ResultColumn<'a> {
+    /// `*`, every column of the tables.
     All,
+    /// `table.*`, every column of one table.
+    TableAll(&'a str),
+    Expr {
+        expr: Expr<'a>,
+        alias: Option<&'a str>,
+        /// The expression as written, which names the column when it has no alias and isn't a column reference.
+        text: &'a str,
+    },
 }
 
-#[derive(Debug, PartialEq, Eq)]
-pub enum SelectColStmt<'a> {
-    List(SelectCols<'a>),
-    Count(SelectCols<'a>),
-    Avg(&'a str),
+#[derive(Debug, PartialEq, Clone)]
+pub struct OrderingTerm<'a> {
+    pub expr: Expr<'a>,
+    pub desc: bool,
+    /// Whether NULLs come first, without `NULLS FIRST` or `NULLS LAST` they sort as the smallest value.
+    pub nulls_first: bool,
 }
 
-#[derive(Debug, PartialEq)]
+/// `LIMIT count OFFSET offset`, also written `LIMIT offset, count`.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Limit<'a> {
+    pub count: Expr<'a>,
+    pub offset: Option<Expr<'a>>,
+}
+
+/// A table of the FROM clause, `name AS alias`, `(SELECT ...) AS alias` or `function(args) AS alias`.
+#[derive(Debug, PartialEq, Clone)]
+pub struct TableRef<'a> {
+    pub source: TableSource<'a>,
+    pub alias: Option<&'a str>,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum TableSource<'a> {
+    Table(&'a str),
+    Subquery(Box<Select<'a>>),
+    /// A table-valued function, whose rows are computed from its arguments.
+    Function {
+        name: &'a str,
+        args: Vec<Expr<'a>>,
+    },
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum JoinConstraint<'a> {
+    On(Expr<'a>),
+    /// `USING (columns)`, the columns have to be equal in both tables.
+    Using(Vec<&'a str>),
+}
+
+/// A table joined to the ones before it. Comma joins and `CROSS JOIN` are inner joins without a constraint.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Join<'a> {
+    pub table: TableRef<'a>,
+    /// Whether it's a `LEFT JOIN`, which keeps the rows without a match in this table.
+    pub left: bool,
+    pub constraint: Option<JoinConstraint<'a>>,
+}
+
+/// A common table expression of a WITH clause.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Cte<'a> {
+    pub name: &'a str,
+    /// Names of the columns of the table, the ones of the result columns are used when there are none.
+    pub columns: Vec<&'a str>,
+    pub select: Select<'a>,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum CompoundOp {
+    Union,
+    UnionAll,
+    Intersect,
+    Except,
+}
+
+#[derive(Debug, PartialEq, Clone)]
 pub struct Select<'a> {
-    pub columns: SelectColStmt<'a>,
-    pub table: &'a str,
-    pub expr: Option<WhereExpr<'a>>,
+    /// The tables of the WITH clause, which the statement and its subqueries can read.
+    pub with: Vec<Cte<'a>>,
+    /// Whether repeated result rows are left out.
+    pub distinct: bool,
+    pub columns: Vec<ResultColumn<'a>>,
+    /// `None` for a SELECT without a FROM clause, which produces a single row.
+    pub from: Option<TableRef<'a>>,
+    pub joins: Vec<Join<'a>>,
+    pub expr: Option<Expr<'a>>,
+    pub group_by: Vec<Expr<'a>>,
+    pub having: Option<Expr<'a>>,
+    /// The windows of the WINDOW clause, which the window function calls of the select can refer to by name.
+    pub windows: Vec<(&'a str, WindowDef<'a>)>,
+    /// The selects whose rows are combined with the ones of this one, in order. Their own WITH, ORDER BY and LIMIT
+    /// are empty, the ones of this select apply to the rows of the whole compound.
+    pub compound: Vec<(CompoundOp, Select<'a>)>,
+    pub order_by: Vec<OrderingTerm<'a>>,
+    pub limit: Option<Limit<'a>>,
 }
 
+impl Select<'_> {
+    /// Whether an expression of the select, of its FROM subqueries or of the tables of its WITH clause is more than
+    /// `max` levels deep.
+    pub fn deeper_than(&self, max: usize) -> bool {
+        let tables = self.from.iter().chain(self.joins.iter().map(|j| &j.table));
+        let mut exprs: Vec<&Expr> = vec![];
+        let mut selects: Vec<&Select> = self.with.iter().map(|c| &c.select).collect();
+        selects.extend(self.compound.iter().map(|(_, s)| s));
+        for table in tables {
+            match &table.source {
+                TableSource::Table(_) => {}
+                TableSource::Subquery(select) => selects.push(select),
+                TableSource::Function { args, .. } => exprs.extend(args),
+            }
+        }
+        for column in &self.columns {
+            if let ResultColumn::Expr { expr, .. } = column {
+                exprs.push(expr);
+            }
+        }
+        for join in &self.joins {
+            if let Some(JoinConstraint::On(e)) = &join.constraint {
+                exprs.push(e);
+            }
+        }
+        exprs.extend(self.expr.iter().chain(&self.group_by).chain(&self.having));
+        exprs.extend(self.windows.iter().flat_map(|(_, w)| w.exprs()));
+        exprs.extend(self.order_by.iter().map(|t| &t.expr));
+        if let Some(limit) = &self.limit {
+            exprs.extend(std::iter::once(&limit.count).chain(&limit.offset));
+        }
+        exprs.iter().any(|e| e.deeper_than(max)) || selects.iter().any(|s| s.deeper_than(max))
+    }
+}
+
+/// The window of a window function call, `OVER (PARTITION BY ... ORDER BY ... frame)`. A call can name a window of
+/// the WINDOW clause instead, `OVER name`, or extend it, `OVER (name ORDER BY ...)`.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct WindowDef<'a> {
+    pub base: Option<&'a str>,
+    pub partition_by: Vec<Expr<'a>>,
+    pub order_by: Vec<OrderingTerm<'a>>,
+    /// The rows of the partition the function is computed over, for each row. Without one, they are the rows up to the
+    /// last one sorting like the current row.
+    pub frame: Option<Frame<'a>>,
+}
+
+impl<'a> WindowDef<'a> {
+    /// The expressions of the PARTITION BY and ORDER BY clauses and of the bounds of the frame.
+    pub fn exprs(&self) -> Vec<&Expr<'a>> {
+        let mut exprs: Vec<_> = self
+            .partition_by
+            .iter()
+            .chain(self.order_by.iter().map(|t| &t.expr))
+            .collect();
+        if let Some(frame) = &self.frame {
+            for bound in [&frame.start, &frame.end] {
+                if let FrameBound::Preceding(e) | FrameBound::Following(e) = bound {
+                    exprs.push(e);
+                }
+            }
+        }
+        exprs
+    }
+}
+
+/// `ROWS`, `RANGE` or `GROUPS` `BETWEEN start AND end`, an end that isn't given is the current row.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Frame<'a> {
+    pub units: FrameUnits,
+    pub start: FrameBound<'a>,
+    pub end: FrameBound<'a>,
+    pub exclude: FrameExclude,
+}
+
+/// What the offsets of a frame count: rows, differences of the value of the ORDER BY term, or groups of rows
+/// sorting alike.
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum FrameUnits {
+    Rows,
+    Range,
+    Groups,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum FrameBound<'a> {
+    UnboundedPreceding,
+    Preceding(Expr<'a>),
+    CurrentRow,
+    Following(Expr<'a>),
+    UnboundedFollowing,
+}
+
+/// The rows of the frame left out, `EXCLUDE NO OTHERS` leaves out none.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum FrameExclude {
+    #[default]
+    NoOthers,
+    CurrentRow,
+    /// The current row and the ones sorting like it.
+    Group,
+    /// The rows sorting like the current row, but not the current row itself.
+    Ties,
+}
+
+#[derive(Debug, PartialEq, Clone)]
 pub struct ColumnDef<'a> {
     pub sql_type: SqlType,
+    /// Type name as written in the column definition, empty if none was given.
+    pub decl_type: &'a str,
     pub name: &'a str,
     pub primary_key: bool,
+    /// Value of the `DEFAULT` clause, also used for rows written before the column was added.
+    pub default: Option<Value<'a>>,
+    /// Name of the `COLLATE` clause, the collation comparisons of the column use.
+    pub collation: Option<&'a str>,
+    pub generated: Option<Generated<'a>>,
+}
+
+impl ColumnDef<'_> {
+    /// Whether the column is computed each time it's read, rather than stored in the records.
+    pub fn is_virtual(&self) -> bool {
+        self.generated.as_ref().is_some_and(|g| !g.stored)
+    }
 }
 
-#[derive(Debug, PartialEq, Eq)]
+/// `GENERATED ALWAYS AS (expr) STORED` or `VIRTUAL`, the column's value is computed from the other columns of its row.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Generated<'a> {
+    pub expr: Expr<'a>,
+    pub stored: bool,
+}
+
+#[derive(Debug, PartialEq, Clone)]
 pub struct CreateTable<'a> {
     pub table_name: &'a str,
     pub columns: Vec<ColumnDef<'a>>,
-    pub primary_key: usize,
+    /// Indices of the `PRIMARY KEY` columns, in key order.
+    pub primary_key: Vec<usize>,
+    /// The `PRIMARY KEY` columns with their collations and sort orders, which order the b-tree of a `WITHOUT ROWID`
+    /// table.
+    pub key_columns: Vec<IndexedColumn<'a>>,
+    /// Index of the `INTEGER PRIMARY KEY` column, whose value is the rowid itself.
+    pub rowid_alias: Option<usize>,
+    pub without_rowid: bool,
+}
+
+impl CreateTable<'_> {
+    /// Indices of the columns in the order their values are stored in the table's records, virtual generated columns
+    /// aren't stored.
+    ///
+    /// `WITHOUT ROWID` tables are index b-trees keyed by the primary key, so the key columns come first and the
+    /// remaining ones follow in declaration order.
+    pub fn storage_order(&self) -> Vec<usize> {
+        let stored = (0..self.columns.len()).filter(|&i| !self.columns[i].is_virtual());
+        if !self.without_rowid {
+            return stored.collect();
+        }
+        let mut order = self.primary_key.clone();
+        order.extend(stored.filter(|i| !self.primary_key.contains(i)));
+        order
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct IndexedColumn<'a> {
+    pub name: &'a str,
+    pub collation: Option<&'a str>,
+    pub desc: bool,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct CreateIndex<'a> {
+    pub index_name: &'a str,
+    pub table_name: &'a str,
+    pub unique: bool,
+    pub columns: Vec<IndexedColumn<'a>>,
+    /// The `WHERE` clause of a partial index, which only holds the rows matching it.
+    pub partial: Option<Expr<'a>>,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum UnaryOp {
+    Neg,
+    Plus,
+    Not,
+    BitNot,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+    Concat,
+    /// `->`, the JSON of the element of a JSON value at a path.
+    Arrow,
+    /// `->>`, the SQL value of the element of a JSON value at a path.
+    LongArrow,
+    BitAnd,
+    BitOr,
+    ShiftLeft,
+    ShiftRight,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Is,
+    IsNot,
+    And,
+    Or,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Expr<'a> {
+    Literal(Value<'a>),
+    Column {
+        table: Option<&'a str>,
+        name: &'a str,
+    },
+    Unary(UnaryOp, Box<Expr<'a>>),
+    Binary(Box<Expr<'a>>, BinaryOp, Box<Expr<'a>>),
+    Between {
+        expr: Box<Expr<'a>>,
+        low: Box<Expr<'a>>,
+        high: Box<Expr<'a>>,
+        negated: bool,
+    },
+    InList {
+        expr: Box<Expr<'a>>,
+        list: Vec<Expr<'a>>,
+        negated: bool,
+    },
+    /// `LIKE`, or `GLOB` when `glob` is set.
+    Like {
+        expr: Box<Expr<'a>>,
+        pattern: Box<Expr<'a>>,
+        escape: Option<Box<Expr<'a>>>,
+        glob: bool,
+        negated: bool,
+    },
+    Case {
+        operand: Option<Box<Expr<'a>>>,
+        branches: Vec<(Expr<'a>, Expr<'a>)>,
+        otherwise: Option<Box<Expr<'a>>>,
+    },
+    Cast(Box<Expr<'a>>, SqlType),
+    /// `expr COLLATE name`, the collation comparisons of the expression use.
+    Collate(Box<Expr<'a>>, &'a str),
+    /// A parameter placeholder as written, e.g. `?2` or `:name`. Its offset in the text tells apart the `?`
+    /// placeholders, which stand for different parameters.
+    Parameter {
+        name: &'a str,
+        offset: usize,
+    },
+    /// A function call, `f(*)` is parsed as a call without arguments.
+    Function {
+        name: &'a str,
+        args: Vec<Expr<'a>>,
+        distinct: bool,
+        /// The scalar function the call is bound to when the statement is compiled, an index into the functions the
+        /// engine has. `None` for the calls to aggregates and before then.
+        function: Option<usize>,
+    },
+    /// A window function call, `name(args) OVER window`, computed over rows of the result related to the current one.
+    Window {
+        name: &'a str,
+        args: Vec<Expr<'a>>,
+        distinct: bool,
+        window: Box<WindowDef<'a>>,
+    },
+    /// A scalar subquery, the value of the first column of its first row, NULL if it has none.
+    Subquery(Box<Select<'a>>),
+    /// `EXISTS (SELECT ...)`.
+    Exists(Box<Select<'a>>),
+    InSelect {
+        expr: Box<Expr<'a>>,
+        select: Box<Select<'a>>,
+        negated: bool,
+    },
+}
+
+impl<'a> Expr<'a> {
+    pub fn binary(l: Expr<'a>, op: BinaryOp, r: Expr<'a>) -> Self {
+        Self::Binary(Box::new(l), op, Box::new(r))
+    }
+
+    pub fn column(name: &'a str) -> Self {
+        Self::Column { table: None, name }
+    }
+
+    /// Calls `f` on the expression and its subexpressions, depth first. The subexpressions of an expression are
+    /// skipped when `f` returns false for it. Subqueries are not walked into, their expressions belong to a query of
+    /// their own.
+    pub fn walk<'e>(&'e self, f: &mut impl FnMut(&'e Expr<'a>) -> bool) {
+        if f(self) {
+            self.for_each_child(&mut |e| e.walk(f));
+        }
+    }
+
+    /// Calls `f` on each subexpression of the expression, leaving out the ones of its subqueries.
+    fn for_each_child<'e>(&'e self, f: &mut impl FnMut(&'e Expr<'a>)) {
+        match self {
+            Self::Literal(_) | Self::Column { .. } | Self::Parameter { .. } | Self::Subquery(_) | Self::Exists(_) => {}
+            Self::Unary(_, e) | Self::Cast(e, _) | Self::Collate(e, _) | Self::InSelect { expr: e, .. } => f(e),
+            Self::Binary(l, _, r) => {
+                f(l);
+                f(r);
+            }
+            Self::Between { expr, low, high, .. } => {
+                f(expr);
+                f(low);
+                f(high);
+            }
+            Self::InList { expr, list, .. } => {
+                f(expr);
+                list.iter().for_each(f);
+            }
+            Self::Like {
+                expr, pattern, escape, ..
+            } => {
+                f(expr);
+                f(pattern);
+                if let Some(e) = escape {
+                    f(e);
+                }
+            }
+            Self::Case {
+                operand,
+                branches,
+                otherwise,
+            } => {
+                if let Some(e) = operand {
+                    f(e);
+                }
+                for (when, then) in branches {
+                    f(when);
+                    f(then);
+                }
+                if let Some(e) = otherwise {
+                    f(e);
+                }
+            }
+            Self::Function { args, .. } => args.iter().for_each(f),
+            Self::Window { args, window, .. } => {
+                args.iter().for_each(&mut *f);
+                window.exprs().into_iter().for_each(f);
+            }
+        }
+    }
+
+    /// Whether the expression is more than `max` levels deep, the expressions of its subqueries being a level below it
+    /// like SQLite counts them. It looks no more than `max` levels down.
+    pub fn deeper_than(&self, max: usize) -> bool {
+        let Some(max) = max.checked_sub(1) else {
+            return true;
+        };
+        let mut deeper = match self {
+            Self::Subquery(select) | Self::Exists(select) | Self::InSelect { select, .. } => select.deeper_than(max),
+            _ => false,
+        };
+        self.for_each_child(&mut |e| deeper = deeper || e.deeper_than(max));
+        deeper
+    }
+
+    /// Like [`Expr::walk`], with mutable access to the expressions.
+    pub fn walk_mut(&mut self, f: &mut impl FnMut(&mut Expr<'a>) -> bool) {
+        if !f(self) {
+            return;
+        }
+        match self {
+            Self::Literal(_) | Self::Column { .. } | Self::Parameter { .. } | Self::Subquery(_) | Self::Exists(_) => {}
+            Self::Unary(_, e) | Self::Cast(e, _) | Self::Collate(e, _) | Self::InSelect { expr: e, .. } => {
+                e.walk_mut(f)
+            }
+            Self::Binary(l, _, r) => {
+                l.walk_mut(f);
+                r.walk_mut(f);
+            }
+            Self::Between { expr, low, high, .. } => {
+                expr.walk_mut(f);
+                low.walk_mut(f);
+                high.walk_mut(f);
+            }
+            Self::InList { expr, list, .. } => {
+                expr.walk_mut(f);
+                list.iter_mut().for_each(|e| e.walk_mut(f));
+            }
+            Self::Like {
+                expr, pattern, escape, ..
+            } => {
+                expr.walk_mut(f);
+                pattern.walk_mut(f);
+                if let Some(e) = escape {
+                    e.walk_mut(f);
+                }
+            }
+            Self::Case {
+                operand,
+                branches,
+                otherwise,
+            } => {
+                if let Some(e) = operand {
+                    e.walk_mut(f);
+                }
+                for (when, then) in branches {
+                    when.walk_mut(f);
+                    then.walk_mut(f);
+                }
+                if let Some(e) = otherwise {
+                    e.walk_mut(f);
+                }
+            }
+            Self::Function { args, .. } => args.iter_mut().for_each(|e| e.walk_mut(f)),
+            Self::Window { args, window, .. } => {
+                args.iter_mut().for_each(|e| e.walk_mut(f));
+                window.partition_by.iter_mut().for_each(|e| e.walk_mut(f));
+                window.order_by.iter_mut().for_each(|t| t.expr.walk_mut(f));
+                if let Some(frame) = &mut window.frame {
+                    for bound in [&mut frame.start, &mut frame.end] {
+                        if let FrameBound::Preceding(e) | FrameBound::Following(e) = bound {
+                            e.walk_mut(f);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl BinaryOp {
+    /// The operator as written in SQL.
+    pub fn symbol(self) -> &'static str {
+        match self {
+            Self::Add => "+",
+            Self::Sub => "-",
+            Self::Mul => "*",
+            Self::Div => "/",
+            Self::Rem => "%",
+            Self::Concat => "||",
+            Self::Arrow => "->",
+            Self::LongArrow => "->>",
+            Self::BitAnd => "&",
+            Self::BitOr => "|",
+            Self::ShiftLeft => "<<",
+            Self::ShiftRight => ">>",
+            Self::Eq => "=",
+            Self::Ne => "!=",
+            Self::Lt => "<",
+            Self::Le => "<=",
+            Self::Gt => ">",
+            Self::Ge => ">=",
+            Self::Is => "IS",
+            Self::IsNot => "IS NOT",
+            Self::And => "AND",
+            Self::Or => "OR",
+        }
+    }
+}
+
+/// Writes the expression back as SQL, with parentheses around the operands that are operations themselves rather than
+/// the ones precedence would need. Subqueries are written as `(SELECT ...)`.
+impl Display for Expr<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let not = |negated: bool| if negated { "NOT " } else { "" };
+        match self {
+            Self::Literal(Value::String(s)) => write!(f, "'{}'", s.replace('\'', "''")),
+            Self::Literal(Value::Blob(b)) => {
+                write!(f, "x'")?;
+                b.iter().try_for_each(|b| write!(f, "{b:02X}"))?;
+                write!(f, "'")
+            }
+            Self::Literal(Value::Null) => write!(f, "NULL"),
+            Self::Literal(v) => write!(f, "{v}"),
+            Self::Column { table: Some(t), name } => write!(f, "{t}.{name}"),
+            Self::Column { table: None, name } => write!(f, "{name}"),
+            Self::Parameter { name, .. } => write!(f, "{name}"),
+            Self::Unary(op, e) => {
+                let op = match op {
+                    UnaryOp::Neg => "-",
+                    UnaryOp::Plus => "+",
+                    UnaryOp::Not => "NOT ",
+                    UnaryOp::BitNot => "~",
+                };
+                write!(f, "{op}{}", Operand(e))
+            }
+            Self::Binary(l, op, r) => write!(f, "{} {} {}", Operand(l), op.symbol(), Operand(r)),
+            Self::Between {
+                expr,
+                low,
+                high,
+                negated,
+            } => write!(
+                f,
+                "{} {}BETWEEN {} AND {}",
+                Operand(expr),
+                not(*negated),
+                Operand(low),
+                Operand(high)
+            ),
+            Self::InList { expr, list, negated } => {
+                write!(f, "{} {}IN (", Operand(expr), not(*negated))?;
+                for (i, e) in list.iter().enumerate() {
+                    write!(f, "{}{e}", if i > 0 { ", " } else { "" })?;
+                }
+                write!(f, ")")
+            }
+            Self::Like {
+                expr,
+                pattern,
+                escape,
+                glob,
+                negated,
+            } => {
+                let op = if *glob { "GLOB" } else { "LIKE" };
+                write!(f, "{} {}{op} {}", Operand(expr), not(*negated), Operand(pattern))?;
+                match escape {
+                    Some(e) => write!(f, " ESCAPE {}", Operand(e)),
+                    None => Ok(()),
+                }
+            }
+            Self::Case {
+                operand,
+                branches,
+                otherwise,
+            } => {
+                write!(f, "CASE")?;
+                if let Some(e) = operand {
+                    write!(f, " {e}")?;
+                }
+                for (when, then) in branches {
+                    write!(f, " WHEN {when} THEN {then}")?;
+                }
+                if let Some(e) = otherwise {
+                    write!(f, " ELSE {e}")?;
+                }
+                write!(f, " END")
+            }
+            Self::Cast(e, ty) => write!(f, "CAST({e} AS {})", format!("{ty:?}").to_uppercase()),
+            Self::Collate(e, name) => write!(f, "{} COLLATE {name}", Operand(e)),
+            Self::Function {
+                name, args, distinct, ..
+            } => {
+                write!(f, "{name}({}", if *distinct { "DISTINCT " } else { "" })?;
+                // `count(*)` is parsed as a call without arguments.
+                if args.is_empty() && name.eq_ignore_ascii_case("count") {
+                    write!(f, "*")?;
+                }
+                for (i, e) in args.iter().enumerate() {
+                    write!(f, "{}{e}", if i > 0 { ", " } else { "" })?;
+                }
+                write!(f, ")")
+            }
+            Self::Window {
+                name,
+                args,
+                distinct,
+                window,
+            } => {
+                let call = Self::Function {
+                    name,
+                    args: args.clone(),
+                    distinct: *distinct,
+                    function: None,
+                };
+                match window.as_ref() {
+                    WindowDef {
+                        base: Some(base),
+                        partition_by,
+                        order_by,
+                        frame: None,
+                    } if partition_by.is_empty() && order_by.is_empty() => write!(f, "{call} OVER {base}"),
+                    window => write!(f, "{call} OVER ({window})"),
+                }
+            }
+            Self::Subquery(_) => write!(f, "(SELECT ...)"),
+            Self::Exists(_) => write!(f, "EXISTS (SELECT ...)"),
+            Self::InSelect { expr, negated, .. } => write!(f, "{} {}IN (SELECT ...)", Operand(expr), not(*negated)),
+        }
+    }
+}
+
+impl Display for WindowDef<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut clauses = vec![];
+        clauses.extend(self.base.map(str::to_string));
+        if !self.partition_by.is_empty() {
+            let exprs: Vec<_> = self.partition_by.iter().map(|e| e.to_string()).collect();
+            clauses.push(format!("PARTITION BY {}", exprs.join(", ")));
+        }
+        if !self.order_by.is_empty() {
+            let terms: Vec<_> = self
+                .order_by
+                .iter()
+                .map(|t| match (t.desc, t.nulls_first) {
+                    (false, true) => t.expr.to_string(),
+                    (false, false) => format!("{} NULLS LAST", t.expr),
+                    (true, false) => format!("{} DESC", t.expr),
+                    (true, true) => format!("{} DESC NULLS FIRST", t.expr),
+                })
+                .collect();
+            clauses.push(format!("ORDER BY {}", terms.join(", ")));
+        }
+        if let Some(frame) = &self.frame {
+            let bound = |bound: &FrameBound| match bound {
+                FrameBound::UnboundedPreceding => "UNBOUNDED PRECEDING".to_string(),
+                FrameBound::Preceding(e) => format!("{e} PRECEDING"),
+                FrameBound::CurrentRow => "CURRENT ROW".to_string(),
+                FrameBound::Following(e) => format!("{e} FOLLOWING"),
+                FrameBound::UnboundedFollowing => "UNBOUNDED FOLLOWING".to_string(),
+            };
+            let units = match frame.units {
+                FrameUnits::Rows => "ROWS",
+                FrameUnits::Range => "RANGE",
+                FrameUnits::Groups => "GROUPS",
+            };
+            let exclude = match frame.exclude {
+                FrameExclude::NoOthers => "",
+                FrameExclude::CurrentRow => " EXCLUDE CURRENT ROW",
+                FrameExclude::Group => " EXCLUDE GROUP",
+                FrameExclude::Ties => " EXCLUDE TIES",
+            };
+            clauses.push(format!(
+                "{units} BETWEEN {} AND {}{exclude}",
+                bound(&frame.start),
+                bound(&frame.end)
+            ));
+        }
+        write!(f, "{}", clauses.join(" "))
+    }
+}
+
+/// An operand of an operator, in parentheses when it's an operation itself.
+struct Operand<'e, 'a>(&'e Expr<'a>);
+
+impl Display for Operand<'_, '_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.0 {
+            Expr::Binary(..)
+            | Expr::Between { .. }
+            | Expr::InList { .. }
+            | Expr::Like { .. }
+            | Expr::InSelect { .. } => {
+                write!(f, "({})", self.0)
+            }
+            e => write!(f, "{e}"),
+        }
+    }
 }
 
+/// A statement the database can run.
 #[derive(Debug, PartialEq, Clone)]
-pub enum WhereExpr<'a> {
-    Neq(&'a str, Value<'a>),
-    Eq(&'a str, Value<'a>),
-    Leq(&'a str, Value<'a>),
-    Geq(&'a str, Value<'a>),
-    Le(&'a str, Value<'a>),
-    Ge(&'a str, Value<'a>),
-    And(Box<WhereExpr<'a>>, Box<WhereExpr<'a>>),
-    Or(Box<WhereExpr<'a>>, Box<WhereExpr<'a>>),
+pub enum Statement<'a> {
+    Select(Select<'a>),
+    /// `EXPLAIN <select>`, lists the program the select compiles to instead of running it.
+    Explain(Select<'a>),
+    /// `EXPLAIN QUERY PLAN <select>`, describes how the select reads its tables instead of running it.
+    ExplainQueryPlan(Select<'a>),
+    /// `ANALYZE [<table or index>]`, gathers the statistics of the tables and indexes into `sqlite_stat1`, all of them
+    /// when none is named.
+    Analyze(Option<&'a str>),
 }
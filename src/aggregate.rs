@@ -0,0 +1,733 @@
+//! Aggregate functions and the grouping of rows they are computed over.
+//!
+//! Rows fed to an aggregation are flat lists of values: the group key, then the arguments of each aggregate call, then
+//! the values of the bare columns, the ones used outside of aggregates. Like SQLite, bare columns are taken from the
+//! first row of a group, or from the row the last min() or max() call took its result from.
+
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::hash::DefaultHasher;
+use std::hash::Hash;
+use std::hash::Hasher;
+use std::rc::Rc;
+
+use anyhow::Result;
+use anyhow::bail;
+use parser::Collation;
+use parser::SqlType;
+use parser::Value;
+
+use crate::json::GroupArray;
+use crate::json::GroupObject;
+use crate::spill::RunReader;
+use crate::spill::RunWriter;
+use crate::spill::TempFile;
+use crate::spill::row_size;
+
+pub type AggregateRow = Vec<Value<'static>>;
+
+/// Number of files the rows of groups that don't fit in memory are split into.
+const PARTITIONS: usize = 16;
+
+/// An aggregate function call over the rows of a group.
+pub trait Aggregate {
+    /// Adds the arguments of a row.
+    fn step(&mut self, args: &[Value]) -> Result<()>;
+
+    /// The result over the rows added so far.
+    fn finalize(&self) -> Result<Value<'static>>;
+
+    /// For min() and max(), whether the result comes from the last row added.
+    fn picked_row(&self) -> Option<bool> {
+        None
+    }
+}
+
+/// Creates the state of an aggregate function for a new group.
+pub type AggregateFactory = fn() -> Box<dyn Aggregate>;
+
+/// Creates the state of an aggregate function for a new group, built-in or defined by the application.
+pub type AggregateConstructor = Rc<dyn Fn() -> Box<dyn Aggregate>>;
+
+/// Built-in aggregate functions, with the numbers of arguments they take.
+const BUILTINS: &[(&str, &[usize], AggregateFactory)] = &[
+    ("avg", &[1], || Box::new(Sum::new(SumKind::Avg))),
+    ("count", &[0, 1], || Box::new(Count(0))),
+    ("group_concat", &[1, 2], || Box::new(GroupConcat(None))),
+    ("json_group_array", &[1], || Box::new(GroupArray::new(false))),
+    ("json_group_object", &[2], || Box::new(GroupObject::new(false))),
+    ("jsonb_group_array", &[1], || Box::new(GroupArray::new(true))),
+    ("jsonb_group_object", &[2], || Box::new(GroupObject::new(true))),
+    ("max", &[1], || Box::new(MinMax::new(true))),
+    ("min", &[1], || Box::new(MinMax::new(false))),
+    ("string_agg", &[2], || Box::new(GroupConcat(None))),
+    ("sum", &[1], || Box::new(Sum::new(SumKind::Sum))),
+    ("total", &[1], || Box::new(Sum::new(SumKind::Total))),
+];
+
+/// The aggregate function with that name taking that many arguments.
+pub fn find_aggregate(name: &str, args: usize) -> Option<AggregateFactory> {
+    BUILTINS
+        .iter()
+        .find(|(n, arities, _)| n.eq_ignore_ascii_case(name) && arities.contains(&args))
+        .map(|(_, _, factory)| *factory)
+}
+
+/// Whether a call to the function with that many arguments is an aggregate.
+pub fn is_aggregate(name: &str, args: usize) -> bool {
+    find_aggregate(name, args).is_some()
+}
+
+/// Whether there is an aggregate function with that name, whatever number of arguments it takes.
+pub fn is_aggregate_name(name: &str) -> bool {
+    BUILTINS.iter().any(|(n, _, _)| n.eq_ignore_ascii_case(name))
+}
+
+/// `count(*)` has no arguments and counts every row, `count(x)` counts the rows where `x` is not NULL.
+struct Count(i64);
+
+impl Aggregate for Count {
+    fn step(&mut self, args: &[Value]) -> Result<()> {
+        self.0 += !matches!(args.first(), Some(Value::Null)) as i64;
+        Ok(())
+    }
+
+    fn finalize(&self) -> Result<Value<'static>> {
+        Ok(Value::Int(self.0))
+    }
+}
+
+#[derive(Clone, Copy)]
+enum SumKind {
+    /// An integer while every value is one, failing on overflow, NULL without values.
+    Sum,
+    /// Always a real, 0.0 without values.
+    Total,
+    /// A real, NULL without values.
+    Avg,
+}
+
+/// State shared by sum(), total() and avg(). Integers are added exactly until a real shows up or they overflow, from
+/// then on the sum is a real kept with Kahan-Babuska-Neumaier compensation, like SQLite does.
+struct Sum {
+    kind: SumKind,
+    count: i64,
+    int: i64,
+    real: f64,
+    err: f64,
+    approx: bool,
+    overflow: bool,
+}
+
+/// Integers at least this large in magnitude don't fit a real exactly, they are added in two parts.
+const EXACT_REAL_LIMIT: i64 = 1 << 52;
+
+impl Sum {
+    fn new(kind: SumKind) -> Self {
+        Self {
+            kind,
+            count: 0,
+            int: 0,
+            real: 0.0,
+            err: 0.0,
+            approx: false,
+            overflow: false,
+        }
+    }
+
+    fn start_real(&mut self) {
+        self.approx = true;
+        if self.int <= -EXACT_REAL_LIMIT || self.int >= EXACT_REAL_LIMIT {
+            let small = self.int % 16384;
+            self.real = (self.int - small) as f64;
+            self.err = small as f64;
+        } else {
+            self.real = self.int as f64;
+            self.err = 0.0;
+        }
+    }
+
+    fn add_real(&mut self, r: f64) {
+        let s = self.real;
+        let t = s + r;
+        if s.abs() > r.abs() {
+            self.err += (s - t) + r;
+        } else {
+            self.err += (r - t) + s;
+        }
+        self.real = t;
+    }
+
+    fn add_int(&mut self, i: i64) {
+        if i <= -EXACT_REAL_LIMIT || i >= EXACT_REAL_LIMIT {
+            let small = i % 16384;
+            self.add_real((i - small) as f64);
+            self.add_real(small as f64);
+        } else {
+            self.add_real(i as f64);
+        }
+    }
+
+    fn real_sum(&self) -> f64 {
+        if !self.approx {
+            self.int as f64
+        } else if self.err.is_finite() {
+            self.real + self.err
+        } else {
+            self.real
+        }
+    }
+}
+
+/// The argument the way sum() sees it: text that looks like a number is one, without reals turning into integers.
+fn numeric_operand(v: &Value) -> Option<Value<'static>> {
+    match v {
+        Value::Null => None,
+        Value::Int(i) => Some(Value::Int(*i)),
+        Value::String(s) if !s.contains(['.', 'e', 'E']) => match v.clone().with_affinity(SqlType::Numeric) {
+            Value::Int(i) => Some(Value::Int(i)),
+            _ => Some(Value::Float(as_real(v))),
+        },
+        v => Some(Value::Float(as_real(v))),
+    }
+}
+
+fn as_real(v: &Value) -> f64 {
+    match v.to_number() {
+        Value::Int(i) => i as f64,
+        Value::Float(f) => f,
+        _ => unreachable!(),
+    }
+}
+
+impl Aggregate for Sum {
+    fn step(&mut self, args: &[Value]) -> Result<()> {
+        let Some(v) = numeric_operand(&args[0]) else {
+            return Ok(());
+        };
+        self.count += 1;
+        match (v, self.approx) {
+            (Value::Int(i), false) => match self.int.checked_add(i) {
+                Some(sum) => self.int = sum,
+                None => {
+                    self.overflow = true;
+                    self.start_real();
+                    self.add_int(i);
+                }
+            },
+            (Value::Int(i), true) => self.add_int(i),
+            (v, approx) => {
+                if !approx {
+                    self.start_real();
+                }
+                // A real makes an overflowed sum() a real instead of an error.
+                self.overflow &= !approx;
+                self.add_real(as_real(&v));
+            }
+        }
+        Ok(())
+    }
+
+    fn finalize(&self) -> Result<Value<'static>> {
+        Ok(match self.kind {
+            SumKind::Total => Value::Float(self.real_sum()),
+            _ if self.count == 0 => Value::Null,
+            SumKind::Avg => Value::Float(self.real_sum() / self.count as f64),
+            SumKind::Sum if self.overflow => bail!("integer overflow"),
+            SumKind::Sum if self.approx => Value::Float(self.real_sum()),
+            SumKind::Sum => Value::Int(self.int),
+        })
+    }
+}
+
+/// min() and max() ignore NULLs and keep the first of the values comparing equal.
+struct MinMax {
+    max: bool,
+    best: Option<Value<'static>>,
+    picked: bool,
+}
+
+impl MinMax {
+    fn new(max: bool) -> Self {
+        Self {
+            max,
+            best: None,
+            picked: false,
+        }
+    }
+}
+
+impl Aggregate for MinMax {
+    fn step(&mut self, args: &[Value]) -> Result<()> {
+        let v = &args[0];
+        self.picked = match &self.best {
+            None => true,
+            Some(_) if matches!(v, Value::Null) => false,
+            Some(best) if self.max => best.sql_cmp(v).is_lt(),
+            Some(best) => best.sql_cmp(v).is_gt(),
+        };
+        if self.picked && !matches!(v, Value::Null) {
+            self.best = Some(v.clone().into_owned());
+        }
+        Ok(())
+    }
+
+    fn finalize(&self) -> Result<Value<'static>> {
+        Ok(self.best.clone().unwrap_or(Value::Null))
+    }
+
+    fn picked_row(&self) -> Option<bool> {
+        Some(self.picked)
+    }
+}
+
+/// Concatenates the values that are not NULL as text, each one after the separator given with it, a comma by default.
+struct GroupConcat(Option<String>);
+
+impl Aggregate for GroupConcat {
+    fn step(&mut self, args: &[Value]) -> Result<()> {
+        let text = |v: &Value| match v.clone().cast(SqlType::Text) {
+            Value::String(s) => s.into_owned(),
+            _ => String::new(),
+        };
+        if matches!(args[0], Value::Null) {
+            return Ok(());
+        }
+        match &mut self.0 {
+            None => self.0 = Some(text(&args[0])),
+            Some(s) => {
+                s.push_str(&args.get(1).map_or(",".to_string(), text));
+                s.push_str(&text(&args[0]));
+            }
+        }
+        Ok(())
+    }
+
+    fn finalize(&self) -> Result<Value<'static>> {
+        Ok(self.0.clone().map_or(Value::Null, |s| Value::String(s.into())))
+    }
+}
+
+/// A call with DISTINCT, which only passes on the first of the values comparing equal.
+struct Distinct {
+    inner: Box<dyn Aggregate>,
+    seen: HashSet<GroupKey>,
+    stepped: bool,
+}
+
+impl Aggregate for Distinct {
+    fn step(&mut self, args: &[Value]) -> Result<()> {
+        self.stepped = self.seen.insert(GroupKey(vec![args[0].clone().into_owned()]));
+        if self.stepped {
+            self.inner.step(args)?;
+        }
+        Ok(())
+    }
+
+    fn finalize(&self) -> Result<Value<'static>> {
+        self.inner.finalize()
+    }
+
+    fn picked_row(&self) -> Option<bool> {
+        self.inner.picked_row().map(|picked| picked && self.stepped)
+    }
+}
+
+/// An aggregate function call in a query.
+pub struct AggregateCall {
+    pub factory: AggregateConstructor,
+    pub args: usize,
+    pub distinct: bool,
+}
+
+impl AggregateCall {
+    fn start(&self) -> Box<dyn Aggregate> {
+        let inner = (self.factory)();
+        if self.distinct {
+            Box::new(Distinct {
+                inner,
+                seen: HashSet::new(),
+                stepped: false,
+            })
+        } else {
+            inner
+        }
+    }
+}
+
+/// How the rows fed to an aggregation are laid out.
+pub struct AggregateSpec {
+    /// Collation of each value of the group key, which decides the values that are in the same group.
+    pub keys: Vec<Collation>,
+    pub calls: Vec<AggregateCall>,
+    /// Number of bare column values.
+    pub bare: usize,
+}
+
+pub struct Group {
+    pub key: Vec<Value<'static>>,
+    aggregates: Vec<Box<dyn Aggregate>>,
+    pub bare: Vec<Value<'static>>,
+    empty: bool,
+}
+
+impl Group {
+    /// A group without rows, its bare columns are NULL.
+    pub fn new(spec: &AggregateSpec, key: Vec<Value<'static>>) -> Self {
+        Self {
+            key,
+            aggregates: spec.calls.iter().map(AggregateCall::start).collect(),
+            bare: vec![Value::Null; spec.bare],
+            empty: true,
+        }
+    }
+
+    fn step(&mut self, spec: &AggregateSpec, mut row: AggregateRow) -> Result<()> {
+        let mut args = &row[spec.keys.len()..];
+        let mut take_bare = self.empty;
+        for (aggregate, call) in self.aggregates.iter_mut().zip(&spec.calls) {
+            aggregate.step(&args[..call.args])?;
+            take_bare = aggregate.picked_row().unwrap_or(take_bare);
+            args = &args[call.args..];
+        }
+        if take_bare {
+            self.bare = row.split_off(row.len() - args.len());
+        }
+        self.empty = false;
+        Ok(())
+    }
+
+    /// Results of the aggregate calls.
+    pub fn results(&self) -> Result<Vec<Value<'static>>> {
+        self.aggregates.iter().map(|a| a.finalize()).collect()
+    }
+}
+
+/// Groups rows that come ordered by their key, so a group is complete as soon as a row with another key shows up.
+pub struct StreamAggregate<'s> {
+    spec: &'s AggregateSpec,
+    current: Option<Group>,
+}
+
+impl<'s> StreamAggregate<'s> {
+    pub fn new(spec: &'s AggregateSpec) -> Self {
+        Self { spec, current: None }
+    }
+
+    /// Adds a row, returns the previous group if the row starts a new one.
+    pub fn push(&mut self, row: AggregateRow) -> Result<Option<Group>> {
+        let key = &row[..self.spec.keys.len()];
+        let done = match &self.current {
+            Some(group) if keys_equal(&self.spec.keys, &group.key, key) => None,
+            _ => self.current.replace(Group::new(self.spec, key.to_vec())),
+        };
+        self.current.as_mut().unwrap().step(self.spec, row)?;
+        Ok(done)
+    }
+
+    /// Returns the last group. Without a GROUP BY there is always one group, even if no rows were pushed.
+    pub fn finish(self) -> Option<Group> {
+        match self.current {
+            None if self.spec.keys.is_empty() => Some(Group::new(self.spec, vec![])),
+            group => group,
+        }
+    }
+}
+
+/// Groups rows in a hash table. Once it takes more than the memory limit, rows of groups that are not in the table yet
+/// are written to temp files, split by the hash of their key, and each file is aggregated on its own afterwards.
+pub struct HashAggregate<'s> {
+    spec: &'s AggregateSpec,
+    index: HashMap<GroupKey, usize>,
+    groups: Vec<Group>,
+    memory: usize,
+    memory_limit: usize,
+    partitions: Vec<(TempFile, RunWriter)>,
+    /// Number of times the rows were partitioned already, each level hashes keys differently.
+    level: u64,
+}
+
+impl<'s> HashAggregate<'s> {
+    pub fn new(spec: &'s AggregateSpec, memory_limit: usize) -> Self {
+        Self::with_level(spec, memory_limit, 0)
+    }
+
+    fn with_level(spec: &'s AggregateSpec, memory_limit: usize, level: u64) -> Self {
+        Self {
+            spec,
+            index: HashMap::new(),
+            groups: vec![],
+            memory: 0,
+            memory_limit,
+            partitions: vec![],
+            level,
+        }
+    }
+
+    pub fn push(&mut self, row: AggregateRow) -> Result<()> {
+        let key = &row[..self.spec.keys.len()];
+        let folded = GroupKey(self.spec.keys.iter().zip(key).map(|(c, v)| c.fold(v.clone())).collect());
+        if let Some(&i) = self.index.get(&folded) {
+            return self.groups[i].step(self.spec, row);
+        }
+        if self.partitions.is_empty() && self.memory <= self.memory_limit {
+            // The key is kept twice, once in the group and once in the index.
+            self.memory += 2 * row_size(&row) + self.spec.calls.len() * size_of::<Box<dyn Aggregate>>();
+            let mut group = Group::new(self.spec, key.to_vec());
+            group.step(self.spec, row)?;
+            self.index.insert(folded, self.groups.len());
+            self.groups.push(group);
+            return Ok(());
+        }
+        if self.partitions.is_empty() {
+            for _ in 0..PARTITIONS {
+                let file = TempFile::create()?;
+                let writer = RunWriter::new(&file)?;
+                self.partitions.push((file, writer));
+            }
+        }
+        let mut hasher = DefaultHasher::new();
+        self.level.hash(&mut hasher);
+        folded.hash(&mut hasher);
+        let partition = hasher.finish() as usize % PARTITIONS;
+        self.partitions[partition].1.write(&row)
+    }
+
+    /// Returns the groups, in no particular order.
+    pub fn finish(self) -> Result<HashGroups<'s>> {
+        let mut partitions = vec![];
+        for (file, writer) in self.partitions {
+            writer.finish()?;
+            partitions.push(file);
+        }
+        Ok(HashGroups {
+            spec: self.spec,
+            memory_limit: self.memory_limit,
+            level: self.level,
+            groups: self.groups.into_iter(),
+            partitions: partitions.into_iter(),
+            partition: None,
+        })
+    }
+}
+
+/// The groups of a hash aggregation, the ones that were in memory followed by the ones of each partition, which is
+/// aggregated when its turn comes.
+pub struct HashGroups<'s> {
+    spec: &'s AggregateSpec,
+    memory_limit: usize,
+    level: u64,
+    groups: std::vec::IntoIter<Group>,
+    partitions: std::vec::IntoIter<TempFile>,
+    /// The groups of the partition being read.
+    partition: Option<Box<HashGroups<'s>>>,
+}
+
+impl HashGroups<'_> {
+    fn aggregate_partition(&self, file: TempFile) -> Result<Self> {
+        let mut partition = HashAggregate::with_level(self.spec, self.memory_limit, self.level + 1);
+        let mut reader = RunReader::open(file)?;
+        while let Some(row) = reader.read()? {
+            partition.push(row)?;
+        }
+        partition.finish()
+    }
+}
+
+impl Iterator for HashGroups<'_> {
+    type Item = Result<Group>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(group) = self.groups.next() {
+            return Some(Ok(group));
+        }
+        loop {
+            if let Some(group) = self.partition.as_mut().and_then(|p| p.next()) {
+                return Some(group);
+            }
+            let file = self.partitions.next()?;
+            match self.aggregate_partition(file) {
+                Ok(partition) => self.partition = Some(Box::new(partition)),
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+/// Keys are equal if every value compares equal with its collation, NULLs included.
+fn keys_equal(collations: &[Collation], a: &[Value], b: &[Value]) -> bool {
+    a.len() == b.len()
+        && collations
+            .iter()
+            .zip(a.iter().zip(b))
+            .all(|(c, (a, b))| c.compare(a, b).is_eq())
+}
+
+/// Values usable as a hash map key, equal when the values compare equal, NULLs included.
+pub struct GroupKey(pub Vec<Value<'static>>);
+
+impl PartialEq for GroupKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.len() == other.0.len() && self.0.iter().zip(&other.0).all(|(a, b)| a.sql_cmp(b).is_eq())
+    }
+}
+
+impl Eq for GroupKey {}
+
+impl Hash for GroupKey {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        for value in &self.0 {
+            match value {
+                Value::Null => 0u8.hash(state),
+                Value::Int(i) => (1u8, i).hash(state),
+                // Reals equal to an integer have to hash like it.
+                Value::Float(f) if f.fract() == 0.0 && (i64::MIN as f64..i64::MAX as f64).contains(f) => {
+                    (1u8, *f as i64).hash(state)
+                }
+                Value::Float(f) => (2u8, f.to_bits()).hash(state),
+                Value::String(s) => (3u8, s).hash(state),
+                Value::Blob(b) => (4u8, b).hash(state),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn call(name: &str, args: usize, distinct: bool) -> AggregateCall {
+        AggregateCall {
+            factory: Rc::new(find_aggregate(name, args).unwrap()),
+            args,
+            distinct,
+        }
+    }
+
+    /// Result of a call over the values, one argument per row.
+    fn aggregate(name: &str, distinct: bool, values: &[Value]) -> Result<Value<'static>> {
+        let mut aggregate = call(name, 1, distinct).start();
+        for v in values {
+            aggregate.step(std::slice::from_ref(v))?;
+        }
+        aggregate.finalize()
+    }
+
+    fn count_by_key(rows: &[AggregateRow], memory_limit: usize) -> Vec<(Vec<Value<'static>>, Value<'static>)> {
+        let spec = AggregateSpec {
+            keys: vec![Collation::Binary],
+            calls: vec![call("count", 0, false)],
+            bare: 0,
+        };
+        let mut aggregate = HashAggregate::new(&spec, memory_limit);
+        for row in rows {
+            aggregate.push(row.clone()).unwrap();
+        }
+        let mut groups: Vec<_> = aggregate
+            .finish()
+            .unwrap()
+            .map(|g| {
+                let g = g.unwrap();
+                (g.key.clone(), g.results().unwrap().remove(0))
+            })
+            .collect();
+        groups.sort_by(|a, b| a.0[0].sql_cmp(&b.0[0]));
+        groups
+    }
+
+    #[test]
+    fn hash_aggregate_spills() {
+        let rows: Vec<AggregateRow> = (0..3000i64)
+            .map(|i| match i % 7 {
+                0 => vec![Value::Null],
+                // Reals equal to an integer belong to the integer's group.
+                1 => vec![Value::Float((i % 100) as f64)],
+                _ => vec![Value::Int(i % 100)],
+            })
+            .collect();
+        let expected = count_by_key(&rows, usize::MAX);
+        assert_eq!(expected.len(), 101);
+        assert_eq!(expected[0], (vec![Value::Null], Value::Int(429)));
+        assert_eq!(count_by_key(&rows, 500), expected);
+    }
+
+    #[test]
+    fn stream_aggregate() {
+        let spec = AggregateSpec {
+            keys: vec![Collation::Binary],
+            calls: vec![call("avg", 1, false)],
+            bare: 1,
+        };
+        let mut aggregate = StreamAggregate::new(&spec);
+        let row = |k: i64, v: Value<'static>| vec![Value::Int(k), v, Value::Int(k * 10 + 1)];
+        assert!(aggregate.push(row(1, Value::Int(1))).unwrap().is_none());
+        assert!(aggregate.push(row(1, Value::Null)).unwrap().is_none());
+        let first = aggregate.push(row(2, Value::String("4.5".into()))).unwrap().unwrap();
+        assert_eq!(
+            (first.results().unwrap(), first.key, first.bare),
+            (vec![Value::Float(1.0)], vec![Value::Int(1)], vec![Value::Int(11)])
+        );
+        let last = aggregate.finish().unwrap();
+        assert_eq!(last.results().unwrap(), vec![Value::Float(4.5)]);
+        assert!(StreamAggregate::new(&spec).finish().is_none());
+    }
+
+    #[test]
+    fn sums() {
+        let ints = [Value::Int(i64::MAX), Value::Int(1)];
+        assert_eq!(
+            aggregate("sum", false, &ints).unwrap_err().to_string(),
+            "integer overflow"
+        );
+        assert_eq!(
+            aggregate("total", false, &ints).unwrap(),
+            Value::Float(9.223372036854776e18)
+        );
+        let mixed = [ints[0].clone(), ints[1].clone(), Value::Float(1.5)];
+        assert_eq!(
+            aggregate("sum", false, &mixed).unwrap(),
+            Value::Float(9.223372036854776e18)
+        );
+        let text = [Value::String(" 5 ".into()), Value::String("abc".into()), Value::Null];
+        assert_eq!(aggregate("sum", false, &text).unwrap(), Value::Float(5.0));
+        assert_eq!(aggregate("sum", false, &text[..1]).unwrap(), Value::Int(5));
+        assert_eq!(aggregate("avg", false, &text).unwrap(), Value::Float(2.5));
+        let tenths = [Value::Float(0.1), Value::Float(0.2), Value::Float(0.3)];
+        assert_eq!(aggregate("sum", false, &tenths).unwrap(), Value::Float(0.6));
+        assert_eq!(aggregate("sum", false, &[Value::Null]).unwrap(), Value::Null);
+        assert_eq!(aggregate("total", false, &[]).unwrap(), Value::Float(0.0));
+    }
+
+    #[test]
+    fn distinct_min_max_and_concat() {
+        let values = [Value::Int(1), Value::Null, Value::String("x".into()), Value::Float(1.0)];
+        assert_eq!(aggregate("count", false, &values).unwrap(), Value::Int(3));
+        assert_eq!(aggregate("count", true, &values).unwrap(), Value::Int(2));
+        assert_eq!(aggregate("min", false, &values).unwrap(), Value::Int(1));
+        assert_eq!(aggregate("max", false, &values).unwrap(), Value::String("x".into()));
+        assert_eq!(
+            aggregate("group_concat", true, &values).unwrap(),
+            Value::String("1,x".into())
+        );
+
+        let mut concat = call("group_concat", 2, false).start();
+        for (v, sep) in [(1, Value::Null), (2, Value::Null), (3, Value::String("+".into()))] {
+            concat.step(&[Value::Int(v), sep]).unwrap();
+        }
+        assert_eq!(concat.finalize().unwrap(), Value::String("12+3".into()));
+
+        // Bare columns come from the row of the maximum, the first one if there are ties.
+        let spec = AggregateSpec {
+            keys: vec![],
+            calls: vec![call("count", 0, false), call("max", 1, false)],
+            bare: 1,
+        };
+        let mut aggregate = StreamAggregate::new(&spec);
+        for (v, bare) in [(5, 1), (9, 2), (2, 3), (9, 4)] {
+            aggregate.push(vec![Value::Int(v), Value::Int(bare)]).unwrap();
+        }
+        let group = aggregate.finish().unwrap();
+        assert_eq!(group.results().unwrap(), vec![Value::Int(4), Value::Int(9)]);
+        assert_eq!(group.bare, vec![Value::Int(2)]);
+    }
+}
@@ -0,0 +1,362 @@
+//! ANALYZE, which counts the rows of the tables and indexes for the planner to estimate the cost of reading them.
+//!
+//! The statistics go to `sqlite_stat1` in the format SQLite uses, so each reads the ones the other writes. A table has
+//! a row for each of its indexes, whose `stat` is the number of entries of the index followed by the average number of
+//! entries sharing the values of each prefix of its columns, and a row holding the number of rows alone when it has no
+//! index. Empty tables have no row.
+
+use std::borrow::Cow;
+use std::path::Path;
+
+use anyhow::Context;
+use anyhow::Result;
+use anyhow::bail;
+use parser::Value;
+use parser::sql;
+
+use crate::Database;
+use crate::Schema;
+use crate::btree::PageNumber;
+use crate::pager::Transaction;
+use crate::record::make_record;
+use crate::record::parse_record;
+
+/// Definition of the table the statistics are kept in.
+const STAT1_SQL: &str = "CREATE TABLE sqlite_stat1(tbl,idx,stat)";
+
+/// A row of `sqlite_stat1`.
+pub struct Stat {
+    pub tbl: String,
+    /// The index the statistics are about, the table itself when it has none. WITHOUT ROWID tables go by their name.
+    pub idx: Option<String>,
+    pub stat: String,
+}
+
+impl Stat {
+    /// The numbers the statistics start with, the keywords SQLite may add after them are left out.
+    pub fn counts(&self) -> Vec<u64> {
+        self.stat.split_whitespace().map_while(|n| n.parse().ok()).collect()
+    }
+}
+
+/// The rows of `sqlite_stat1`, none if ANALYZE never ran.
+pub fn read_stats(db: &Database) -> Vec<Stat> {
+    let Some(schema) = db
+        .get_page(1)
+        .entries()
+        .map(|e| Schema::new(e.payload))
+        .find(|s| s.ty == "table" && s.name.eq_ignore_ascii_case("sqlite_stat1"))
+    else {
+        return vec![];
+    };
+    let text = |value: Option<&Value>| match value {
+        Some(Value::String(s)) => Some(s.to_string()),
+        _ => None,
+    };
+    db.get_page(schema.rootpage)
+        .entries()
+        .filter_map(|e| {
+            let values = parse_record(&e.payload);
+            Some(Stat {
+                tbl: text(values.first())?,
+                idx: text(values.get(1)),
+                stat: text(values.get(2))?,
+            })
+        })
+        .collect()
+}
+
+/// Gathers the statistics of the table or index named `name`, or of all the tables when there's no name, replacing
+/// the ones `sqlite_stat1` had about them in the database at `path`.
+pub fn analyze(path: &Path, name: Option<&str>) -> Result<()> {
+    // The statistics are gathered once the database is locked, for no change to come in between.
+    let mut tx = Transaction::new(path)?;
+    let db = tx.database();
+    let entries: Vec<_> = db.get_page(1).entries().collect();
+    let schema: Vec<_> = entries.iter().map(|e| Schema::new(e.payload.clone())).collect();
+    // The tables SQLite keeps for itself aren't analyzed, and virtual tables have no b-tree.
+    let tables: Vec<_> = schema
+        .iter()
+        .filter(|s| s.ty == "table" && !s.name.to_ascii_lowercase().starts_with("sqlite_") && s.rootpage != 0)
+        .collect();
+    let (analyzed, index): (Vec<_>, _) = match name {
+        None => (tables, None),
+        Some(name) => match tables.iter().find(|t| t.name.eq_ignore_ascii_case(name)) {
+            Some(table) => (vec![*table], None),
+            None => match schema
+                .iter()
+                .find(|s| s.ty == "index" && s.name.eq_ignore_ascii_case(name))
+            {
+                Some(index) => (
+                    tables.into_iter().filter(|t| t.name == index.tbl_name).collect(),
+                    Some(index),
+                ),
+                None => bail!("no such table or index: {name}"),
+            },
+        },
+    };
+
+    let mut stats: Vec<_> = read_stats(db)
+        .into_iter()
+        .filter(|s| match index {
+            Some(index) => !s.idx.as_ref().is_some_and(|idx| idx.eq_ignore_ascii_case(&index.name)),
+            None => !analyzed.iter().any(|t| t.name.eq_ignore_ascii_case(&s.tbl)),
+        })
+        .collect();
+    for table in analyzed {
+        let indexes: Vec<_> = schema
+            .iter()
+            .filter(|s| s.ty == "index" && s.tbl_name == table.name)
+            .filter(|s| index.is_none_or(|index| index.name == s.name))
+            .collect();
+        // Analyzing an index leaves out the other rows of its table.
+        stats.extend(
+            table_stats(db, table, &indexes)?
+                .into_iter()
+                .filter(|s| index.is_none_or(|index| s.idx.as_ref() == Some(&index.name))),
+        );
+    }
+
+    let root = match schema.iter().find(|s| s.ty == "table" && s.name == "sqlite_stat1") {
+        Some(stat1) => {
+            tx.free_tree(stat1.rootpage, true);
+            stat1.rootpage
+        }
+        None => {
+            let root = tx.allocate();
+            let mut rows: Vec<_> = entries.iter().map(|e| (e.key, e.payload.clone())).collect();
+            let rowid = rows.last().map_or(1, |(rowid, _)| rowid + 1);
+            let text = |s: &'static str| Value::String(Cow::Borrowed(s));
+            rows.push((
+                rowid,
+                make_record(&[
+                    text("table"),
+                    text("sqlite_stat1"),
+                    text("sqlite_stat1"),
+                    Value::Int(root as i64),
+                    text(STAT1_SQL),
+                ]),
+            ));
+            tx.free_tree(1, true);
+            tx.write_table(1, &rows);
+            tx.schema_changed();
+            root
+        }
+    };
+    let rows: Vec<_> = stats
+        .iter()
+        .enumerate()
+        .map(|(i, s)| {
+            let text = |s: &str| Value::String(Cow::Owned(s.to_string()));
+            let record = make_record(&[text(&s.tbl), s.idx.as_deref().map_or(Value::Null, text), text(&s.stat)]);
+            (i as i64 + 1, record)
+        })
+        .collect();
+    tx.write_table(root, &rows);
+    tx.commit()
+}
+
+/// The statistics of a table and of the given indexes of it.
+fn table_stats(db: &Database, table: &Schema, indexes: &[&Schema]) -> Result<Vec<Stat>> {
+    let ct = sql::create_table(&table.sql).with_context(|| format!("cannot parse the definition of {}", table.name))?;
+    let stat = |idx: Option<&str>, stat| Stat {
+        tbl: table.name.clone(),
+        idx: idx.map(str::to_string),
+        stat,
+    };
+    let rows = db.get_page(table.rootpage).entries().count();
+    if rows == 0 {
+        return Ok(vec![]);
+    }
+    let mut stats = vec![];
+    // The rows of a WITHOUT ROWID table are the entries of the index on its primary key, which goes by the name of the
+    // table.
+    if ct.without_rowid {
+        let nocase = vec![false; ct.primary_key.len()];
+        stats.extend(index_stat(db, table.rootpage, &nocase).map(|s| stat(Some(&table.name), s)));
+    } else if indexes.is_empty() {
+        stats.push(stat(None, rows.to_string()));
+    }
+    // The entries of the indexes of a table hold the values of the indexed columns followed by its key, which is the
+    // rowid or the primary key columns.
+    let key_columns = if ct.without_rowid { ct.primary_key.len() } else { 1 };
+    for index in indexes.iter().filter(|i| i.rootpage != table.rootpage) {
+        // The indexes SQLite makes for UNIQUE and PRIMARY KEY constraints have no definition to read the columns from.
+        let nocase = match index.sql.is_empty() {
+            true => db
+                .get_page(index.rootpage)
+                .entries()
+                .next()
+                .map_or(vec![], |e| vec![false; parse_record(&e.payload).len() - key_columns]),
+            false => {
+                let ci = sql::create_index(&index.sql)
+                    .with_context(|| format!("cannot parse the definition of {}", index.name))?;
+                ci.columns
+                    .iter()
+                    .map(|c| c.collation.is_some_and(|c| c.eq_ignore_ascii_case("nocase")))
+                    .collect()
+            }
+        };
+        stats.extend(index_stat(db, index.rootpage, &nocase).map(|s| stat(Some(&index.name), s)));
+    }
+    Ok(stats)
+}
+
+/// The `stat` of an index whose leading columns compare case-insensitively where `nocase` is set, none if it has no
+/// entries.
+fn index_stat(db: &Database, root: PageNumber, nocase: &[bool]) -> Option<String> {
+    let mut entries = 0u64;
+    // Number of distinct values of each prefix of the columns, counted as they change from an entry to the next.
+    let mut distinct = vec![0u64; nocase.len()];
+    let mut previous: Vec<Value<'static>> = vec![];
+    for entry in db.get_page(root).entries() {
+        let values: Vec<_> = parse_record(&entry.payload)
+            .into_iter()
+            .map(Value::into_owned)
+            .collect();
+        let same = match entries {
+            0 => 0,
+            _ => (0..nocase.len())
+                .take_while(|&i| equal(&values[i], &previous[i], nocase[i]))
+                .count(),
+        };
+        for d in &mut distinct[same..] {
+            *d += 1;
+        }
+        entries += 1;
+        previous = values;
+    }
+    if entries == 0 {
+        return None;
+    }
+    // Like SQLite, prefixes that are nearly unique count as unique.
+    let averages = distinct.iter().map(|&d| match entries.div_ceil(d) {
+        2 if entries * 10 <= d * 11 => "1".to_string(),
+        average => average.to_string(),
+    });
+    Some(
+        std::iter::once(entries.to_string())
+            .chain(averages)
+            .collect::<Vec<_>>()
+            .join(" "),
+    )
+}
+
+fn equal(a: &Value, b: &Value, nocase: bool) -> bool {
+    match (a, b) {
+        (Value::String(a), Value::String(b)) if nocase => a.eq_ignore_ascii_case(b),
+        _ => a.sql_cmp(b).is_eq(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::fs::File;
+    use std::path::PathBuf;
+    use std::process::Command;
+
+    use super::*;
+    use crate::Connection;
+
+    /// A copy of sample.db, or of another database, that can be written to.
+    struct Copy(PathBuf);
+
+    impl Copy {
+        fn new(name: &str) -> Self {
+            Self::of(concat!(env!("CARGO_MANIFEST_DIR"), "/sample.db"), name)
+        }
+
+        fn of(source: &str, name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!("rusqlite-{}-{name}.db", std::process::id()));
+            fs::copy(source, &path).unwrap();
+            Self(path)
+        }
+
+        fn open(&self) -> Database {
+            Database::open(&File::open(&self.0).unwrap()).unwrap()
+        }
+    }
+
+    impl Drop for Copy {
+        fn drop(&mut self) {
+            _ = fs::remove_file(&self.0);
+        }
+    }
+
+    /// What SQLite's `PRAGMA integrity_check` finds wrong with the database, `None` when the sqlite3 shell isn't
+    /// installed.
+    fn integrity_check(path: &Path) -> Option<String> {
+        let output = Command::new("sqlite3")
+            .arg(path)
+            .arg("PRAGMA integrity_check")
+            .output()
+            .ok()?;
+        Some(String::from_utf8(output.stdout).unwrap().trim_end().to_string())
+    }
+
+    fn plan(copy: &Copy, query: &str) -> String {
+        let conn = Connection::open(&copy.0).unwrap();
+        let statement = conn.prepare(&format!("EXPLAIN QUERY PLAN {query}")).unwrap();
+        statement.explain().unwrap().to_string()
+    }
+
+    #[test]
+    fn stats() {
+        let copy = Copy::new("stats");
+        analyze(&copy.0, None).unwrap();
+        let db = copy.open();
+        let stats: Vec<_> = read_stats(&db).into_iter().map(|s| (s.tbl, s.idx, s.stat)).collect();
+        let stat = |tbl: &str, stat: &str| (tbl.to_string(), None, stat.to_string());
+        assert_eq!(stats, [stat("apples", "4"), stat("oranges", "6")]);
+        // Analyzing again replaces the rows of the table.
+        analyze(&copy.0, Some("oranges")).unwrap();
+        let db = copy.open();
+        assert_eq!(read_stats(&db).len(), 2);
+        assert_eq!(db.get_page(1).entries().count(), 4);
+        assert!(analyze(&copy.0, Some("pears")).is_err());
+    }
+
+    #[test]
+    fn integrity() {
+        let copy = Copy::new("integrity");
+        analyze(&copy.0, None).unwrap();
+        assert!(integrity_check(&copy.0).is_none_or(|result| result == "ok"));
+        // Auto-vacuum databases are left as they are.
+        let copy = Copy::of(
+            concat!(env!("CARGO_MANIFEST_DIR"), "/testdata/auto_vacuum.db"),
+            "auto-vacuum",
+        );
+        let err = analyze(&copy.0, None).unwrap_err();
+        assert_eq!(err.to_string(), "cannot write to a database in auto-vacuum mode");
+        assert!(integrity_check(&copy.0).is_none_or(|result| result == "ok"));
+        assert!(read_stats(&copy.open()).is_empty());
+    }
+
+    #[test]
+    fn join_order() {
+        let copy = Copy::new("join-order");
+        let query = "SELECT * FROM oranges o, apples a WHERE a.id = o.id";
+        // Without statistics both tables are guessed to be as large, and are read in the order of the FROM clause.
+        assert_eq!(
+            plan(&copy, query),
+            "QUERY PLAN\n|--SCAN o\n`--SEARCH a USING INTEGER PRIMARY KEY (rowid=?)\n"
+        );
+        analyze(&copy.0, None).unwrap();
+        assert_eq!(
+            plan(&copy, query),
+            "QUERY PLAN\n|--SCAN a\n`--SEARCH o USING INTEGER PRIMARY KEY (rowid=?)\n"
+        );
+    }
+
+    #[test]
+    fn counts() {
+        let stat = |stat: &str| Stat {
+            tbl: "t".to_string(),
+            idx: Some("i".to_string()),
+            stat: stat.to_string(),
+        };
+        assert_eq!(stat("3000 30 1").counts(), [3000, 30, 1]);
+        assert_eq!(stat("3000 30 unordered sz=12").counts(), [3000, 30]);
+    }
+}
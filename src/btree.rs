@@ -1,9 +1,12 @@
+use std::cmp::Ordering;
 use std::fs::File;
 
 use anyhow::Result;
 use memmap2::Mmap;
 use memmap2::MmapOptions;
+use parser::Value;
 
+use crate::record::parse_record;
 use crate::varint::read_varint;
 
 pub type PageNumber = u32;
@@ -13,8 +16,12 @@ const MAX_PAGE_SIZE: u32 = 1 << 16; // 65536, 64 KB
 
 #[derive(Debug)]
 pub struct Database {
+    /// The file the database is read from.
+    pub file: File,
     pub mmap: Mmap,
     pub page_size: u32,
+    /// Page size minus the bytes reserved at the end of each page by extensions.
+    pub usable_size: u32,
     pub page_count: PageNumber,
 }
 
@@ -25,10 +32,13 @@ impl Database {
         let page_size = u16::from_be_bytes([mmap[16], mmap[17]]) as u32;
         assert!((MIN_PAGE_SIZE..=MAX_PAGE_SIZE).contains(&page_size));
         assert!(page_size.is_power_of_two());
+        let usable_size = page_size - mmap[20] as u32;
         let page_count = PageNumber::from_be_bytes([mmap[28], mmap[29], mmap[30], mmap[31]]);
         Ok(Self {
+            file: file.try_clone()?,
             mmap,
             page_size,
+            usable_size,
             page_count,
         })
     }
@@ -37,10 +47,169 @@ impl Database {
         assert!(page_number <= self.page_count);
         Page::parse(self, page_number)
     }
+
+    /// Looks up the entry with the given rowid in a table b-tree.
+    pub fn seek_rowid(&self, root: PageNumber, rowid: i64) -> Option<Entry> {
+        let mut page = self.get_page(root);
+        loop {
+            // Cells are sorted by rowid, the key of an interior cell being the largest rowid of its left child.
+            let (mut cell, mut end) = (0, page.cell_count());
+            while cell < end {
+                let mid = (cell + end) / 2;
+                if page.cell_key(mid) < rowid {
+                    cell = mid + 1;
+                } else {
+                    end = mid;
+                }
+            }
+            match page {
+                Page::Interior { right_child, .. } => {
+                    let child = if cell < page.cell_count() {
+                        page.left_child(cell)
+                    } else {
+                        right_child
+                    };
+                    page = self.get_page(child);
+                }
+                Page::Leaf { .. } => {
+                    if cell == page.cell_count() || page.cell_key(cell) != rowid {
+                        return None;
+                    }
+                    let Cell::Leaf(entry) = page.parse_cell(page.cell_offset(cell)) else {
+                        unreachable!()
+                    };
+                    return Some(entry);
+                }
+            }
+        }
+    }
+
+    /// Positions an iterator over a b-tree on the first entry whose leading values are not below `key`, or are above it
+    /// when `after` is set, `desc` telling which of the index columns are in descending order. The entries of a table
+    /// b-tree go by their rowid.
+    pub fn seek(&self, root: PageNumber, key: &[Value], desc: &[bool], after: bool) -> EntryIter<'_> {
+        let mut iter = EntryIter::new(self, root);
+        loop {
+            let page = iter.curr_page;
+            let below = |cell| {
+                let ord = match page.common().index {
+                    true => {
+                        let (Cell::Leaf(entry) | Cell::IndexInterior { entry, .. }) =
+                            page.parse_cell(page.cell_offset(cell))
+                        else {
+                            unreachable!()
+                        };
+                        compare_key(&parse_record(&entry.payload), key, desc)
+                    }
+                    // The key of an interior cell is the largest rowid of its left child.
+                    false => Value::Int(page.cell_key(cell)).sql_cmp(&key[0]),
+                };
+                ord.is_lt() || (after && ord.is_eq())
+            };
+            let (mut cell, mut end) = (0, page.cell_count());
+            while cell < end {
+                let mid = (cell + end) / 2;
+                if below(mid) {
+                    cell = mid + 1;
+                } else {
+                    end = mid;
+                }
+            }
+            match page {
+                // Entries of the left child of a cell sort before the cell's own entry, which the iterator yields when
+                // coming back from the child.
+                Page::Interior { right_child, .. } => {
+                    if cell < page.cell_count() {
+                        iter.curr_cell = cell;
+                        iter.move_to_child(page.left_child(cell));
+                    } else {
+                        iter.curr_cell = cell + 1;
+                        iter.move_to_child(right_child);
+                    }
+                }
+                Page::Leaf { .. } => {
+                    iter.curr_cell = cell;
+                    return iter;
+                }
+            }
+        }
+    }
+
+    /// Pages of a b-tree, its root first, along with the overflow pages of its cells.
+    pub fn tree_pages(&self, root: PageNumber) -> Vec<PageNumber> {
+        let mut pages = vec![];
+        let mut stack = vec![root];
+        while let Some(number) = stack.pop() {
+            pages.push(number);
+            let page = self.get_page(number);
+            for cell in 0..page.cell_count() {
+                let mut next = page.cell_overflow(cell);
+                while let Some(overflow) = next.filter(|&n| n != 0) {
+                    pages.push(overflow);
+                    let data = self.page_data(overflow);
+                    next = Some(PageNumber::from_be_bytes([data[0], data[1], data[2], data[3]]));
+                }
+            }
+            if let Page::Interior { right_child, .. } = page {
+                stack.push(right_child);
+                stack.extend((0..page.cell_count()).rev().map(|cell| page.left_child(cell)));
+            }
+        }
+        pages
+    }
+
+    pub fn page_data(&self, page_number: PageNumber) -> &[u8] {
+        let offset = ((page_number - 1) * self.page_size) as usize;
+        &self.mmap[offset..offset + self.page_size as usize]
+    }
+
+    /// Assembles a payload whose tail spilled into a chain of overflow pages.
+    fn read_overflow(&self, local: &[u8], payload_size: usize, mut next: PageNumber) -> Vec<u8> {
+        let mut payload = Vec::with_capacity(payload_size);
+        payload.extend_from_slice(local);
+        while payload.len() < payload_size {
+            assert!(next != 0, "corrupt database, overflow chain ended early");
+            let data = self.page_data(next);
+            let chunk = (payload_size - payload.len()).min(self.usable_size as usize - 4);
+            payload.extend_from_slice(&data[4..4 + chunk]);
+            next = PageNumber::from_be_bytes([data[0], data[1], data[2], data[3]]);
+        }
+        payload
+    }
+
+    /// Number of payload bytes stored in the b-tree page itself, the rest goes to overflow pages.
+    ///
+    /// See the "Cell Payload Overflow Pages" section of <https://sqlite.org/fileformat2.html>.
+    pub fn local_payload_size(&self, payload_size: u64, index: bool) -> usize {
+        let usable = self.usable_size as u64;
+        let max_local = if index {
+            (usable - 12) * 64 / 255 - 23
+        } else {
+            usable - 35
+        };
+        if payload_size <= max_local {
+            return payload_size as usize;
+        }
+        let min_local = (usable - 12) * 32 / 255 - 23;
+        let k = min_local + (payload_size - min_local) % (usable - 4);
+        if k <= max_local { k as usize } else { min_local as usize }
+    }
+}
+
+/// Compares the leading values of an index entry with a key, in the order of the index.
+pub fn compare_key(values: &[Value], key: &[Value], desc: &[bool]) -> Ordering {
+    for ((v, k), desc) in values.iter().zip(key).zip(desc) {
+        let ord = v.sql_cmp(k);
+        if ord.is_ne() {
+            return if *desc { ord.reverse() } else { ord };
+        }
+    }
+    Ordering::Equal
 }
 
-// No support for index tables.
+const PT_INTERIOR_INDEX: u8 = 0x02;
 const PT_INTERIOR_TABLE: u8 = 0x05;
+const PT_LEAF_INDEX: u8 = 0x0a;
 const PT_LEAF_TABLE: u8 = 0x0d;
 const HDR_INTERIOR: usize = 12;
 const HDR_LEAF: usize = 8;
@@ -52,6 +221,8 @@ pub struct PageCommon<'a> {
     data: &'a [u8],
     size: u32,
     number: PageNumber,
+    /// Whether the page belongs to an index b-tree, whose cells carry keys instead of rowids.
+    index: bool,
     cell_area_offset: u16,
     cell_count: u32,
     cell_offset_list: &'a [u8],
@@ -64,26 +235,34 @@ pub enum Page<'a> {
 }
 
 impl<'a> Page<'a> {
-    pub fn entries(&self) -> EntryIter<'_> {
+    pub fn entries(&self) -> EntryIter<'a> {
         EntryIter::new(self.common().db, self.common().number)
     }
 
+    /// Walks the b-tree from its last entry back to the first.
+    pub fn entries_rev(&self) -> EntryIter<'a> {
+        let mut iter = self.entries();
+        iter.reverse = true;
+        iter.curr_cell = self.child_count();
+        iter
+    }
+
     fn parse(db: &'a Database, page_number: PageNumber) -> Self {
         assert!(db.page_size != 0 && page_number != 0);
 
-        let (offset, page_data) = if page_number == 1 {
-            (100, &db.mmap[..db.page_size as usize])
+        let page_data = db.page_data(page_number);
+        let offset = if page_number == 1 {
+            100
         } else {
-            let offset = ((page_number - 1) * db.page_size) as usize;
-            (offset, &db.mmap[offset..offset + db.page_size as usize])
+            ((page_number - 1) * db.page_size) as usize
         };
         let page_type = db.mmap[offset];
         let cell_count = u16::from_be_bytes([db.mmap[offset + 3], db.mmap[offset + 4]]) as u32;
         assert!(cell_count < Page::max_cell_count(db.page_size));
         let cell_area_offset = u16::from_be_bytes([db.mmap[offset + 5], db.mmap[offset + 6]]);
-        // Assuming that we have no index tables.
+        let index = matches!(page_type, PT_INTERIOR_INDEX | PT_LEAF_INDEX);
         let (header_len, right_child) = match page_type {
-            PT_INTERIOR_TABLE => {
+            PT_INTERIOR_TABLE | PT_INTERIOR_INDEX => {
                 let rc = u32::from_be_bytes([
                     db.mmap[offset + 8],
                     db.mmap[offset + 9],
@@ -92,7 +271,7 @@ impl<'a> Page<'a> {
                 ]);
                 (HDR_INTERIOR, Some(rc))
             }
-            PT_LEAF_TABLE => (HDR_LEAF, None),
+            PT_LEAF_TABLE | PT_LEAF_INDEX => (HDR_LEAF, None),
             v => panic!("corrupt database, page type has value: 0x{v:x}"),
         };
         let cell_offset_len = (cell_count as usize) * 2;
@@ -102,6 +281,7 @@ impl<'a> Page<'a> {
             data: page_data,
             size: db.page_size,
             number: page_number,
+            index,
             cell_area_offset,
             cell_count,
             cell_offset_list,
@@ -119,35 +299,110 @@ impl<'a> Page<'a> {
         assert!(offset >= self.common().cell_area_offset);
         assert!((offset as u32) < self.common().size);
 
-        let mut cell_content = &self.common().data[offset as usize..];
-        match self {
+        let common = self.common();
+        let mut cell_content = &common.data[offset as usize..];
+        let left_child = match self {
             Self::Interior { .. } => {
                 let left_child =
                     PageNumber::from_be_bytes([cell_content[0], cell_content[1], cell_content[2], cell_content[3]]);
-                let (key, _) = read_varint(&mut &cell_content[4..]);
-                Cell::Interior { left_child, key }
-            }
-            Self::Leaf { .. } => {
-                let (payload_size, _) = read_varint(&mut cell_content);
-                let (key, _) = read_varint(&mut cell_content);
-                let payload = &cell_content[..payload_size as usize];
-                Cell::Leaf(Entry {
-                    payload_size: payload_size as u64,
-                    key,
-                    payload: payload.to_vec(),
-                })
+                cell_content = &cell_content[4..];
+                Some(left_child)
             }
+            Self::Leaf { .. } => None,
+        };
+        if let (Some(left_child), false) = (left_child, common.index) {
+            let (key, _) = read_varint(&mut cell_content);
+            return Cell::Interior { left_child, key };
+        }
+        let (payload_size, _) = read_varint(&mut cell_content);
+        let key = if common.index {
+            0
+        } else {
+            read_varint(&mut cell_content).0
+        };
+        let local = common.db.local_payload_size(payload_size as u64, common.index);
+        let payload = if local < payload_size as usize {
+            let overflow = &cell_content[local..local + 4];
+            let overflow = PageNumber::from_be_bytes([overflow[0], overflow[1], overflow[2], overflow[3]]);
+            common
+                .db
+                .read_overflow(&cell_content[..local], payload_size as usize, overflow)
+        } else {
+            cell_content[..local].to_vec()
+        };
+        let entry = Entry {
+            payload_size: payload_size as u64,
+            key,
+            payload,
+        };
+        match left_child {
+            Some(left_child) => Cell::IndexInterior { left_child, entry },
+            None => Cell::Leaf(entry),
         }
     }
 
-    fn common(&self) -> &PageCommon<'_> {
+    /// First overflow page of a cell whose payload doesn't fit in the page.
+    fn cell_overflow(&self, cell: usize) -> Option<PageNumber> {
+        let common = self.common();
+        let mut content = &common.data[self.cell_offset(cell) as usize..];
+        match self {
+            Self::Interior { .. } if !common.index => return None,
+            Self::Interior { .. } => content = &content[4..],
+            Self::Leaf { .. } => {}
+        }
+        let (payload_size, _) = read_varint(&mut content);
+        if !common.index {
+            read_varint(&mut content);
+        }
+        let local = common.db.local_payload_size(payload_size as u64, common.index);
+        let overflow = content
+            .get(local..local + 4)
+            .filter(|_| local < payload_size as usize)?;
+        Some(PageNumber::from_be_bytes([
+            overflow[0],
+            overflow[1],
+            overflow[2],
+            overflow[3],
+        ]))
+    }
+
+    fn common(&self) -> &PageCommon<'a> {
         match self {
             Self::Interior { common, .. } | Self::Leaf { common } => common,
         }
     }
 
-    fn cell_offset_list(&self) -> &[u8] {
-        self.common().cell_offset_list
+    fn cell_count(&self) -> usize {
+        self.common().cell_count as usize
+    }
+
+    /// Number of cells of a leaf, or of children of an interior page, the right child being the last one.
+    fn child_count(&self) -> usize {
+        match self {
+            Self::Interior { .. } => self.cell_count() + 1,
+            Self::Leaf { .. } => self.cell_count(),
+        }
+    }
+
+    fn left_child(&self, cell: usize) -> PageNumber {
+        let offset = self.cell_offset(cell) as usize;
+        let data = &self.common().data[offset..offset + 4];
+        PageNumber::from_be_bytes([data[0], data[1], data[2], data[3]])
+    }
+
+    /// Rowid of a cell in a table b-tree page, read without parsing the payload.
+    fn cell_key(&self, cell: usize) -> i64 {
+        let mut content = &self.common().data[self.cell_offset(cell) as usize..];
+        match self {
+            Self::Interior { .. } => content = &content[4..],
+            Self::Leaf { .. } => _ = read_varint(&mut content),
+        }
+        read_varint(&mut content).0
+    }
+
+    fn cell_offset(&self, cell: usize) -> u16 {
+        let list = self.common().cell_offset_list;
+        u16::from_be_bytes([list[cell * 2], list[cell * 2 + 1]])
     }
 
     fn max_cell_count(page_size: u32) -> u32 {
@@ -157,7 +412,15 @@ impl<'a> Page<'a> {
 
 #[allow(dead_code)]
 pub enum Cell {
-    Interior { left_child: PageNumber, key: i64 },
+    Interior {
+        left_child: PageNumber,
+        key: i64,
+    },
+    /// Interior cells of index b-trees hold an entry of their own, which sorts after every entry of the left child.
+    IndexInterior {
+        left_child: PageNumber,
+        entry: Entry,
+    },
     Leaf(Entry),
 }
 
@@ -165,19 +428,24 @@ pub enum Cell {
 #[allow(dead_code)]
 pub struct Entry {
     pub payload_size: u64,
+    /// Rowid of table b-tree entries, index b-tree entries have no rowid of their own and always use 0.
     pub key: i64,
     pub payload: Vec<u8>,
 }
 
 const ITER_MAX_DEPTH: usize = 20;
 
+/// Walks a table or index b-tree in key order, or in reverse key order.
 #[derive(Clone, Copy)]
 pub struct EntryIter<'a> {
     db: &'a Database,
     curr_page: Page<'a>,
+    /// The next cell of the page in key order. Walking in reverse, the number of cells, or children of an interior
+    /// page, that are still to be visited.
     curr_cell: usize,
     parents: [Option<(PageNumber, usize)>; ITER_MAX_DEPTH - 1],
     last_parent: usize,
+    reverse: bool,
 }
 
 impl<'a> EntryIter<'a> {
@@ -188,6 +456,7 @@ impl<'a> EntryIter<'a> {
             curr_cell: 0,
             parents: [None; ITER_MAX_DEPTH - 1],
             last_parent: 0,
+            reverse: false,
         }
     }
 
@@ -195,8 +464,11 @@ impl<'a> EntryIter<'a> {
         assert!(self.last_parent < ITER_MAX_DEPTH - 1);
         self.parents[self.last_parent] = Some((self.curr_page.common().number, self.curr_cell));
         self.last_parent += 1;
-        self.curr_cell = 0;
         self.curr_page = self.db.get_page(child);
+        self.curr_cell = match self.reverse {
+            true => self.curr_page.child_count(),
+            false => 0,
+        };
     }
 
     fn move_to_parent(&mut self) {
@@ -213,31 +485,84 @@ impl<'a> Iterator for EntryIter<'a> {
     type Item = Entry;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if self.curr_cell >= self.curr_page.cell_offset_list().len() {
-            self.curr_cell += 1;
-            if let Page::Interior { right_child, .. } = &self.curr_page {
-                self.move_to_child(*right_child);
-            } else {
-                while self.curr_cell > self.curr_page.cell_offset_list().len() {
+        if self.reverse {
+            return self.next_back();
+        }
+        loop {
+            let cell_count = self.curr_page.cell_count();
+            match self.curr_page {
+                // The parent cell is left unvisited, so coming back from the child can yield index entries.
+                Page::Interior { .. } if self.curr_cell < cell_count => {
+                    self.move_to_child(self.curr_page.left_child(self.curr_cell))
+                }
+                Page::Interior { right_child, .. } if self.curr_cell == cell_count => {
+                    self.curr_cell += 1;
+                    self.move_to_child(right_child);
+                }
+                Page::Leaf { .. } if self.curr_cell < cell_count => {
+                    let cell = self.curr_page.parse_cell(self.curr_page.cell_offset(self.curr_cell));
+                    self.curr_cell += 1;
+                    let Cell::Leaf(entry) = cell else { unreachable!() };
+                    return Some(entry);
+                }
+                _ => {
                     if self.last_parent == 0 {
                         return None;
                     }
                     self.move_to_parent();
+                    if self.curr_cell < self.curr_page.cell_count() {
+                        let cell = self.curr_cell;
+                        self.curr_cell += 1;
+                        if self.curr_page.common().index
+                            && let Cell::IndexInterior { entry, .. } =
+                                self.curr_page.parse_cell(self.curr_page.cell_offset(cell))
+                        {
+                            return Some(entry);
+                        }
+                    }
                 }
-                return self.next();
             }
         }
-        let raw_list = self.curr_page.cell_offset_list();
-        let offset_bytes = &raw_list[self.curr_cell..self.curr_cell + 2];
-        let offset = u16::from_be_bytes([offset_bytes[0], offset_bytes[1]]);
-        let cell = self.curr_page.parse_cell(offset);
-        self.curr_cell += 2;
-        match cell {
-            Cell::Interior { left_child, .. } => {
-                self.move_to_child(left_child);
-                self.next()
+    }
+}
+
+impl EntryIter<'_> {
+    /// The entry before the last one yielded, walking the children of an interior page from the right one back to
+    /// the first. The entry of an index interior cell comes after its left child is done with.
+    fn next_back(&mut self) -> Option<Entry> {
+        loop {
+            match self.curr_page {
+                Page::Interior { right_child, .. } if self.curr_cell > 0 => {
+                    self.curr_cell -= 1;
+                    let child = match self.curr_cell == self.curr_page.cell_count() {
+                        true => right_child,
+                        false => self.curr_page.left_child(self.curr_cell),
+                    };
+                    self.move_to_child(child);
+                }
+                Page::Leaf { .. } if self.curr_cell > 0 => {
+                    self.curr_cell -= 1;
+                    let cell = self.curr_page.parse_cell(self.curr_page.cell_offset(self.curr_cell));
+                    let Cell::Leaf(entry) = cell else { unreachable!() };
+                    return Some(entry);
+                }
+                _ => {
+                    if self.last_parent == 0 {
+                        return None;
+                    }
+                    self.move_to_parent();
+                    // Coming back from the child at `curr_cell`, the cell before it sorts between it and the child
+                    // visited next.
+                    if self.curr_page.common().index
+                        && self.curr_cell > 0
+                        && let Cell::IndexInterior { entry, .. } = self
+                            .curr_page
+                            .parse_cell(self.curr_page.cell_offset(self.curr_cell - 1))
+                    {
+                        return Some(entry);
+                    }
+                }
             }
-            Cell::Leaf(data) => Some(data),
         }
     }
 }
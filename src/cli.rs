@@ -8,6 +8,10 @@ pub struct Args {
     pub cmd: Option<Cmd>,
 
     pub query: Option<String>,
+
+    /// Bytes of rows that sorting, grouping and hash joins keep in memory before spilling them to temp files.
+    #[arg(long, default_value_t = 64 << 20)]
+    pub memory_limit: usize,
 }
 
 #[derive(Debug, Subcommand, Clone)]
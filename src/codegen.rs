@@ -0,0 +1,1521 @@
+//! Code generation, turning a planned [`Query`] into a [`Program`] for the virtual machine.
+//!
+//! The tables of a query become nested loops, the first table being the outer one. The subqueries of the query are
+//! compiled into subprograms of their own, which the loops run through cursors or which expressions run when they are
+//! evaluated.
+
+use anyhow::Result;
+use anyhow::bail;
+use parser::Collation;
+use parser::CompoundOp;
+use parser::CreateIndex;
+use parser::Expr;
+use parser::Limit;
+use parser::SqlType;
+
+use crate::aggregate::AggregateCall;
+use crate::aggregate::AggregateSpec;
+use crate::functions::Functions;
+use crate::planner::Access;
+use crate::planner::CompoundQuery;
+use crate::planner::KeyBound;
+use crate::planner::Query;
+use crate::planner::RecursiveQuery;
+use crate::planner::Scan;
+use crate::planner::Source;
+use crate::planner::Strategy;
+use crate::planner::Subquery;
+use crate::planner::Table;
+use crate::planner::Term;
+use crate::planner::WindowQuery;
+use crate::planner::collect_bare_columns;
+use crate::planner::column_affinity;
+use crate::planner::column_collation;
+use crate::planner::expr_collation;
+use crate::planner::plan_strategy;
+use crate::planner::subquery;
+use crate::planner::table_index;
+use crate::planner::used_columns;
+use crate::sorter::SortOrder;
+use crate::vm::Addr;
+use crate::vm::ColumnRegister;
+use crate::vm::CursorId;
+use crate::vm::Expression;
+use crate::vm::Op;
+use crate::vm::Program;
+use crate::vm::Reg;
+use crate::vm::Subprogram;
+use crate::vm::SubqueryProgram;
+
+/// Compiles a query, its subqueries included.
+pub fn compile<'a>(q: &Query<'a>, functions: &'a Functions) -> Result<Program<'a>> {
+    let mut codegen = Codegen {
+        functions,
+        subprograms: vec![],
+    };
+    codegen.select(q, &[], false)?;
+    Ok(codegen.finish())
+}
+
+/// Ascending order, NULLs first, the order of GROUP BY and of compound selects.
+const ASC: SortOrder = SortOrder {
+    desc: false,
+    nulls_first: true,
+    collation: Collation::Binary,
+};
+
+struct Codegen<'a> {
+    functions: &'a Functions,
+    /// The subprograms compiled so far, `None` for the ones being compiled.
+    subprograms: Vec<Option<Code<'a>>>,
+}
+
+/// The code of a subprogram being generated. Jump targets are labels until the subprograms are put together.
+struct Code<'a> {
+    ops: Vec<Op<'a>>,
+    comments: Vec<String>,
+    /// Address of each label, once it is placed.
+    labels: Vec<Option<Addr>>,
+    params: Vec<(&'a str, &'a str)>,
+    registers: usize,
+    cursors: usize,
+}
+
+type Label = Addr;
+
+impl<'a> Code<'a> {
+    /// Starts a subprogram taking the `params` columns, whose affinities and collations are the ones the enclosing
+    /// query gives them. Returns it along with the registers of the columns.
+    fn new(params: &[(&'a str, &'a str)], outer: &[ColumnRegister<'a>]) -> (Self, Vec<ColumnRegister<'a>>) {
+        let columns = params
+            .iter()
+            .enumerate()
+            .map(|(reg, &(table, name))| ColumnRegister {
+                reg,
+                ..find_column(outer, table, name)
+                    .expect("column of an unknown table")
+                    .clone()
+            })
+            .collect();
+        let code = Self {
+            ops: vec![],
+            comments: vec![],
+            labels: vec![],
+            params: params.to_vec(),
+            registers: params.len(),
+            cursors: 0,
+        };
+        (code, columns)
+    }
+
+    fn emit(&mut self, op: Op<'a>) {
+        self.ops.push(op);
+        self.comments.push(String::new());
+    }
+
+    /// Notes something about the last opcode for EXPLAIN.
+    fn comment(&mut self, comment: String) {
+        *self.comments.last_mut().unwrap() = comment;
+    }
+
+    fn label(&mut self) -> Label {
+        self.labels.push(None);
+        self.labels.len() - 1
+    }
+
+    /// Makes the label point to the next opcode.
+    fn place(&mut self, label: Label) {
+        self.labels[label] = Some(self.ops.len());
+    }
+
+    fn register(&mut self) -> Reg {
+        self.registers(1)
+    }
+
+    fn registers(&mut self, n: usize) -> Reg {
+        self.registers += n;
+        self.registers - n
+    }
+
+    fn cursor(&mut self) -> CursorId {
+        self.cursors += 1;
+        self.cursors - 1
+    }
+
+    fn expr(&mut self, scope: &Scope<'_, 'a>, expr: &Expr<'a>, dest: Reg) {
+        let src = match expr {
+            Expr::Column { table: Some(t), name } => find_column(&scope.columns, t, name).map(|c| c.reg),
+            _ => scope
+                .aggregates
+                .iter()
+                .find(|(call, _)| call == expr)
+                .map(|(_, reg)| *reg),
+        };
+        match src {
+            Some(src) => self.emit(Op::Copy { src, dest, n: 1 }),
+            None => self.emit(Op::Eval {
+                expr: Box::new(scope.expression(expr)),
+                dest,
+            }),
+        }
+    }
+
+    /// Jumps to `target` unless all the conditions are true.
+    fn conditions(&mut self, scope: &Scope<'_, 'a>, exprs: &[Expr<'a>], target: Label) {
+        for expr in exprs {
+            let reg = self.register();
+            self.expr(scope, expr, reg);
+            self.emit(Op::IfNot { reg, target });
+        }
+    }
+
+    /// Loads the columns of a table the query uses from the row its cursor is at, or from the entry of a covering
+    /// `index`.
+    fn load(
+        &mut self,
+        functions: &Functions,
+        table: &Table<'a>,
+        cursor: CursorId,
+        columns: &[ColumnRegister<'a>],
+        index: Option<&CreateIndex>,
+    ) {
+        let ct = &table.ct;
+        let storage = ct.storage_order();
+        let position = |i: usize| match index {
+            Some(index) => index
+                .columns
+                .iter()
+                .position(|ic| ic.name.eq_ignore_ascii_case(ct.columns[i].name))
+                .unwrap(),
+            None => storage.iter().position(|&s| s == i).unwrap(),
+        };
+        for column in columns {
+            let dest = column.reg;
+            match ct.columns.iter().position(|c| c.name.eq_ignore_ascii_case(column.name)) {
+                // The columns a virtual column is computed from are loaded before it.
+                Some(i) if ct.columns[i].is_virtual() => {
+                    let c = &ct.columns[i];
+                    let mut expr = c.generated.as_ref().unwrap().expr.clone();
+                    expr.walk_mut(&mut |e| {
+                        if let Expr::Column { table, .. } = e {
+                            *table = Some(column.table);
+                        }
+                        true
+                    });
+                    functions.bind(&mut expr);
+                    let expression = Expression {
+                        expr,
+                        columns: columns.to_vec(),
+                        aggregates: vec![],
+                        subqueries: vec![],
+                    };
+                    self.emit(Op::Eval {
+                        expr: Box::new(expression),
+                        dest,
+                    });
+                    self.comment(format!("r[{dest}]={}.{}", column.table, column.name));
+                    self.emit(Op::Affinity {
+                        reg: dest,
+                        affinity: c.sql_type,
+                    });
+                }
+                // The rowid alias is stored as NULL in the record, its value is the entry key.
+                Some(i) if ct.rowid_alias != Some(i) => {
+                    let c = &ct.columns[i];
+                    // Rows written before an ALTER TABLE ADD COLUMN have fewer values than the table has columns, the
+                    // missing ones take the column's default value.
+                    let default = c.default.clone().map(|d| d.with_affinity(c.sql_type));
+                    self.emit(Op::Column {
+                        cursor,
+                        column: position(i),
+                        dest,
+                        default,
+                    });
+                    self.comment(format!("r[{dest}]={}.{}", column.table, column.name));
+                    // SQLite may store reals without a fractional part as integers on disk.
+                    if c.sql_type == SqlType::Real {
+                        self.emit(Op::RealAffinity { reg: dest });
+                    }
+                }
+                _ => {
+                    match index {
+                        Some(_) => self.emit(Op::IdxRowid { cursor, dest }),
+                        None => self.emit(Op::Rowid { cursor, dest }),
+                    }
+                    self.comment(format!("r[{dest}]={}.{}", column.table, column.name));
+                }
+            }
+        }
+    }
+
+    /// Hands a row to the caller, unless the OFFSET skips it. Ends the subprogram once the LIMIT is reached.
+    fn output(&mut self, limit: &LimitRegisters, start: Reg, n: usize, skip: Label, end: Label) {
+        if let Some(offset) = limit.offset {
+            self.emit(Op::IfPos {
+                reg: offset,
+                target: skip,
+                decrement: 1,
+            });
+        }
+        self.emit(Op::ResultRow { start, n });
+        if let Some(reg) = limit.limit {
+            self.emit(Op::DecrJumpZero { reg, target: end });
+        }
+    }
+
+    /// Evaluates the LIMIT and OFFSET, ending the subprogram right away for LIMIT 0.
+    fn limit(&mut self, scope: &Scope<'_, 'a>, limit: &Option<Limit<'a>>, end: Label) -> LimitRegisters {
+        let Some(limit) = limit else {
+            return LimitRegisters {
+                limit: None,
+                offset: None,
+            };
+        };
+        let mut value = |expr| {
+            let reg = self.register();
+            self.expr(scope, expr, reg);
+            self.emit(Op::MustBeInt { reg });
+            reg
+        };
+        let count = value(&limit.count);
+        let offset = limit.offset.as_ref().map(value);
+        self.emit(Op::IfNot {
+            reg: count,
+            target: end,
+        });
+        LimitRegisters {
+            limit: Some(count),
+            offset,
+        }
+    }
+
+    /// Opens a sorter keeping the rows the LIMIT and OFFSET leave.
+    fn sorter_open(&mut self, limit: &LimitRegisters, order: Vec<SortOrder>, distinct: bool) -> CursorId {
+        let cursor = self.cursor();
+        let limit = limit.limit.map(|reg| {
+            let dest = self.register();
+            self.emit(Op::OffsetLimit {
+                limit: reg,
+                offset: limit.offset,
+                dest,
+            });
+            dest
+        });
+        self.emit(Op::SorterOpen {
+            cursor,
+            order,
+            limit,
+            distinct,
+        });
+        cursor
+    }
+
+    /// Copies the values of the columns a subprogram takes into consecutive registers, for it to be started with.
+    fn args(&mut self, scope: &Scope<'_, 'a>, columns: &[(&'a str, &'a str)], extra: usize) -> Reg {
+        let start = self.registers(columns.len() + extra);
+        for (i, &(table, name)) in columns.iter().enumerate() {
+            let src = find_column(&scope.columns, table, name)
+                .expect("column of an unknown table")
+                .reg;
+            self.emit(Op::Copy {
+                src,
+                dest: start + i,
+                n: 1,
+            });
+        }
+        start
+    }
+
+    /// Runs a subprogram, `each` being the code run on each of its rows with the registers the row is loaded in.
+    fn each_row(&mut self, program: usize, args: Reg, n: usize, columns: usize, each: impl FnOnce(&mut Self, Reg)) {
+        let cursor = self.cursor();
+        let (top, done) = (self.label(), self.label());
+        self.emit(Op::OpenSubquery {
+            cursor,
+            program,
+            args,
+            n,
+        });
+        self.emit(Op::Rewind { cursor, target: done });
+        self.place(top);
+        let row = self.registers(columns);
+        for column in 0..columns {
+            self.emit(Op::Column {
+                cursor,
+                column,
+                dest: row + column,
+                default: None,
+            });
+        }
+        each(self, row);
+        self.emit(Op::Next { cursor, target: top });
+        self.place(done);
+    }
+
+    /// Computes the value an equality of a join compares a column with, converted the way the comparison converts
+    /// it. Jumps to `null` if it's NULL, which is never equal to anything.
+    fn join_key(&mut self, scope: &Scope<'_, 'a>, key: &Expr<'a>, affinity: Option<SqlType>, dest: Reg, null: Label) {
+        self.expr(scope, key, dest);
+        self.emit(Op::IsNull {
+            reg: dest,
+            target: null,
+        });
+        if let Some(affinity) = affinity {
+            self.emit(Op::Affinity { reg: dest, affinity });
+        }
+    }
+
+    /// Moves the cursor to the first entry within the lower bound of a range, or to the first entry when there's none.
+    /// Jumps to `done` if there's no such entry, or if the bound is NULL, which no value is above.
+    fn range_start(&mut self, scope: &Scope<'_, 'a>, cursor: CursorId, lower: Option<&KeyBound<'a>>, done: Label) {
+        let Some(lower) = lower else {
+            self.emit(Op::Rewind { cursor, target: done });
+            return;
+        };
+        let key = self.register();
+        self.join_key(scope, &lower.key, lower.affinity, key, done);
+        self.emit(match lower.inclusive {
+            true => Op::SeekGE {
+                cursor,
+                key,
+                desc: false,
+                target: done,
+            },
+            false => Op::SeekGT {
+                cursor,
+                key,
+                desc: false,
+                target: done,
+            },
+        });
+    }
+
+    /// Computes the upper bound of a range ahead of the loop, giving the register holding it and whether it's in the
+    /// range. Jumps to `done` if it's NULL, which no value is below.
+    fn range_end(&mut self, scope: &Scope<'_, 'a>, upper: Option<&KeyBound<'a>>, done: Label) -> Option<(Reg, bool)> {
+        let upper = upper?;
+        let key = self.register();
+        self.join_key(scope, &upper.key, upper.affinity, key, done);
+        Some((key, upper.inclusive))
+    }
+
+    /// Adds the rows of the sorted `left` rows that the sorted `right` ones have, or the ones they don't have, to
+    /// `out`. Both are walked once, side by side.
+    fn merge(&mut self, left: CursorId, right: CursorId, out: CursorId, n: usize, intersect: bool) {
+        let eof = self.register();
+        let (right_row, left_row) = (self.registers(n), self.registers(n));
+        let [right_empty, top, advance, step, reload, found, absent, next, done] = [(); 9].map(|_| self.label());
+        self.emit(Op::Integer { value: 1, dest: eof });
+        self.emit(Op::SorterSort {
+            cursor: right,
+            target: right_empty,
+        });
+        self.emit(Op::Integer { value: 0, dest: eof });
+        self.emit(Op::SorterData {
+            cursor: right,
+            column: 0,
+            n,
+            dest: right_row,
+        });
+        self.place(right_empty);
+        self.emit(Op::SorterSort {
+            cursor: left,
+            target: done,
+        });
+        self.place(top);
+        self.emit(Op::SorterData {
+            cursor: left,
+            column: 0,
+            n,
+            dest: left_row,
+        });
+        self.place(advance);
+        self.emit(Op::IfPos {
+            reg: eof,
+            target: absent,
+            decrement: 0,
+        });
+        self.emit(Op::Compare {
+            left: right_row,
+            right: left_row,
+            n,
+        });
+        self.emit(Op::Jump {
+            lt: step,
+            eq: found,
+            gt: absent,
+        });
+        self.place(step);
+        self.emit(Op::SorterNext {
+            cursor: right,
+            target: reload,
+        });
+        self.emit(Op::Integer { value: 1, dest: eof });
+        self.emit(Op::Goto { target: absent });
+        self.place(reload);
+        self.emit(Op::SorterData {
+            cursor: right,
+            column: 0,
+            n,
+            dest: right_row,
+        });
+        self.emit(Op::Goto { target: advance });
+        let insert = Op::SorterInsert {
+            cursor: out,
+            start: left_row,
+            n,
+        };
+        self.place(found);
+        if intersect {
+            self.emit(insert);
+            self.emit(Op::Goto { target: next });
+            self.place(absent);
+        } else {
+            self.emit(Op::Goto { target: next });
+            self.place(absent);
+            self.emit(insert);
+        }
+        self.place(next);
+        self.emit(Op::SorterNext {
+            cursor: left,
+            target: top,
+        });
+        self.place(done);
+    }
+}
+
+/// Registers holding the LIMIT and OFFSET, the rows left to hand out and the ones left to skip.
+struct LimitRegisters {
+    limit: Option<Reg>,
+    offset: Option<Reg>,
+}
+
+/// Where the values expressions refer to are.
+struct Scope<'q, 'a> {
+    functions: &'q Functions,
+    columns: Vec<ColumnRegister<'a>>,
+    /// The results of the aggregate function calls, for the expressions about a whole group.
+    aggregates: Vec<(Expr<'a>, Reg)>,
+    subqueries: &'q [Subquery<'a>],
+    programs: &'q [SubqueryProgram<'a>],
+}
+
+impl<'a> Scope<'_, 'a> {
+    /// The expression along with the registers of the values it reads.
+    fn expression(&self, expr: &Expr<'a>) -> Expression<'a> {
+        let mut expression = Expression {
+            expr: expr.clone(),
+            columns: vec![],
+            aggregates: vec![],
+            subqueries: vec![],
+        };
+        let add = |columns: &mut Vec<ColumnRegister<'a>>, table: &str, name: &str| {
+            if find_column(columns, table, name).is_none() {
+                let column = find_column(&self.columns, table, name).expect("column missing from the scope");
+                columns.push(column.clone());
+            }
+        };
+        expr.walk(&mut |e| match e {
+            Expr::Function { name, args, .. } if self.functions.is_aggregate(name, args.len()) => {
+                if let Some(aggregate) = self.aggregates.iter().find(|(call, _)| call == e)
+                    && !expression.aggregates.iter().any(|(call, _)| call == e)
+                {
+                    expression.aggregates.push(aggregate.clone());
+                }
+                false
+            }
+            Expr::Column { table: Some(t), name } => {
+                add(&mut expression.columns, t, name);
+                false
+            }
+            Expr::Subquery(_) | Expr::Exists(_) | Expr::InSelect { .. } => {
+                for (table, name) in &subquery(self.subqueries, e).query.outer {
+                    add(&mut expression.columns, table, name);
+                }
+                if !expression.subqueries.iter().any(|s| s.expr == *e) {
+                    let program = self
+                        .programs
+                        .iter()
+                        .find(|s| s.expr == *e)
+                        .expect("subquery not compiled");
+                    expression.subqueries.push(program.clone());
+                }
+                true
+            }
+            _ => true,
+        });
+        // The lists are bound like the expression is, for its calls to be found in them.
+        self.functions.bind(&mut expression.expr);
+        for (call, _) in &mut expression.aggregates {
+            self.functions.bind(call);
+        }
+        for subquery in &mut expression.subqueries {
+            self.functions.bind(&mut subquery.expr);
+        }
+        expression
+    }
+}
+
+fn find_column<'c, 'a>(columns: &'c [ColumnRegister<'a>], table: &str, name: &str) -> Option<&'c ColumnRegister<'a>> {
+    columns.iter().find(|c| c.table == table && c.name == name)
+}
+
+/// How the loop over a table was opened, for the code closing it.
+struct Level {
+    /// Where the loop goes for each row.
+    top: Label,
+    /// Where the next row of the table is picked.
+    next: Label,
+    /// Where the rows of the table are done with.
+    done: Label,
+    /// Where a row passing the ON clause goes, the row of NULLs of a LEFT JOIN goes there too.
+    matched: Label,
+    /// The cursor the columns are loaded from.
+    read: CursorId,
+    /// The cursor `Next` moves, `None` when a single row is looked up.
+    advance: Option<CursorId>,
+    /// Whether the b-tree is walked backwards, moved by `Prev` instead.
+    reverse: bool,
+    /// The cursors moved to a row of NULLs when no row matched.
+    cursors: Vec<CursorId>,
+    /// Whether a row passed the ON clause, for LEFT JOINs.
+    found: Option<Reg>,
+}
+
+impl<'a> Codegen<'a> {
+    /// Makes room for a subprogram, the subprograms it runs are compiled before it's done.
+    fn reserve(&mut self) -> usize {
+        self.subprograms.push(None);
+        self.subprograms.len() - 1
+    }
+
+    /// Puts the subprograms together, the main one first.
+    fn finish(self) -> Program<'a> {
+        let mut program = Program {
+            ops: vec![],
+            comments: vec![],
+            subprograms: vec![],
+        };
+        for (i, code) in self.subprograms.into_iter().enumerate() {
+            let code = code.expect("subprogram not compiled");
+            let start = program.ops.len();
+            for mut op in code.ops {
+                for target in op.targets_mut() {
+                    *target = code.labels[*target].expect("label not placed") + start;
+                }
+                program.ops.push(op);
+            }
+            let mut comments = code.comments;
+            if i > 0 {
+                comments[0] = match comments[0].is_empty() {
+                    true => format!("subprogram {i}"),
+                    false => format!("subprogram {i}, {}", comments[0]),
+                };
+            }
+            program.comments.extend(comments);
+            program.subprograms.push(Subprogram {
+                start,
+                params: code.params,
+                registers: code.registers,
+                cursors: code.cursors,
+            });
+        }
+        program
+    }
+
+    /// Compiles the subqueries of expressions.
+    fn subqueries(
+        &mut self,
+        subqueries: &[Subquery<'a>],
+        outer: &[ColumnRegister<'a>],
+    ) -> Result<Vec<SubqueryProgram<'a>>> {
+        subqueries
+            .iter()
+            .map(|sub| {
+                Ok(SubqueryProgram {
+                    expr: sub.expr.clone(),
+                    program: self.select(&sub.query, outer, false)?,
+                    affinity: sub.affinity,
+                })
+            })
+            .collect()
+    }
+
+    /// Compiles the subprogram producing the rows of a FROM subquery.
+    fn source(&mut self, table: &Table<'a>, outer: &[ColumnRegister<'a>]) -> Result<usize> {
+        match &table.source {
+            Source::Subquery(query) => self.select(query, outer, false),
+            Source::Recursive(query) => self.recursive(query, outer),
+            Source::Compound(query) => self.compound(query, outer),
+            Source::Window(query) => self.window(query, outer),
+            Source::Btree(_) | Source::Current | Source::Function { .. } => unreachable!("not a FROM subquery"),
+        }
+    }
+
+    /// Compiles a select, returns the index of its subprogram. The recursive selects of a common table expression also
+    /// take the row the work queue is at, after the columns of the enclosing queries.
+    fn select(&mut self, q: &Query<'a>, outer: &[ColumnRegister<'a>], recursive: bool) -> Result<usize> {
+        let program = self.reserve();
+        let (mut code, params) = Code::new(&q.outer, outer);
+        let current = recursive.then(|| {
+            let table = q.tables.iter().find(|t| matches!(t.source, Source::Current));
+            code.registers(table.expect("recursive select without its table").ct.columns.len())
+        });
+
+        let mut table_columns = vec![vec![]; q.tables.len()];
+        for column in used_columns(q) {
+            if let Some(i) = table_index(&q.tables, column) {
+                table_columns[i].push(ColumnRegister {
+                    table: column.0,
+                    name: column.1,
+                    affinity: column_affinity(&q.tables[i].ct, column.1).unwrap(),
+                    collation: column_collation(&q.tables[i].ct, column.1)?,
+                    reg: code.register(),
+                });
+            }
+        }
+        // The columns of the tables come before the ones of the enclosing queries, which they hide.
+        let columns: Vec<_> = table_columns.iter().flatten().cloned().chain(params).collect();
+        let programs = self.subqueries(&q.subqueries, &columns)?;
+        let scope = Scope {
+            functions: self.functions,
+            columns,
+            aggregates: vec![],
+            subqueries: &q.subqueries,
+            programs: &programs,
+        };
+
+        let end = code.label();
+        let limit = code.limit(&scope, &q.limit, end);
+        let aggregate = q.is_aggregate();
+        let Strategy { scan, sort, adjacent } = plan_strategy(q);
+        let set = q.distinct.then(|| {
+            let cursor = code.cursor();
+            let collations = q.columns.iter().map(|c| expr_collation(&q.tables, &c.expr)).collect();
+            code.emit(Op::OpenSet {
+                cursor,
+                adjacent,
+                collations,
+            });
+            cursor
+        });
+        // Aggregates take the group keys, the arguments of the calls and the bare columns of each row.
+        let aggregation = match aggregate {
+            true => {
+                let (spec, bare) = aggregate_spec(q, self.functions)?;
+                let cursor = code.cursor();
+                let group = code.registers(spec.keys.len() + spec.bare + spec.calls.len());
+                code.emit(Op::AggOpen {
+                    cursor,
+                    spec: Box::new(spec),
+                    sorted: scan.sorted,
+                });
+                Some(Aggregation { cursor, group, bare })
+            }
+            false => None,
+        };
+        // Like SQLite, groups come out in key order when there's no ORDER BY, and ties of the ORDER BY keep that
+        // order, so the group key sorts the rows after the ORDER BY terms.
+        let group_order: Vec<_> = match aggregation {
+            Some(_) => q
+                .group_by
+                .iter()
+                .map(|e| SortOrder {
+                    collation: expr_collation(&q.tables, e),
+                    ..ASC
+                })
+                .collect(),
+            None => vec![],
+        };
+        let sorter = sort.then(|| {
+            let order = [&q.order[..], &group_order].concat();
+            code.sorter_open(&limit, order, false)
+        });
+
+        // Open the tables, run the FROM subqueries that aren't the first table and build the hash tables.
+        let mut cursors = vec![];
+        for (i, table) in q.tables.iter().enumerate() {
+            let cursor = code.cursor();
+            let mut lookup = None;
+            match &table.source {
+                Source::Btree(root) => {
+                    let index = match &table.access {
+                        Access::Index { root, .. } | Access::IndexRange { root, .. } => Some(*root),
+                        Access::Scan if i == 0 => scan.index,
+                        _ => None,
+                    };
+                    // A covering index is read instead of the table.
+                    if !(i == 0 && scan.covering) {
+                        code.emit(Op::OpenRead { cursor, root: *root });
+                        code.comment(table.name.to_string());
+                    }
+                    if let Some(root) = index {
+                        let c = code.cursor();
+                        code.emit(Op::OpenRead { cursor: c, root });
+                        let (_, index) = table.indexes.iter().find(|(r, _)| *r == root).unwrap();
+                        code.comment(index.index_name.to_string());
+                        lookup = Some(c);
+                    }
+                }
+                Source::Current => code.emit(Op::OpenPseudo {
+                    cursor,
+                    start: current.unwrap(),
+                    n: table.ct.columns.len(),
+                }),
+                // The function is called as its loop starts, once the rows of the tables before it are known.
+                Source::Function { .. } => {}
+                _ => {
+                    let program = self.source(table, &scope.columns)?;
+                    let args = code.args(&scope, table.outer(), 0);
+                    let n = table.outer().len();
+                    // The rows of the first table are produced as the loop reads them, so it can stop early.
+                    if i == 0 {
+                        code.emit(Op::OpenSubquery {
+                            cursor,
+                            program,
+                            args,
+                            n,
+                        });
+                    } else {
+                        code.emit(Op::OpenEphemeral { cursor });
+                        let columns = table.ct.columns.len();
+                        code.each_row(program, args, n, columns, |code, row| {
+                            code.emit(Op::Insert {
+                                cursor,
+                                start: row,
+                                n: columns,
+                            })
+                        });
+                    }
+                }
+            }
+            cursors.push((cursor, lookup));
+        }
+        for (i, table) in q.tables.iter().enumerate() {
+            let Access::Hash { column, affinity, .. } = &table.access else {
+                continue;
+            };
+            let (source, hash) = (cursors[i].0, code.cursor());
+            let (top, skip, built) = (code.label(), code.label(), code.label());
+            code.emit(Op::OpenHash { cursor: hash, source });
+            code.emit(Op::Rewind {
+                cursor: source,
+                target: built,
+            });
+            code.place(top);
+            code.load(scope.functions, table, source, &table_columns[i], None);
+            let key = code.register();
+            code.join_key(&scope, column, *affinity, key, skip);
+            code.emit(Op::HashInsert { cursor: hash, key });
+            code.place(skip);
+            code.emit(Op::Next {
+                cursor: source,
+                target: top,
+            });
+            code.place(built);
+            cursors[i].1 = Some(hash);
+        }
+
+        let select = SelectCode {
+            q,
+            table_columns,
+            limit,
+            set,
+            sorter,
+            scan,
+            end,
+        };
+        let loops = select.open_loops(&mut code, &scope, &cursors);
+        code.conditions(&scope, &q.filter, loops.next());
+        match &aggregation {
+            None => {
+                select.result(&mut code, &scope, None, loops.next());
+                select.close_loops(&mut code, &scope, &loops);
+                select.drain(&mut code, q.order_by.len());
+            }
+            Some(aggregation) => {
+                let ret = code.register();
+                let routine = code.label();
+                select.aggregate_step(&mut code, &scope, aggregation);
+                // Rows that come ordered by the group key finish a group as soon as the key changes.
+                if scan.sorted {
+                    code.emit(Op::AggGroup {
+                        cursor: aggregation.cursor,
+                        dest: aggregation.group,
+                        target: loops.next(),
+                    });
+                    code.emit(Op::Gosub { ret, target: routine });
+                }
+                select.close_loops(&mut code, &scope, &loops);
+                let (top, groups_done) = (code.label(), code.label());
+                code.emit(Op::AggFinal {
+                    cursor: aggregation.cursor,
+                });
+                code.place(top);
+                code.emit(Op::AggGroup {
+                    cursor: aggregation.cursor,
+                    dest: aggregation.group,
+                    target: groups_done,
+                });
+                code.emit(Op::Gosub { ret, target: routine });
+                code.emit(Op::Goto { target: top });
+                code.place(groups_done);
+                select.drain(&mut code, q.order_by.len() + group_order.len());
+                code.emit(Op::Goto { target: end });
+
+                // The subroutine turning a group into a result row, if it passes the HAVING clause.
+                code.place(routine);
+                let (keys, bare) = (q.group_by.len(), aggregation.bare.len());
+                let mut columns = vec![];
+                for (i, (table, name)) in aggregation.bare.iter().enumerate() {
+                    let table = table.expect("unqualified column reference");
+                    let column = find_column(&scope.columns, table, name).expect("column missing from the scope");
+                    columns.push(ColumnRegister {
+                        reg: aggregation.group + keys + i,
+                        ..column.clone()
+                    });
+                }
+                columns.extend(scope.columns.iter().cloned());
+                let calls = q.calls.iter().enumerate();
+                let group_scope = Scope {
+                    functions: self.functions,
+                    columns,
+                    aggregates: calls
+                        .map(|(i, call)| (call.clone(), aggregation.group + keys + bare + i))
+                        .collect(),
+                    subqueries: &q.subqueries,
+                    programs: &programs,
+                };
+                let done = code.label();
+                code.conditions(&group_scope, q.having.as_slice(), done);
+                select.result(&mut code, &group_scope, Some(aggregation.group), done);
+                code.place(done);
+                code.emit(Op::Return { ret });
+            }
+        }
+        code.place(end);
+        code.emit(Op::Halt);
+        self.subprograms[program] = Some(code);
+        Ok(program)
+    }
+}
+
+/// The aggregation of a select, and the registers a group is loaded in: its key, its bare columns and the results of
+/// the calls.
+struct Aggregation<'a> {
+    cursor: CursorId,
+    group: Reg,
+    bare: Vec<BareColumn<'a>>,
+}
+
+/// A column used outside of aggregate function calls, whose value a group keeps from one of its rows.
+type BareColumn<'a> = (Option<&'a str>, &'a str);
+
+/// How the rows fed to the aggregation of a select are laid out, along with the bare columns they end with.
+fn aggregate_spec<'a>(q: &Query<'a>, functions: &Functions) -> Result<(AggregateSpec, Vec<BareColumn<'a>>)> {
+    let mut bare = vec![];
+    for expr in q
+        .columns
+        .iter()
+        .map(|c| &c.expr)
+        .chain(&q.having)
+        .chain(q.order_by.iter().filter_map(|t| match t {
+            Term::Expr(expr) => Some(expr),
+            Term::Column(_) => None,
+        }))
+    {
+        collect_bare_columns(functions, expr, &q.subqueries, &mut bare);
+    }
+    let mut calls = vec![];
+    for call in &q.calls {
+        let Expr::Function {
+            name, args, distinct, ..
+        } = call
+        else {
+            unreachable!()
+        };
+        if *distinct && args.len() != 1 {
+            bail!("DISTINCT aggregates must have exactly one argument");
+        }
+        calls.push(AggregateCall {
+            factory: functions.aggregate(name, args.len()).unwrap(),
+            args: args.len(),
+            distinct: *distinct,
+        });
+    }
+    let spec = AggregateSpec {
+        keys: q.group_by.iter().map(|e| expr_collation(&q.tables, e)).collect(),
+        calls,
+        bare: bare.len(),
+    };
+    Ok((spec, bare))
+}
+
+/// The loops over the tables of a select.
+struct Loops {
+    levels: Vec<Level>,
+    /// Where the loops are done with.
+    done: Label,
+}
+
+impl Loops {
+    /// Where the innermost loop picks its next row.
+    fn next(&self) -> Label {
+        self.levels.last().map_or(self.done, |level| level.next)
+    }
+}
+
+/// What the code of the parts of a select shares.
+struct SelectCode<'s, 'a> {
+    q: &'s Query<'a>,
+    /// The registers of the columns of each table the query uses.
+    table_columns: Vec<Vec<ColumnRegister<'a>>>,
+    limit: LimitRegisters,
+    /// The set leaving out repeated result rows, for SELECT DISTINCT.
+    set: Option<CursorId>,
+    /// The sorter putting the result rows in order, when they don't come sorted.
+    sorter: Option<CursorId>,
+    /// How the first table is read.
+    scan: Scan,
+    end: Label,
+}
+
+impl<'a> SelectCode<'_, 'a> {
+    /// The index the columns of table `i` are read from, when it covers them.
+    fn covering(&self, i: usize) -> Option<&CreateIndex<'a>> {
+        let root = self.scan.index.filter(|_| i == 0 && self.scan.covering)?;
+        let (_, index) = self.q.tables[0].indexes.iter().find(|(r, _)| *r == root)?;
+        Some(index)
+    }
+
+    /// Opens a loop over the rows of each table matching the rows of the tables before it.
+    fn open_loops(
+        &self,
+        code: &mut Code<'a>,
+        scope: &Scope<'_, 'a>,
+        cursors: &[(CursorId, Option<CursorId>)],
+    ) -> Loops {
+        let mut loops = Loops {
+            levels: vec![],
+            done: code.label(),
+        };
+        for (i, table) in self.q.tables.iter().enumerate() {
+            let (cursor, lookup) = cursors[i];
+            let (top, next, done, matched) = (code.label(), code.label(), code.label(), code.label());
+            let found = table.left.then(|| code.register());
+            if let Some(reg) = found {
+                code.emit(Op::Integer { value: 0, dest: reg });
+            }
+            let reverse = i == 0 && self.scan.reverse;
+            let (read, advance, null) = match (&table.access, lookup) {
+                // The entries of the index point to the rows of the table.
+                (Access::Scan, Some(index)) => {
+                    code.emit(match reverse {
+                        true => Op::Last {
+                            cursor: index,
+                            target: done,
+                        },
+                        false => Op::Rewind {
+                            cursor: index,
+                            target: done,
+                        },
+                    });
+                    code.place(top);
+                    if self.covering(i).is_some() {
+                        (index, Some(index), vec![index])
+                    } else {
+                        let rowid = code.register();
+                        code.emit(Op::IdxRowid {
+                            cursor: index,
+                            dest: rowid,
+                        });
+                        code.emit(Op::SeekRowid {
+                            cursor,
+                            key: rowid,
+                            target: next,
+                        });
+                        (cursor, Some(index), vec![cursor, index])
+                    }
+                }
+                (Access::Scan, None) => {
+                    if let Source::Function { function, name, args } = &table.source {
+                        let start = code.registers(args.len());
+                        for (i, arg) in args.iter().enumerate() {
+                            code.expr(scope, arg, start + i);
+                        }
+                        code.emit(Op::OpenFunction {
+                            cursor,
+                            function: *function,
+                            name,
+                            args: start,
+                            n: args.len(),
+                        });
+                    }
+                    code.emit(match reverse {
+                        true => Op::Last { cursor, target: done },
+                        false => Op::Rewind { cursor, target: done },
+                    });
+                    code.place(top);
+                    (cursor, Some(cursor), vec![cursor])
+                }
+                (Access::Rowid(key), _) => {
+                    let reg = code.register();
+                    code.expr(scope, key, reg);
+                    code.emit(Op::SeekRowid {
+                        cursor,
+                        key: reg,
+                        target: done,
+                    });
+                    (cursor, None, vec![cursor])
+                }
+                (
+                    Access::Index {
+                        desc, key, affinity, ..
+                    },
+                    Some(index),
+                ) => {
+                    let reg = code.register();
+                    code.join_key(scope, key, *affinity, reg, done);
+                    code.emit(Op::SeekGE {
+                        cursor: index,
+                        key: reg,
+                        desc: *desc,
+                        target: done,
+                    });
+                    code.place(top);
+                    code.emit(Op::IdxGT {
+                        cursor: index,
+                        key: reg,
+                        desc: *desc,
+                        target: done,
+                    });
+                    let rowid = code.register();
+                    code.emit(Op::IdxRowid {
+                        cursor: index,
+                        dest: rowid,
+                    });
+                    code.emit(Op::SeekRowid {
+                        cursor,
+                        key: rowid,
+                        target: next,
+                    });
+                    (cursor, Some(index), vec![cursor, index])
+                }
+                (Access::RowidRange { lower, upper }, _) => {
+                    let upper = code.range_end(scope, upper.as_ref(), done);
+                    code.range_start(scope, cursor, lower.as_ref(), done);
+                    code.place(top);
+                    // Rowids past the upper bound end the loop.
+                    if let Some((key, inclusive)) = upper {
+                        let (rowid, within) = (code.register(), code.label());
+                        code.emit(Op::Rowid { cursor, dest: rowid });
+                        code.emit(Op::Compare {
+                            left: rowid,
+                            right: key,
+                            n: 1,
+                        });
+                        code.emit(Op::Jump {
+                            lt: within,
+                            eq: if inclusive { within } else { done },
+                            gt: done,
+                        });
+                        code.place(within);
+                    }
+                    (cursor, Some(cursor), vec![cursor])
+                }
+                (Access::IndexRange { lower, upper, .. }, Some(index)) => {
+                    let upper = code.range_end(scope, upper.as_ref(), done);
+                    code.range_start(scope, index, lower.as_ref(), done);
+                    code.place(top);
+                    if let Some((key, inclusive)) = upper {
+                        code.emit(match inclusive {
+                            true => Op::IdxGT {
+                                cursor: index,
+                                key,
+                                desc: false,
+                                target: done,
+                            },
+                            false => Op::IdxGE {
+                                cursor: index,
+                                key,
+                                desc: false,
+                                target: done,
+                            },
+                        });
+                    }
+                    let rowid = code.register();
+                    code.emit(Op::IdxRowid {
+                        cursor: index,
+                        dest: rowid,
+                    });
+                    code.emit(Op::SeekRowid {
+                        cursor,
+                        key: rowid,
+                        target: next,
+                    });
+                    (cursor, Some(index), vec![cursor, index])
+                }
+                (Access::Hash { key, affinity, .. }, Some(hash)) => {
+                    let reg = code.register();
+                    code.join_key(scope, key, *affinity, reg, done);
+                    code.emit(Op::HashSeek {
+                        cursor: hash,
+                        key: reg,
+                        target: done,
+                    });
+                    code.place(top);
+                    (hash, Some(hash), vec![hash])
+                }
+                _ => unreachable!("lookup without its cursor"),
+            };
+            code.load(scope.functions, table, read, &self.table_columns[i], self.covering(i));
+            code.conditions(scope, &table.on, next);
+            code.place(matched);
+            if let Some(reg) = found {
+                code.emit(Op::Integer { value: 1, dest: reg });
+            }
+            code.conditions(scope, &table.filter, next);
+            loops.levels.push(Level {
+                top,
+                next,
+                done,
+                matched,
+                read,
+                advance,
+                reverse,
+                cursors: null,
+                found,
+            });
+        }
+        loops
+    }
+
+    /// Closes the loops, from the innermost one out. The table of a LEFT JOIN whose rows didn't match gets a row of
+    /// NULLs, which goes through the rest of the loops like the rows that matched.
+    fn close_loops(&self, code: &mut Code<'a>, scope: &Scope<'_, 'a>, loops: &Loops) {
+        for (i, level) in loops.levels.iter().enumerate().rev() {
+            code.place(level.next);
+            if let Some(cursor) = level.advance {
+                code.emit(match level.reverse {
+                    true => Op::Prev {
+                        cursor,
+                        target: level.top,
+                    },
+                    false => Op::Next {
+                        cursor,
+                        target: level.top,
+                    },
+                });
+            }
+            code.place(level.done);
+            if let Some(reg) = level.found {
+                let parent = i.checked_sub(1).map_or(loops.done, |p| loops.levels[p].next);
+                code.emit(Op::IfPos {
+                    reg,
+                    target: parent,
+                    decrement: 0,
+                });
+                for &cursor in &level.cursors {
+                    code.emit(Op::NullRow { cursor });
+                }
+                code.load(
+                    scope.functions,
+                    &self.q.tables[i],
+                    level.read,
+                    &self.table_columns[i],
+                    self.covering(i),
+                );
+                code.emit(Op::Goto { target: level.matched });
+            }
+        }
+        code.place(loops.done);
+    }
+
+    /// Computes a result row, and hands it out or gives it to the sorter. `group` holds the group the row is about,
+    /// whose key sorts the rows after the ORDER BY terms. `skip` is where the code goes on once done with the row.
+    fn result(&self, code: &mut Code<'a>, scope: &Scope<'_, 'a>, group: Option<Reg>, skip: Label) {
+        let q = self.q;
+        let n = q.columns.len();
+        let group_keys = group.map_or(0, |_| q.group_by.len());
+        let keys = match self.sorter {
+            Some(_) => q.order_by.len() + group_keys,
+            None => 0,
+        };
+        let block = code.registers(keys + n);
+        let result = block + keys;
+        for (i, column) in q.columns.iter().enumerate() {
+            code.expr(scope, &column.expr, result + i);
+        }
+        if let Some(cursor) = self.set {
+            code.emit(Op::SetInsert {
+                cursor,
+                start: result,
+                n,
+                target: skip,
+            });
+        }
+        let Some(cursor) = self.sorter else {
+            code.output(&self.limit, result, n, skip, self.end);
+            return;
+        };
+        for (i, term) in q.order_by.iter().enumerate() {
+            match term {
+                Term::Column(c) => code.emit(Op::Copy {
+                    src: result + c,
+                    dest: block + i,
+                    n: 1,
+                }),
+                Term::Expr(expr) => code.expr(scope, expr, block + i),
+            }
+        }
+        if let Some(group) = group
+            && group_keys > 0
+        {
+            code.emit(Op::Copy {
+                src: group,
+                dest: block + q.order_by.len(),
+                n: group_keys,
+            });
+        }
+        code.emit(Op::SorterInsert {
+            cursor,
+            start: block,
+            n: keys + n,
+        });
+    }
+
+    /// Hands out the rows of the sorter, which start with `keys` sort keys.
+    fn drain(&self, code: &mut Code<'a>, keys: usize) {
+        let Some(cursor) = self.sorter else {
+            return;
+        };
+        let n = self.q.columns.len();
+        let row = code.registers(n);
+        let (top, next) = (code.label(), code.label());
+        code.emit(Op::SorterSort {
+            cursor,
+            target: self.end,
+        });
+        code.place(top);
+        code.emit(Op::SorterData {
+            cursor,
+            column: keys,
+            n,
+            dest: row,
+        });
+        code.output(&self.limit, row, n, next, self.end);
+        code.place(next);
+        code.emit(Op::SorterNext { cursor, target: top });
+    }
+
+    /// Adds the row of the tables to the aggregation.
+    fn aggregate_step(&self, code: &mut Code<'a>, scope: &Scope<'_, 'a>, aggregation: &Aggregation<'a>) {
+        let q = self.q;
+        let args = q.calls.iter().flat_map(|call| match call {
+            Expr::Function { args, .. } => args,
+            _ => unreachable!(),
+        });
+        let exprs: Vec<_> = q.group_by.iter().chain(args).collect();
+        let n = exprs.len() + aggregation.bare.len();
+        let start = code.registers(n);
+        for (i, expr) in exprs.iter().enumerate() {
+            code.expr(scope, expr, start + i);
+        }
+        for (i, (table, name)) in aggregation.bare.iter().enumerate() {
+            let table = table.expect("unqualified column reference");
+            let src = find_column(&scope.columns, table, name)
+                .expect("column missing from the scope")
+                .reg;
+            code.emit(Op::Copy {
+                src,
+                dest: start + exprs.len() + i,
+                n: 1,
+            });
+        }
+        code.emit(Op::AggStep {
+            cursor: aggregation.cursor,
+            start,
+            n,
+        });
+    }
+}
+
+impl<'a> Codegen<'a> {
+    /// Runs a select of a compound or of a common table expression, `each` being the code run on each of its rows.
+    fn rows(
+        &mut self,
+        code: &mut Code<'a>,
+        scope: &Scope<'_, 'a>,
+        q: &Query<'a>,
+        each: impl FnOnce(&mut Code<'a>, Reg),
+    ) -> Result<()> {
+        let program = self.select(q, &scope.columns, false)?;
+        let args = code.args(scope, &q.outer, 0);
+        code.each_row(program, args, q.outer.len(), q.columns.len(), each);
+        Ok(())
+    }
+
+    /// Compiles a compound select. The rows of the selects up to the last operator that isn't UNION ALL are made
+    /// distinct and sorted, the ones of the selects after it are handed out as they come.
+    fn compound(&mut self, cq: &CompoundQuery<'a>, outer: &[ColumnRegister<'a>]) -> Result<usize> {
+        let program = self.reserve();
+        let (mut code, params) = Code::new(&cq.outer, outer);
+        let scope = Scope {
+            functions: self.functions,
+            columns: params,
+            aggregates: vec![],
+            subqueries: &[],
+            programs: &[],
+        };
+        let n = cq.parts[0].columns.len();
+        let no_limit = LimitRegisters {
+            limit: None,
+            offset: None,
+        };
+        let mut rest = 0;
+        if let Some(last) = cq.ops.iter().rposition(|op| *op != CompoundOp::UnionAll) {
+            rest = last + 2;
+            let sort = |cursor| move |code: &mut Code<'a>, row| code.emit(Op::SorterInsert { cursor, start: row, n });
+            let mut rows = code.sorter_open(&no_limit, vec![ASC; n], true);
+            self.rows(&mut code, &scope, &cq.parts[0], sort(rows))?;
+            for (op, q) in cq.ops.iter().zip(&cq.parts[1..rest]) {
+                if matches!(op, CompoundOp::Union | CompoundOp::UnionAll) {
+                    self.rows(&mut code, &scope, q, sort(rows))?;
+                    continue;
+                }
+                // Each INTERSECT and EXCEPT merges the sorted rows of the selects before it with the ones of its
+                // select.
+                let right = code.sorter_open(&no_limit, vec![ASC; n], true);
+                self.rows(&mut code, &scope, q, sort(right))?;
+                let merged = code.sorter_open(&no_limit, vec![ASC; n], true);
+                code.merge(rows, right, merged, n, *op == CompoundOp::Intersect);
+                rows = merged;
+            }
+            let row = code.registers(n);
+            let (top, done) = (code.label(), code.label());
+            code.emit(Op::SorterSort {
+                cursor: rows,
+                target: done,
+            });
+            code.place(top);
+            code.emit(Op::SorterData {
+                cursor: rows,
+                column: 0,
+                n,
+                dest: row,
+            });
+            code.emit(Op::ResultRow { start: row, n });
+            code.emit(Op::SorterNext {
+                cursor: rows,
+                target: top,
+            });
+            code.place(done);
+        }
+        for q in &cq.parts[rest..] {
+            self.rows(&mut code, &scope, q, |code, row| {
+                code.emit(Op::ResultRow { start: row, n })
+            })?;
+        }
+        code.emit(Op::Halt);
+        self.subprograms[program] = Some(code);
+        Ok(program)
+    }
+
+    /// Compiles the rows of a select calling window functions. They all go to the window, which hands them out with
+    /// the results of the calls once it has them all.
+    fn window(&mut self, wq: &WindowQuery<'a>, outer: &[ColumnRegister<'a>]) -> Result<usize> {
+        let program = self.reserve();
+        let (mut code, params) = Code::new(&wq.query.outer, outer);
+        let scope = Scope {
+            functions: self.functions,
+            columns: params,
+            aggregates: vec![],
+            subqueries: &[],
+            programs: &[],
+        };
+        let cursor = code.cursor();
+        code.emit(Op::WindowOpen {
+            cursor,
+            spec: Box::new(wq.spec.clone()),
+        });
+        let n = wq.query.columns.len();
+        self.rows(&mut code, &scope, &wq.query, |code, row| {
+            code.emit(Op::WindowInsert { cursor, start: row, n })
+        })?;
+        let n = n + wq.spec.passes.iter().map(|p| p.calls.len()).sum::<usize>();
+        let row = code.registers(n);
+        let (top, done) = (code.label(), code.label());
+        code.place(top);
+        code.emit(Op::WindowNext {
+            cursor,
+            dest: row,
+            target: done,
+        });
+        code.emit(Op::ResultRow { start: row, n });
+        code.emit(Op::Goto { target: top });
+        code.place(done);
+        code.emit(Op::Halt);
+        self.subprograms[program] = Some(code);
+        Ok(program)
+    }
+
+    /// Compiles a recursive common table expression. The rows of the initial selects go to the work queue, and each
+    /// row taken out of it is handed out and runs the recursive selects, whose rows go to the queue too.
+    fn recursive(&mut self, rq: &RecursiveQuery<'a>, outer: &[ColumnRegister<'a>]) -> Result<usize> {
+        let program = self.reserve();
+        let (mut code, params) = Code::new(&rq.outer, outer);
+        let programs = self.subqueries(&rq.subqueries, &params)?;
+        let scope = Scope {
+            functions: self.functions,
+            columns: params,
+            aggregates: vec![],
+            subqueries: &rq.subqueries,
+            programs: &programs,
+        };
+        let end = code.label();
+        let limit = code.limit(&scope, &rq.limit, end);
+        let n = rq.initial[0].columns.len();
+        let queue = code.cursor();
+        code.emit(Op::OpenQueue {
+            cursor: queue,
+            order_by: rq.order_by.clone(),
+            order: rq.order.clone(),
+            distinct: rq.distinct,
+        });
+        let push = |code: &mut Code<'a>, row| {
+            code.emit(Op::QueueInsert {
+                cursor: queue,
+                start: row,
+                n,
+            })
+        };
+        for q in &rq.initial {
+            self.rows(&mut code, &scope, q, push)?;
+        }
+        let row = code.registers(n);
+        let (top, next) = (code.label(), code.label());
+        code.place(top);
+        code.emit(Op::QueuePop {
+            cursor: queue,
+            dest: row,
+            target: end,
+        });
+        code.output(&limit, row, n, next, end);
+        code.place(next);
+        for q in &rq.recursive {
+            let program = self.select(q, &scope.columns, true)?;
+            let args = code.args(&scope, &q.outer, n);
+            code.emit(Op::Copy {
+                src: row,
+                dest: args + q.outer.len(),
+                n,
+            });
+            code.each_row(program, args, q.outer.len() + n, n, push);
+        }
+        code.emit(Op::Goto { target: top });
+        code.place(end);
+        code.emit(Op::Halt);
+        self.subprograms[program] = Some(code);
+        Ok(program)
+    }
+}
@@ -0,0 +1,942 @@
+//! Connections to a database, which run statements and hold the functions the application defines for them.
+
+use std::cell::Cell;
+use std::cell::RefCell;
+use std::fs::File;
+use std::path::Path;
+use std::path::PathBuf;
+use std::rc::Rc;
+
+use anyhow::Result;
+use parser::Value;
+
+use crate::Database;
+use crate::Schema;
+use crate::aggregate::Aggregate;
+use crate::functions::FunctionFlags;
+use crate::functions::Functions;
+use crate::planner::Catalog;
+use crate::statement::Statement;
+use crate::vm::Config;
+
+/// An open database. It's opened for reading, and again for writing when a statement writes to it.
+pub struct Connection {
+    path: PathBuf,
+    db: RefCell<Rc<Database>>,
+    config: Config,
+    functions: Functions,
+    /// The schema and statistics of the database, read when it's opened and again when a statement changes them.
+    catalog: RefCell<Rc<Catalog>>,
+    /// Number of statements whose rows are being read.
+    running: Cell<usize>,
+}
+
+/// A statement counted as running until this is dropped.
+pub(crate) struct Running<'c>(&'c Cell<usize>);
+
+impl Drop for Running<'_> {
+    fn drop(&mut self) {
+        self.0.set(self.0.get() - 1);
+    }
+}
+
+impl Connection {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        Self::open_with_config(path, Config::default())
+    }
+
+    pub fn open_with_config(path: impl AsRef<Path>, config: Config) -> Result<Self> {
+        let path = path.as_ref();
+        let db = Database::open(&File::open(path)?)?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            catalog: RefCell::new(Rc::new(Catalog::new(&db))),
+            db: RefCell::new(Rc::new(db)),
+            config,
+            functions: Functions::default(),
+            running: Cell::new(0),
+        })
+    }
+
+    /// Runs a statement to the end, leaving out its rows.
+    pub fn execute(&self, sql: &str) -> Result<()> {
+        self.prepare(sql)?.execute(&[])
+    }
+
+    /// Compiles a statement to run it as many times as needed, with other values bound to its parameters each time.
+    pub fn prepare(&self, sql: &str) -> Result<Statement<'_>> {
+        Statement::new(self, sql)
+    }
+
+    /// Size of the pages of the database in bytes.
+    pub fn page_size(&self) -> u32 {
+        self.db.borrow().page_size
+    }
+
+    /// The rows of `sqlite_schema`.
+    pub fn schema(&self) -> Vec<Schema> {
+        self.catalog.borrow().schema().to_vec()
+    }
+
+    /// The database as it was last read, which statements keep reading until they're compiled again.
+    pub(crate) fn database(&self) -> Rc<Database> {
+        self.db.borrow().clone()
+    }
+
+    /// The catalog of the database as it was last read, the statements compiled against another one are compiled
+    /// again.
+    pub(crate) fn catalog(&self) -> Rc<Catalog> {
+        self.catalog.borrow().clone()
+    }
+
+    pub(crate) fn config(&self) -> &Config {
+        &self.config
+    }
+
+    pub(crate) fn functions(&self) -> &Functions {
+        &self.functions
+    }
+
+    /// Path of the database file, which is opened again to write to it.
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Counts a statement as running while its rows are read.
+    pub(crate) fn run(&self) -> Running<'_> {
+        self.running.set(self.running.get() + 1);
+        Running(&self.running)
+    }
+
+    /// Whether some statement is in the middle of reading its rows, from the pages a write could change under it.
+    pub(crate) fn is_running(&self) -> bool {
+        self.running.get() > 0
+    }
+
+    /// Reads the database again once a statement committed changes to it, mapping the file again since it may have
+    /// grown and reading the schema and statistics again.
+    pub(crate) fn reload(&self) -> Result<()> {
+        let db = Database::open(&self.db.borrow().file)?;
+        *self.catalog.borrow_mut() = Rc::new(Catalog::new(&db));
+        *self.db.borrow_mut() = Rc::new(db);
+        Ok(())
+    }
+
+    /// Defines a scalar function taking `n_args` arguments, or any number of them when it's -1. It takes the place of
+    /// a function with the same name and number of arguments, built-in or defined before.
+    pub fn create_scalar_function<F>(
+        &mut self,
+        name: &str,
+        n_args: i32,
+        flags: FunctionFlags,
+        function: F,
+    ) -> Result<()>
+    where
+        F: Fn(&[Value]) -> Result<Value<'static>> + 'static,
+    {
+        self.functions.define_scalar(name, n_args, flags, Rc::new(function))
+    }
+
+    /// Defines an aggregate function, `new` making the state that adds up the rows of each group.
+    pub fn create_aggregate_function<F>(&mut self, name: &str, n_args: i32, flags: FunctionFlags, new: F) -> Result<()>
+    where
+        F: Fn() -> Box<dyn Aggregate> + 'static,
+    {
+        self.functions.define_aggregate(name, n_args, flags, Rc::new(new))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use anyhow::bail;
+
+    use super::*;
+
+    fn open() -> Connection {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/sample.db");
+        Connection::open_with_config(path, Config { memory_limit: 1 << 20 }).unwrap()
+    }
+
+    /// Opens the fixture built from `testdata/tests.sql`.
+    fn fixture() -> Connection {
+        fixture_with_limit(1 << 20)
+    }
+
+    fn fixture_with_limit(memory_limit: usize) -> Connection {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/testdata/tests.db");
+        Connection::open_with_config(path, Config { memory_limit }).unwrap()
+    }
+
+    fn query(conn: &Connection, query: &str) -> Result<Vec<String>> {
+        run(&mut conn.prepare(query)?, &[])
+    }
+
+    fn run(statement: &mut Statement, params: &[Value]) -> Result<Vec<String>> {
+        let mut rows = statement.query(params)?;
+        let mut lines = vec![];
+        while let Some(row) = rows.next()? {
+            lines.push(row.values().iter().map(|v| v.to_string()).collect::<Vec<_>>().join("|"));
+        }
+        Ok(lines)
+    }
+
+    #[test]
+    fn scalar_functions() {
+        let mut conn = open();
+        conn.create_scalar_function("shout", 1, FunctionFlags::DETERMINISTIC, |args| {
+            Ok(Value::String(format!("{}!", args[0]).into()))
+        })
+        .unwrap();
+        assert_eq!(
+            query(&conn, "SELECT shout(name) FROM apples WHERE id = 2").unwrap(),
+            ["Fuji!"]
+        );
+        // Defined functions take the place of the built-in ones.
+        conn.create_scalar_function("upper", 1, FunctionFlags::NONE, |_| Ok(Value::Int(7)))
+            .unwrap();
+        assert_eq!(query(&conn, "SELECT upper('a'), lower('A')").unwrap(), ["7|a"]);
+        conn.create_scalar_function("fail", -1, FunctionFlags::NONE, |_| bail!("failed"))
+            .unwrap();
+        assert_eq!(query(&conn, "SELECT fail(1, 2)").unwrap_err().to_string(), "failed");
+        let err = query(&conn, "SELECT shout()").unwrap_err();
+        assert_eq!(err.to_string(), "wrong number of arguments to function shout()");
+        assert!(
+            conn.create_scalar_function("bad", 200, FunctionFlags::NONE, |_| Ok(Value::Null))
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn deterministic_functions() {
+        let calls = Rc::new(Cell::new(0));
+        let mut conn = open();
+        for (name, flags) in [("det", FunctionFlags::DETERMINISTIC), ("nondet", FunctionFlags::NONE)] {
+            let calls = calls.clone();
+            conn.create_scalar_function(name, 1, flags, move |args| {
+                calls.set(calls.get() + 1);
+                Ok(args[0].clone().into_owned())
+            })
+            .unwrap();
+        }
+        // Calls with constant arguments are computed once, when planning.
+        assert_eq!(query(&conn, "SELECT det(1) FROM oranges").unwrap().len(), 6);
+        assert_eq!(calls.replace(0), 1);
+        assert_eq!(query(&conn, "SELECT nondet(1) FROM oranges").unwrap().len(), 6);
+        assert_eq!(calls.replace(0), 6);
+        assert_eq!(query(&conn, "SELECT det(id) FROM oranges").unwrap().len(), 6);
+        assert_eq!(calls.replace(0), 6);
+    }
+
+    struct Longest(String);
+
+    impl Aggregate for Longest {
+        fn step(&mut self, args: &[Value]) -> Result<()> {
+            let s = args[0].to_string();
+            if s.len() > self.0.len() {
+                self.0 = s;
+            }
+            Ok(())
+        }
+
+        fn finalize(&self) -> Result<Value<'static>> {
+            Ok(Value::String(self.0.clone().into()))
+        }
+    }
+
+    #[test]
+    fn aggregate_functions() {
+        let mut conn = open();
+        conn.create_aggregate_function("longest", 1, FunctionFlags::DETERMINISTIC, || {
+            Box::new(Longest(String::new()))
+        })
+        .unwrap();
+        assert_eq!(
+            query(&conn, "SELECT longest(name) FROM apples").unwrap(),
+            ["Golden Delicious"]
+        );
+        assert_eq!(
+            query(&conn, "SELECT length(name) > 8, longest(color) FROM apples GROUP BY 1").unwrap(),
+            ["0|Red", "1|Light Green"]
+        );
+        assert!(query(&conn, "SELECT longest(name, color) FROM apples").is_err());
+    }
+
+    #[test]
+    fn prepared_statements() {
+        let conn = open();
+        let mut statement = conn.prepare("SELECT name FROM apples WHERE id = ?").unwrap();
+        assert_eq!(run(&mut statement, &[Value::Int(2)]).unwrap(), ["Fuji"]);
+        assert_eq!(run(&mut statement, &[Value::Int(4)]).unwrap(), ["Golden Delicious"]);
+        // Values stay bound for the next runs.
+        assert_eq!(run(&mut statement, &[]).unwrap(), ["Golden Delicious"]);
+        let err = run(&mut statement, &[Value::Int(1), Value::Int(2)]).unwrap_err();
+        assert_eq!(err.to_string(), "2 values for 1 parameters");
+        // The results of subqueries are computed again for the new values.
+        let mut statement = conn
+            .prepare("SELECT (SELECT count(*) FROM oranges WHERE id > ?1), name FROM apples LIMIT ?1")
+            .unwrap();
+        assert_eq!(run(&mut statement, &[Value::Int(4)]).unwrap().len(), 4);
+        assert_eq!(run(&mut statement, &[Value::Int(1)]).unwrap(), ["5|Granny Smith"]);
+        assert_eq!(
+            run(&mut statement, &[Value::Null]).unwrap_err().to_string(),
+            "datatype mismatch"
+        );
+        // A statement keeps its own copy of its text, which can be dropped once it's prepared.
+        let sql = String::from("SELECT name FROM apples WHERE id = :id");
+        let mut statement = conn.prepare(&sql).unwrap();
+        drop(sql);
+        assert_eq!(statement.sql(), "SELECT name FROM apples WHERE id = :id");
+        assert_eq!(statement.parameter_index(":id"), Some(1));
+        assert_eq!(run(&mut statement, &[Value::Int(3)]).unwrap(), ["Honeycrisp"]);
+    }
+
+    #[test]
+    fn named_parameters() {
+        let conn = open();
+        let mut statement = conn.prepare("SELECT ?2, ?, :x, @y || $z, :x, ?1").unwrap();
+        assert_eq!(statement.parameter_count(), 6);
+        assert_eq!(statement.parameter_index(":x"), Some(4));
+        assert_eq!(statement.parameter_index("$z"), Some(6));
+        assert_eq!(statement.parameter_index("x"), None);
+        statement.bind_named(":x", Value::String("x".into())).unwrap();
+        statement.bind_named("@y", Value::Int(1)).unwrap();
+        statement.bind(6, Value::Float(2.5)).unwrap();
+        statement.bind(1, Value::Int(7)).unwrap();
+        assert_eq!(run(&mut statement, &[]).unwrap(), ["||x|12.5|x|7"]);
+        assert!(statement.bind(0, Value::Null).is_err());
+        assert_eq!(
+            statement.bind(7, Value::Null).unwrap_err().to_string(),
+            "column index out of range"
+        );
+        let err = statement.bind_named(":w", Value::Null).unwrap_err();
+        assert_eq!(err.to_string(), "no such parameter: :w");
+        // Strings don't have parameters.
+        assert_eq!(query(&conn, "SELECT '?', ':x', '$1'").unwrap(), ["?|:x|$1"]);
+        let err = query(&conn, "SELECT ?0").unwrap_err();
+        assert_eq!(err.to_string(), "variable number must be between ?1 and ?32766");
+    }
+
+    #[test]
+    fn rows() {
+        let conn = open();
+        let mut statement = conn
+            .prepare("SELECT id, name AS n, length(color) > 5 FROM apples WHERE id > ? ORDER BY id")
+            .unwrap();
+        assert_eq!(statement.column_names(), ["id", "n", "length(color) > 5"]);
+        let mut rows = statement.query(&[Value::Int(2)]).unwrap();
+        let row = rows.next().unwrap().unwrap();
+        assert_eq!(row.get::<i64>("ID").unwrap(), 3);
+        assert_eq!(row.get::<&str>("n").unwrap(), "Honeycrisp");
+        assert!(row.get::<bool>(2).unwrap());
+        assert_eq!(row.get::<String>(1).unwrap(), "Honeycrisp");
+        let row = rows.next().unwrap().unwrap();
+        assert_eq!(
+            row.get::<Option<String>>("n").unwrap().as_deref(),
+            Some("Golden Delicious")
+        );
+        assert!(row.get::<bool>(2).unwrap());
+        assert!(rows.next().unwrap().is_none());
+        assert!(rows.next().unwrap().is_none());
+        // Statements without rows.
+        let statement = conn.prepare("EXPLAIN QUERY PLAN SELECT * FROM apples").unwrap();
+        assert_eq!(statement.explain(), Some("QUERY PLAN\n`--SCAN apples\n"));
+        assert!(conn.prepare("SELECT 1").unwrap().explain().is_none());
+    }
+
+    #[test]
+    fn analyze_reloads() {
+        // ANALYZE writes to a copy of sample.db, which the connection reads again.
+        let path = std::env::temp_dir().join(format!("rusqlite-{}-reload.db", std::process::id()));
+        std::fs::copy(concat!(env!("CARGO_MANIFEST_DIR"), "/sample.db"), &path).unwrap();
+        let conn = Connection::open(&path).unwrap();
+        let mut statement = conn.prepare("SELECT count(*) FROM apples").unwrap();
+        let before = query(&conn, "SELECT * FROM sqlite_stat1").map_err(|e| e.to_string());
+        conn.execute("ANALYZE").unwrap();
+        let stats = query(&conn, "SELECT * FROM sqlite_stat1 ORDER BY tbl");
+        // Statements compiled before are compiled again.
+        let count = run(&mut statement, &[]);
+        let tables = conn.schema().len();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(before.unwrap_err(), "no such table: sqlite_stat1");
+        assert_eq!(stats.unwrap(), ["apples||4", "oranges||6"]);
+        assert_eq!(count.unwrap(), ["4"]);
+        assert_eq!(tables, 4);
+    }
+
+    #[test]
+    fn opened_for_writing_when_written() {
+        // The file is only opened again for writing by ANALYZE, which fails once it's gone.
+        let path = std::env::temp_dir().join(format!("rusqlite-{}-writer.db", std::process::id()));
+        std::fs::copy(concat!(env!("CARGO_MANIFEST_DIR"), "/sample.db"), &path).unwrap();
+        let conn = Connection::open(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(query(&conn, "SELECT count(*) FROM apples").unwrap(), ["4"]);
+        let err = conn.execute("ANALYZE").unwrap_err();
+        assert_eq!(err.to_string(), "attempt to write a readonly database");
+    }
+
+    #[test]
+    fn analyze_while_reading() {
+        let path = std::env::temp_dir().join(format!("rusqlite-{}-running.db", std::process::id()));
+        std::fs::copy(concat!(env!("CARGO_MANIFEST_DIR"), "/sample.db"), &path).unwrap();
+        let conn = Connection::open(&path).unwrap();
+        let mut statement = conn.prepare("SELECT * FROM apples").unwrap();
+        let mut rows = statement.query(&[]).unwrap();
+        rows.next().unwrap();
+        let err = conn.execute("ANALYZE").unwrap_err();
+        assert_eq!(err.to_string(), "cannot ANALYZE - SQL statements in progress");
+        // Statements stop running once their rows are all read, or when they're dropped.
+        while rows.next().unwrap().is_some() {}
+        conn.execute("ANALYZE").unwrap();
+        drop(rows);
+        let mut rows = statement.query(&[]).unwrap();
+        rows.next().unwrap();
+        drop(rows);
+        conn.execute("ANALYZE").unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn three_valued_where() {
+        let conn = fixture();
+        let ids = |filter: &str| query(&conn, &format!("SELECT id FROM t WHERE {filter} ORDER BY id")).unwrap();
+        // Rows where the condition is NULL are left out, whether it's negated or not.
+        assert_eq!(ids("a > 1"), ["1", "4", "6"]);
+        assert_eq!(ids("NOT a > 1"), ["3", "5"]);
+        assert_eq!(ids("a IS NULL"), ["2"]);
+        assert_eq!(ids("a IS NOT 3"), ["2", "3", "5", "6"]);
+        assert_eq!(ids("b = 'y' OR a > 2"), ["1", "2", "4", "6"]);
+        assert_eq!(ids("NOT (b = 'y' AND c < 0)"), ["1", "4", "5"]);
+        assert_eq!(ids("c < 0"), ["2", "4"]);
+        assert_eq!(
+            query(&conn, "SELECT NULL OR 1, NULL AND 0, NULL AND 1, NOT NULL").unwrap(),
+            ["1|0||"]
+        );
+    }
+
+    #[test]
+    fn generated_columns() {
+        let conn = fixture();
+        // Virtual columns aren't stored, the columns after them are read from the right place in the records.
+        assert_eq!(
+            query(&conn, "SELECT * FROM g").unwrap(),
+            ["3|6|x|7|x!", "5|10|yz|12|yz!", "||w||w!"]
+        );
+        assert_eq!(query(&conn, "SELECT c, d FROM g WHERE b > 6").unwrap(), ["yz|12"]);
+        assert_eq!(query(&conn, "SELECT sum(b), max(d) FROM g").unwrap(), ["16|12"]);
+        assert_eq!(
+            query(&conn, "SELECT g.b, t.id FROM t JOIN g ON g.b = t.a * 2 ORDER BY t.id").unwrap(),
+            ["6|1", "6|4"]
+        );
+    }
+
+    #[test]
+    fn without_rowid_tables() {
+        let conn = fixture();
+        // The rows come in primary key order, with the columns back in declaration order.
+        assert_eq!(
+            query(&conn, "SELECT k, v, quote(x) FROM w").unwrap(),
+            ["a|-3|'a-3'", "a|9|NULL", "b|1|1.5", "b|2|'b2'", "c|0|X'00FF'"]
+        );
+        assert_eq!(query(&conn, "SELECT x FROM w WHERE k = 'b'").unwrap(), ["1.5", "b2"]);
+        assert_eq!(
+            query(&conn, "SELECT k, v FROM w WHERE k = 'a' AND v > 0").unwrap(),
+            ["a|9"]
+        );
+        assert_eq!(query(&conn, "SELECT count(*), max(v) FROM w").unwrap(), ["5|9"]);
+        let err = query(&conn, "SELECT rowid FROM w").unwrap_err();
+        assert_eq!(err.to_string(), "no such column: rowid");
+    }
+
+    #[test]
+    fn rowid_columns() {
+        let conn = fixture();
+        assert_eq!(
+            query(&conn, "SELECT rowid, oid, _rowid_, id FROM t WHERE id < 3").unwrap(),
+            ["1|1|1|1", "2|2|2|2"]
+        );
+        // A column named like the rowid hides it, the other names still refer to the rowid.
+        assert_eq!(
+            query(&conn, "SELECT rowid, oid, _rowid_, x FROM o").unwrap(),
+            ["minus five|-5|-5|2", "ten|10|10|1"]
+        );
+        assert_eq!(query(&conn, "SELECT x FROM o WHERE _rowid_ = -5").unwrap(), ["2"]);
+        assert_eq!(
+            query(&conn, "SELECT t.rowid, o.oid FROM t JOIN o ON o.x = t.id ORDER BY 1").unwrap(),
+            ["1|10", "2|-5"]
+        );
+        let err = query(&conn, "SELECT rowid FROM (SELECT id FROM t)").unwrap_err();
+        assert_eq!(err.to_string(), "no such column: rowid");
+    }
+
+    #[test]
+    fn left_joins() {
+        let conn = fixture();
+        // The rows without a match get NULLs for every column of the table, its rowid too.
+        assert_eq!(
+            query(
+                &conn,
+                "SELECT t.id, o.rowid, o.oid, o.x FROM t LEFT JOIN o ON o.x = t.id ORDER BY t.id"
+            )
+            .unwrap(),
+            ["1|ten|10|1", "2|minus five|-5|2", "3|||", "4|||", "5|||", "6|||"]
+        );
+        // The ON clause decides which rows match, the WHERE clause filters the padded rows.
+        assert_eq!(
+            query(
+                &conn,
+                "SELECT t.id, o.x FROM t LEFT JOIN o ON o.x = t.id AND t.b = 'y' ORDER BY t.id"
+            )
+            .unwrap(),
+            ["1|", "2|2", "3|", "4|", "5|", "6|"]
+        );
+        assert_eq!(
+            query(
+                &conn,
+                "SELECT t.id FROM t LEFT JOIN o ON o.x = t.id WHERE o.x IS NULL ORDER BY t.id"
+            )
+            .unwrap(),
+            ["3", "4", "5", "6"]
+        );
+        assert_eq!(
+            query(
+                &conn,
+                "SELECT t.id, count(b.id) FROM t LEFT JOIN big b ON b.k = t.a GROUP BY t.id"
+            )
+            .unwrap(),
+            ["1|10", "2|0", "3|10", "4|10", "5|0", "6|10"]
+        );
+    }
+
+    #[test]
+    fn hash_joins_spill() {
+        let join = "SELECT count(*), sum(a.id * b.id) FROM big a JOIN big b ON b.k = a.id";
+        let left = "SELECT t.id, count(b.id), sum(b.id) FROM t LEFT JOIN big b ON b.k = t.a * 10 GROUP BY t.id";
+        for conn in [fixture(), fixture_with_limit(2000)] {
+            let plan = conn.prepare(&format!("EXPLAIN QUERY PLAN {join}")).unwrap();
+            assert_eq!(
+                plan.explain(),
+                Some("QUERY PLAN\n|--SCAN a\n`--SEARCH b USING HASH TABLE (k=?)\n")
+            );
+            assert_eq!(query(&conn, join).unwrap(), ["990|25558500"]);
+            assert_eq!(
+                query(&conn, left).unwrap(),
+                ["1|10|4800", "2|0|", "3|10|4600", "4|10|4800", "5|0|", "6|10|4700"]
+            );
+        }
+    }
+
+    #[test]
+    fn correlated_subqueries() {
+        let conn = fixture();
+        assert_eq!(
+            query(
+                &conn,
+                "SELECT id, (SELECT count(*) FROM t u WHERE u.a < t.a) FROM t ORDER BY id"
+            )
+            .unwrap(),
+            ["1|3", "2|0", "3|1", "4|3", "5|0", "6|2"]
+        );
+        assert_eq!(
+            query(
+                &conn,
+                "SELECT id FROM t WHERE EXISTS (SELECT 1 FROM w WHERE w.v = t.id) ORDER BY id"
+            )
+            .unwrap(),
+            ["1", "2"]
+        );
+        assert_eq!(
+            query(
+                &conn,
+                "SELECT id FROM t WHERE NOT EXISTS (SELECT 1 FROM t u WHERE u.a > t.a) ORDER BY id"
+            )
+            .unwrap(),
+            ["1", "2", "4"]
+        );
+        assert_eq!(
+            query(
+                &conn,
+                "SELECT id FROM t WHERE a IN (SELECT v FROM w WHERE w.k <> t.b) ORDER BY id"
+            )
+            .unwrap(),
+            ["6"]
+        );
+        // A subquery with no rows is NULL.
+        assert_eq!(
+            query(
+                &conn,
+                "SELECT id, (SELECT max(u.id) FROM t u WHERE u.b = t.b AND u.id <> t.id) FROM t ORDER BY id"
+            )
+            .unwrap(),
+            ["1|", "2|6", "3|", "4|", "5|", "6|2"]
+        );
+        assert_eq!(
+            query(
+                &conn,
+                "SELECT b, (SELECT group_concat(id) FROM t u WHERE u.b = t.b) FROM t GROUP BY b"
+            )
+            .unwrap(),
+            ["|", "X|4", "x|1", "y|2,6", "z|5"]
+        );
+        assert_eq!(
+            query(
+                &conn,
+                "SELECT id FROM t WHERE c > (SELECT avg(c) FROM t u WHERE u.b IS NOT t.b)"
+            )
+            .unwrap(),
+            ["5"]
+        );
+    }
+
+    #[test]
+    fn recursive_ctes() {
+        let conn = fixture();
+        // The recursion stops once a step adds no rows.
+        assert_eq!(
+            query(
+                &conn,
+                "WITH RECURSIVE c(x) AS (SELECT 1 UNION ALL SELECT x + 1 FROM c WHERE x < 5) SELECT x FROM c"
+            )
+            .unwrap(),
+            ["1", "2", "3", "4", "5"]
+        );
+        assert_eq!(
+            query(
+                &conn,
+                "WITH RECURSIVE c(x) AS (SELECT 1 UNION ALL SELECT x + 1 FROM c WHERE x < 1000) \
+                 SELECT count(*), sum(x) FROM c"
+            )
+            .unwrap(),
+            ["1000|500500"]
+        );
+        // UNION leaves out the rows seen already, which ends a cycle.
+        assert_eq!(
+            query(
+                &conn,
+                "WITH RECURSIVE c(x) AS (SELECT 1 UNION SELECT x % 3 + 1 FROM c) SELECT x FROM c"
+            )
+            .unwrap(),
+            ["1", "2", "3"]
+        );
+        // A LIMIT, of the CTE or of the query reading it, ends a recursion that wouldn't stop otherwise.
+        assert_eq!(
+            query(
+                &conn,
+                "WITH RECURSIVE c(x) AS (SELECT 1 UNION ALL SELECT x + 1 FROM c LIMIT 3) SELECT x FROM c"
+            )
+            .unwrap(),
+            ["1", "2", "3"]
+        );
+        assert_eq!(
+            query(
+                &conn,
+                "WITH RECURSIVE c(x) AS (SELECT 1 UNION ALL SELECT x + 1 FROM c) SELECT x FROM c LIMIT 4"
+            )
+            .unwrap(),
+            ["1", "2", "3", "4"]
+        );
+        // Each recursive select stops on its own.
+        assert_eq!(
+            query(
+                &conn,
+                "WITH RECURSIVE c(x, d) AS (SELECT 1, 0 UNION ALL SELECT x * 2, d + 1 FROM c WHERE d < 3 \
+                 UNION ALL SELECT x * 3, d + 1 FROM c WHERE d < 2) SELECT x, d FROM c ORDER BY x"
+            )
+            .unwrap(),
+            [
+                "1|0", "2|1", "3|1", "4|2", "6|2", "6|2", "8|3", "9|2", "12|3", "12|3", "18|3"
+            ]
+        );
+    }
+
+    #[test]
+    fn intersect_and_except() {
+        let conn = fixture();
+        // The distinct rows that are left come out sorted.
+        assert_eq!(
+            query(&conn, "SELECT a FROM t INTERSECT SELECT v FROM w").unwrap(),
+            ["1", "2"]
+        );
+        assert_eq!(
+            query(&conn, "SELECT a FROM t EXCEPT SELECT v FROM w").unwrap(),
+            ["", "-200", "3"]
+        );
+        assert!(
+            query(&conn, "SELECT b FROM t INTERSECT SELECT k FROM w")
+                .unwrap()
+                .is_empty()
+        );
+        // NULLs are equal to each other, and integers to the equal reals.
+        assert_eq!(query(&conn, "SELECT a FROM t INTERSECT SELECT NULL").unwrap(), [""]);
+        assert_eq!(
+            query(&conn, "SELECT b FROM t EXCEPT SELECT b FROM t WHERE id > 3").unwrap(),
+            ["", "x"]
+        );
+        assert_eq!(query(&conn, "SELECT 1 INTERSECT SELECT 1.0").unwrap(), ["1"]);
+        assert_eq!(
+            query(&conn, "SELECT c FROM t EXCEPT SELECT 1.5").unwrap(),
+            ["", "-2.5", "-1.0", "70000.0"]
+        );
+        // The operators apply from left to right.
+        assert_eq!(
+            query(&conn, "SELECT a FROM t EXCEPT SELECT 3 INTERSECT SELECT 1").unwrap(),
+            ["1"]
+        );
+        assert_eq!(
+            query(
+                &conn,
+                "SELECT a, b FROM t INTERSECT SELECT 3, 'x' UNION SELECT NULL, NULL"
+            )
+            .unwrap(),
+            ["|", "3|x"]
+        );
+        assert_eq!(
+            query(&conn, "SELECT a FROM t EXCEPT SELECT v FROM w ORDER BY 1 DESC LIMIT 2").unwrap(),
+            ["3", "-200"]
+        );
+    }
+
+    #[test]
+    fn distinct() {
+        let conn = fixture();
+        // NULLs are equal to each other, and 1 to 1.0 but not to '1'.
+        assert_eq!(
+            query(&conn, "SELECT DISTINCT x FROM d").unwrap(),
+            ["", "1", "2", "2.5", "1"]
+        );
+        assert_eq!(
+            query(&conn, "SELECT count(*) FROM (SELECT DISTINCT x, 1 FROM d)").unwrap(),
+            ["5"]
+        );
+        assert_eq!(
+            query(&conn, "SELECT DISTINCT b IS NULL, a IS NULL FROM t ORDER BY 1, 2").unwrap(),
+            ["0|0", "0|1", "1|0"]
+        );
+        // Walking an index puts the repeated rows next to each other, the index has all the columns the query uses.
+        let plan = conn.prepare("EXPLAIN QUERY PLAN SELECT DISTINCT a FROM t").unwrap();
+        assert_eq!(plan.explain(), Some("QUERY PLAN\n`--SCAN t USING COVERING INDEX ta\n"));
+        let program = conn.prepare("EXPLAIN SELECT DISTINCT a FROM t").unwrap();
+        assert!(!program.explain().unwrap().contains("SeekRowid"));
+        assert_eq!(
+            query(&conn, "SELECT DISTINCT a FROM t").unwrap(),
+            ["", "-200", "1", "2", "3"]
+        );
+        let plan = conn.prepare("EXPLAIN QUERY PLAN SELECT DISTINCT a, b FROM t").unwrap();
+        assert_eq!(
+            plan.explain(),
+            Some("QUERY PLAN\n|--SCAN t\n`--USE TEMP B-TREE FOR DISTINCT\n")
+        );
+    }
+
+    #[test]
+    fn collations() {
+        let conn = fixture();
+        assert_eq!(
+            query(
+                &conn,
+                "SELECT 'a' COLLATE NOCASE = 'A', 'a ' COLLATE RTRIM = 'a', 'a' = 'A'"
+            )
+            .unwrap(),
+            ["1|1|0"]
+        );
+        assert_eq!(
+            query(&conn, "SELECT id FROM t WHERE b = 'X' COLLATE NOCASE").unwrap(),
+            ["1", "4"]
+        );
+        // The collation of a column applies to the comparisons, groups, distinct rows and sorting of its values.
+        assert_eq!(
+            query(&conn, "SELECT s FROM n WHERE s = 'A' ORDER BY r").unwrap(),
+            ["a", "A"]
+        );
+        assert_eq!(query(&conn, "SELECT r FROM n WHERE r = 'b'").unwrap(), ["b  "]);
+        assert_eq!(
+            query(&conn, "SELECT s, count(*) FROM n GROUP BY s").unwrap(),
+            ["|1", "a|2", "B|2"]
+        );
+        assert_eq!(query(&conn, "SELECT DISTINCT s FROM n").unwrap(), ["a", "B", ""]);
+        assert_eq!(
+            query(&conn, "SELECT s FROM n ORDER BY s, r").unwrap(),
+            ["", "a", "A", "b", "B"]
+        );
+        let err = query(&conn, "SELECT 'a' COLLATE foo").unwrap_err();
+        assert_eq!(err.to_string(), "no such collation sequence: foo");
+    }
+
+    #[test]
+    fn smallest_integer() {
+        let conn = fixture();
+        assert_eq!(
+            query(&conn, "SELECT typeof(-9223372036854775808), -9223372036854775808 - 1").unwrap(),
+            ["integer|-9.22337203685478e+18"]
+        );
+        let err = query(&conn, "SELECT abs(-9223372036854775808)").unwrap_err();
+        assert_eq!(err.to_string(), "integer overflow");
+    }
+
+    #[test]
+    fn expression_depth() {
+        // Debug builds take kilobytes of stack for each level of an expression, the 8 MB a main thread has are enough.
+        let test = || {
+            let conn = fixture();
+            let parens = format!("SELECT {}1{}", "(".repeat(400), ")".repeat(400));
+            assert_eq!(query(&conn, &parens).unwrap(), ["1"]);
+            let sum = |n| format!("SELECT {} FROM t WHERE id = 1", vec!["a"; n].join(" + "));
+            assert_eq!(query(&conn, &sum(1000)).unwrap(), ["3000"]);
+            let err = query(&conn, &sum(1001)).unwrap_err();
+            assert_eq!(err.to_string(), "Expression tree is too large (maximum depth 1000)");
+            let or = format!("SELECT count(*) FROM t WHERE {}", vec!["a = 1"; 3000].join(" OR "));
+            assert!(query(&conn, &or).is_err());
+        };
+        std::thread::Builder::new()
+            .stack_size(8 << 20)
+            .spawn(test)
+            .unwrap()
+            .join()
+            .unwrap();
+    }
+
+    #[test]
+    fn identifier_case() {
+        let conn = fixture();
+        let queries = [
+            "SELECT ID, A FROM T WHERE Id = 1",
+            "SELECT T.id, t.A FROM t WHERE T.ID = 1",
+            "SELECT X.Id, x.a FROM T AS x WHERE X.ID = 1",
+            "SELECT id, a FROM t WHERE ID IN (SELECT Id FROM T WHERE B = 'x')",
+            "SELECT U.Id, t.A FROM T JOIN t AS u USING (ID) WHERE t.Id = 1 ORDER BY U.a",
+            "SELECT * FROM (SELECT Id AS X, A FROM T) WHERE x = 1",
+            "SELECT Id, s.a FROM T JOIN (SELECT ID, A FROM t) AS S USING (id) WHERE Id = 1",
+        ];
+        for q in queries {
+            assert_eq!(query(&conn, q).unwrap(), ["1|3"], "{q}");
+        }
+        // Columns keep the collation they were declared with whatever case they are written in.
+        assert_eq!(query(&conn, "SELECT count(*) FROM N WHERE S = 'A'").unwrap(), ["2"]);
+        assert_eq!(
+            query(&conn, "SELECT X FROM W WHERE K = 'b' AND V = 1").unwrap(),
+            ["1.5"]
+        );
+        let plan = conn.prepare("EXPLAIN QUERY PLAN SELECT B FROM T WHERE A = 3").unwrap();
+        assert_eq!(
+            plan.explain().unwrap(),
+            "QUERY PLAN\n`--SEARCH T USING INDEX ta (a=?)\n"
+        );
+        assert_eq!(
+            query(
+                &conn,
+                "SELECT A FROM T WHERE a > 1 UNION SELECT a FROM t WHERE ID = 1 ORDER BY T.A"
+            )
+            .unwrap(),
+            ["2", "3"]
+        );
+    }
+
+    #[test]
+    fn reverse_scans() {
+        let conn = fixture();
+        let plan = |q: &str| {
+            conn.prepare(&format!("EXPLAIN QUERY PLAN {q}"))
+                .unwrap()
+                .explain()
+                .unwrap()
+                .to_owned()
+        };
+        // Descending orders walk the table or the index backwards instead of sorting.
+        assert_eq!(plan("SELECT id FROM t ORDER BY id DESC"), "QUERY PLAN\n`--SCAN t\n");
+        assert_eq!(
+            query(&conn, "SELECT id FROM t ORDER BY id DESC").unwrap(),
+            ["6", "5", "4", "3", "2", "1"]
+        );
+        assert_eq!(
+            plan("SELECT b FROM t ORDER BY a DESC"),
+            "QUERY PLAN\n`--SCAN t USING INDEX ta\n"
+        );
+        assert_eq!(
+            query(&conn, "SELECT id, a FROM t ORDER BY a DESC, id DESC").unwrap(),
+            ["4|3", "1|3", "6|2", "3|1", "5|-200", "2|"]
+        );
+        // The NULLs come first walking an index forward, so they can't come first walking it backwards.
+        assert!(plan("SELECT id FROM t ORDER BY a DESC NULLS FIRST").contains("USE TEMP B-TREE"));
+        // A WITHOUT ROWID table is walked in the order of its primary key.
+        assert_eq!(plan("SELECT k, v FROM w ORDER BY k, v"), "QUERY PLAN\n`--SCAN w\n");
+        assert_eq!(
+            plan("SELECT k, v FROM w ORDER BY k DESC, v DESC"),
+            "QUERY PLAN\n`--SCAN w\n"
+        );
+        assert_eq!(
+            query(&conn, "SELECT k, v FROM w ORDER BY k DESC, v DESC").unwrap(),
+            ["c|0", "b|2", "b|1", "a|9", "a|-3"]
+        );
+        assert!(plan("SELECT k, v FROM w ORDER BY k, v DESC").contains("USE TEMP B-TREE"));
+        // Trees of several pages, the entries of the interior cells of an index come between their children.
+        let ids = query(&conn, "SELECT id FROM big ORDER BY id DESC").unwrap();
+        assert_eq!(ids, (1..=1000).rev().map(|i| i.to_string()).collect::<Vec<_>>());
+        let mut values = query(&conn, "SELECT v FROM big ORDER BY v").unwrap();
+        assert_eq!(values.len(), 1000);
+        assert!(values.is_sorted());
+        values.reverse();
+        assert_eq!(query(&conn, "SELECT v FROM big ORDER BY v DESC").unwrap(), values);
+    }
+
+    #[test]
+    fn ranges() {
+        let conn = fixture();
+        let plan = |q: &str| {
+            conn.prepare(&format!("EXPLAIN QUERY PLAN {q}"))
+                .unwrap()
+                .explain()
+                .unwrap()
+                .to_owned()
+        };
+        assert_eq!(
+            plan("SELECT * FROM big WHERE id > 995"),
+            "QUERY PLAN\n`--SEARCH big USING INTEGER PRIMARY KEY (rowid>?)\n"
+        );
+        assert_eq!(
+            query(&conn, "SELECT id FROM big WHERE id > 995").unwrap(),
+            ["996", "997", "998", "999", "1000"]
+        );
+        assert_eq!(
+            query(&conn, "SELECT id FROM big WHERE id >= 998.5").unwrap(),
+            ["999", "1000"]
+        );
+        assert_eq!(query(&conn, "SELECT id FROM big WHERE id <= '2'").unwrap(), ["1", "2"]);
+        assert_eq!(
+            query(&conn, "SELECT id FROM big WHERE id > 3 AND id <= 5").unwrap(),
+            ["4", "5"]
+        );
+        // Text is above any number, and NULL matches nothing.
+        assert_eq!(
+            query(&conn, "SELECT count(*) FROM big WHERE id < 'a'").unwrap(),
+            ["1000"]
+        );
+        assert_eq!(query(&conn, "SELECT count(*) FROM big WHERE id > 'a'").unwrap(), ["0"]);
+        assert_eq!(query(&conn, "SELECT count(*) FROM big WHERE id > NULL").unwrap(), ["0"]);
+        assert_eq!(
+            plan("SELECT * FROM big WHERE v > '990'"),
+            "QUERY PLAN\n`--SEARCH big USING INDEX bv (v>?)\n"
+        );
+        assert_eq!(
+            query(&conn, "SELECT id FROM big WHERE v > '990' AND v < '993' ORDER BY v").unwrap(),
+            ["210", "889", "568"]
+        );
+        // The NULLs of the index come before the bound, and the values of other types are compared as they are.
+        assert_eq!(
+            plan("SELECT x FROM d WHERE x < 2"),
+            "QUERY PLAN\n`--SEARCH d USING INDEX dx (x<?)\n"
+        );
+        assert_eq!(query(&conn, "SELECT x FROM d WHERE x < 2").unwrap(), ["1", "1.0"]);
+        assert_eq!(query(&conn, "SELECT x FROM d WHERE x >= 2").unwrap(), ["2", "2.5", "1"]);
+        // A range of the rowid of a joined table is walked for each row of the tables before it.
+        assert_eq!(
+            query(
+                &conn,
+                "SELECT t.id, b.id FROM t, big b WHERE b.id > t.id * 100 AND b.id <= t.id * 100 + 1 ORDER BY t.id"
+            )
+            .unwrap(),
+            ["1|101", "2|201", "3|301", "4|401", "5|501", "6|601"]
+        );
+    }
+}
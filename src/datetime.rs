@@ -0,0 +1,1088 @@
+//! Date and time functions: `date()`, `time()`, `datetime()`, `julianday()`, `unixepoch()`, `strftime()` and
+//! `timediff()`.
+//!
+//! This follows SQLite's date.c closely so that the results are the same, down to the rounding and to the handling of
+//! malformed input. A moment is a Julian day number in milliseconds, along with its calendar date and time of day, each
+//! computed from the other when needed. See <https://sqlite.org/lang_datefunc.html> for the input formats and the
+//! modifiers.
+
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use anyhow::Result;
+use anyhow::bail;
+use parser::Value;
+
+use crate::functions::general;
+
+/// Milliseconds in a day.
+const DAY_MS: i64 = 86_400_000;
+/// The Julian day of 1970-01-01, in milliseconds.
+const UNIX_EPOCH_MS: i64 = 210_866_760_000_000;
+/// The last millisecond of the year 9999, the largest moment supported.
+const MAX_JD: i64 = 464_269_060_799_999;
+
+/// Units of the `+N days` modifiers, with the magnitude they can't reach and their length in seconds.
+const UNITS: &[(&str, f64, f64)] = &[
+    ("second", 4.6427e14, 1.0),
+    ("minute", 7.7379e12, 60.0),
+    ("hour", 1.2897e11, 3600.0),
+    ("day", 5373485.0, 86400.0),
+    ("month", 176546.0, 2592000.0),
+    ("year", 14713.0, 31536000.0),
+];
+
+/// A moment being computed, SQLite's `DateTime`.
+#[derive(Clone, Default)]
+struct DateTime {
+    /// The Julian day number times 86400000.
+    jd: i64,
+    year: i32,
+    month: i32,
+    day: i32,
+    hour: i32,
+    minute: i32,
+    second: f64,
+    /// Offset from UTC of the time read, in minutes.
+    tz: i32,
+    valid_jd: bool,
+    valid_ymd: bool,
+    valid_hms: bool,
+    /// The input was a number, in `second`, which is yet to be read as a Julian day or a unix timestamp.
+    raw_s: bool,
+    is_utc: bool,
+    is_local: bool,
+    /// The `subsec` modifier, for milliseconds in the results.
+    use_subsec: bool,
+    error: bool,
+    /// Days past the end of the month the last month or year shift overflowed into, taken back by `floor`.
+    overflow: i64,
+}
+
+impl DateTime {
+    /// Reads a date and time given as text.
+    fn parse(&mut self, z: &str) -> Option<()> {
+        let b = z.as_bytes();
+        if self.parse_ymd(b).is_some() || self.parse_hms(b).is_some() {
+            return Some(());
+        }
+        if z.eq_ignore_ascii_case("now") {
+            self.set_now();
+            return Some(());
+        }
+        if let Some(r) = parse_number(b) {
+            self.set_raw(r);
+            return Some(());
+        }
+        if z.eq_ignore_ascii_case("subsec") || z.eq_ignore_ascii_case("subsecond") {
+            self.use_subsec = true;
+            self.set_now();
+            return Some(());
+        }
+        None
+    }
+
+    /// `YYYY-MM-DD`, optionally followed by a time.
+    fn parse_ymd(&mut self, z: &[u8]) -> Option<()> {
+        let (negative, z) = match z.first() {
+            Some(b'-') => (true, &z[1..]),
+            _ => (false, z),
+        };
+        let ymd = digits(
+            z,
+            &[(4, 0, 14712, Some(b'-')), (2, 1, 12, Some(b'-')), (2, 1, 31, None)],
+        )?;
+        let mut rest = &z[10..];
+        while rest.first().is_some_and(|&c| is_space(c) || c == b'T') {
+            rest = &rest[1..];
+        }
+        if self.parse_hms(rest).is_none() {
+            if !rest.is_empty() {
+                return None;
+            }
+            self.valid_hms = false;
+        }
+        self.valid_jd = false;
+        self.valid_ymd = true;
+        self.year = if negative { -ymd[0] } else { ymd[0] };
+        self.month = ymd[1];
+        self.day = ymd[2];
+        self.compute_floor();
+        if self.tz != 0 {
+            self.compute_jd();
+        }
+        Some(())
+    }
+
+    /// `HH:MM`, `HH:MM:SS` or `HH:MM:SS.SSS`, optionally followed by a timezone.
+    fn parse_hms(&mut self, z: &[u8]) -> Option<()> {
+        let hm = digits(z, &[(2, 0, 24, Some(b':')), (2, 0, 59, None)])?;
+        let mut z = &z[5..];
+        let mut second = 0.0;
+        if z.first() == Some(&b':') {
+            second = digits(&z[1..], &[(2, 0, 59, None)])?[0] as f64;
+            z = &z[3..];
+            if z.first() == Some(&b'.') && z.get(1).is_some_and(u8::is_ascii_digit) {
+                let (mut fraction, mut scale) = (0.0, 1.0);
+                z = &z[1..];
+                while let Some(&c) = z.first().filter(|c| c.is_ascii_digit()) {
+                    fraction = fraction * 10.0 + (c - b'0') as f64;
+                    scale *= 10.0;
+                    z = &z[1..];
+                }
+                // Truncated, rounding to the next second would be surprising.
+                second += (fraction / scale).min(0.999);
+            }
+        }
+        self.valid_jd = false;
+        self.raw_s = false;
+        self.valid_hms = true;
+        self.hour = hm[0];
+        self.minute = hm[1];
+        self.second = second;
+        self.parse_timezone(z)
+    }
+
+    /// `[+-]HH:MM` or `Z`, or nothing, surrounded by spaces.
+    fn parse_timezone(&mut self, z: &[u8]) -> Option<()> {
+        let mut z = skip_spaces(z);
+        self.tz = 0;
+        let sign = match z.first() {
+            Some(b'-') => -1,
+            Some(b'+') => 1,
+            Some(b'Z' | b'z') => {
+                self.is_local = false;
+                self.is_utc = true;
+                return skip_spaces(&z[1..]).is_empty().then_some(());
+            }
+            Some(_) => return None,
+            None => return Some(()),
+        };
+        let hm = digits(&z[1..], &[(2, 0, 14, Some(b':')), (2, 0, 59, None)])?;
+        z = &z[6..];
+        self.tz = sign * (hm[1] + hm[0] * 60);
+        skip_spaces(z).is_empty().then_some(())
+    }
+
+    fn set_now(&mut self) {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        self.jd = UNIX_EPOCH_MS + now.as_millis() as i64;
+        self.valid_jd = true;
+        self.is_utc = true;
+        self.is_local = false;
+        self.clear();
+    }
+
+    /// A number, which is a Julian day unless a modifier says otherwise.
+    fn set_raw(&mut self, r: f64) {
+        self.second = r;
+        self.raw_s = true;
+        if (0.0..5373484.5).contains(&r) {
+            self.jd = (r * DAY_MS as f64 + 0.5) as i64;
+            self.valid_jd = true;
+        }
+    }
+
+    fn set_error(&mut self) {
+        *self = Self {
+            error: true,
+            ..Self::default()
+        };
+    }
+
+    /// Forgets the date and time, once they no longer match the Julian day.
+    fn clear(&mut self) {
+        self.valid_ymd = false;
+        self.valid_hms = false;
+        self.tz = 0;
+    }
+
+    fn compute_jd(&mut self) {
+        if self.valid_jd {
+            return;
+        }
+        let (mut y, mut m, d) = match self.valid_ymd {
+            true => (self.year, self.month, self.day),
+            false => (2000, 1, 1),
+        };
+        if !(-4713..=9999).contains(&y) || self.raw_s {
+            self.set_error();
+            return;
+        }
+        if m <= 2 {
+            y -= 1;
+            m += 12;
+        }
+        let a = (y + 4800) / 100;
+        let b = 38 - a + a / 4;
+        let x1 = 36525 * (y + 4716) / 100;
+        let x2 = 306001 * (m + 1) / 10000;
+        self.jd = (((x1 + x2 + d + b) as f64 - 1524.5) * DAY_MS as f64) as i64;
+        self.valid_jd = true;
+        if self.valid_hms {
+            self.jd += (self.hour * 3600000 + self.minute * 60000) as i64 + (self.second * 1000.0 + 0.5) as i64;
+            if self.tz != 0 {
+                self.jd -= self.tz as i64 * 60000;
+                self.valid_ymd = false;
+                self.valid_hms = false;
+                self.tz = 0;
+                self.is_utc = true;
+                self.is_local = false;
+            }
+        }
+    }
+
+    fn compute_ymd(&mut self) {
+        if self.valid_ymd {
+            return;
+        }
+        if !self.valid_jd {
+            (self.year, self.month, self.day) = (2000, 1, 1);
+        } else if !valid_julian_day(self.jd) {
+            self.set_error();
+            return;
+        } else {
+            let z = ((self.jd + 43200000) / DAY_MS) as i32;
+            let alpha = ((z as f64 + 32044.75) / 36524.25) as i32 - 52;
+            let a = z + 1 + alpha - ((alpha + 100) / 4) + 25;
+            let b = a + 1524;
+            let c = ((b as f64 - 122.1) / 365.25) as i32;
+            let d = (36525 * (c & 32767)) / 100;
+            let e = ((b - d) as f64 / 30.6001) as i32;
+            let x1 = (30.6001 * e as f64) as i32;
+            self.day = b - d - x1;
+            self.month = if e < 14 { e - 1 } else { e - 13 };
+            self.year = if self.month > 2 { c - 4716 } else { c - 4715 };
+        }
+        self.valid_ymd = true;
+    }
+
+    fn compute_hms(&mut self) {
+        if self.valid_hms {
+            return;
+        }
+        self.compute_jd();
+        let day_ms = ((self.jd + 43200000) % DAY_MS) as i32;
+        self.second = (day_ms % 60000) as f64 / 1000.0;
+        let day_minute = day_ms / 60000;
+        self.minute = day_minute % 60;
+        self.hour = day_minute / 60;
+        self.raw_s = false;
+        self.valid_hms = true;
+    }
+
+    fn compute_ymd_hms(&mut self) {
+        self.compute_ymd();
+        self.compute_hms();
+    }
+
+    /// Records how far past the end of its month the day is, for the `floor` modifier.
+    fn compute_floor(&mut self) {
+        self.overflow = if self.day <= 28 || (1 << self.month) & 0x15aa != 0 {
+            0
+        } else if self.month != 2 {
+            (self.day == 31) as i64
+        } else if self.year % 4 != 0 || (self.year % 100 == 0 && self.year % 400 != 0) {
+            self.day as i64 - 28
+        } else {
+            self.day as i64 - 29
+        };
+    }
+
+    /// Converts from UTC to local time. The C library only knows the offsets of the years 1970 to 2037, the ones of
+    /// other years are taken from a year in that range starting on the same day of the week.
+    fn apply_localtime(&mut self) -> Result<()> {
+        self.compute_jd();
+        let (t, year_diff) = match self.jd < 210866760000000 || self.jd > 213014145600000 {
+            true => {
+                let mut x = self.clone();
+                x.compute_ymd_hms();
+                let year_diff = (2000 + x.year % 4) - x.year;
+                x.year += year_diff;
+                x.valid_jd = false;
+                x.compute_jd();
+                (x.jd / 1000 - UNIX_EPOCH_MS / 1000, year_diff)
+            }
+            false => (self.jd / 1000 - UNIX_EPOCH_MS / 1000, 0),
+        };
+        let tm = localtime(t)?;
+        self.year = tm.tm_year + 1900 - year_diff;
+        self.month = tm.tm_mon + 1;
+        self.day = tm.tm_mday;
+        self.hour = tm.tm_hour;
+        self.minute = tm.tm_min;
+        self.second = tm.tm_sec as f64 + (self.jd % 1000) as f64 * 0.001;
+        self.valid_ymd = true;
+        self.valid_hms = true;
+        self.valid_jd = false;
+        self.raw_s = false;
+        self.tz = 0;
+        self.error = false;
+        Ok(())
+    }
+
+    /// Applies the modifier that is argument `index` of the call, returns whether it is a valid one.
+    fn modify(&mut self, z: &str, index: usize) -> Result<bool> {
+        let lower = z.to_ascii_lowercase();
+        Ok(match lower.as_bytes().first() {
+            Some(b'a') if lower == "auto" => {
+                if index > 1 {
+                    return Ok(false);
+                }
+                if !self.raw_s || self.valid_jd {
+                    self.raw_s = false;
+                    return Ok(true);
+                }
+                // Numbers that aren't Julian days are unix timestamps.
+                let valid = (-210866760000.0..=253402300799.0).contains(&self.second);
+                if valid {
+                    self.set_unix_timestamp();
+                }
+                valid
+            }
+            Some(b'c') if lower == "ceiling" => {
+                self.compute_jd();
+                self.clear();
+                self.overflow = 0;
+                true
+            }
+            Some(b'f') if lower == "floor" => {
+                self.compute_jd();
+                self.jd -= self.overflow * DAY_MS;
+                self.clear();
+                true
+            }
+            Some(b'j') if lower == "julianday" => {
+                let valid = index == 1 && self.valid_jd && self.raw_s;
+                if valid {
+                    self.raw_s = false;
+                }
+                valid
+            }
+            Some(b'l') if lower == "localtime" => {
+                if !self.is_local {
+                    self.apply_localtime()?;
+                }
+                self.is_utc = false;
+                self.is_local = true;
+                true
+            }
+            Some(b'u') if lower == "unixepoch" && self.raw_s => {
+                let valid =
+                    index == 1 && (0.0..464269060800000.0).contains(&(self.second * 1000.0 + UNIX_EPOCH_MS as f64));
+                if valid {
+                    self.set_unix_timestamp();
+                }
+                valid
+            }
+            Some(b'u') if lower == "utc" => {
+                if !self.is_utc {
+                    self.apply_utc()?;
+                }
+                true
+            }
+            Some(b'w') if lower.starts_with("weekday ") => {
+                let Some(n) =
+                    parse_number(&lower.as_bytes()[8..]).filter(|r| (0.0..7.0).contains(r) && r.fract() == 0.0)
+                else {
+                    return Ok(false);
+                };
+                self.compute_ymd_hms();
+                self.tz = 0;
+                self.valid_jd = false;
+                self.compute_jd();
+                let mut weekday = ((self.jd + 129600000) / DAY_MS) % 7;
+                if weekday > n as i64 {
+                    weekday -= 7;
+                }
+                self.jd += (n as i64 - weekday) * DAY_MS;
+                self.clear();
+                true
+            }
+            Some(b's') => match lower.strip_prefix("start of ") {
+                None => {
+                    let valid = lower == "subsec" || lower == "subsecond";
+                    self.use_subsec |= valid;
+                    valid
+                }
+                Some(_) if !self.valid_jd && !self.valid_ymd && !self.valid_hms => false,
+                Some(unit) => {
+                    self.compute_ymd();
+                    self.valid_hms = true;
+                    (self.hour, self.minute, self.second) = (0, 0, 0.0);
+                    self.raw_s = false;
+                    self.tz = 0;
+                    self.valid_jd = false;
+                    match unit {
+                        "month" => self.day = 1,
+                        "year" => (self.month, self.day) = (1, 1),
+                        "day" => {}
+                        _ => return Ok(false),
+                    }
+                    true
+                }
+            },
+            Some(b'+' | b'-' | b'0'..=b'9') => self.shift(z.as_bytes()),
+            _ => false,
+        })
+    }
+
+    /// Reads the raw number as a unix timestamp.
+    fn set_unix_timestamp(&mut self) {
+        let r = self.second * 1000.0 + UNIX_EPOCH_MS as f64;
+        self.clear();
+        self.jd = (r + 0.5) as i64;
+        self.valid_jd = true;
+        self.raw_s = false;
+    }
+
+    /// Converts from local time to UTC, by guessing the UTC time whose local time is the one given.
+    fn apply_utc(&mut self) -> Result<()> {
+        self.compute_jd();
+        let original = self.jd;
+        let mut guess = original;
+        let mut error = 0;
+        for _ in 0..4 {
+            guess -= error;
+            let mut local = Self {
+                jd: guess,
+                valid_jd: true,
+                ..Self::default()
+            };
+            local.apply_localtime()?;
+            local.compute_jd();
+            error = local.jd - original;
+            if error == 0 {
+                break;
+            }
+        }
+        *self = Self {
+            jd: guess,
+            valid_jd: true,
+            is_utc: true,
+            ..Self::default()
+        };
+        Ok(())
+    }
+
+    /// The modifiers that add to the moment: `+N unit`, `+HH:MM:SS.SSS` and `+YYYY-MM-DD HH:MM:SS.SSS`, or the same
+    /// with a minus sign or no sign.
+    fn shift(&mut self, z: &[u8]) -> bool {
+        let sign = z[0];
+        // The number ends at a time, a unit or the dash after the years of a date.
+        let mut n = 1;
+        while n < z.len() && z[n] != b':' && !is_space(z[n]) {
+            if z[n] == b'-'
+                && ((n == 5 && digits(&z[1..], &[(4, 0, 14712, None)]).is_some())
+                    || (n == 6 && digits(&z[1..], &[(5, 0, 14712, None)]).is_some()))
+            {
+                break;
+            }
+            n += 1;
+        }
+        let Some(mut r) = parse_number(&z[..n]) else {
+            return false;
+        };
+        let (mut time, mut time_end) = (z, n);
+        if z.get(n) == Some(&b'-') {
+            if sign != b'+' && sign != b'-' {
+                return false;
+            }
+            let Some(ymd) = digits(
+                &z[1..],
+                &[(n - 1, 0, 14712, Some(b'-')), (2, 0, 11, Some(b'-')), (2, 0, 30, None)],
+            ) else {
+                return false;
+            };
+            self.compute_ymd_hms();
+            self.valid_jd = false;
+            let mut days = ymd[2];
+            if sign == b'-' {
+                self.year -= ymd[0];
+                self.month -= ymd[1];
+                days = -days;
+            } else {
+                self.year += ymd[0];
+                self.month += ymd[1];
+            }
+            self.normalize_month();
+            self.compute_floor();
+            self.compute_jd();
+            self.valid_hms = false;
+            self.valid_ymd = false;
+            self.jd += days as i64 * DAY_MS;
+            let end = n + 6;
+            match z.get(end) {
+                None => return true,
+                Some(&c)
+                    if is_space(c) && digits(&z[end + 1..], &[(2, 0, 24, Some(b':')), (2, 0, 59, None)]).is_some() =>
+                {
+                    (time, time_end) = (&z[end + 1..], 2);
+                }
+                Some(_) => return false,
+            }
+        }
+        if time.get(time_end) == Some(&b':') {
+            let time = if time[0].is_ascii_digit() { time } else { &time[1..] };
+            let mut tx = Self::default();
+            if tx.parse_hms(time).is_none() {
+                return false;
+            }
+            tx.compute_jd();
+            // The time of day of the moment read.
+            tx.jd -= 43200000;
+            tx.jd -= tx.jd / DAY_MS * DAY_MS;
+            if sign == b'-' {
+                tx.jd = -tx.jd;
+            }
+            self.compute_jd();
+            self.clear();
+            self.jd += tx.jd;
+            return true;
+        }
+
+        let rest = skip_spaces(&z[n..]);
+        let mut len = rest.len();
+        if !(3..=10).contains(&len) {
+            return false;
+        }
+        if rest[len - 1].eq_ignore_ascii_case(&b's') {
+            len -= 1;
+        }
+        self.compute_jd();
+        let rounder = if r < 0.0 { -0.5 } else { 0.5 };
+        self.overflow = 0;
+        let unit = UNITS
+            .iter()
+            .find(|(name, limit, _)| name.as_bytes().eq_ignore_ascii_case(&rest[..len]) && r > -limit && r < *limit);
+        if let Some((name, _, seconds)) = unit {
+            // Months and years have different lengths, they change the date and leave the rest of the number to add.
+            if *name == "month" || *name == "year" {
+                self.compute_ymd_hms();
+                match *name {
+                    "month" => self.month += r as i32,
+                    _ => self.year += r as i32,
+                }
+                self.normalize_month();
+                self.compute_floor();
+                self.valid_jd = false;
+                r -= r.trunc();
+            }
+            self.compute_jd();
+            self.jd += (r * 1000.0 * seconds + rounder) as i64;
+        }
+        self.clear();
+        unit.is_some()
+    }
+
+    /// Brings the month back between 1 and 12, moving to another year.
+    fn normalize_month(&mut self) {
+        let years = if self.month > 0 {
+            (self.month - 1) / 12
+        } else {
+            (self.month - 12) / 12
+        };
+        self.year += years;
+        self.month -= years * 12;
+    }
+
+    /// Days since the first of January of the year, at the same time of day.
+    fn days_after_jan01(&self) -> i64 {
+        let mut jan01 = Self {
+            month: 1,
+            day: 1,
+            valid_jd: false,
+            ..self.clone()
+        };
+        jan01.compute_jd();
+        (self.jd - jan01.jd + 43200000) / DAY_MS
+    }
+
+    /// Days since the last Monday, 0 for Mondays.
+    fn days_after_monday(&self) -> i64 {
+        ((self.jd + 43200000) / DAY_MS) % 7
+    }
+
+    /// Days since the last Sunday, 0 for Sundays.
+    fn days_after_sunday(&self) -> i64 {
+        ((self.jd + 129600000) / DAY_MS) % 7
+    }
+
+    /// The Thursday of the same week, which decides the year the ISO 8601 week belongs to.
+    fn thursday(&self) -> Self {
+        let mut thursday = self.clone();
+        thursday.jd += (3 - self.days_after_monday()) * DAY_MS;
+        thursday.valid_ymd = false;
+        thursday.compute_ymd();
+        thursday
+    }
+
+    fn date(&self) -> String {
+        let sign = if self.year < 0 { "-" } else { "" };
+        format!("{sign}{:04}-{:02}-{:02}", self.year.abs(), self.month, self.day)
+    }
+
+    fn time(&self) -> String {
+        match self.use_subsec {
+            true => {
+                let ms = (1000.0 * self.second + 0.5) as i32;
+                format!("{:02}:{:02}:{:02}.{:03}", self.hour, self.minute, ms / 1000, ms % 1000)
+            }
+            false => format!("{:02}:{:02}:{:02}", self.hour, self.minute, self.second as i32),
+        }
+    }
+}
+
+/// Reads fixed-width numbers, each field being its number of digits, the smallest and largest values it can have and
+/// the character that has to follow it, if any.
+fn digits(z: &[u8], fields: &[(usize, i32, i32, Option<u8>)]) -> Option<Vec<i32>> {
+    let mut values = vec![];
+    let mut z = z;
+    for &(width, min, max, next) in fields {
+        if z.len() < width || !z[..width].iter().all(u8::is_ascii_digit) {
+            return None;
+        }
+        let value = z[..width].iter().fold(0, |v, d| v * 10 + (d - b'0') as i32);
+        z = &z[width..];
+        if !(min..=max).contains(&value) || next.is_some_and(|next| z.first() != Some(&next)) {
+            return None;
+        }
+        values.push(value);
+        z = z.get(1..).unwrap_or_default();
+    }
+    Some(values)
+}
+
+/// The number text is made of, if it is only that and spaces.
+fn parse_number(z: &[u8]) -> Option<f64> {
+    let s = std::str::from_utf8(z).ok()?.trim_matches(|c: char| is_space(c as u8));
+    let unsigned = s.strip_prefix(['+', '-']).unwrap_or(s);
+    let mantissa = unsigned.split(['e', 'E']).next().unwrap();
+    // Rust also reads `inf` and `nan`, which aren't numbers in SQL.
+    match mantissa.bytes().any(|c| c.is_ascii_digit())
+        && unsigned.bytes().all(|c| c.is_ascii_digit() || b".eE+-".contains(&c))
+    {
+        true => s.parse().ok(),
+        false => None,
+    }
+}
+
+fn is_space(c: u8) -> bool {
+    matches!(c, b' ' | b'\t' | b'\n' | 0x0b | 0x0c | b'\r')
+}
+
+fn skip_spaces(z: &[u8]) -> &[u8] {
+    let start = z.iter().position(|&c| !is_space(c)).unwrap_or(z.len());
+    &z[start..]
+}
+
+fn valid_julian_day(jd: i64) -> bool {
+    (0..=MAX_JD).contains(&jd)
+}
+
+/// The local time of a unix timestamp, from the C library.
+#[cfg(unix)]
+fn localtime(t: i64) -> Result<libc::tm> {
+    // SAFETY: `tm` is plain data, which the call fills in.
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    // SAFETY: both pointers are valid for the duration of the call.
+    let result = unsafe { libc::localtime_r(&(t as libc::time_t), &mut tm) };
+    if result.is_null() {
+        bail!("local time unavailable");
+    }
+    Ok(tm)
+}
+
+/// The local time of a unix timestamp, from the C runtime, which has `localtime_s()` with its arguments the other
+/// way around instead.
+#[cfg(windows)]
+fn localtime(t: i64) -> Result<libc::tm> {
+    // SAFETY: `tm` is plain data, which the call fills in.
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    // SAFETY: both pointers are valid for the duration of the call.
+    if unsafe { libc::localtime_s(&mut tm, &(t as libc::time_t)) } != 0 {
+        bail!("local time unavailable");
+    }
+    Ok(tm)
+}
+
+/// The moment the arguments of a date and time function describe: a time value followed by modifiers, or no arguments
+/// for the current time. There is none when an argument is NULL or isn't valid.
+fn moment(args: &[Value]) -> Result<Option<DateTime>> {
+    let mut p = DateTime::default();
+    let Some(first) = args.first() else {
+        p.set_now();
+        return Ok(Some(p));
+    };
+    match first {
+        Value::Null => return Ok(None),
+        Value::Int(i) => p.set_raw(*i as f64),
+        Value::Float(f) => p.set_raw(*f),
+        v => {
+            if p.parse(&v.to_string()).is_none() {
+                return Ok(None);
+            }
+        }
+    }
+    for (i, modifier) in args.iter().enumerate().skip(1) {
+        if matches!(modifier, Value::Null) || !p.modify(&modifier.to_string(), i)? {
+            return Ok(None);
+        }
+    }
+    p.compute_jd();
+    if p.error || !valid_julian_day(p.jd) {
+        return Ok(None);
+    }
+    // A date past the end of its month moves to the next one.
+    if args.len() == 1 && p.valid_ymd && p.day > 28 {
+        p.valid_ymd = false;
+    }
+    Ok(Some(p))
+}
+
+pub fn julianday(args: &[Value]) -> Result<Value<'static>> {
+    Ok(moment(args)?.map_or(Value::Null, |p| Value::Float(p.jd as f64 / DAY_MS as f64)))
+}
+
+pub fn unixepoch(args: &[Value]) -> Result<Value<'static>> {
+    Ok(moment(args)?.map_or(Value::Null, |p| match p.use_subsec {
+        true => Value::Float((p.jd - UNIX_EPOCH_MS) as f64 / 1000.0),
+        false => Value::Int(p.jd / 1000 - UNIX_EPOCH_MS / 1000),
+    }))
+}
+
+/// `YYYY-MM-DD HH:MM:SS`.
+pub fn datetime(args: &[Value]) -> Result<Value<'static>> {
+    Ok(moment(args)?.map_or(Value::Null, |mut p| {
+        p.compute_ymd_hms();
+        Value::String(format!("{} {}", p.date(), p.time()).into())
+    }))
+}
+
+/// `YYYY-MM-DD`.
+pub fn date(args: &[Value]) -> Result<Value<'static>> {
+    Ok(moment(args)?.map_or(Value::Null, |mut p| {
+        p.compute_ymd();
+        Value::String(p.date().into())
+    }))
+}
+
+/// `HH:MM:SS`.
+pub fn time(args: &[Value]) -> Result<Value<'static>> {
+    Ok(moment(args)?.map_or(Value::Null, |mut p| {
+        p.compute_hms();
+        Value::String(p.time().into())
+    }))
+}
+
+/// `strftime(format, time, modifiers...)`, NULL when the format has an unknown conversion.
+pub fn strftime(args: &[Value]) -> Result<Value<'static>> {
+    let (Some(format), Some(mut p)) = (args.first().filter(|f| !matches!(f, Value::Null)), moment(&args[1..])?) else {
+        return Ok(Value::Null);
+    };
+    p.compute_jd();
+    p.compute_ymd_hms();
+    let format = format.to_string();
+    let mut out = String::new();
+    let mut chars = format.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            out.push(c);
+            continue;
+        }
+        let hour12 = match p.hour {
+            0 => 12,
+            h if h > 12 => h - 12,
+            h => h,
+        };
+        let converted = match chars.next() {
+            Some('d') => format!("{:02}", p.day),
+            Some('e') => format!("{:2}", p.day),
+            Some('f') => format!("{:06.3}", p.second.min(59.999)),
+            Some('F') => format!("{:04}-{:02}-{:02}", p.year, p.month, p.day),
+            Some('G') => format!("{:04}", p.thursday().year),
+            Some('g') => format!("{:02}", p.thursday().year % 100),
+            Some('H') => format!("{:02}", p.hour),
+            Some('k') => format!("{:2}", p.hour),
+            Some('I') => format!("{hour12:02}"),
+            Some('l') => format!("{hour12:2}"),
+            Some('j') => format!("{:03}", p.days_after_jan01() + 1),
+            Some('J') => general(p.jd as f64 / DAY_MS as f64, 16, 16, false),
+            Some('m') => format!("{:02}", p.month),
+            Some('M') => format!("{:02}", p.minute),
+            Some('p') => (if p.hour >= 12 { "PM" } else { "AM" }).to_string(),
+            Some('P') => (if p.hour >= 12 { "pm" } else { "am" }).to_string(),
+            Some('R') => format!("{:02}:{:02}", p.hour, p.minute),
+            Some('s') => match p.use_subsec {
+                true => format!("{:.3}", (p.jd - UNIX_EPOCH_MS) as f64 / 1000.0),
+                false => (p.jd / 1000 - UNIX_EPOCH_MS / 1000).to_string(),
+            },
+            Some('S') => format!("{:02}", p.second as i32),
+            Some('T') => format!("{:02}:{:02}:{:02}", p.hour, p.minute, p.second as i32),
+            Some('u') => match p.days_after_sunday() {
+                0 => "7".to_string(),
+                d => d.to_string(),
+            },
+            Some('w') => p.days_after_sunday().to_string(),
+            Some('U') => format!("{:02}", (p.days_after_jan01() - p.days_after_sunday() + 7) / 7),
+            Some('V') => format!("{:02}", p.thursday().days_after_jan01() / 7 + 1),
+            Some('W') => format!("{:02}", (p.days_after_jan01() - p.days_after_monday() + 7) / 7),
+            Some('Y') => format!("{:04}", p.year),
+            Some('%') => "%".to_string(),
+            _ => return Ok(Value::Null),
+        };
+        out += &converted;
+    }
+    Ok(Value::String(out.into()))
+}
+
+/// `timediff(a, b)`, the time to add to `b` to get `a`, as `+YYYY-MM-DD HH:MM:SS.SSS`.
+pub fn timediff(args: &[Value]) -> Result<Value<'static>> {
+    let (Some(mut d1), Some(mut d2)) = (moment(&args[..1])?, moment(&args[1..])?) else {
+        return Ok(Value::Null);
+    };
+    d1.compute_ymd_hms();
+    d2.compute_ymd_hms();
+    // Whole years and months are counted on the calendar, by moving `b` to the month of `a` and back until it doesn't
+    // go past it. The rest is a number of days and a time.
+    let forward = d1.jd >= d2.jd;
+    let mut years = (d1.year - d2.year).abs();
+    if years != 0 {
+        d2.year = d1.year;
+        d2.valid_jd = false;
+        d2.compute_jd();
+    }
+    let mut months = if forward {
+        d1.month - d2.month
+    } else {
+        d2.month - d1.month
+    };
+    if months < 0 {
+        years -= 1;
+        months += 12;
+    }
+    if months != 0 {
+        d2.month = d1.month;
+        d2.valid_jd = false;
+        d2.compute_jd();
+    }
+    while (forward && d1.jd < d2.jd) || (!forward && d1.jd > d2.jd) {
+        months -= 1;
+        if months < 0 {
+            months = 11;
+            years -= 1;
+        }
+        d2.month += if forward { -1 } else { 1 };
+        if d2.month < 1 {
+            d2.month = 12;
+            d2.year -= 1;
+        } else if d2.month > 12 {
+            d2.month = 1;
+            d2.year += 1;
+        }
+        d2.valid_jd = false;
+        d2.compute_jd();
+    }
+    // The difference as a moment, counted from 0000-01-01 00:00:00.
+    d1.jd = (d1.jd - d2.jd).abs() + 148699540800000;
+    d1.clear();
+    d1.compute_ymd_hms();
+    Ok(Value::String(
+        format!(
+            "{}{years:04}-{months:02}-{:02} {:02}:{:02}:{:06.3}",
+            if forward { '+' } else { '-' },
+            d1.day - 1,
+            d1.hour,
+            d1.minute,
+            d1.second
+        )
+        .into(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::functions::find_function;
+
+    /// Calls with text arguments, and the results sqlite3 gives for them.
+    const CASES: &[(&str, &[&str], Option<&str>)] = &[
+        ("date", &["2024-03-05"], Some("2024-03-05")),
+        ("time", &["2024-03-05 14:07:09"], Some("14:07:09")),
+        ("datetime", &["2024-03-05T14:07:09.123"], Some("2024-03-05 14:07:09")),
+        ("julianday", &["2024-03-05"], Some("2460374.5")),
+        ("unixepoch", &["2024-03-05 14:07:09"], Some("1709647629")),
+        ("datetime", &["14:07"], Some("2000-01-01 14:07:00")),
+        ("datetime", &["2024-03-05 14:07:09+02:00"], Some("2024-03-05 12:07:09")),
+        ("datetime", &["2024-03-05 14:07:09 -05:30"], Some("2024-03-05 19:37:09")),
+        ("datetime", &["2024-03-05 14:07:09Z"], Some("2024-03-05 14:07:09")),
+        ("datetime", &["2460375.5"], Some("2024-03-06 00:00:00")),
+        ("datetime", &["2024-03-05 24:00:00"], Some("2024-03-05 24:00:00")),
+        ("date", &["2023-02-29"], Some("2023-03-01")),
+        ("date", &["2024-13-01"], None),
+        ("date", &["2024-1-5"], None),
+        ("date", &["abc"], None),
+        ("date", &["2024-03-05x"], None),
+        ("date", &["-0001-03-01"], Some("-0001-03-01")),
+        ("julianday", &["-4713-11-24 12:00:00"], Some("0.0")),
+        ("date", &["9999-12-31", "+1 day"], None),
+        ("datetime", &["1709647629", "unixepoch"], Some("2024-03-05 14:07:09")),
+        (
+            "datetime",
+            &["1709647629.5", "unixepoch", "subsec"],
+            Some("2024-03-05 14:07:09.500"),
+        ),
+        ("datetime", &["1709647629", "auto"], Some("2024-03-05 14:07:09")),
+        ("datetime", &["2460375.5", "auto"], Some("2024-03-06 00:00:00")),
+        ("datetime", &["1709647629", "julianday"], None),
+        ("datetime", &["2024-03-05", "unixepoch"], None),
+        ("date", &["2024-01-31", "+1 month"], Some("2024-03-02")),
+        ("date", &["2024-01-31", "+1 month", "floor"], Some("2024-02-29")),
+        ("date", &["2024-01-31", "+1 month", "ceiling"], Some("2024-03-02")),
+        ("date", &["2024-02-29", "+1 year"], Some("2025-03-01")),
+        ("date", &["2024-02-29", "+1 year", "floor"], Some("2025-02-28")),
+        ("date", &["2024-05-31", "-15 months"], Some("2023-03-03")),
+        (
+            "datetime",
+            &["2024-03-05 14:07:09", "+1.5 days"],
+            Some("2024-03-07 02:07:09"),
+        ),
+        (
+            "datetime",
+            &["2024-03-05 14:07:09", "-2 hours"],
+            Some("2024-03-05 12:07:09"),
+        ),
+        (
+            "datetime",
+            &["2024-03-05 14:07:09", "+90 minutes"],
+            Some("2024-03-05 15:37:09"),
+        ),
+        (
+            "datetime",
+            &["2024-03-05 14:07:09", "+30 seconds"],
+            Some("2024-03-05 14:07:39"),
+        ),
+        (
+            "datetime",
+            &["2024-03-05 14:07:09", "+1.5 months"],
+            Some("2024-04-20 14:07:09"),
+        ),
+        (
+            "datetime",
+            &["2024-03-05 14:07:09", "1 DAYS"],
+            Some("2024-03-06 14:07:09"),
+        ),
+        ("datetime", &["2024-03-05 14:07:09", "+1 fortnight"], None),
+        (
+            "datetime",
+            &["2024-03-05 14:07:09", "+01:30"],
+            Some("2024-03-05 15:37:09"),
+        ),
+        (
+            "datetime",
+            &["2024-03-05 14:07:09", "-01:30:15.5"],
+            Some("2024-03-05 12:36:53"),
+        ),
+        ("datetime", &["2024-03-05 14:07:09", "+1-02-03"], None),
+        (
+            "datetime",
+            &["2024-03-05 14:07:09", "-0001-02-03 04:05:06"],
+            Some("2023-01-02 10:02:03"),
+        ),
+        ("datetime", &["2024-03-05 14:07:09", "1-02-03"], None),
+        ("date", &["2024-03-05", "start of month"], Some("2024-03-01")),
+        ("date", &["2024-03-05", "start of year"], Some("2024-01-01")),
+        (
+            "datetime",
+            &["2024-03-05 14:07:09", "start of day"],
+            Some("2024-03-05 00:00:00"),
+        ),
+        ("date", &["2024-03-05", "start of week"], None),
+        ("date", &["2024-03-05", "weekday 0"], Some("2024-03-10")),
+        ("date", &["2024-03-05", "weekday 2"], Some("2024-03-05")),
+        ("date", &["2024-03-05", "weekday 7"], None),
+        (
+            "date",
+            &["2024-03-05", "start of month", "+1 month", "-1 day"],
+            Some("2024-03-31"),
+        ),
+        (
+            "datetime",
+            &["2024-03-05 14:07:09.1234", "subsec"],
+            Some("2024-03-05 14:07:09.123"),
+        ),
+        ("time", &["14:07:09.9999", "subsec"], Some("14:07:09.999")),
+        (
+            "unixepoch",
+            &["2024-03-05 14:07:09.123", "subsec"],
+            Some("1709647629.123"),
+        ),
+        (
+            "strftime",
+            &[
+                "%d|%e|%f|%F|%G|%g|%H|%I|%j|%J|%k|%l|%m|%M|%p|%P|%R|%s|%S|%T|%u|%U|%V|%w|%W|%Y|%%",
+                "2024-03-05 14:07:09.123",
+            ],
+            Some(
+                "05| 5|09.123|2024-03-05|2024|24|14|02|065|2460375.088300035|14| 2|03|07|PM|pm|14:07|1709647629|09|14:07:09|2|09|10|2|10|2024|%",
+            ),
+        ),
+        (
+            "strftime",
+            &["%G-%V-%u %U %W %j", "2021-01-01"],
+            Some("2020-53-5 00 00 001"),
+        ),
+        ("strftime", &["%G-%V-%u", "2024-12-30"], Some("2025-01-1")),
+        ("strftime", &["%I %l %p", "2024-01-01 00:30"], Some("12 12 AM")),
+        ("strftime", &["%Q", "2024-01-01"], None),
+        (
+            "timediff",
+            &["2024-03-05", "2023-01-31"],
+            Some("+0001-01-03 00:00:00.000"),
+        ),
+        (
+            "timediff",
+            &["2023-01-31", "2024-03-05"],
+            Some("-0001-01-05 00:00:00.000"),
+        ),
+        (
+            "timediff",
+            &["2024-03-01", "2024-01-31"],
+            Some("+0000-00-30 00:00:00.000"),
+        ),
+    ];
+
+    fn call(name: &str, args: &[Value]) -> Option<String> {
+        match find_function(name, args.len()).unwrap()(args).unwrap() {
+            Value::Null => None,
+            value => Some(value.to_string()),
+        }
+    }
+
+    #[test]
+    fn sqlite_outputs() {
+        for &(name, args, expected) in CASES {
+            let args = args
+                .iter()
+                .map(|a| Value::String(a.to_string().into()))
+                .collect::<Vec<_>>();
+            assert_eq!(call(name, &args).as_deref(), expected, "{name}({args:?})");
+        }
+    }
+
+    #[test]
+    fn numeric_arguments() {
+        let unixepoch = Value::String("unixepoch".into());
+        assert_eq!(
+            call("datetime", &[Value::Int(1709647629), unixepoch.clone()]).as_deref(),
+            Some("2024-03-05 14:07:09")
+        );
+        assert_eq!(call("date", &[Value::Float(2460374.5)]).as_deref(), Some("2024-03-05"));
+        assert_eq!(
+            call("date", &[Value::Int(-1), unixepoch]).as_deref(),
+            Some("1969-12-31")
+        );
+        assert_eq!(call("date", &[Value::Null]), None);
+        assert_eq!(call("date", &[Value::String("2024-03-05".into()), Value::Null]), None);
+    }
+}
@@ -0,0 +1,545 @@
+//! Evaluation of SQL expressions against a row.
+//!
+//! Expressions follow SQLite's semantics: arithmetic converts text to numbers, integer overflow falls back to reals,
+//! comparisons apply affinity conversions first and anything involving NULL is unknown unless stated otherwise.
+use std::cell::OnceCell;
+use std::cmp::Ordering;
+use std::collections::HashSet;
+use std::rc::Rc;
+
+use anyhow::Result;
+use anyhow::bail;
+use parser::BinaryOp;
+use parser::Collation;
+use parser::Expr;
+use parser::SqlType;
+use parser::UnaryOp;
+use parser::Value;
+
+use crate::aggregate::GroupKey;
+use crate::functions::Functions;
+use crate::json;
+
+/// Source of column values for expression evaluation.
+pub trait Row<'a> {
+    /// Returns the affinity and the value of a column.
+    fn column(&self, table: Option<&str>, name: &str) -> Result<(SqlType, Value<'a>)>;
+
+    /// Returns the collation of a column.
+    fn collation(&self, table: &str, name: &str) -> Collation;
+
+    /// Returns the result of an aggregate function call, only rows standing for a whole group have them.
+    fn aggregate(&self, call: &Expr<'a>) -> Result<Value<'a>> {
+        let Expr::Function { name, .. } = call else {
+            unreachable!()
+        };
+        bail!("misuse of aggregate function {name}()")
+    }
+
+    /// Runs the subquery of a `(SELECT ...)`, `EXISTS (SELECT ...)` or `IN (SELECT ...)` expression, with the values
+    /// of this row for the columns it refers to.
+    fn subquery(&self, expr: &Expr<'a>) -> Result<Rc<SubqueryValues>>;
+
+    /// The functions the expression can call.
+    fn functions(&self) -> &Functions;
+
+    /// Returns the value bound to the parameter whose placeholder is at `offset` in the text of the statement.
+    fn parameter(&self, offset: usize) -> Value<'a>;
+}
+
+/// The values of the first column of the rows of a subquery.
+pub struct SubqueryValues {
+    pub values: Vec<Value<'static>>,
+    /// Affinity of the column, the one of the expression it is.
+    pub affinity: Option<SqlType>,
+    /// The values converted for the comparisons of `IN`, built the first time one is made. The comparisons are the
+    /// ones of a single `IN` expression, so they all use the same affinity.
+    set: OnceCell<HashSet<GroupKey>>,
+    has_null: bool,
+}
+
+impl SubqueryValues {
+    pub fn new(values: Vec<Value<'static>>, affinity: Option<SqlType>) -> Self {
+        Self {
+            has_null: values.iter().any(|v| matches!(v, Value::Null)),
+            values,
+            affinity,
+            set: OnceCell::new(),
+        }
+    }
+
+    /// Whether one of the values is equal to the operand, unknown when it isn't found but NULLs are involved. The
+    /// comparisons of a single `IN` expression all use the same collation too.
+    fn contains(&self, (value, affinity): &Operand, collation: Collation) -> Option<bool> {
+        if self.values.is_empty() {
+            return Some(false);
+        }
+        if matches!(value, Value::Null) {
+            return None;
+        }
+        let affinity = comparison_affinity(*affinity, self.affinity);
+        let convert = |v: Value| match affinity {
+            Some(affinity) => collation.fold(v.with_affinity(affinity)).into_owned(),
+            None => collation.fold(v).into_owned(),
+        };
+        let set = self.set.get_or_init(|| {
+            let values = self.values.iter().filter(|v| !matches!(v, Value::Null));
+            values.map(|v| GroupKey(vec![convert(v.clone())])).collect()
+        });
+        if set.contains(&GroupKey(vec![convert(value.clone())])) {
+            Some(true)
+        } else if self.has_null {
+            None
+        } else {
+            Some(false)
+        }
+    }
+}
+
+pub fn eval<'a>(expr: &Expr<'a>, row: &impl Row<'a>) -> Result<Value<'a>> {
+    Ok(eval_with_affinity(expr, row)?.0)
+}
+
+/// Whether the value counts as true in a boolean context, `None` stands for NULL.
+pub fn truth(value: &Value) -> Option<bool> {
+    match value.to_number() {
+        Value::Int(i) => Some(i != 0),
+        Value::Float(f) => Some(f != 0.0),
+        _ => None,
+    }
+}
+
+fn bool_value<'a>(b: Option<bool>) -> Value<'a> {
+    b.map_or(Value::Null, |b| Value::Int(b as i64))
+}
+
+fn and(l: Option<bool>, r: Option<bool>) -> Option<bool> {
+    match (l, r) {
+        (Some(false), _) | (_, Some(false)) => Some(false),
+        (Some(true), Some(true)) => Some(true),
+        _ => None,
+    }
+}
+
+fn or(l: Option<bool>, r: Option<bool>) -> Option<bool> {
+    match (l, r) {
+        (Some(true), _) | (_, Some(true)) => Some(true),
+        (Some(false), Some(false)) => Some(false),
+        _ => None,
+    }
+}
+
+type Operand<'a> = (Value<'a>, Option<SqlType>);
+
+/// Evaluates the expression along with its affinity. Columns have the affinity of their declared type, casts the one
+/// of their target type and every other expression has none.
+fn eval_with_affinity<'a>(expr: &Expr<'a>, row: &impl Row<'a>) -> Result<Operand<'a>> {
+    Ok(match expr {
+        Expr::Literal(v) => (v.clone(), None),
+        Expr::Column { table, name } => {
+            let (affinity, v) = row.column(*table, name)?;
+            (v, Some(affinity))
+        }
+        Expr::Parameter { offset, .. } => (row.parameter(*offset), None),
+        Expr::Cast(e, ty) => (eval(e, row)?.cast(*ty), Some(*ty)),
+        // The collation only matters to the comparisons the expression is an operand of.
+        Expr::Collate(e, _) => eval_with_affinity(e, row)?,
+        Expr::Unary(op, e) => (unary(*op, eval(e, row)?), None),
+        Expr::Binary(l, op, r) => (binary(l, *op, r, row)?, None),
+        Expr::Between {
+            expr,
+            low,
+            high,
+            negated,
+        } => (between(expr, low, high, *negated, row)?, None),
+        Expr::InList { expr, list, negated } => (in_list(expr, list, *negated, row)?, None),
+        Expr::Like {
+            expr,
+            pattern,
+            escape,
+            glob,
+            negated,
+        } => (like(expr, pattern, escape.as_deref(), *glob, *negated, row)?, None),
+        Expr::Case {
+            operand,
+            branches,
+            otherwise,
+        } => (case(operand.as_deref(), branches, otherwise.as_deref(), row)?, None),
+        Expr::Function {
+            args,
+            function: Some(function),
+            ..
+        } => {
+            let args = args.iter().map(|a| eval(a, row)).collect::<Result<Vec<_>>>()?;
+            (row.functions().get(*function)(&args)?, None)
+        }
+        // The calls to aggregates aren't bound to a function, the row has their result.
+        Expr::Function { .. } => (row.aggregate(expr)?, None),
+        // Windows compute the calls, the planner replaces them with the columns they add.
+        Expr::Window { name, .. } => bail!("misuse of window function {name}()"),
+        Expr::Subquery(_) => {
+            let result = row.subquery(expr)?;
+            (result.values.first().cloned().unwrap_or(Value::Null), result.affinity)
+        }
+        Expr::Exists(_) => (bool_value(Some(!row.subquery(expr)?.values.is_empty())), None),
+        Expr::InSelect { expr: x, negated, .. } => {
+            let collation = collation(x, |t, n| row.collation(t, n));
+            let x = eval_with_affinity(x, row)?;
+            let found = row.subquery(expr)?.contains(&x, collation);
+            (bool_value(found.map(|b| b != *negated)), None)
+        }
+    })
+}
+
+// The cases of `eval_with_affinity` that need more than a few values are functions of their own, which keeps the stack
+// it takes for each level of an expression small.
+
+fn between<'a>(
+    expr: &Expr<'a>,
+    low: &Expr<'a>,
+    high: &Expr<'a>,
+    negated: bool,
+    row: &impl Row<'a>,
+) -> Result<Value<'a>> {
+    let x = eval_with_affinity(expr, row)?;
+    let collation = |other| comparison_collation(expr, other, |t, n| row.collation(t, n));
+    let ge = compare(&x, &eval_with_affinity(low, row)?, collation(low)).map(Ordering::is_ge);
+    let le = compare(&x, &eval_with_affinity(high, row)?, collation(high)).map(Ordering::is_le);
+    Ok(bool_value(and(ge, le).map(|b| b != negated)))
+}
+
+fn in_list<'a>(expr: &Expr<'a>, list: &[Expr<'a>], negated: bool, row: &impl Row<'a>) -> Result<Value<'a>> {
+    let x = eval_with_affinity(expr, row)?;
+    let mut found = Some(false);
+    for item in list {
+        let collation = comparison_collation(expr, item, |t, n| row.collation(t, n));
+        match compare(&x, &eval_with_affinity(item, row)?, collation) {
+            Some(Ordering::Equal) => {
+                found = Some(true);
+                break;
+            }
+            None => found = None,
+            _ => {}
+        }
+    }
+    Ok(bool_value(found.map(|b| b != negated)))
+}
+
+fn like<'a>(
+    expr: &Expr<'a>,
+    pattern: &Expr<'a>,
+    escape: Option<&Expr<'a>>,
+    glob: bool,
+    negated: bool,
+    row: &impl Row<'a>,
+) -> Result<Value<'a>> {
+    let text = eval(expr, row)?;
+    let pattern = eval(pattern, row)?;
+    let escape = escape.map(|e| eval(e, row)).transpose()?;
+    if matches!(text, Value::Null) || matches!(pattern, Value::Null) || matches!(escape, Some(Value::Null)) {
+        return Ok(Value::Null);
+    }
+    let escape = match escape.map(|e| e.to_string().chars().collect::<Vec<_>>()) {
+        Some(e) if e.len() != 1 => bail!("ESCAPE expression must be a single character"),
+        e => e.map(|e| e[0]),
+    };
+    let text: Vec<char> = text.to_string().chars().collect();
+    let pattern: Vec<char> = pattern.to_string().chars().collect();
+    let matched = if glob {
+        glob_match(&pattern, &text)
+    } else {
+        like_match(&pattern, &text, escape)
+    };
+    Ok(bool_value(Some(matched != negated)))
+}
+
+fn case<'a>(
+    operand: Option<&Expr<'a>>,
+    branches: &[(Expr<'a>, Expr<'a>)],
+    otherwise: Option<&Expr<'a>>,
+    row: &impl Row<'a>,
+) -> Result<Value<'a>> {
+    let value = operand.map(|o| eval_with_affinity(o, row)).transpose()?;
+    for (when, then) in branches {
+        let hit = match (operand, &value) {
+            (Some(operand), Some(o)) => {
+                let collation = comparison_collation(operand, when, |t, n| row.collation(t, n));
+                compare(o, &eval_with_affinity(when, row)?, collation) == Some(Ordering::Equal)
+            }
+            _ => truth(&eval(when, row)?) == Some(true),
+        };
+        if hit {
+            return eval(then, row);
+        }
+    }
+    match otherwise {
+        Some(e) => eval(e, row),
+        None => Ok(Value::Null),
+    }
+}
+
+fn binary<'a>(l: &Expr<'a>, op: BinaryOp, r: &Expr<'a>, row: &impl Row<'a>) -> Result<Value<'a>> {
+    use BinaryOp::*;
+    Ok(match op {
+        And => {
+            let l = truth(&eval(l, row)?);
+            if l == Some(false) {
+                return Ok(Value::Int(0));
+            }
+            bool_value(and(l, truth(&eval(r, row)?)))
+        }
+        Or => {
+            let l = truth(&eval(l, row)?);
+            if l == Some(true) {
+                return Ok(Value::Int(1));
+            }
+            bool_value(or(l, truth(&eval(r, row)?)))
+        }
+        Eq | Ne | Lt | Le | Gt | Ge => {
+            let collation = comparison_collation(l, r, |t, n| row.collation(t, n));
+            let ord = compare(&eval_with_affinity(l, row)?, &eval_with_affinity(r, row)?, collation);
+            bool_value(ord.map(|o| match op {
+                Eq => o.is_eq(),
+                Ne => o.is_ne(),
+                Lt => o.is_lt(),
+                Le => o.is_le(),
+                Gt => o.is_gt(),
+                _ => o.is_ge(),
+            }))
+        }
+        // Unlike `=`, `IS` treats NULLs as equal and never yields unknown.
+        Is | IsNot => {
+            let collation = comparison_collation(l, r, |t, n| row.collation(t, n));
+            let (l, r) = (eval_with_affinity(l, row)?, eval_with_affinity(r, row)?);
+            let equal = match (&l.0, &r.0) {
+                (Value::Null, Value::Null) => true,
+                (Value::Null, _) | (_, Value::Null) => false,
+                _ => compare(&l, &r, collation) == Some(Ordering::Equal),
+            };
+            Value::Int((equal == (op == Is)) as i64)
+        }
+        Arrow | LongArrow => json::arrow(&eval(l, row)?, &eval(r, row)?, op == LongArrow)?,
+        _ => arithmetic(op, eval(l, row)?, eval(r, row)?),
+    })
+}
+
+/// The affinity both sides of a comparison are converted to, given their own, either NUMERIC, TEXT or none.
+pub fn comparison_affinity(l: Option<SqlType>, r: Option<SqlType>) -> Option<SqlType> {
+    let affinity = match (l, r) {
+        (Some(a), Some(b)) if a.is_numeric() || b.is_numeric() => Some(SqlType::Numeric),
+        (Some(_), Some(_)) | (None, None) => None,
+        (Some(a), None) | (None, Some(a)) => Some(a),
+    };
+    match affinity {
+        Some(a) if a.is_numeric() => Some(SqlType::Numeric),
+        Some(SqlType::Text) => Some(SqlType::Text),
+        _ => None,
+    }
+}
+
+/// The collation given by a `COLLATE` operator in the expression, the leftmost one.
+fn explicit_collation(expr: &Expr) -> Option<Collation> {
+    let mut collation = None;
+    expr.walk(&mut |e| match e {
+        _ if collation.is_some() => false,
+        Expr::Collate(_, name) => {
+            // The names were checked when planning.
+            collation = Some(Collation::from_name(name).unwrap_or_default());
+            false
+        }
+        _ => true,
+    });
+    collation
+}
+
+/// The collation of the column the expression is, a column preceded by unary `+` or a CAST is still one.
+fn column_collation(expr: &Expr, column: &impl Fn(&str, &str) -> Collation) -> Option<Collation> {
+    match expr {
+        Expr::Unary(UnaryOp::Plus, e) | Expr::Cast(e, _) => column_collation(e, column),
+        Expr::Column { table: Some(t), name } => Some(column(t, name)),
+        _ => None,
+    }
+}
+
+/// The collation of an expression, the one of its `COLLATE` operator or else of the column it is. `column` gives the
+/// collation of a qualified column.
+pub fn collation(expr: &Expr, column: impl Fn(&str, &str) -> Collation) -> Collation {
+    explicit_collation(expr)
+        .or_else(|| column_collation(expr, &column))
+        .unwrap_or_default()
+}
+
+/// The collation a comparison uses, see <https://sqlite.org/datatype3.html#collation>: an explicit `COLLATE` of
+/// either operand, then the one of a column operand, the left operand going first each time.
+pub fn comparison_collation(l: &Expr, r: &Expr, column: impl Fn(&str, &str) -> Collation) -> Collation {
+    explicit_collation(l)
+        .or_else(|| explicit_collation(r))
+        .or_else(|| column_collation(l, &column))
+        .or_else(|| column_collation(r, &column))
+        .unwrap_or_default()
+}
+
+/// Compares two operands after applying the affinity conversions SQLite performs before a comparison, see
+/// <https://sqlite.org/datatype3.html#type_conversions_prior_to_comparison>. Comparisons involving NULL are unknown.
+fn compare(l: &Operand, r: &Operand, collation: Collation) -> Option<Ordering> {
+    let ((lv, la), (rv, ra)) = (l, r);
+    if matches!(lv, Value::Null) || matches!(rv, Value::Null) {
+        return None;
+    }
+    let Some(affinity) = comparison_affinity(*la, *ra) else {
+        return Some(collation.compare(lv, rv));
+    };
+    Some(collation.compare(&lv.clone().with_affinity(affinity), &rv.clone().with_affinity(affinity)))
+}
+
+fn unary<'a>(op: UnaryOp, v: Value<'a>) -> Value<'a> {
+    match op {
+        UnaryOp::Plus => v,
+        UnaryOp::Not => bool_value(truth(&v).map(|b| !b)),
+        UnaryOp::Neg => match v.to_number() {
+            Value::Int(i) => i.checked_neg().map_or(Value::Float(-(i as f64)), Value::Int),
+            Value::Float(f) => Value::Float(-f),
+            _ => Value::Null,
+        },
+        UnaryOp::BitNot => match v.to_number() {
+            Value::Null => Value::Null,
+            n => Value::Int(!as_int(&n)),
+        },
+    }
+}
+
+fn as_int(v: &Value) -> i64 {
+    match v {
+        Value::Int(i) => *i,
+        Value::Float(f) => *f as i64,
+        _ => 0,
+    }
+}
+
+fn as_float(v: &Value) -> f64 {
+    match v {
+        Value::Int(i) => *i as f64,
+        Value::Float(f) => *f,
+        _ => 0.0,
+    }
+}
+
+fn shift_left(value: i64, amount: i64) -> i64 {
+    match amount {
+        64.. => 0,
+        0..64 => value << amount,
+        -63..0 => value >> -amount,
+        _ if value < 0 => -1,
+        _ => 0,
+    }
+}
+
+fn arithmetic<'a>(op: BinaryOp, l: Value<'a>, r: Value<'a>) -> Value<'a> {
+    use BinaryOp::*;
+    use Value::*;
+    if op == Concat {
+        return match (&l, &r) {
+            (Null, _) | (_, Null) => Null,
+            _ => String(format!("{l}{r}").into()),
+        };
+    }
+    let (l, r) = (l.to_number(), r.to_number());
+    if matches!(l, Null) || matches!(r, Null) {
+        return Null;
+    }
+    match op {
+        BitAnd => Int(as_int(&l) & as_int(&r)),
+        BitOr => Int(as_int(&l) | as_int(&r)),
+        ShiftLeft => Int(shift_left(as_int(&l), as_int(&r))),
+        ShiftRight => Int(shift_left(as_int(&l), as_int(&r).saturating_neg())),
+        // The remainder is computed on integers, but stays a real if either operand was one.
+        Rem => match as_int(&r) {
+            0 => Null,
+            -1 => Int(0),
+            b if matches!((&l, &r), (Int(_), Int(_))) => Int(as_int(&l) % b),
+            b => Float((as_int(&l) % b) as f64),
+        },
+        _ => match (l, r) {
+            (Int(a), Int(b)) => {
+                let (checked, approx) = match op {
+                    Add => (a.checked_add(b), a as f64 + b as f64),
+                    Sub => (a.checked_sub(b), a as f64 - b as f64),
+                    Mul => (a.checked_mul(b), a as f64 * b as f64),
+                    _ if b == 0 => return Null,
+                    _ => (a.checked_div(b), a as f64 / b as f64),
+                };
+                checked.map_or(Float(approx), Int)
+            }
+            (l, r) => {
+                let (a, b) = (as_float(&l), as_float(&r));
+                match op {
+                    Add => Float(a + b),
+                    Sub => Float(a - b),
+                    Mul => Float(a * b),
+                    _ if b == 0.0 => Null,
+                    _ => Float(a / b),
+                }
+            }
+        },
+    }
+}
+
+/// `LIKE` matching, case insensitive for ASCII characters.
+pub fn like_match(pattern: &[char], text: &[char], escape: Option<char>) -> bool {
+    let Some((&c, rest)) = pattern.split_first() else {
+        return text.is_empty();
+    };
+    let next_matches = |c: char, rest| {
+        text.first().is_some_and(|t| t.eq_ignore_ascii_case(&c)) && like_match(rest, &text[1..], escape)
+    };
+    if Some(c) == escape {
+        return rest.split_first().is_some_and(|(&c, rest)| next_matches(c, rest));
+    }
+    match c {
+        '%' => (0..=text.len()).any(|i| like_match(rest, &text[i..], escape)),
+        '_' => !text.is_empty() && like_match(rest, &text[1..], escape),
+        c => next_matches(c, rest),
+    }
+}
+
+/// `GLOB` matching, case sensitive and using `*`, `?` and `[...]` like Unix file globs.
+pub fn glob_match(pattern: &[char], text: &[char]) -> bool {
+    let Some((&c, rest)) = pattern.split_first() else {
+        return text.is_empty();
+    };
+    match c {
+        '*' => (0..=text.len()).any(|i| glob_match(rest, &text[i..])),
+        '?' => !text.is_empty() && glob_match(rest, &text[1..]),
+        '[' => text.first().is_some_and(|&t| {
+            glob_class(rest, t).is_some_and(|(matched, rest)| matched && glob_match(rest, &text[1..]))
+        }),
+        c => text.first() == Some(&c) && glob_match(rest, &text[1..]),
+    }
+}
+
+/// Matches a character against a `[...]` class, given the pattern after the `[`. Returns whether it matched and the
+/// rest of the pattern, or `None` if the class is never closed.
+fn glob_class(pattern: &[char], c: char) -> Option<(bool, &[char])> {
+    let negate = pattern.first() == Some(&'^');
+    let mut i = negate as usize;
+    let mut matched = false;
+    let mut first = true;
+    loop {
+        let &pc = pattern.get(i)?;
+        if pc == ']' && !first {
+            break;
+        }
+        first = false;
+        match (pattern.get(i + 1), pattern.get(i + 2)) {
+            (Some('-'), Some(&end)) if end != ']' => {
+                matched |= (pc..=end).contains(&c);
+                i += 3;
+            }
+            _ => {
+                matched |= pc == c;
+                i += 1;
+            }
+        }
+    }
+    Some((matched != negate, &pattern[i + 1..]))
+}
@@ -0,0 +1,1326 @@
+//! Scalar functions, computing a value from the values of their arguments for each row.
+//!
+//! They behave like SQLite's: most return NULL when an argument is NULL, the text functions work on the text form of
+//! numbers, and the math functions return NULL for arguments that aren't numbers or are outside of their domain.
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::f64::consts::PI;
+use std::hash::BuildHasher;
+use std::hash::Hasher;
+use std::hash::RandomState;
+use std::ops::BitOr;
+use std::ops::Range;
+use std::ops::RangeInclusive;
+use std::rc::Rc;
+use std::sync::LazyLock;
+
+use anyhow::Context;
+use anyhow::Result;
+use anyhow::bail;
+use parser::Expr;
+use parser::SqlType;
+use parser::Value;
+use parser::format_real;
+
+use crate::aggregate::AggregateConstructor;
+use crate::aggregate::find_aggregate;
+use crate::aggregate::is_aggregate;
+use crate::aggregate::is_aggregate_name;
+use crate::datetime;
+use crate::expr::glob_match;
+use crate::expr::like_match;
+use crate::expr::truth;
+use crate::json;
+use crate::window::find_window_function;
+
+/// Computes the result of a call from the values of its arguments.
+pub type ScalarFunction = fn(&[Value]) -> Result<Value<'static>>;
+
+/// Most arguments a call can have, SQLite's default limit.
+const MAX_ARGS: usize = 127;
+
+/// Longest text or blob the functions make, SQLite's default limit.
+const MAX_LENGTH: i64 = 1_000_000_000;
+
+/// Built-in scalar functions, with the numbers of arguments they take.
+const BUILTINS: &[(&str, RangeInclusive<usize>, ScalarFunction)] = &[
+    ("abs", 1..=1, abs),
+    ("acos", 1..=1, |args| Ok(math(args, f64::acos))),
+    ("acosh", 1..=1, |args| Ok(math(args, f64::acosh))),
+    ("asin", 1..=1, |args| Ok(math(args, f64::asin))),
+    ("asinh", 1..=1, |args| Ok(math(args, f64::asinh))),
+    ("atan", 1..=1, |args| Ok(math(args, f64::atan))),
+    ("atan2", 2..=2, |args| Ok(math2(args, f64::atan2))),
+    ("atanh", 1..=1, |args| Ok(math(args, f64::atanh))),
+    ("ceil", 1..=1, |args| Ok(integral(args, f64::ceil))),
+    ("ceiling", 1..=1, |args| Ok(integral(args, f64::ceil))),
+    ("char", 0..=MAX_ARGS, char),
+    ("coalesce", 2..=MAX_ARGS, coalesce),
+    ("concat", 1..=MAX_ARGS, |args| Ok(concat("", args))),
+    ("concat_ws", 2..=MAX_ARGS, |args| {
+        Ok(match &args[0] {
+            Value::Null => Value::Null,
+            separator => concat(&text(separator), &args[1..]),
+        })
+    }),
+    ("current_date", 0..=0, |_| datetime::date(&[])),
+    ("current_time", 0..=0, |_| datetime::time(&[])),
+    ("current_timestamp", 0..=0, |_| datetime::datetime(&[])),
+    ("cos", 1..=1, |args| Ok(math(args, f64::cos))),
+    ("cosh", 1..=1, |args| Ok(math(args, f64::cosh))),
+    ("date", 0..=MAX_ARGS, datetime::date),
+    ("datetime", 0..=MAX_ARGS, datetime::datetime),
+    ("degrees", 1..=1, |args| Ok(math(args, f64::to_degrees))),
+    ("exp", 1..=1, |args| Ok(math(args, f64::exp))),
+    ("floor", 1..=1, |args| Ok(integral(args, f64::floor))),
+    ("format", 1..=MAX_ARGS, printf),
+    ("glob", 2..=2, glob),
+    ("hex", 1..=1, hex),
+    ("ifnull", 2..=2, coalesce),
+    ("iif", 2..=3, |args| {
+        let chosen = match truth(&args[0]) {
+            Some(true) => args.get(1),
+            _ => args.get(2),
+        };
+        Ok(chosen.map_or(Value::Null, |v| v.clone().into_owned()))
+    }),
+    ("instr", 2..=2, instr),
+    ("json", 1..=1, |args| json::json(args, false)),
+    ("json_array", 0..=MAX_ARGS, |args| json::array(args, false)),
+    ("json_array_length", 1..=2, json::array_length),
+    ("json_extract", 1..=MAX_ARGS, |args| json::extract(args, false)),
+    ("json_insert", 1..=MAX_ARGS, |args| json::insert(args, false)),
+    ("json_object", 0..=MAX_ARGS, |args| json::object(args, false)),
+    ("json_quote", 1..=1, json::quote),
+    ("json_remove", 1..=MAX_ARGS, |args| json::remove(args, false)),
+    ("json_replace", 1..=MAX_ARGS, |args| json::replace(args, false)),
+    ("json_set", 1..=MAX_ARGS, |args| json::set(args, false)),
+    ("json_type", 1..=2, json::type_name),
+    ("json_valid", 1..=2, json::valid),
+    ("jsonb", 1..=1, |args| json::json(args, true)),
+    ("jsonb_array", 0..=MAX_ARGS, |args| json::array(args, true)),
+    ("jsonb_extract", 1..=MAX_ARGS, |args| json::extract(args, true)),
+    ("jsonb_insert", 1..=MAX_ARGS, |args| json::insert(args, true)),
+    ("jsonb_object", 0..=MAX_ARGS, |args| json::object(args, true)),
+    ("jsonb_remove", 1..=MAX_ARGS, |args| json::remove(args, true)),
+    ("jsonb_replace", 1..=MAX_ARGS, |args| json::replace(args, true)),
+    ("jsonb_set", 1..=MAX_ARGS, |args| json::set(args, true)),
+    ("julianday", 0..=MAX_ARGS, datetime::julianday),
+    ("length", 1..=1, length),
+    ("like", 2..=3, like),
+    ("likelihood", 2..=2, |args| Ok(args[0].clone().into_owned())),
+    ("likely", 1..=1, |args| Ok(args[0].clone().into_owned())),
+    ("ln", 1..=1, |args| Ok(math(args, |x| logarithm(x, f64::ln)))),
+    ("log", 1..=2, log),
+    ("log10", 1..=1, |args| Ok(math(args, |x| logarithm(x, f64::log10)))),
+    ("log2", 1..=1, |args| Ok(math(args, |x| logarithm(x, f64::log2)))),
+    ("lower", 1..=1, |args| Ok(map_text(args, |s| s.to_ascii_lowercase()))),
+    ("ltrim", 1..=2, |args| Ok(trim(args, true, false))),
+    ("max", 2..=MAX_ARGS, |args| Ok(min_max(args, true))),
+    ("min", 2..=MAX_ARGS, |args| Ok(min_max(args, false))),
+    ("mod", 2..=2, |args| Ok(math2(args, |x, y| x % y))),
+    ("nullif", 2..=2, |args| {
+        Ok(match args[0].sql_cmp(&args[1]).is_eq() {
+            true => Value::Null,
+            false => args[0].clone().into_owned(),
+        })
+    }),
+    ("octet_length", 1..=1, |args| {
+        Ok(match &args[0] {
+            Value::Null => Value::Null,
+            Value::Blob(b) => Value::Int(b.len() as i64),
+            v => Value::Int(text(v).len() as i64),
+        })
+    }),
+    ("pi", 0..=0, |_| Ok(Value::Float(PI))),
+    ("pow", 2..=2, |args| Ok(math2(args, f64::powf))),
+    ("power", 2..=2, |args| Ok(math2(args, f64::powf))),
+    ("printf", 1..=MAX_ARGS, printf),
+    ("quote", 1..=1, |args| Ok(Value::String(quote(&args[0]).into()))),
+    ("radians", 1..=1, |args| Ok(math(args, f64::to_radians))),
+    ("random", 0..=0, |_| Ok(Value::Int(random()))),
+    ("randomblob", 1..=1, |args| {
+        let len = blob_length(&args[0])?.max(1);
+        let bytes = std::iter::repeat_with(|| random().to_le_bytes()).flatten().take(len);
+        Ok(Value::Blob(bytes.collect::<Vec<_>>().into()))
+    }),
+    ("replace", 3..=3, replace),
+    ("round", 1..=2, round),
+    ("rtrim", 1..=2, |args| Ok(trim(args, false, true))),
+    ("sign", 1..=1, |args| {
+        Ok(match numeric(&args[0]) {
+            Some(Value::Int(i)) => Value::Int(i.signum()),
+            Some(Value::Float(f)) => Value::Int((f > 0.0) as i64 - (f < 0.0) as i64),
+            _ => Value::Null,
+        })
+    }),
+    ("sin", 1..=1, |args| Ok(math(args, f64::sin))),
+    ("sinh", 1..=1, |args| Ok(math(args, f64::sinh))),
+    ("sqrt", 1..=1, |args| Ok(math(args, f64::sqrt))),
+    ("strftime", 1..=MAX_ARGS, datetime::strftime),
+    ("substr", 2..=3, substr),
+    ("substring", 2..=3, substr),
+    ("tan", 1..=1, |args| Ok(math(args, f64::tan))),
+    ("tanh", 1..=1, |args| Ok(math(args, f64::tanh))),
+    ("time", 0..=MAX_ARGS, datetime::time),
+    ("timediff", 2..=2, datetime::timediff),
+    ("trim", 1..=2, |args| Ok(trim(args, true, true))),
+    ("trunc", 1..=1, |args| Ok(integral(args, f64::trunc))),
+    ("typeof", 1..=1, |args| {
+        Ok(Value::String(Cow::Borrowed(args[0].type_name())))
+    }),
+    ("unhex", 1..=2, unhex),
+    ("unicode", 1..=1, |args| {
+        Ok(match &args[0] {
+            Value::Null => Value::Null,
+            v => text(v).chars().next().map_or(Value::Null, |c| Value::Int(c as i64)),
+        })
+    }),
+    ("unixepoch", 0..=MAX_ARGS, datetime::unixepoch),
+    ("unlikely", 1..=1, |args| Ok(args[0].clone().into_owned())),
+    ("upper", 1..=1, |args| Ok(map_text(args, |s| s.to_ascii_uppercase()))),
+    ("zeroblob", 1..=1, |args| {
+        Ok(Value::Blob(vec![0; blob_length(&args[0])?].into()))
+    }),
+];
+
+/// Computes the rows of a table-valued function from the values of its arguments.
+pub type TableFunction = fn(&[Value]) -> Result<Vec<Vec<Value<'static>>>>;
+
+/// Built-in table-valued functions, with the most arguments they take and the names of their columns.
+const TABLE_FUNCTIONS: &[(&str, usize, &[&str], TableFunction)] = &[
+    ("json_each", 2, json::TABLE_COLUMNS, json::each),
+    ("json_tree", 2, json::TABLE_COLUMNS, json::tree),
+];
+
+/// The table-valued function with that name and the names of its columns, if there is one taking that many
+/// arguments.
+pub fn find_table_function(name: &str, args: usize) -> Result<Option<(TableFunction, &'static [&'static str])>> {
+    let Some((name, max, columns, function)) = TABLE_FUNCTIONS.iter().find(|(n, ..)| n.eq_ignore_ascii_case(name))
+    else {
+        return Ok(None);
+    };
+    if args > *max {
+        bail!("too many arguments on {name}() - max {max}");
+    }
+    Ok(Some((*function, *columns)))
+}
+
+/// Built-in functions whose result can change from one call to the next with the same arguments, the date and time
+/// ones because of `'now'`.
+const NONDETERMINISTIC: &[&str] = &[
+    "current_date",
+    "current_time",
+    "current_timestamp",
+    "date",
+    "datetime",
+    "julianday",
+    "random",
+    "randomblob",
+    "strftime",
+    "time",
+    "timediff",
+    "unixepoch",
+];
+
+/// Indexes of the built-in scalar functions in `BUILTINS`, by name and number of arguments.
+static BUILTIN_INDEX: LazyLock<HashMap<(String, usize), usize>> = LazyLock::new(|| {
+    let mut index = HashMap::new();
+    for (i, (name, arities, _)) in BUILTINS.iter().enumerate() {
+        for args in arities.clone() {
+            index.insert((name.to_string(), args), i);
+        }
+    }
+    index
+});
+
+/// Index of the built-in scalar function with that name taking that many arguments.
+fn builtin(name: &str, args: usize) -> Option<usize> {
+    BUILTIN_INDEX.get(&(name.to_ascii_lowercase(), args)).copied()
+}
+
+/// The built-in scalar function with that name taking that many arguments.
+pub fn find_function(name: &str, args: usize) -> Result<ScalarFunction> {
+    match builtin(name, args) {
+        Some(i) => Ok(BUILTINS[i].2),
+        None if is_aggregate_name(name) || BUILTINS.iter().any(|(n, _, _)| n.eq_ignore_ascii_case(name)) => {
+            bail!("wrong number of arguments to function {name}()")
+        }
+        None => bail!("no such function: {name}"),
+    }
+}
+
+/// Options of a function the application defines, which can be combined with `|`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FunctionFlags(u32);
+
+impl FunctionFlags {
+    pub const NONE: Self = Self(0);
+    /// The function always gives the same result for the same arguments, so a call whose arguments are constants is
+    /// computed once, when the statement is planned. It's SQLite's `SQLITE_DETERMINISTIC`.
+    pub const DETERMINISTIC: Self = Self(0x800);
+
+    pub fn contains(self, flags: Self) -> bool {
+        self.0 & flags.0 == flags.0
+    }
+}
+
+impl BitOr for FunctionFlags {
+    type Output = Self;
+
+    fn bitor(self, flags: Self) -> Self {
+        Self(self.0 | flags.0)
+    }
+}
+
+/// A scalar function, built-in or defined by the application.
+pub type Scalar = dyn Fn(&[Value]) -> Result<Value<'static>>;
+
+/// A scalar function the application defines.
+pub type UserFunction = Rc<Scalar>;
+
+/// The functions statements can call: the built-in ones and the ones the application defines, which take the place
+/// of the built-in ones with the same name and number of arguments.
+///
+/// Calls to scalar functions are bound to them when a statement is compiled, by an index that's the one of the
+/// function in `BUILTINS` for the built-in ones and comes after them for the defined ones.
+#[derive(Clone, Default)]
+pub struct Functions {
+    defined: Vec<Definition>,
+    /// Indexes of `defined` by lowercased name and number of arguments, `None` for the functions taking any number.
+    names: HashMap<(String, Option<usize>), usize>,
+}
+
+/// A function the application defines.
+#[derive(Clone)]
+struct Definition {
+    flags: FunctionFlags,
+    kind: DefinitionKind,
+}
+
+#[derive(Clone)]
+enum DefinitionKind {
+    Scalar(UserFunction),
+    Aggregate(AggregateConstructor),
+}
+
+impl Functions {
+    /// Defines a scalar function taking `args` arguments, any number of them when it's -1. It replaces the function
+    /// defined with the same name and number of arguments, if there is one.
+    pub fn define_scalar(&mut self, name: &str, args: i32, flags: FunctionFlags, function: UserFunction) -> Result<()> {
+        self.define(name, args, flags, DefinitionKind::Scalar(function))
+    }
+
+    /// Defines an aggregate function, `constructor` making the state of each group it's computed over.
+    pub fn define_aggregate(
+        &mut self,
+        name: &str,
+        args: i32,
+        flags: FunctionFlags,
+        constructor: AggregateConstructor,
+    ) -> Result<()> {
+        self.define(name, args, flags, DefinitionKind::Aggregate(constructor))
+    }
+
+    fn define(&mut self, name: &str, args: i32, flags: FunctionFlags, kind: DefinitionKind) -> Result<()> {
+        // The limits SQLite checks.
+        if name.is_empty() || name.len() > 255 || !(-1..=MAX_ARGS as i32).contains(&args) {
+            bail!("bad parameter or other API misuse");
+        }
+        let key = (name.to_ascii_lowercase(), usize::try_from(args).ok());
+        let definition = Definition { flags, kind };
+        match self.names.get(&key) {
+            Some(&i) => self.defined[i] = definition,
+            None => {
+                self.names.insert(key, self.defined.len());
+                self.defined.push(definition);
+            }
+        }
+        Ok(())
+    }
+
+    /// Index in `defined` of the function the application defined with that name for that many arguments,
+    /// preferably one taking exactly that many.
+    fn defined_index(&self, name: &str, args: usize) -> Option<usize> {
+        let name = name.to_ascii_lowercase();
+        match self.names.get(&(name.clone(), Some(args))) {
+            Some(&i) => Some(i),
+            None => self.names.get(&(name, None)).copied(),
+        }
+    }
+
+    fn defined(&self, name: &str, args: usize) -> Option<&Definition> {
+        self.defined_index(name, args).map(|i| &self.defined[i])
+    }
+
+    /// The scalar function a call with that many arguments is bound to, `None` when it's to an aggregate or to a
+    /// function that doesn't exist.
+    fn resolve(&self, name: &str, args: usize) -> Option<usize> {
+        match self.defined_index(name, args) {
+            Some(i) => matches!(self.defined[i].kind, DefinitionKind::Scalar(_)).then_some(BUILTINS.len() + i),
+            None => builtin(name, args),
+        }
+    }
+
+    /// Binds the calls to scalar functions of the expression to the functions they call, see [`Functions::get`].
+    /// The calls to the functions that don't exist are left for their error to be reported when they run.
+    pub fn bind(&self, expr: &mut Expr) {
+        expr.walk_mut(&mut |e| {
+            if let Expr::Function {
+                name, args, function, ..
+            } = e
+            {
+                *function = self.resolve(name, args.len());
+            }
+            true
+        });
+    }
+
+    /// The scalar function a call is bound to.
+    pub fn get(&self, function: usize) -> &Scalar {
+        match function.checked_sub(BUILTINS.len()) {
+            Some(i) => match &self.defined[i].kind {
+                DefinitionKind::Scalar(function) => &**function,
+                DefinitionKind::Aggregate(_) => unreachable!("calls are bound to scalar functions"),
+            },
+            None => &BUILTINS[function].2,
+        }
+    }
+
+    /// Whether a call to the function with that many arguments is an aggregate.
+    pub fn is_aggregate(&self, name: &str, args: usize) -> bool {
+        match self.defined(name, args) {
+            Some(d) => matches!(d.kind, DefinitionKind::Aggregate(_)),
+            None => is_aggregate(name, args),
+        }
+    }
+
+    /// The scalar function with that name taking that many arguments.
+    pub fn scalar(&self, name: &str, args: usize) -> Result<&Scalar> {
+        if let Some(function) = self.resolve(name, args) {
+            return Ok(self.get(function));
+        }
+        let lowercase = name.to_ascii_lowercase();
+        match self.defined_index(name, args) {
+            Some(_) => bail!("misuse of aggregate function {name}()"),
+            None if self.names.keys().any(|(n, _)| *n == lowercase) => {
+                bail!("wrong number of arguments to function {name}()")
+            }
+            None => Err(find_function(name, args).unwrap_err()),
+        }
+    }
+
+    /// The aggregate function with that name taking that many arguments.
+    pub fn aggregate(&self, name: &str, args: usize) -> Option<AggregateConstructor> {
+        match self.defined(name, args) {
+            Some(d) => match &d.kind {
+                DefinitionKind::Aggregate(constructor) => Some(constructor.clone()),
+                DefinitionKind::Scalar(_) => None,
+            },
+            None => find_aggregate(name, args).map(|factory| Rc::new(factory) as AggregateConstructor),
+        }
+    }
+
+    /// Whether calls to the function with the same arguments always give the same result.
+    pub fn is_deterministic(&self, name: &str, args: usize) -> bool {
+        match self.defined(name, args) {
+            Some(d) => d.flags.contains(FunctionFlags::DETERMINISTIC),
+            None => !NONDETERMINISTIC.iter().any(|n| n.eq_ignore_ascii_case(name)),
+        }
+    }
+
+    /// Checks that a call is to a function that exists, aggregate or not, with arguments it takes.
+    pub fn check_call(&self, name: &str, args: &[Expr]) -> Result<()> {
+        if self.is_aggregate(name, args.len()) {
+            return Ok(());
+        }
+        if self.defined(name, args.len()).is_none() && !matches!(find_window_function(name, args.len()), Ok(None)) {
+            bail!("misuse of window function {name}()");
+        }
+        self.scalar(name, args.len())?;
+        // The planner is told how likely a condition is with a constant.
+        if name.eq_ignore_ascii_case("likelihood")
+            && self.defined(name, args.len()).is_none()
+            && !matches!(&args[1], Expr::Literal(v @ (Value::Int(_) | Value::Float(_))) if (0.0..=1.0).contains(&real(v)))
+        {
+            bail!("second argument to likelihood() must be a constant between 0.0 and 1.0");
+        }
+        Ok(())
+    }
+
+    /// Checks that a call with an OVER clause is to a window function or an aggregate, with arguments it takes.
+    pub fn check_window_call(&self, name: &str, args: &[Expr]) -> Result<()> {
+        if self.is_aggregate(name, args.len()) || find_window_function(name, args.len())?.is_some() {
+            return Ok(());
+        }
+        self.scalar(name, args.len())?;
+        bail!("{name}() may not be used as a window function")
+    }
+
+    /// The value of a call to a deterministic scalar function whose arguments are constants, `None` for other calls
+    /// and for ones failing, whose error is left for when the statement runs.
+    pub fn constant_call(&self, name: &str, args: &[Expr]) -> Option<Value<'static>> {
+        if self.is_aggregate(name, args.len()) || !self.is_deterministic(name, args.len()) {
+            return None;
+        }
+        let args = args
+            .iter()
+            .map(|arg| match arg {
+                Expr::Literal(v) => Some(v.clone()),
+                _ => None,
+            })
+            .collect::<Option<Vec<_>>>()?;
+        self.scalar(name, args.len()).ok()?(&args).ok()
+    }
+}
+
+fn has_null(args: &[Value]) -> bool {
+    args.iter().any(|v| matches!(v, Value::Null))
+}
+
+/// The text form of a value, blobs being read as UTF-8.
+fn text<'v>(v: &'v Value) -> Cow<'v, str> {
+    match v {
+        Value::String(s) => Cow::Borrowed(s),
+        Value::Blob(b) => String::from_utf8_lossy(b),
+        v => Cow::Owned(v.to_string()),
+    }
+}
+
+/// The value as an integer, text and blobs using their longest numeric prefix.
+fn integer(v: &Value) -> i64 {
+    match v.to_number() {
+        Value::Int(i) => i,
+        Value::Float(f) => f as i64,
+        _ => 0,
+    }
+}
+
+/// The value as a float, text and blobs using their longest numeric prefix.
+fn real(v: &Value) -> f64 {
+    match v.to_number() {
+        Value::Int(i) => i as f64,
+        Value::Float(f) => f,
+        _ => 0.0,
+    }
+}
+
+/// The value as a number for the math functions, none when it isn't one or is text that doesn't look like one.
+fn numeric(v: &Value) -> Option<Value<'static>> {
+    match v {
+        Value::Int(_) | Value::Float(_) => Some(v.clone().into_owned()),
+        Value::String(_) => match v.clone().with_affinity(SqlType::Numeric) {
+            n @ (Value::Int(_) | Value::Float(_)) => Some(n.into_owned()),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// The result of a math function, NULL when it's not a number.
+fn real_value(f: f64) -> Value<'static> {
+    match f.is_nan() {
+        true => Value::Null,
+        false => Value::Float(f),
+    }
+}
+
+fn math(args: &[Value], f: fn(f64) -> f64) -> Value<'static> {
+    numeric(&args[0]).map_or(Value::Null, |x| real_value(f(real(&x))))
+}
+
+fn math2(args: &[Value], f: fn(f64, f64) -> f64) -> Value<'static> {
+    match (numeric(&args[0]), numeric(&args[1])) {
+        (Some(x), Some(y)) => real_value(f(real(&x), real(&y))),
+        _ => Value::Null,
+    }
+}
+
+/// `ceil()`, `floor()` and `trunc()`, which leave integers as they are.
+fn integral(args: &[Value], f: fn(f64) -> f64) -> Value<'static> {
+    match numeric(&args[0]) {
+        Some(Value::Float(x)) => Value::Float(f(x)),
+        Some(i) => i,
+        None => Value::Null,
+    }
+}
+
+/// Logarithms are only defined for positive numbers.
+fn logarithm(x: f64, f: fn(f64) -> f64) -> f64 {
+    match x > 0.0 {
+        true => f(x),
+        false => f64::NAN,
+    }
+}
+
+/// `log(x)` is the base 10 logarithm, `log(b, x)` the base `b` one.
+fn log(args: &[Value]) -> Result<Value<'static>> {
+    Ok(match args {
+        [_] => math(args, |x| logarithm(x, f64::log10)),
+        _ => math2(args, |b, x| match b > 0.0 && b != 1.0 {
+            true => logarithm(x, f64::ln) / b.ln(),
+            false => f64::NAN,
+        }),
+    })
+}
+
+fn abs(args: &[Value]) -> Result<Value<'static>> {
+    Ok(match &args[0] {
+        Value::Null => Value::Null,
+        Value::Int(i) => Value::Int(i.checked_abs().context("integer overflow")?),
+        v => Value::Float(real(v).abs()),
+    })
+}
+
+/// `round(x, n)`, rounding half away from zero to `n` decimal places.
+fn round(args: &[Value]) -> Result<Value<'static>> {
+    if has_null(args) {
+        return Ok(Value::Null);
+    }
+    let places = args.get(1).map_or(0, integer).clamp(0, 30) as usize;
+    let x = real(&args[0]);
+    // Floats that large have no fractional part.
+    if x.abs() >= 4503599627370496.0 {
+        return Ok(Value::Float(x));
+    }
+    if places == 0 {
+        return Ok(Value::Float((x + 0.5f64.copysign(x)).trunc()));
+    }
+    let digits = fixed(x.abs(), places, ROUND_DIGITS, false);
+    Ok(Value::Float(digits.parse::<f64>()?.copysign(x)))
+}
+
+fn char(args: &[Value]) -> Result<Value<'static>> {
+    let chars = args.iter().map(|v| {
+        u32::try_from(integer(v))
+            .ok()
+            .and_then(char::from_u32)
+            .unwrap_or(char::REPLACEMENT_CHARACTER)
+    });
+    Ok(Value::String(chars.collect::<String>().into()))
+}
+
+fn coalesce(args: &[Value]) -> Result<Value<'static>> {
+    let value = args.iter().find(|v| !matches!(v, Value::Null));
+    Ok(value.map_or(Value::Null, |v| v.clone().into_owned()))
+}
+
+/// The text of the arguments that aren't NULL, joined by `separator`.
+fn concat(separator: &str, args: &[Value]) -> Value<'static> {
+    let parts: Vec<_> = args.iter().filter(|v| !matches!(v, Value::Null)).map(text).collect();
+    Value::String(parts.join(separator).into())
+}
+
+/// Applies `f` to the text of the argument.
+fn map_text(args: &[Value], f: fn(&str) -> String) -> Value<'static> {
+    match &args[0] {
+        Value::Null => Value::Null,
+        v => Value::String(f(&text(v)).into()),
+    }
+}
+
+/// Number of characters of text up to the first NUL one, bytes of blobs, and characters of the text form of numbers.
+fn length(args: &[Value]) -> Result<Value<'static>> {
+    Ok(match &args[0] {
+        Value::Null => Value::Null,
+        Value::Blob(b) => Value::Int(b.len() as i64),
+        v => Value::Int(text(v).chars().take_while(|&c| c != '\0').count() as i64),
+    })
+}
+
+/// `substr(x, start, length)`, the characters of text or the bytes of a blob from the 1-based `start`, counting from
+/// the end when it's negative. A negative `length` takes the characters before `start` instead.
+fn substr(args: &[Value]) -> Result<Value<'static>> {
+    if has_null(args) {
+        return Ok(Value::Null);
+    }
+    let start = integer(&args[1]);
+    let length = args.get(2).map(integer);
+    Ok(match &args[0] {
+        Value::Blob(b) => Value::Blob(b[substr_range(b.len(), start, length)].to_vec().into()),
+        v => {
+            let chars: Vec<char> = text(v).chars().collect();
+            Value::String(
+                chars[substr_range(chars.len(), start, length)]
+                    .iter()
+                    .collect::<String>()
+                    .into(),
+            )
+        }
+    })
+}
+
+fn substr_range(len: usize, start: i64, length: Option<i64>) -> Range<usize> {
+    let len = len as i64;
+    let (mut p1, mut p2) = (start, length.map_or(MAX_LENGTH, i64::saturating_abs));
+    if p1 < 0 {
+        p1 = p1.saturating_add(len);
+        if p1 < 0 {
+            p2 = p2.saturating_add(p1).max(0);
+            p1 = 0;
+        }
+    } else if p1 > 0 {
+        p1 -= 1;
+    } else if p2 > 0 {
+        p2 -= 1;
+    }
+    if length.is_some_and(|l| l < 0) {
+        p1 = p1.saturating_sub(p2);
+        if p1 < 0 {
+            p2 += p1;
+            p1 = 0;
+        }
+    }
+    let start = p1.min(len);
+    start as usize..p1.saturating_add(p2).clamp(start, len) as usize
+}
+
+/// Removes the characters of the second argument, spaces if there's none, from the ends of the text.
+fn trim(args: &[Value], start: bool, end: bool) -> Value<'static> {
+    if has_null(args) {
+        return Value::Null;
+    }
+    let s = text(&args[0]);
+    let chars: Vec<char> = args.get(1).map_or(vec![' '], |c| text(c).chars().collect());
+    let mut trimmed: &str = &s;
+    if start {
+        trimmed = trimmed.trim_start_matches(chars.as_slice());
+    }
+    if end {
+        trimmed = trimmed.trim_end_matches(chars.as_slice());
+    }
+    Value::String(trimmed.to_string().into())
+}
+
+fn replace(args: &[Value]) -> Result<Value<'static>> {
+    if has_null(args) {
+        return Ok(Value::Null);
+    }
+    let (s, from) = (text(&args[0]), text(&args[1]));
+    Ok(Value::String(match from.is_empty() {
+        true => s.into_owned().into(),
+        false => s.replace(&*from, &text(&args[2])).into(),
+    }))
+}
+
+/// The 1-based position of the first occurrence of the second argument in the first one, 0 if there's none. It counts
+/// bytes when both are blobs, characters otherwise.
+fn instr(args: &[Value]) -> Result<Value<'static>> {
+    if has_null(args) {
+        return Ok(Value::Null);
+    }
+    let position = match (&args[0], &args[1]) {
+        (_, Value::Blob(needle)) if needle.is_empty() => Some(0),
+        (Value::Blob(haystack), Value::Blob(needle)) => haystack.windows(needle.len()).position(|w| w == &needle[..]),
+        (haystack, needle) => {
+            let haystack = text(haystack);
+            haystack.find(&*text(needle)).map(|i| haystack[..i].chars().count())
+        }
+    };
+    Ok(Value::Int(position.map_or(0, |p| p as i64 + 1)))
+}
+
+/// `like(pattern, text, escape)`, the function form of `text LIKE pattern ESCAPE escape`.
+fn like(args: &[Value]) -> Result<Value<'static>> {
+    if has_null(args) {
+        return Ok(Value::Null);
+    }
+    let escape = match args.get(2).map(|e| text(e).chars().collect::<Vec<_>>()) {
+        Some(e) if e.len() != 1 => bail!("ESCAPE expression must be a single character"),
+        e => e.map(|e| e[0]),
+    };
+    let pattern: Vec<char> = text(&args[0]).chars().collect();
+    let text: Vec<char> = text(&args[1]).chars().collect();
+    Ok(Value::Int(like_match(&pattern, &text, escape) as i64))
+}
+
+/// `glob(pattern, text)`, the function form of `text GLOB pattern`.
+fn glob(args: &[Value]) -> Result<Value<'static>> {
+    if has_null(args) {
+        return Ok(Value::Null);
+    }
+    let pattern: Vec<char> = text(&args[0]).chars().collect();
+    let text: Vec<char> = text(&args[1]).chars().collect();
+    Ok(Value::Int(glob_match(&pattern, &text) as i64))
+}
+
+/// The smallest or largest argument, NULL if one of them is. Like SQLite, ties go to the first argument for `max()` and
+/// to the last one for `min()`.
+fn min_max(args: &[Value], max: bool) -> Value<'static> {
+    if has_null(args) {
+        return Value::Null;
+    }
+    let mut best = &args[0];
+    for v in &args[1..] {
+        let order = v.sql_cmp(best);
+        if (max && order.is_gt()) || (!max && order.is_le()) {
+            best = v;
+        }
+    }
+    best.clone().into_owned()
+}
+
+/// Upper case hexadecimal digits of the bytes of a blob or of the text form of any other value.
+fn hex(args: &[Value]) -> Result<Value<'static>> {
+    let bytes = match &args[0] {
+        Value::Null => &[][..],
+        Value::Blob(b) => b,
+        Value::String(s) => s.as_bytes(),
+        v => return hex(&[Value::String(v.to_string().into())]),
+    };
+    let digits: String = bytes.iter().map(|b| format!("{b:02X}")).collect();
+    Ok(Value::String(digits.into()))
+}
+
+/// The blob whose bytes the hexadecimal digits of the text are, NULL if it has anything else than pairs of digits
+/// separated by the characters of the second argument.
+fn unhex(args: &[Value]) -> Result<Value<'static>> {
+    if has_null(args) {
+        return Ok(Value::Null);
+    }
+    let ignored: Vec<char> = args.get(1).map_or(vec![], |v| text(v).chars().collect());
+    let mut bytes = vec![];
+    let mut high = None;
+    for c in text(&args[0]).chars() {
+        match (c.to_digit(16), high.take()) {
+            (Some(low), Some(high)) => bytes.push((high * 16 + low) as u8),
+            (Some(digit), None) => high = Some(digit),
+            (None, None) if ignored.contains(&c) => {}
+            (None, _) => return Ok(Value::Null),
+        }
+    }
+    Ok(match high {
+        Some(_) => Value::Null,
+        None => Value::Blob(bytes.into()),
+    })
+}
+
+/// The value as an SQL literal.
+fn quote(v: &Value) -> String {
+    match v {
+        Value::Null => "NULL".to_string(),
+        Value::Int(i) => i.to_string(),
+        Value::Float(f) if f.is_infinite() => format!("{}9.0e+999", if *f < 0.0 { "-" } else { "" }),
+        Value::Float(f) => {
+            // The shorter form when it reads back as the same float.
+            let short = format_real(*f);
+            if short.parse::<f64>().ok() == Some(*f) {
+                return short;
+            }
+            let sign = if *f < 0.0 { "-" } else { "" };
+            let long = scientific(f.abs(), QUOTE_DIGITS - 1, ROUND_DIGITS, false);
+            let (mantissa, exponent) = long.split_once('e').unwrap();
+            let mantissa = mantissa.trim_end_matches('0');
+            let zero = if mantissa.ends_with('.') { "0" } else { "" };
+            format!("{sign}{mantissa}{zero}e{exponent}")
+        }
+        Value::String(s) => format!("'{}'", s.replace('\'', "''")),
+        Value::Blob(b) => format!("X'{}'", b.iter().map(|b| format!("{b:02X}")).collect::<String>()),
+    }
+}
+
+fn random() -> i64 {
+    // Each hasher is seeded with different keys.
+    RandomState::new().build_hasher().finish() as i64
+}
+
+/// The length of a blob to make.
+fn blob_length(v: &Value) -> Result<usize> {
+    let len = integer(v).max(0);
+    if len > MAX_LENGTH {
+        bail!("string or blob too big");
+    }
+    Ok(len as usize)
+}
+
+/// Significant digits `printf()` rounds floats to, unless the `!` flag asks for more.
+const PRINTF_DIGITS: usize = 16;
+/// Significant digits `round()` and `printf()` with the `!` flag round floats to.
+const ROUND_DIGITS: usize = 26;
+/// Significant digits `quote()` writes floats that don't read back the same with fewer.
+const QUOTE_DIGITS: usize = 19;
+
+/// The decimal digits of a positive float, which is `0.d1d2d3... * 10^point`.
+struct Decimal {
+    digits: Vec<u8>,
+    point: i32,
+}
+
+impl Decimal {
+    fn new(x: f64) -> Self {
+        // Rust writes the exact value of the float, to more digits than any rounding needs.
+        let exact = format!("{x:.40e}");
+        let (mantissa, exponent) = exact.split_once('e').unwrap();
+        Self {
+            digits: mantissa.bytes().filter(u8::is_ascii_digit).map(|d| d - b'0').collect(),
+            point: exponent.parse::<i32>().unwrap() + 1,
+        }
+    }
+
+    /// Rounds half up to the first `count` digits.
+    fn round(&mut self, count: i32) {
+        let Ok(count) = usize::try_from(count) else {
+            self.digits.clear();
+            return;
+        };
+        if count >= self.digits.len() {
+            return;
+        }
+        let up = self.digits[count] >= 5;
+        self.digits.truncate(count);
+        if up {
+            match self.digits.iter().rposition(|&d| d != 9) {
+                Some(i) => {
+                    self.digits[i] += 1;
+                    self.digits[i + 1..].fill(0);
+                }
+                None => {
+                    self.digits.fill(0);
+                    self.digits.insert(0, 1);
+                    self.point += 1;
+                }
+            }
+        }
+    }
+
+    /// The digit at a position, counted from the first digit.
+    fn digit(&self, i: i32) -> char {
+        let digit = usize::try_from(i).ok().and_then(|i| self.digits.get(i)).copied();
+        (b'0' + digit.unwrap_or(0)) as char
+    }
+
+    /// The digits at the positions of the range.
+    fn digits(&self, range: Range<i32>) -> String {
+        range.map(|i| self.digit(i)).collect()
+    }
+}
+
+/// A positive float with `precision` decimal places, rounded to at most `max_digits` significant digits. The decimal
+/// point is left out when there are no decimal places, unless `point` is set.
+fn fixed(x: f64, precision: usize, max_digits: usize, point: bool) -> String {
+    let mut decimal = Decimal::new(x);
+    decimal.round((decimal.point + precision as i32).min(max_digits as i32));
+    let mut s = match decimal.point > 0 {
+        true => decimal.digits(0..decimal.point),
+        false => "0".to_string(),
+    };
+    if precision > 0 || point {
+        s.push('.');
+    }
+    s + &decimal.digits(decimal.point..decimal.point + precision as i32)
+}
+
+/// A positive float in scientific notation with `precision` digits after the decimal point.
+fn scientific(x: f64, precision: usize, max_digits: usize, point: bool) -> String {
+    let mut decimal = Decimal::new(x);
+    decimal.round((precision as i32 + 1).min(max_digits as i32));
+    let exponent = if x == 0.0 { 0 } else { decimal.point - 1 };
+    let mut s = decimal.digits(0..1);
+    if precision > 0 || point {
+        s.push('.');
+    }
+    let sign = if exponent < 0 { '-' } else { '+' };
+    format!(
+        "{s}{}e{sign}{:02}",
+        decimal.digits(1..precision as i32 + 1),
+        exponent.abs()
+    )
+}
+
+/// A positive float with `precision` significant digits, in scientific notation when its exponent is below -4 or not
+/// below the precision, and without trailing zeros unless `alternate` is set.
+pub fn general(x: f64, precision: usize, max_digits: usize, alternate: bool) -> String {
+    let precision = precision.max(1);
+    let mut decimal = Decimal::new(x);
+    decimal.round((precision as i32).min(max_digits as i32));
+    let exponent = if x == 0.0 { 0 } else { decimal.point - 1 };
+    let s = match exponent < -4 || exponent >= precision as i32 {
+        true => scientific(x, precision - 1, max_digits, alternate),
+        false => fixed(x, (precision as i32 - 1 - exponent) as usize, max_digits, alternate),
+    };
+    if alternate {
+        return s;
+    }
+    let (mantissa, exponent) = s.split_once('e').map_or((&*s, None), |(m, e)| (m, Some(e)));
+    let mantissa = match mantissa.contains('.') {
+        true => mantissa.trim_end_matches('0').trim_end_matches('.'),
+        false => mantissa,
+    };
+    match exponent {
+        Some(exponent) => format!("{mantissa}e{exponent}"),
+        None => mantissa.to_string(),
+    }
+}
+
+/// A conversion of a `printf()` format.
+#[derive(Default)]
+struct Spec {
+    left: bool,
+    plus: bool,
+    space: bool,
+    zero: bool,
+    alternate: bool,
+    thousands: bool,
+    /// The `!` flag, more digits for floats.
+    exact: bool,
+    width: usize,
+    precision: Option<usize>,
+}
+
+/// `printf(format, ...)`, formatting the arguments like C's `printf()`. It has SQLite's `%q`, `%Q` and `%w`
+/// conversions, which quote text for SQL, and its `,` flag, which groups the digits of integers by thousands.
+fn printf(args: &[Value]) -> Result<Value<'static>> {
+    if matches!(args[0], Value::Null) {
+        return Ok(Value::Null);
+    }
+    let format = text(&args[0]);
+    let mut values = args[1..].iter();
+    let mut next = || values.next().cloned().unwrap_or(Value::Null);
+    let mut out = String::new();
+    let mut chars = format.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            out.push(c);
+            continue;
+        }
+        let mut spec = Spec::default();
+        while let Some(flag) = chars.next_if(|c| "-+ 0#,!".contains(*c)) {
+            match flag {
+                '-' => spec.left = true,
+                '+' => spec.plus = true,
+                ' ' => spec.space = true,
+                '0' => spec.zero = true,
+                '#' => spec.alternate = true,
+                ',' => spec.thousands = true,
+                _ => spec.exact = true,
+            }
+        }
+        let number = |chars: &mut std::iter::Peekable<std::str::Chars>| {
+            let mut n = 0i64;
+            while let Some(d) = chars.next_if(char::is_ascii_digit) {
+                n = n.saturating_mul(10).saturating_add(d as i64 - '0' as i64);
+            }
+            n
+        };
+        let width = match chars.next_if_eq(&'*') {
+            Some(_) => integer(&next()),
+            None => number(&mut chars),
+        };
+        spec.left |= width < 0;
+        spec.width = width.unsigned_abs() as usize;
+        if chars.next_if_eq(&'.').is_some() {
+            let precision = match chars.next_if_eq(&'*') {
+                Some(_) => integer(&next()),
+                None => number(&mut chars),
+            };
+            spec.precision = usize::try_from(precision).ok();
+        }
+        if spec.width as i64 > MAX_LENGTH || spec.precision.is_some_and(|p| p as i64 > MAX_LENGTH) {
+            bail!("string or blob too big");
+        }
+        while chars.next_if(|c| *c == 'l').is_some() {}
+        // Like SQLite, an unknown conversion ends the output.
+        let Some(conversion) = chars.next() else { break };
+        let (prefix, body, numeric) = match conversion {
+            'd' | 'i' | 'u' => {
+                let v = integer(&next());
+                let magnitude = match conversion {
+                    'u' => v as u64,
+                    _ => v.unsigned_abs(),
+                };
+                let mut digits = magnitude.to_string();
+                if let Some(precision) = spec.precision {
+                    digits = format!("{digits:0>precision$}");
+                }
+                if spec.thousands {
+                    digits = group_thousands(&digits);
+                }
+                (sign(v < 0 && conversion != 'u', &spec), digits, true)
+            }
+            'x' | 'X' | 'o' => {
+                let v = integer(&next()) as u64;
+                let mut digits = match conversion {
+                    'x' => format!("{v:x}"),
+                    'X' => format!("{v:X}"),
+                    _ => format!("{v:o}"),
+                };
+                if let Some(precision) = spec.precision {
+                    digits = format!("{digits:0>precision$}");
+                }
+                let prefix = match conversion {
+                    _ if !spec.alternate || v == 0 => "",
+                    'x' => "0x",
+                    'X' => "0X",
+                    _ if digits.starts_with('0') => "",
+                    _ => "0",
+                };
+                (prefix.to_string(), digits, true)
+            }
+            'f' | 'e' | 'E' | 'g' | 'G' => {
+                let x = real(&next());
+                let sign = sign(x < 0.0, &spec);
+                if !x.is_finite() {
+                    let body = if x.is_nan() { "NaN" } else { "Inf" };
+                    (sign, body.to_string(), false)
+                } else {
+                    let precision = spec.precision.unwrap_or(6);
+                    let digits = if spec.exact { ROUND_DIGITS } else { PRINTF_DIGITS };
+                    let body = match conversion {
+                        'f' => fixed(x.abs(), precision, digits, spec.alternate),
+                        'e' | 'E' => scientific(x.abs(), precision, digits, spec.alternate),
+                        _ => general(x.abs(), precision, digits, spec.alternate),
+                    };
+                    let body = match conversion.is_ascii_uppercase() {
+                        true => body.to_ascii_uppercase(),
+                        false => body,
+                    };
+                    (sign, body, true)
+                }
+            }
+            's' | 'z' | 'q' | 'Q' | 'w' => {
+                let v = next();
+                let s: String = match &v {
+                    Value::Null => String::new(),
+                    v => text(v).chars().take(spec.precision.unwrap_or(usize::MAX)).collect(),
+                };
+                let body = match (conversion, &v) {
+                    ('q', Value::Null) => "(NULL)".to_string(),
+                    ('Q', Value::Null) => "NULL".to_string(),
+                    ('q', _) => s.replace('\'', "''"),
+                    ('Q', _) => format!("'{}'", s.replace('\'', "''")),
+                    ('w', _) => s.replace('"', "\"\""),
+                    _ => s,
+                };
+                (String::new(), body, false)
+            }
+            'c' => (String::new(), text(&next()).chars().take(1).collect(), false),
+            '%' => (String::new(), "%".to_string(), false),
+            _ => break,
+        };
+        let len = prefix.chars().count() + body.chars().count();
+        let padding = spec.width.saturating_sub(len);
+        if spec.left {
+            out += &format!("{prefix}{body}{}", " ".repeat(padding));
+        } else if spec.zero && numeric {
+            out += &format!("{prefix}{}{body}", "0".repeat(padding));
+        } else {
+            out += &format!("{}{prefix}{body}", " ".repeat(padding));
+        }
+    }
+    Ok(Value::String(out.into()))
+}
+
+/// What a number starts with, depending on its sign and on the `+` and ` ` flags.
+fn sign(negative: bool, spec: &Spec) -> String {
+    let sign = match () {
+        _ if negative => "-",
+        _ if spec.plus => "+",
+        _ if spec.space => " ",
+        _ => "",
+    };
+    sign.to_string()
+}
+
+/// Separates the digits by thousands with commas.
+fn group_thousands(digits: &str) -> String {
+    let mut grouped = String::new();
+    for (i, c) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i).is_multiple_of(3) {
+            grouped.push(',');
+        }
+        grouped.push(c);
+    }
+    grouped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(s: &str) -> Value<'static> {
+        Value::String(s.to_string().into())
+    }
+
+    /// The result of a call, as the text sqlite3 prints for it and its type.
+    fn call(name: &str, args: &[Value]) -> (String, &'static str) {
+        let result = find_function(name, args.len()).unwrap()(args).unwrap();
+        (result.to_string(), result.type_name())
+    }
+
+    fn printf(format: &str, args: &[Value]) -> String {
+        call("printf", &[&[text(format)], args].concat()).0
+    }
+
+    #[test]
+    fn strings() {
+        assert_eq!(call("length", &[text("héllo")]), ("5".into(), "integer"));
+        assert_eq!(call("length", &[Value::Float(12.5)]), ("4".into(), "integer"));
+        assert_eq!(call("upper", &[text("äb")]), ("äB".into(), "text"));
+        assert_eq!(call("substr", &[text("hello"), Value::Int(-3), Value::Int(2)]).0, "ll");
+        assert_eq!(call("substr", &[text("hello"), Value::Int(0), Value::Int(2)]).0, "h");
+        assert_eq!(call("substr", &[text("hello"), Value::Int(2), Value::Int(-1)]).0, "h");
+        assert_eq!(call("substr", &[text("hello"), Value::Int(-7)]).0, "hello");
+        assert_eq!(
+            call("substr", &[Value::Int(12345), Value::Int(2)]),
+            ("2345".into(), "text")
+        );
+        assert_eq!(call("instr", &[text("héllo"), text("l")]).0, "3");
+        assert_eq!(call("replace", &[text("aaa"), text("a"), text("bb")]).0, "bbbbbb");
+        assert_eq!(call("ltrim", &[text("xxaxx"), text("x")]).0, "axx");
+        assert_eq!(call("trim", &[text("  x  ")]).0, "x");
+        assert_eq!(call("char", &[Value::Int(72), Value::Int(105)]).0, "Hi");
+        assert_eq!(call("unicode", &[text("é")]).0, "233");
+        assert_eq!(call("hex", &[Value::Int(12)]).0, "3132");
+        assert_eq!(
+            call("concat_ws", &[text(","), text("a"), Value::Null, Value::Int(1)]).0,
+            "a,1"
+        );
+        assert_eq!(call("quote", &[text("it's")]).0, "'it''s'");
+        assert_eq!(call("quote", &[Value::Blob(vec![10, 255].into())]).0, "X'0AFF'");
+        assert_eq!(call("quote", &[Value::Float(1.0 / 3.0)]).0, "3.333333333333333148e-01");
+        assert_eq!(call("like", &[text("a%"), text("ABC")]).0, "1");
+    }
+
+    #[test]
+    fn numbers() {
+        assert_eq!(call("abs", &[text("-5")]), ("5.0".into(), "real"));
+        assert_eq!(call("round", &[Value::Float(2.675), Value::Int(2)]).0, "2.67");
+        assert_eq!(call("round", &[Value::Float(1.25), Value::Int(1)]).0, "1.3");
+        assert_eq!(call("round", &[Value::Float(-2.5)]).0, "-3.0");
+        assert_eq!(call("round", &[Value::Int(5)]), ("5.0".into(), "real"));
+        assert_eq!(call("ceil", &[Value::Int(3)]), ("3".into(), "integer"));
+        assert_eq!(call("floor", &[Value::Float(-1.5)]).0, "-2.0");
+        assert_eq!(call("sqrt", &[text("4")]).0, "2.0");
+        assert_eq!(call("sqrt", &[Value::Int(-1)]).1, "null");
+        assert_eq!(call("sqrt", &[text("x")]).1, "null");
+        assert_eq!(call("log", &[Value::Int(2), Value::Int(8)]).0, "3.0");
+        assert_eq!(call("ln", &[Value::Int(0)]).1, "null");
+        assert_eq!(call("mod", &[Value::Float(7.5), Value::Int(2)]).0, "1.5");
+        assert_eq!(call("sign", &[Value::Float(-2.5)]), ("-1".into(), "integer"));
+        assert_eq!(call("max", &[Value::Int(1), Value::Float(2.5)]).0, "2.5");
+        assert_eq!(call("min", &[Value::Int(1), Value::Null]).1, "null");
+        assert_eq!(call("nullif", &[Value::Int(1), text("1")]).0, "1");
+        assert_eq!(call("typeof", &[call_random()]).0, "integer");
+        assert!(find_function("abs", 1).unwrap()(&[Value::Int(i64::MIN)]).is_err());
+    }
+
+    fn call_random() -> Value<'static> {
+        find_function("random", 0).unwrap()(&[]).unwrap()
+    }
+
+    #[test]
+    fn formats() {
+        assert_eq!(
+            printf(
+                "%5.2f|%-5d|%05d",
+                &[Value::Float(1.2345), Value::Int(42), Value::Int(42)]
+            ),
+            " 1.23|42   |00042"
+        );
+        assert_eq!(
+            printf(
+                "%,d|%x|%e",
+                &[Value::Int(1234567), Value::Int(255), Value::Float(12345.678)]
+            ),
+            "1,234,567|ff|1.234568e+04"
+        );
+        assert_eq!(
+            printf(
+                "%g|%g|%#g",
+                &[Value::Float(0.0001), Value::Float(123456789.0), Value::Float(1.5)]
+            ),
+            "0.0001|1.23457e+08|1.50000"
+        );
+        assert_eq!(
+            printf("%q|%Q|%w", &[text("it's"), Value::Null, text("a\"b")]),
+            "it''s|NULL|a\"\"b"
+        );
+        assert_eq!(
+            printf(
+                "%10.3s|%+d|% d|%#x|%.3d|%%",
+                &[
+                    text("abcdef"),
+                    Value::Int(5),
+                    Value::Int(5),
+                    Value::Int(255),
+                    Value::Int(7)
+                ]
+            ),
+            "       abc|+5| 5|0xff|007|%"
+        );
+        assert_eq!(
+            printf(
+                "%.*d|%*d|",
+                &[Value::Int(3), Value::Int(5), Value::Int(-4), Value::Int(6)]
+            ),
+            "005|6   |"
+        );
+        assert_eq!(
+            printf(
+                "%.2f|%.0f|%.20f",
+                &[Value::Float(2.675), Value::Float(2.5), Value::Float(0.1)]
+            ),
+            "2.67|3|0.10000000000000000000"
+        );
+        assert_eq!(printf("%s %s|%d", &[Value::Int(1)]), "1 |0");
+        assert_eq!(printf("a%yb", &[]), "a");
+    }
+
+    #[test]
+    fn errors() {
+        let message = |name, args| find_function(name, args).err().unwrap().to_string();
+        assert_eq!(message("upper", 0), "wrong number of arguments to function upper()");
+        assert_eq!(message("count", 3), "wrong number of arguments to function count()");
+        assert_eq!(message("nosuch", 1), "no such function: nosuch");
+        let literal = |f| Expr::Literal(Value::Float(f));
+        let functions = Functions::default();
+        assert!(
+            functions
+                .check_call("likelihood", &[literal(1.0), literal(0.5)])
+                .is_ok()
+        );
+        assert!(
+            functions
+                .check_call("likelihood", &[literal(1.0), literal(2.0)])
+                .is_err()
+        );
+        assert!(functions.check_call("max", &[literal(1.0)]).is_ok());
+    }
+
+    #[test]
+    fn binding() {
+        let mut functions = Functions::default();
+        let twice: UserFunction = Rc::new(|args| Ok(Value::Int(integer(&args[0]) * 2)));
+        functions.define_scalar("Twice", 1, FunctionFlags::NONE, twice).unwrap();
+        let mut expr = parser::sql::expr("TWICE(abs(x)) + sum(twice(1, 2)) + twice(2)").unwrap();
+        functions.bind(&mut expr);
+        let mut bound = vec![];
+        expr.walk(&mut |e| {
+            if let Expr::Function { name, function, .. } = e {
+                bound.push((*name, function.map(|f| functions.get(f)(&[Value::Int(-3)]).unwrap())));
+            }
+            true
+        });
+        // Calls to aggregates and to functions that don't exist are left unbound.
+        let expected = [
+            ("TWICE", Some(Value::Int(-6))),
+            ("abs", Some(Value::Int(3))),
+            ("sum", None),
+            ("twice", None),
+            ("twice", Some(Value::Int(-6))),
+        ];
+        assert_eq!(bound, expected);
+        // Defining a function again replaces it, whatever the case of its name.
+        let thrice: UserFunction = Rc::new(|args| Ok(Value::Int(integer(&args[0]) * 3)));
+        functions
+            .define_scalar("twice", 1, FunctionFlags::NONE, thrice)
+            .unwrap();
+        assert_eq!(
+            functions.scalar("TWICE", 1).unwrap()(&[Value::Int(2)]).unwrap(),
+            Value::Int(6)
+        );
+        let err = functions.scalar("twice", 2).err().unwrap();
+        assert_eq!(err.to_string(), "wrong number of arguments to function twice()");
+    }
+}
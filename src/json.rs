@@ -0,0 +1,1669 @@
+//! JSON functions, see <https://sqlite.org/json1.html>.
+//!
+//! Like SQLite, JSON is taken as text, JSON5 extensions included, or as JSONB, the binary encoding SQLite stores it in
+//! since 3.45. It's worked on as a tree of nodes, which keep numbers and strings the way they were written: they are
+//! only converted when the JSON is written out as canonical text or a value is taken out of it.
+//!
+//! SQLite marks the text the JSON functions return with a subtype, so that a JSON function given that text embeds it
+//! as JSON rather than as a string. Values have no subtype here, the planner rewrites such arguments to produce JSONB
+//! instead, which is always embedded as JSON, see [`mark_json_arguments`].
+
+use std::borrow::Cow;
+
+use anyhow::Result;
+use anyhow::bail;
+use parser::Expr;
+use parser::Value;
+use parser::format_real;
+
+use crate::aggregate::Aggregate;
+
+/// Deepest nesting of arrays and objects, SQLite's limit.
+const MAX_DEPTH: usize = 1000;
+
+/// The kind of a node, its code being the type of the node in JSONB.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind {
+    Null = 0,
+    True,
+    False,
+    /// An integer in JSON syntax.
+    Int,
+    /// An integer in hexadecimal, a JSON5 extension.
+    Int5,
+    /// A real in JSON syntax.
+    Float,
+    /// A real in JSON5 syntax, with a leading or trailing decimal point, or infinite.
+    Float5,
+    /// A string without escapes.
+    Text,
+    /// A string with JSON escapes.
+    TextJ,
+    /// A string with JSON5 escapes, or characters JSON only has as escapes.
+    Text5,
+    /// A string whose characters are all taken as they are, they are escaped when it's written out.
+    TextRaw,
+    Array,
+    Object,
+}
+
+impl Kind {
+    fn from_code(code: u8) -> Option<Self> {
+        use Kind::*;
+        [
+            Null, True, False, Int, Int5, Float, Float5, Text, TextJ, Text5, TextRaw, Array, Object,
+        ]
+        .get(code as usize)
+        .copied()
+    }
+}
+
+/// A node of a JSON tree.
+#[derive(Debug, Clone, PartialEq)]
+enum Json {
+    Null,
+    True,
+    False,
+    /// A number as written, of kind `Int`, `Int5`, `Float` or `Float5`.
+    Number(Kind, String),
+    /// A string as written, without its quotes, of kind `Text`, `TextJ`, `Text5` or `TextRaw`.
+    String(Kind, String),
+    Array(Vec<Json>),
+    /// The members of an object in order, duplicate labels included. Labels are strings.
+    Object(Vec<(Json, Json)>),
+}
+
+impl Json {
+    fn kind(&self) -> Kind {
+        match self {
+            Self::Null => Kind::Null,
+            Self::True => Kind::True,
+            Self::False => Kind::False,
+            Self::Number(kind, _) | Self::String(kind, _) => *kind,
+            Self::Array(_) => Kind::Array,
+            Self::Object(_) => Kind::Object,
+        }
+    }
+
+    /// The name `json_type()` gives the node.
+    fn type_name(&self) -> &'static str {
+        match self {
+            Self::Null => "null",
+            Self::True => "true",
+            Self::False => "false",
+            Self::Number(Kind::Int | Kind::Int5, _) => "integer",
+            Self::Number(..) => "real",
+            Self::String(..) => "text",
+            Self::Array(_) => "array",
+            Self::Object(_) => "object",
+        }
+    }
+
+    /// A string of SQL text, escaped if it has to be.
+    fn text(s: &str) -> Self {
+        match s.chars().any(needs_escape) {
+            true => {
+                let mut escaped = String::new();
+                escape(s, &mut escaped);
+                Self::String(Kind::TextJ, escaped)
+            }
+            false => Self::String(Kind::Text, s.to_string()),
+        }
+    }
+
+    /// The text of a string, with its escapes replaced by the characters they stand for.
+    fn unescaped(&self) -> Cow<'_, str> {
+        match self {
+            Self::String(Kind::TextJ | Kind::Text5, s) => Cow::Owned(unescape(s)),
+            Self::String(_, s) => Cow::Borrowed(s),
+            _ => unreachable!("not a string"),
+        }
+    }
+
+    /// The node as canonical JSON text.
+    fn to_text(&self) -> String {
+        let mut out = String::new();
+        self.write(&mut out);
+        out
+    }
+
+    fn write(&self, out: &mut String) {
+        match self {
+            Self::Null => out.push_str("null"),
+            Self::True => out.push_str("true"),
+            Self::False => out.push_str("false"),
+            Self::Number(Kind::Int5, s) => match hex_integer(s) {
+                Some(i) => out.push_str(&i.to_string()),
+                None => out.push_str(if s.starts_with('-') { "-9.0e999" } else { "9.0e999" }),
+            },
+            Self::Number(Kind::Float5, s) => out.push_str(&canonical_real(s)),
+            Self::Number(_, s) => out.push_str(s),
+            Self::String(kind, s) => {
+                out.push('"');
+                match kind {
+                    Kind::Text5 => write_text5(s, out),
+                    Kind::TextRaw => escape(s, out),
+                    _ => out.push_str(s),
+                }
+                out.push('"');
+            }
+            Self::Array(items) => {
+                out.push('[');
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        out.push(',');
+                    }
+                    item.write(out);
+                }
+                out.push(']');
+            }
+            Self::Object(members) => {
+                out.push('{');
+                for (i, (label, value)) in members.iter().enumerate() {
+                    if i > 0 {
+                        out.push(',');
+                    }
+                    label.write(out);
+                    out.push(':');
+                    value.write(out);
+                }
+                out.push('}');
+            }
+        }
+    }
+
+    /// The node encoded as JSONB.
+    fn to_blob(&self) -> Vec<u8> {
+        let mut out = vec![];
+        self.encode(&mut out);
+        out
+    }
+
+    fn encode(&self, out: &mut Vec<u8>) {
+        let payload = match self {
+            Self::Null | Self::True | Self::False => Cow::Borrowed(&[][..]),
+            Self::Number(_, s) | Self::String(_, s) => Cow::Borrowed(s.as_bytes()),
+            Self::Array(items) => {
+                let mut payload = vec![];
+                items.iter().for_each(|item| item.encode(&mut payload));
+                Cow::Owned(payload)
+            }
+            Self::Object(members) => {
+                let mut payload = vec![];
+                for (label, value) in members {
+                    label.encode(&mut payload);
+                    value.encode(&mut payload);
+                }
+                Cow::Owned(payload)
+            }
+        };
+        // The size of the payload is in the high nibble of the first byte when it fits, else in the 1, 2, 4 or 8
+        // bytes after it.
+        let kind = self.kind() as u8;
+        let size = payload.len();
+        match size {
+            0..=11 => out.push((size as u8) << 4 | kind),
+            12..=0xff => out.extend([0xc0 | kind, size as u8]),
+            0x100..=0xffff => {
+                out.push(0xd0 | kind);
+                out.extend((size as u16).to_be_bytes());
+            }
+            0x10000..=0xffff_ffff => {
+                out.push(0xe0 | kind);
+                out.extend((size as u32).to_be_bytes());
+            }
+            _ => {
+                out.push(0xf0 | kind);
+                out.extend((size as u64).to_be_bytes());
+            }
+        }
+        out.extend_from_slice(&payload);
+    }
+
+    /// The SQL value of the node: strings are unescaped, numbers converted, `true` and `false` are 1 and 0, and
+    /// arrays and objects are their JSON text.
+    fn to_value(&self) -> Value<'static> {
+        match self {
+            Self::Null => Value::Null,
+            Self::True => Value::Int(1),
+            Self::False => Value::Int(0),
+            Self::Number(Kind::Int, s) => s.parse().map_or_else(|_| Value::Float(real(s)), Value::Int),
+            Self::Number(Kind::Int5, s) => match hex_integer(s).and_then(|i| i64::try_from(i).ok()) {
+                Some(i) => Value::Int(i),
+                None => Value::Float(hex_integer(s).map_or(f64::INFINITY, |i| i as f64)),
+            },
+            Self::Number(_, s) => Value::Float(real(&canonical_real(s))),
+            Self::String(..) => Value::String(self.unescaped().into_owned().into()),
+            Self::Array(_) | Self::Object(_) => Value::String(self.to_text().into()),
+        }
+    }
+
+    /// The node of a SQL value: NULL, a number or a string, or the JSON a blob is the JSONB of. Strings are raw when
+    /// they are kept as they are rather than escaped, the way SQLite stores the values it edits JSON with.
+    fn from_value(value: &Value, raw: bool) -> Result<Self> {
+        Ok(match value {
+            Value::Null => Self::Null,
+            Value::Int(i) => Self::Number(Kind::Int, i.to_string()),
+            Value::Float(f) if f.is_nan() => Self::Null,
+            Value::Float(f) if f.is_infinite() => {
+                let sign = if *f < 0.0 { "-" } else { "" };
+                Self::Number(Kind::Float, format!("{sign}9.0e+999"))
+            }
+            Value::Float(f) => Self::Number(Kind::Float, format_real(*f)),
+            Value::String(s) if raw => Self::String(Kind::TextRaw, s.to_string()),
+            Value::String(s) => Self::text(s),
+            Value::Blob(b) => match decode(b) {
+                Some(json) => json,
+                None => bail!("JSON cannot hold BLOB values"),
+            },
+        })
+    }
+
+    /// The member of an object with that label, the first one if there are several.
+    fn member(members: &[(Json, Json)], label: &str) -> Option<usize> {
+        members.iter().position(|(l, _)| l.unescaped() == label)
+    }
+}
+
+/// The value of a real, infinite when it's too large.
+fn real(s: &str) -> f64 {
+    s.parse().unwrap_or(0.0)
+}
+
+/// The value of a hexadecimal integer, with its sign and `0x` prefix.
+fn hex_integer(s: &str) -> Option<i128> {
+    let (negative, digits) = match s.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, s),
+    };
+    let i = i128::from_str_radix(&digits[2..], 16)
+        .ok()
+        .filter(|i| *i <= u64::MAX as i128)?;
+    Some(if negative { -i } else { i })
+}
+
+/// A JSON5 real written the JSON way, with digits around its decimal point.
+fn canonical_real(s: &str) -> String {
+    let (sign, digits) = match s.strip_prefix('-') {
+        Some(rest) => ("-", rest),
+        None => ("", s),
+    };
+    let mut out = sign.to_string();
+    if digits.starts_with('.') {
+        out.push('0');
+    }
+    let mut chars = digits.chars().peekable();
+    while let Some(c) = chars.next() {
+        out.push(c);
+        if c == '.' && !chars.peek().is_some_and(char::is_ascii_digit) {
+            out.push('0');
+        }
+    }
+    out
+}
+
+fn needs_escape(c: char) -> bool {
+    c == '"' || c == '\\' || c < ' '
+}
+
+/// Appends the characters of a string, escaped for JSON.
+fn escape(s: &str, out: &mut String) {
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\u{8}' => out.push_str("\\b"),
+            '\u{c}' => out.push_str("\\f"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c < ' ' => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+}
+
+/// Appends a string with JSON5 escapes, rewriting what JSON doesn't have.
+fn write_text5(s: &str, out: &mut String) {
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some('\'') => out.push('\''),
+                Some('v') => out.push_str("\\u000b"),
+                Some('0') => out.push_str("\\u0000"),
+                Some('x') => {
+                    out.push_str("\\u00");
+                    out.extend(chars.by_ref().take(2));
+                }
+                // A backslash at the end of a line continues the string on the next one.
+                Some('\r') => {
+                    chars.next_if_eq(&'\n');
+                }
+                Some('\n' | '\u{2028}' | '\u{2029}') => {}
+                Some(c) => {
+                    out.push('\\');
+                    out.push(c);
+                }
+                None => {}
+            },
+            c if needs_escape(c) => escape(c.encode_utf8(&mut [0; 4]), out),
+            c => out.push(c),
+        }
+    }
+}
+
+/// The characters a string with JSON or JSON5 escapes stands for.
+fn unescape(s: &str) -> String {
+    let mut out = String::new();
+    let mut chars = s.chars().peekable();
+    let hex = |chars: &mut std::iter::Peekable<std::str::Chars>, n| {
+        let digits: String = chars.by_ref().take(n).collect();
+        u32::from_str_radix(&digits, 16).unwrap_or(0)
+    };
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('b') => out.push('\u{8}'),
+            Some('f') => out.push('\u{c}'),
+            Some('n') => out.push('\n'),
+            Some('r') => out.push('\r'),
+            Some('t') => out.push('\t'),
+            Some('v') => out.push('\u{b}'),
+            Some('0') => out.push('\0'),
+            Some('x') => out.push(char::from_u32(hex(&mut chars, 2)).unwrap_or('\u{fffd}')),
+            Some('u') => {
+                let mut code = hex(&mut chars, 4);
+                // Characters outside of the basic plane are written as a pair of surrogates.
+                if (0xd800..0xdc00).contains(&code) && chars.clone().take(2).eq(['\\', 'u']) {
+                    let mut ahead = chars.clone();
+                    ahead.nth(1);
+                    let low = hex(&mut ahead, 4);
+                    if (0xdc00..0xe000).contains(&low) {
+                        chars = ahead;
+                        code = 0x10000 + ((code - 0xd800) << 10) + (low - 0xdc00);
+                    }
+                }
+                out.push(char::from_u32(code).unwrap_or('\u{fffd}'));
+            }
+            Some('\r') => {
+                chars.next_if_eq(&'\n');
+            }
+            Some('\n' | '\u{2028}' | '\u{2029}') => {}
+            Some(c) => out.push(c),
+            None => {}
+        }
+    }
+    out
+}
+
+/// Parses JSON text, along with whether it uses JSON5 extensions.
+fn parse(text: &str) -> Option<(Json, bool)> {
+    let mut parser = TextParser {
+        s: text.as_bytes(),
+        text,
+        pos: 0,
+        json5: false,
+        depth: 0,
+    };
+    let json = parser.value()?;
+    parser.skip_space();
+    (parser.pos == text.len()).then_some((json, parser.json5))
+}
+
+struct TextParser<'t> {
+    s: &'t [u8],
+    text: &'t str,
+    pos: usize,
+    /// Whether JSON5 extensions were used.
+    json5: bool,
+    depth: usize,
+}
+
+impl<'t> TextParser<'t> {
+    fn peek(&self) -> Option<u8> {
+        self.s.get(self.pos).copied()
+    }
+
+    fn rest(&self) -> &'t str {
+        &self.text[self.pos..]
+    }
+
+    /// Skips whitespace, and the comments and whitespace characters of JSON5.
+    fn skip_space(&mut self) {
+        loop {
+            match self.peek() {
+                Some(b' ' | b'\t' | b'\n' | b'\r') => self.pos += 1,
+                Some(0x0b | 0x0c) => {
+                    self.json5 = true;
+                    self.pos += 1;
+                }
+                Some(b'/') if self.rest().starts_with("//") => {
+                    self.json5 = true;
+                    self.pos = self.rest().find('\n').map_or(self.s.len(), |end| self.pos + end + 1);
+                }
+                Some(b'/') if self.rest().starts_with("/*") => {
+                    self.json5 = true;
+                    match self.rest()[2..].find("*/") {
+                        Some(end) => self.pos += end + 4,
+                        // An unterminated comment is left for the caller to choke on.
+                        None => return,
+                    }
+                }
+                Some(0x80..) => match self.rest().chars().next() {
+                    Some(
+                        c @ ('\u{a0}'
+                        | '\u{1680}'
+                        | '\u{2000}'..='\u{200a}'
+                        | '\u{2028}'
+                        | '\u{2029}'
+                        | '\u{202f}'
+                        | '\u{205f}'
+                        | '\u{3000}'
+                        | '\u{feff}'),
+                    ) => {
+                        self.json5 = true;
+                        self.pos += c.len_utf8();
+                    }
+                    _ => return,
+                },
+                _ => return,
+            }
+        }
+    }
+
+    fn value(&mut self) -> Option<Json> {
+        self.skip_space();
+        let c = self.peek()?;
+        match c {
+            b'{' | b'[' => {
+                self.depth += 1;
+                if self.depth > MAX_DEPTH {
+                    return None;
+                }
+                self.pos += 1;
+                let json = if c == b'{' { self.object() } else { self.array() };
+                self.depth -= 1;
+                json
+            }
+            b'"' | b'\'' => self.string(),
+            b'-' | b'+' | b'.' | b'0'..=b'9' => self.number(),
+            _ => {
+                let (json, word) = [
+                    (Json::True, "true"),
+                    (Json::False, "false"),
+                    (Json::Null, "null"),
+                    (Json::Null, "NaN"),
+                    (Json::Number(Kind::Float, "9e999".into()), "Infinity"),
+                ]
+                .into_iter()
+                .find(|(_, word)| self.rest().starts_with(word))?;
+                if self.s.get(self.pos + word.len()).is_some_and(u8::is_ascii_alphanumeric) {
+                    return None;
+                }
+                self.json5 |= word.starts_with(|c: char| c.is_ascii_uppercase());
+                self.pos += word.len();
+                Some(json)
+            }
+        }
+    }
+
+    /// The members of an object, after its `{`.
+    fn object(&mut self) -> Option<Json> {
+        let mut members = vec![];
+        loop {
+            self.skip_space();
+            if self.peek()? == b'}' {
+                // A comma after the last member is JSON5.
+                self.json5 |= !members.is_empty();
+                self.pos += 1;
+                return Some(Json::Object(members));
+            }
+            let label = match self.peek()? {
+                b'"' | b'\'' => self.string()?,
+                _ => self.identifier()?,
+            };
+            self.skip_space();
+            if self.peek()? != b':' {
+                return None;
+            }
+            self.pos += 1;
+            members.push((label, self.value()?));
+            self.skip_space();
+            match self.peek()? {
+                b',' => self.pos += 1,
+                b'}' => {
+                    self.pos += 1;
+                    return Some(Json::Object(members));
+                }
+                _ => return None,
+            }
+        }
+    }
+
+    /// The items of an array, after its `[`.
+    fn array(&mut self) -> Option<Json> {
+        let mut items = vec![];
+        loop {
+            self.skip_space();
+            if self.peek()? == b']' {
+                self.json5 |= !items.is_empty();
+                self.pos += 1;
+                return Some(Json::Array(items));
+            }
+            items.push(self.value()?);
+            self.skip_space();
+            match self.peek()? {
+                b',' => self.pos += 1,
+                b']' => {
+                    self.pos += 1;
+                    return Some(Json::Array(items));
+                }
+                _ => return None,
+            }
+        }
+    }
+
+    /// An unquoted JSON5 label.
+    fn identifier(&mut self) -> Option<Json> {
+        let start = self.pos;
+        let is_start = |c: char| c.is_alphabetic() || c == '_' || c == '$';
+        let mut chars = self.rest().char_indices();
+        chars.next().filter(|(_, c)| is_start(*c))?;
+        let end = chars
+            .find(|(_, c)| !is_start(*c) && !c.is_alphanumeric())
+            .map_or(self.s.len(), |(i, _)| start + i);
+        self.json5 = true;
+        self.pos = end;
+        Some(Json::String(Kind::Text, self.text[start..end].to_string()))
+    }
+
+    fn string(&mut self) -> Option<Json> {
+        let quote = self.peek()?;
+        self.pos += 1;
+        let start = self.pos;
+        let mut kind = Kind::Text;
+        loop {
+            let c = self.peek()?;
+            if c == quote {
+                break;
+            }
+            match c {
+                b'\\' => {
+                    self.pos += 1;
+                    let rest = self.rest();
+                    let escape = rest.chars().next()?;
+                    let (len, json5) = match escape {
+                        '"' | '\\' | '/' | 'b' | 'f' | 'n' | 'r' | 't' => (1, false),
+                        'u' if rest.len() >= 5 && rest[1..5].bytes().all(|b| b.is_ascii_hexdigit()) => (5, false),
+                        'x' if rest.len() >= 3 && rest[1..3].bytes().all(|b| b.is_ascii_hexdigit()) => (3, true),
+                        '0' if !rest[1..].starts_with(|c: char| c.is_ascii_digit()) => (1, true),
+                        '\'' | 'v' | '\n' | '\u{2028}' | '\u{2029}' => (escape.len_utf8(), true),
+                        '\r' => (if rest[1..].starts_with('\n') { 2 } else { 1 }, true),
+                        _ => return None,
+                    };
+                    self.pos += len;
+                    kind = match (json5, kind) {
+                        (true, _) | (_, Kind::Text5) => Kind::Text5,
+                        _ => Kind::TextJ,
+                    };
+                    continue;
+                }
+                0 => return None,
+                b'"' | 0x01..=0x1f => kind = Kind::Text5,
+                _ => {}
+            }
+            self.pos += 1;
+        }
+        let s = self.text[start..self.pos].to_string();
+        self.pos += 1;
+        self.json5 |= quote == b'\'' || kind == Kind::Text5;
+        Some(Json::String(kind, s))
+    }
+
+    fn number(&mut self) -> Option<Json> {
+        let mut text = String::new();
+        match self.peek()? {
+            b'-' => {
+                text.push('-');
+                self.pos += 1;
+            }
+            b'+' => {
+                self.json5 = true;
+                self.pos += 1;
+            }
+            _ => {}
+        }
+        if self.rest().starts_with("Infinity") {
+            self.json5 = true;
+            self.pos += "Infinity".len();
+            text.push_str("9e999");
+            return Some(Json::Number(Kind::Float, text));
+        }
+        let rest = self.rest();
+        if rest.starts_with("0x") || rest.starts_with("0X") {
+            let digits = rest[2..].bytes().take_while(u8::is_ascii_hexdigit).count();
+            if digits == 0 {
+                return None;
+            }
+            self.json5 = true;
+            text.push_str(&rest[..2 + digits]);
+            self.pos += 2 + digits;
+            return Some(Json::Number(Kind::Int5, text));
+        }
+        let digits = |s: &[u8]| s.iter().take_while(|b| b.is_ascii_digit()).count();
+        let start = self.pos;
+        let integral = digits(&self.s[self.pos..]);
+        if integral > 1 && self.s[self.pos] == b'0' {
+            return None;
+        }
+        self.pos += integral;
+        let mut kind = Kind::Int;
+        if self.peek() == Some(b'.') {
+            self.pos += 1;
+            let fraction = digits(&self.s[self.pos..]);
+            self.pos += fraction;
+            kind = match (integral, fraction) {
+                (0, 0) => return None,
+                (0, _) | (_, 0) => Kind::Float5,
+                _ => Kind::Float,
+            };
+        } else if integral == 0 {
+            return None;
+        }
+        if let Some(b'e' | b'E') = self.peek() {
+            self.pos += 1;
+            if let Some(b'+' | b'-') = self.peek() {
+                self.pos += 1;
+            }
+            let exponent = digits(&self.s[self.pos..]);
+            if exponent == 0 {
+                return None;
+            }
+            self.pos += exponent;
+            if kind == Kind::Int {
+                kind = Kind::Float;
+            }
+        }
+        self.json5 |= kind == Kind::Float5;
+        text.push_str(&self.text[start..self.pos]);
+        Some(Json::Number(kind, text))
+    }
+}
+
+/// The header of the JSONB node at `pos`: its kind, and where its payload starts and ends.
+fn header(blob: &[u8], pos: usize) -> Option<(Kind, usize, usize)> {
+    let first = *blob.get(pos)?;
+    let kind = Kind::from_code(first & 0x0f)?;
+    let (start, size) = match first >> 4 {
+        size @ 0..=11 => (pos + 1, size as usize),
+        n => {
+            let len = 1 << (n - 12);
+            let bytes = blob.get(pos + 1..pos + 1 + len)?;
+            let size = bytes.iter().fold(0u64, |size, b| size << 8 | *b as u64);
+            (pos + 1 + len, usize::try_from(size).ok()?)
+        }
+    };
+    let end = start.checked_add(size).filter(|end| *end <= blob.len())?;
+    Some((kind, start, end))
+}
+
+/// Decodes JSONB, `None` if it isn't well formed.
+fn decode(blob: &[u8]) -> Option<Json> {
+    let (json, end) = decode_node(blob, 0, 0)?;
+    (end == blob.len()).then_some(json)
+}
+
+/// Decodes the JSONB node at `pos`, returns it along with where it ends.
+fn decode_node(blob: &[u8], pos: usize, depth: usize) -> Option<(Json, usize)> {
+    let (kind, start, end) = header(blob, pos)?;
+    let payload = &blob[start..end];
+    let text = || std::str::from_utf8(payload).ok().map(str::to_string);
+    let json = match kind {
+        Kind::Null | Kind::True | Kind::False if !payload.is_empty() => return None,
+        Kind::Null => Json::Null,
+        Kind::True => Json::True,
+        Kind::False => Json::False,
+        Kind::Int | Kind::Int5 | Kind::Float | Kind::Float5 => {
+            let text = text().filter(|t| !t.is_empty())?;
+            if kind == Kind::Int5 && hex_integer(&text).is_none() {
+                return None;
+            }
+            Json::Number(kind, text)
+        }
+        Kind::Text | Kind::TextJ | Kind::Text5 | Kind::TextRaw => Json::String(kind, text()?),
+        Kind::Array | Kind::Object if depth >= MAX_DEPTH => return None,
+        Kind::Array => {
+            let mut items = vec![];
+            let mut pos = start;
+            while pos < end {
+                let (item, next) = decode_node(&blob[..end], pos, depth + 1)?;
+                items.push(item);
+                pos = next;
+            }
+            Json::Array(items)
+        }
+        Kind::Object => {
+            let mut members = vec![];
+            let mut pos = start;
+            while pos < end {
+                let (label, next) = decode_node(&blob[..end], pos, depth + 1)?;
+                if !matches!(label, Json::String(..)) {
+                    return None;
+                }
+                let (value, next) = decode_node(&blob[..end], next, depth + 1)?;
+                members.push((label, value));
+                pos = next;
+            }
+            Json::Object(members)
+        }
+    };
+    Some((json, end))
+}
+
+/// The JSON an argument holds, `None` for NULL. Blobs are JSONB, anything else is JSON text.
+fn input(value: &Value) -> Result<Option<Json>> {
+    let json = match value {
+        Value::Null => return Ok(None),
+        Value::Blob(b) => decode(b),
+        Value::String(s) => parse(s).map(|(json, _)| json),
+        v => parse(&v.to_string()).map(|(json, _)| json),
+    };
+    match json {
+        Some(json) => Ok(Some(json)),
+        None => bail!("malformed JSON"),
+    }
+}
+
+/// The JSON as the result of a function, text or JSONB.
+fn output(json: &Json, blob: bool) -> Value<'static> {
+    match blob {
+        true => Value::Blob(json.to_blob().into()),
+        false => Value::String(json.to_text().into()),
+    }
+}
+
+/// A step of a JSON path.
+#[derive(Debug, Clone, PartialEq)]
+enum Step {
+    /// `.label`, the member of an object.
+    Key(String),
+    /// `[N]`, the item of an array at that position.
+    Index(usize),
+    /// `[#-N]`, the item of an array that many positions before its end, `[#]` being the position after the last.
+    FromEnd(usize),
+}
+
+impl Step {
+    /// The position in an array of that length the step is at, one past the end for a new item.
+    fn position(&self, len: usize) -> Option<usize> {
+        match self {
+            Self::Index(i) => Some(*i),
+            Self::FromEnd(n) => len.checked_sub(*n),
+            Self::Key(_) => None,
+        }
+    }
+}
+
+/// Parses a path like `$.a[2]."b c"[#-1]`.
+fn parse_path(path: &str) -> Result<Vec<Step>> {
+    let bad = || anyhow::anyhow!("bad JSON path: '{path}'");
+    let mut rest = path.strip_prefix('$').ok_or_else(bad)?;
+    let mut steps = vec![];
+    while !rest.is_empty() {
+        if let Some(after) = rest.strip_prefix('.') {
+            let (key, after) = match after.strip_prefix('"') {
+                Some(quoted) => {
+                    let end = quoted.find('"').ok_or_else(bad)?;
+                    (&quoted[..end], &quoted[end + 1..])
+                }
+                None => {
+                    let end = after.find(['.', '[']).unwrap_or(after.len());
+                    if end == 0 {
+                        return Err(bad());
+                    }
+                    after.split_at(end)
+                }
+            };
+            steps.push(Step::Key(key.to_string()));
+            rest = after;
+        } else if let Some(after) = rest.strip_prefix('[') {
+            let end = after.find(']').ok_or_else(bad)?;
+            let inside = &after[..end];
+            let number = |s: &str| match !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit()) {
+                true => s.parse().ok(),
+                false => None,
+            };
+            let step = match inside.strip_prefix('#') {
+                Some("") => Step::FromEnd(0),
+                Some(offset) => Step::FromEnd(offset.strip_prefix('-').and_then(number).ok_or_else(bad)?),
+                None => Step::Index(number(inside).ok_or_else(bad)?),
+            };
+            steps.push(step);
+            rest = &after[end + 1..];
+        } else {
+            return Err(bad());
+        }
+    }
+    Ok(steps)
+}
+
+/// The path of an argument, `None` for NULL.
+fn path_argument(value: &Value) -> Result<Option<Vec<Step>>> {
+    match value {
+        Value::Null => Ok(None),
+        v => parse_path(&v.to_string()).map(Some),
+    }
+}
+
+/// The node at the end of a path.
+fn lookup<'j>(json: &'j Json, path: &[Step]) -> Option<&'j Json> {
+    path.iter().try_fold(json, |node, step| match (node, step) {
+        (Json::Object(members), Step::Key(key)) => Json::member(members, key).map(|i| &members[i].1),
+        (Json::Array(items), step) => items.get(step.position(items.len())?),
+        _ => None,
+    })
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Edit {
+    /// Replaces the node at the path, or adds it if it's missing.
+    Set,
+    /// Adds the node at the path if it's missing.
+    Insert,
+    /// Replaces the node at the path if it's there.
+    Replace,
+    Remove,
+}
+
+/// Edits the node at the end of a path, which isn't empty. The objects and arrays leading to a node that's added are
+/// created when missing, as long as the steps to them are labels or the first item of an array.
+fn edit(node: &mut Json, path: &[Step], edit: Edit, value: &Json) {
+    let (step, rest) = path.split_first().expect("empty path");
+    let adds = matches!(edit, Edit::Set | Edit::Insert);
+    match (node, step) {
+        (Json::Object(members), Step::Key(key)) => match Json::member(members, key) {
+            Some(i) if rest.is_empty() => match edit {
+                Edit::Remove => {
+                    members.remove(i);
+                }
+                Edit::Set | Edit::Replace => members[i].1 = value.clone(),
+                Edit::Insert => {}
+            },
+            Some(i) => self::edit(&mut members[i].1, rest, edit, value),
+            None if adds => {
+                if let Some(node) = create(rest, value) {
+                    members.push((Json::String(Kind::TextRaw, key.clone()), node));
+                }
+            }
+            None => {}
+        },
+        (Json::Array(items), step @ (Step::Index(_) | Step::FromEnd(_))) => match step.position(items.len()) {
+            Some(i) if i < items.len() && rest.is_empty() => match edit {
+                Edit::Remove => {
+                    items.remove(i);
+                }
+                Edit::Set | Edit::Replace => items[i] = value.clone(),
+                Edit::Insert => {}
+            },
+            Some(i) if i < items.len() => self::edit(&mut items[i], rest, edit, value),
+            Some(i) if i == items.len() && adds => items.extend(create(rest, value)),
+            _ => {}
+        },
+        _ => {}
+    }
+}
+
+/// The node holding `value` at the end of a path, made of new objects and arrays.
+fn create(path: &[Step], value: &Json) -> Option<Json> {
+    let Some((step, rest)) = path.split_first() else {
+        return Some(value.clone());
+    };
+    Some(match step {
+        Step::Key(key) => Json::Object(vec![(Json::String(Kind::TextRaw, key.clone()), create(rest, value)?)]),
+        Step::Index(0) | Step::FromEnd(0) => Json::Array(vec![create(rest, value)?]),
+        _ => return None,
+    })
+}
+
+/// `json(x)` and `jsonb(x)`.
+pub fn json(args: &[Value], blob: bool) -> Result<Value<'static>> {
+    Ok(input(&args[0])?.map_or(Value::Null, |json| output(&json, blob)))
+}
+
+/// `json_valid(x, flags)`, whether `x` is JSON text (flag 1), JSON5 text (flag 2) or JSONB (flags 4 and 8).
+pub fn valid(args: &[Value]) -> Result<Value<'static>> {
+    let flags = match args.get(1) {
+        None => 1,
+        Some(Value::Null) => return Ok(Value::Null),
+        Some(flags) => match flags.to_number() {
+            Value::Int(flags @ 1..=15) => flags,
+            _ => bail!("FLAGS parameter to json_valid() must be between 1 and 15"),
+        },
+    };
+    let valid = match &args[0] {
+        Value::Null => return Ok(Value::Null),
+        Value::Blob(b) => flags & 0x0c != 0 && decode(b).is_some(),
+        v => parse(&v.to_string()).is_some_and(|(_, json5)| flags & 0x02 != 0 || (flags & 0x01 != 0 && !json5)),
+    };
+    Ok(Value::Int(valid as i64))
+}
+
+/// The node of the first argument at the path of the second one, if there is one.
+fn located<'j>(json: &'j Option<Json>, path: Option<&Value>) -> Result<Option<&'j Json>> {
+    let Some(json) = json else {
+        return Ok(None);
+    };
+    match path.map(path_argument).transpose()? {
+        None => Ok(Some(json)),
+        Some(None) => Ok(None),
+        Some(Some(path)) => Ok(lookup(json, &path)),
+    }
+}
+
+/// `json_type(x, path)`.
+pub fn type_name(args: &[Value]) -> Result<Value<'static>> {
+    let json = input(&args[0])?;
+    let node = located(&json, args.get(1))?;
+    Ok(node.map_or(Value::Null, |node| Value::String(node.type_name().into())))
+}
+
+/// `json_array_length(x, path)`, 0 for anything but an array.
+pub fn array_length(args: &[Value]) -> Result<Value<'static>> {
+    let json = input(&args[0])?;
+    let node = located(&json, args.get(1))?;
+    Ok(node.map_or(Value::Null, |node| match node {
+        Json::Array(items) => Value::Int(items.len() as i64),
+        _ => Value::Int(0),
+    }))
+}
+
+/// `json_quote(x)`, the JSON of a SQL value.
+pub fn quote(args: &[Value]) -> Result<Value<'static>> {
+    Ok(Value::String(Json::from_value(&args[0], false)?.to_text().into()))
+}
+
+/// `json_extract(x, path, ...)` and `jsonb_extract()`. With one path, the SQL value at the path, arrays and objects
+/// being JSON. With several, an array of the JSON at each path.
+pub fn extract(args: &[Value], blob: bool) -> Result<Value<'static>> {
+    let Some(json) = input(&args[0])? else {
+        return Ok(Value::Null);
+    };
+    let paths = args[1..].iter().map(path_argument).collect::<Result<Vec<_>>>()?;
+    if let [path] = &paths[..] {
+        let node = path.as_ref().and_then(|path| lookup(&json, path));
+        return Ok(match node {
+            Some(node @ (Json::Array(_) | Json::Object(_))) => output(node, blob),
+            Some(node) => node.to_value(),
+            None => Value::Null,
+        });
+    }
+    let mut items = vec![];
+    for path in &paths {
+        let Some(path) = path else {
+            return Ok(Value::Null);
+        };
+        items.push(lookup(&json, path).cloned().unwrap_or(Json::Null));
+    }
+    Ok(match paths.is_empty() {
+        true => Value::Null,
+        false => output(&Json::Array(items), blob),
+    })
+}
+
+/// `x -> path` and `x ->> path`, the JSON or the SQL value at the path. Besides a path, the right side can be the
+/// label of a member or the position of an item, counted from the end when negative.
+pub fn arrow(json: &Value, path: &Value, value: bool) -> Result<Value<'static>> {
+    let Some(json) = input(json)? else {
+        return Ok(Value::Null);
+    };
+    let path = match path {
+        Value::Null => return Ok(Value::Null),
+        Value::Int(i) if *i < 0 => vec![Step::FromEnd(i.unsigned_abs() as usize)],
+        Value::Int(i) => vec![Step::Index(*i as usize)],
+        v => {
+            let text = v.to_string();
+            match text.starts_with('$') {
+                true => parse_path(&text)?,
+                false => vec![Step::Key(text)],
+            }
+        }
+    };
+    Ok(match lookup(&json, &path) {
+        Some(node) if value => node.to_value(),
+        Some(node) => Value::String(node.to_text().into()),
+        None => Value::Null,
+    })
+}
+
+/// `json_array(...)` and `jsonb_array()`.
+pub fn array(args: &[Value], blob: bool) -> Result<Value<'static>> {
+    let items = args.iter().map(|v| Json::from_value(v, false)).collect::<Result<_>>()?;
+    Ok(output(&Json::Array(items), blob))
+}
+
+/// `json_object(label, value, ...)` and `jsonb_object()`.
+pub fn object(args: &[Value], blob: bool) -> Result<Value<'static>> {
+    if !args.len().is_multiple_of(2) {
+        bail!("json_object() requires an even number of arguments");
+    }
+    let mut members = vec![];
+    for pair in args.chunks(2) {
+        let Value::String(label) = &pair[0] else {
+            bail!("json_object() labels must be TEXT");
+        };
+        members.push((Json::text(label), Json::from_value(&pair[1], false)?));
+    }
+    Ok(output(&Json::Object(members), blob))
+}
+
+/// `json_set()`, `json_insert()` and `json_replace()` and their JSONB forms, which edit the first argument at each of
+/// the paths that follow with the value after it.
+fn edit_function(name: &str, args: &[Value], mode: Edit, blob: bool) -> Result<Value<'static>> {
+    if args.len().is_multiple_of(2) {
+        bail!("{name}() needs an odd number of arguments");
+    }
+    let Some(mut json) = input(&args[0])? else {
+        return Ok(Value::Null);
+    };
+    for pair in args[1..].chunks(2) {
+        let Some(path) = path_argument(&pair[0])? else {
+            return Ok(Value::Null);
+        };
+        let value = Json::from_value(&pair[1], true)?;
+        match path.is_empty() {
+            true if mode != Edit::Insert => json = value,
+            true => {}
+            false => edit(&mut json, &path, mode, &value),
+        }
+    }
+    Ok(output(&json, blob))
+}
+
+pub fn set(args: &[Value], blob: bool) -> Result<Value<'static>> {
+    edit_function(if blob { "jsonb_set" } else { "json_set" }, args, Edit::Set, blob)
+}
+
+pub fn insert(args: &[Value], blob: bool) -> Result<Value<'static>> {
+    edit_function(
+        if blob { "jsonb_insert" } else { "json_insert" },
+        args,
+        Edit::Insert,
+        blob,
+    )
+}
+
+pub fn replace(args: &[Value], blob: bool) -> Result<Value<'static>> {
+    edit_function(
+        if blob { "jsonb_replace" } else { "json_replace" },
+        args,
+        Edit::Replace,
+        blob,
+    )
+}
+
+/// `json_remove(x, path, ...)` and `jsonb_remove()`, NULL once the whole value is removed.
+pub fn remove(args: &[Value], blob: bool) -> Result<Value<'static>> {
+    let Some(mut json) = input(&args[0])? else {
+        return Ok(Value::Null);
+    };
+    for path in &args[1..] {
+        let Some(path) = path_argument(path)? else {
+            return Ok(Value::Null);
+        };
+        if path.is_empty() {
+            return Ok(Value::Null);
+        }
+        edit(&mut json, &path, Edit::Remove, &Json::Null);
+    }
+    Ok(output(&json, blob))
+}
+
+/// `json_group_array(x)` and `jsonb_group_array()`, an array of the values of a group.
+pub struct GroupArray {
+    items: Vec<Json>,
+    blob: bool,
+}
+
+impl GroupArray {
+    pub fn new(blob: bool) -> Self {
+        Self { items: vec![], blob }
+    }
+}
+
+impl Aggregate for GroupArray {
+    fn step(&mut self, args: &[Value]) -> Result<()> {
+        self.items.push(Json::from_value(&args[0], false)?);
+        Ok(())
+    }
+
+    fn finalize(&self) -> Result<Value<'static>> {
+        Ok(output(&Json::Array(self.items.clone()), self.blob))
+    }
+}
+
+/// `json_group_object(label, value)` and `jsonb_group_object()`, an object of the members of a group. Rows whose
+/// label is NULL are left out.
+pub struct GroupObject {
+    members: Vec<(Json, Json)>,
+    blob: bool,
+}
+
+impl GroupObject {
+    pub fn new(blob: bool) -> Self {
+        Self { members: vec![], blob }
+    }
+}
+
+impl Aggregate for GroupObject {
+    fn step(&mut self, args: &[Value]) -> Result<()> {
+        if !matches!(args[0], Value::Null) {
+            let value = Json::from_value(&args[1], false)?;
+            self.members.push((Json::text(&args[0].to_string()), value));
+        }
+        Ok(())
+    }
+
+    fn finalize(&self) -> Result<Value<'static>> {
+        Ok(output(&Json::Object(self.members.clone()), self.blob))
+    }
+}
+
+/// Names of the columns of `json_each()` and `json_tree()`.
+pub const TABLE_COLUMNS: &[&str] = &["key", "value", "type", "atom", "id", "parent", "fullkey", "path"];
+
+/// `json_each(x, path)`, a row for each item of the array or member of the object at the path, or for the value at the
+/// path itself when it's neither.
+pub fn each(args: &[Value]) -> Result<Vec<Vec<Value<'static>>>> {
+    walk(args, false)
+}
+
+/// `json_tree(x, path)`, a row for the value at the path and for every value inside it, recursively.
+pub fn tree(args: &[Value]) -> Result<Vec<Vec<Value<'static>>>> {
+    walk(args, true)
+}
+
+/// A node of JSONB visited by `json_each()` or `json_tree()`.
+struct Visit {
+    /// Where the node starts, or its label does for the member of an object, which is the id of its row.
+    id: usize,
+    pos: usize,
+    key: Value<'static>,
+    /// The path to the node, and the one to the array or object it's in.
+    fullkey: String,
+    path: String,
+    parent: Option<usize>,
+}
+
+/// The rows of `json_each()` or `json_tree()`. They are made from the JSONB of the value, whose offsets are the ids of
+/// the rows like in SQLite.
+fn walk(args: &[Value], recursive: bool) -> Result<Vec<Vec<Value<'static>>>> {
+    let Some(value) = args.first() else {
+        return Ok(vec![]);
+    };
+    let blob = match value {
+        Value::Null => return Ok(vec![]),
+        Value::Blob(b) if decode(b).is_some() => b.to_vec(),
+        v => match input(v)? {
+            Some(json) => json.to_blob(),
+            None => return Ok(vec![]),
+        },
+    };
+    let (root, steps) = match args.get(1) {
+        None => ("$".to_string(), vec![]),
+        Some(Value::Null) => return Ok(vec![]),
+        Some(path) => {
+            let text = path.to_string();
+            let steps = parse_path(&text)?;
+            (text, steps)
+        }
+    };
+    let Some(mut visit) = follow(&blob, &steps) else {
+        return Ok(vec![]);
+    };
+    visit.fullkey = root.clone();
+    let mut rows = vec![];
+    if recursive {
+        (visit.key, visit.path) = root_key(&blob, &root, visit.id);
+        visit_tree(&blob, visit, &mut rows);
+        return Ok(rows);
+    }
+    match header(&blob, visit.pos).map(|(kind, ..)| kind) {
+        Some(Kind::Array | Kind::Object) => {
+            for child in children(&blob, visit.pos) {
+                let child = Visit {
+                    fullkey: format!("{}{}", visit.fullkey, child.fullkey),
+                    path: visit.fullkey.clone(),
+                    ..child
+                };
+                rows.push(row(&blob, &child));
+            }
+        }
+        _ => {
+            let visit = Visit {
+                key: Value::Null,
+                path: root,
+                ..visit
+            };
+            rows.push(row(&blob, &visit));
+        }
+    }
+    Ok(rows)
+}
+
+/// The node at the end of a path through JSONB, `None` if it's missing.
+fn follow(blob: &[u8], steps: &[Step]) -> Option<Visit> {
+    let root = Visit {
+        id: 0,
+        pos: 0,
+        key: Value::Null,
+        fullkey: String::new(),
+        path: String::new(),
+        parent: None,
+    };
+    steps.iter().try_fold(root, |visit, step| {
+        let mut children = children(blob, visit.pos);
+        let i = match step {
+            Step::Key(key) => children
+                .iter()
+                .position(|child| child.key == Value::String(key.into()))?,
+            step => step
+                .position(children.len())
+                .filter(|i| matches!(children.get(*i), Some(c) if c.key == Value::Int(*i as i64)))?,
+        };
+        Some(children.swap_remove(i))
+    })
+}
+
+/// The key and path of the first row of `json_tree()`, for the node at the end of `root`. Like SQLite, they are found
+/// going back through the text of the path to a step leading to the first item or member of an array or object, then
+/// the key is the text after it.
+fn root_key(blob: &[u8], root: &str, id: usize) -> (Value<'static>, String) {
+    if root.len() < 2 {
+        return (Value::Null, root.to_string());
+    }
+    let first_of = |n: usize| {
+        let parent = parse_path(&root[..n]).ok().and_then(|steps| follow(blob, &steps));
+        parent
+            .and_then(|parent| header(blob, parent.pos))
+            .is_some_and(|(_, start, _)| start == id)
+    };
+    let split = (1..root.len())
+        .rev()
+        .find(|&n| matches!(root.as_bytes()[n], b'[' | b'.') && first_of(n))
+        .unwrap_or(1);
+    let step = &root[split..];
+    let key = match step.strip_prefix('[') {
+        Some(index) => {
+            let digits: String = index.chars().take_while(char::is_ascii_digit).collect();
+            Value::Int(digits.parse().unwrap_or(0))
+        }
+        None => {
+            let label = match step[1..].strip_prefix('"') {
+                Some(quoted) => quoted.get(..quoted.len().saturating_sub(1)).unwrap_or(""),
+                None => &step[1..],
+            };
+            Value::String(label.to_string().into())
+        }
+    };
+    (key, root[..split].to_string())
+}
+
+/// Adds the rows of a node and of the nodes inside it, depth first.
+fn visit_tree(blob: &[u8], visit: Visit, rows: &mut Vec<Vec<Value<'static>>>) {
+    rows.push(row(blob, &visit));
+    for child in children(blob, visit.pos) {
+        let child = Visit {
+            fullkey: format!("{}{}", visit.fullkey, child.fullkey),
+            path: visit.fullkey.clone(),
+            parent: Some(visit.id),
+            ..child
+        };
+        visit_tree(blob, child, rows);
+    }
+}
+
+/// The items of the array or members of the object at `pos`, their `fullkey` being the step to them from it.
+fn children(blob: &[u8], pos: usize) -> Vec<Visit> {
+    let Some((kind, start, end)) = header(blob, pos) else {
+        return vec![];
+    };
+    let mut children = vec![];
+    let mut pos = start;
+    while pos < end {
+        let Some((_, _, next)) = header(blob, pos) else {
+            break;
+        };
+        let child = match kind {
+            Kind::Array => Visit {
+                id: pos,
+                pos,
+                key: Value::Int(children.len() as i64),
+                fullkey: format!("[{}]", children.len()),
+                path: String::new(),
+                parent: None,
+            },
+            Kind::Object => {
+                let label = decode_node(blob, pos, 0).map_or_else(String::new, |(l, _)| l.unescaped().into_owned());
+                let simple = label.starts_with(|c: char| c.is_ascii_alphabetic())
+                    && label.chars().all(|c| c.is_ascii_alphanumeric());
+                let step = match simple {
+                    true => format!(".{label}"),
+                    false => format!(".\"{label}\""),
+                };
+                let value = next;
+                let Some((_, _, after)) = header(blob, value) else {
+                    break;
+                };
+                let child = Visit {
+                    id: pos,
+                    pos: value,
+                    key: Value::String(label.into()),
+                    fullkey: step,
+                    path: String::new(),
+                    parent: None,
+                };
+                pos = after;
+                children.push(child);
+                continue;
+            }
+            _ => return vec![],
+        };
+        pos = next;
+        children.push(child);
+    }
+    children
+}
+
+/// The row of a node: key, value, type, atom, id, parent, fullkey and path.
+fn row(blob: &[u8], visit: &Visit) -> Vec<Value<'static>> {
+    let node = decode_node(blob, visit.pos, 0).map_or(Json::Null, |(node, _)| node);
+    let atom = match node {
+        Json::Array(_) | Json::Object(_) => Value::Null,
+        _ => node.to_value(),
+    };
+    vec![
+        visit.key.clone(),
+        node.to_value(),
+        Value::String(node.type_name().into()),
+        atom,
+        Value::Int(visit.id as i64),
+        visit.parent.map_or(Value::Null, |p| Value::Int(p as i64)),
+        Value::String(visit.fullkey.clone().into()),
+        Value::String(visit.path.clone().into()),
+    ]
+}
+
+/// Whether the function embeds its arguments in the JSON it makes, so that it takes the JSON other JSON functions
+/// return as JSON rather than as text.
+fn embeds_arguments(name: &str) -> bool {
+    let name = name.to_ascii_lowercase();
+    let name = name
+        .strip_prefix("jsonb")
+        .or_else(|| name.strip_prefix("json"))
+        .unwrap_or("");
+    [
+        "_array",
+        "_object",
+        "_set",
+        "_insert",
+        "_replace",
+        "_quote",
+        "_group_array",
+        "_group_object",
+    ]
+    .contains(&name)
+}
+
+/// Whether the expression is a call always returning JSON text, which SQLite marks with the JSON subtype.
+fn returns_json(expr: &Expr) -> bool {
+    const JSON_FUNCTIONS: &[&str] = &[
+        "json",
+        "json_array",
+        "json_group_array",
+        "json_group_object",
+        "json_insert",
+        "json_object",
+        "json_quote",
+        "json_remove",
+        "json_replace",
+        "json_set",
+    ];
+    match expr {
+        Expr::Function { name, .. } => JSON_FUNCTIONS.iter().any(|f| f.eq_ignore_ascii_case(name)),
+        Expr::Binary(_, op, _) => *op == parser::BinaryOp::Arrow,
+        _ => false,
+    }
+}
+
+/// Rewrites the arguments of a call to a function embedding them in JSON so that the JSON functions among them return
+/// JSONB, which takes the place of SQLite's JSON subtype: `json_array(json_object('a', 1))` embeds an object rather
+/// than a string. `json_extract()` becomes `jsonb_extract()`, whose strings and numbers stay SQL values, the others
+/// are wrapped in `jsonb()`.
+pub fn mark_json_arguments<'a>(name: &str, args: &mut [Expr<'a>]) {
+    if !embeds_arguments(name) {
+        return;
+    }
+    for arg in args {
+        match arg {
+            Expr::Function { name, .. } if name.eq_ignore_ascii_case("json_extract") => *name = "jsonb_extract",
+            arg if returns_json(arg) => {
+                let json = std::mem::replace(arg, Expr::Literal(Value::Null));
+                *arg = Expr::Function {
+                    name: "jsonb",
+                    args: vec![json],
+                    distinct: false,
+                    function: None,
+                };
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(s: &str) -> Value<'static> {
+        Value::String(s.to_string().into())
+    }
+
+    fn json_text(s: &str) -> String {
+        parse(s).unwrap().0.to_text()
+    }
+
+    #[test]
+    fn canonical_text() {
+        assert_eq!(
+            json_text(r#" { "a" : [1, 2.50, "xA", true, null] , "b":{} } "#),
+            r#"{"a":[1,2.50,"xA",true,null],"b":{}}"#
+        );
+        assert_eq!(
+            json_text("{a:1, 'b':0x1F, c:.5, d:5., e:+1, f:Infinity, g:-Infinity, h:NaN, i:[1,2,],}"),
+            r#"{"a":1,"b":31,"c":0.5,"d":5.0,"e":1,"f":9e999,"g":-9e999,"h":null,"i":[1,2]}"#
+        );
+        assert_eq!(
+            json_text(r#"['a"b', "aA", 'x\'y', "\x41", "a\/b"]"#),
+            r#"["a\"b","aA","x'y","\u0041","a\/b"]"#
+        );
+        assert_eq!(json_text("// c\n[1 /* x */]"), "[1]");
+        assert!(parse("[1,2").is_none());
+        assert!(parse("{\"a\"}").is_none());
+        assert!(parse("01").is_none());
+        assert!(parse("").is_none());
+        assert_eq!(parse("[1]").map(|(_, json5)| json5), Some(false));
+        assert_eq!(parse("{a:1}").map(|(_, json5)| json5), Some(true));
+    }
+
+    #[test]
+    fn jsonb() {
+        let hex = |json: &str| {
+            let blob = parse(json).unwrap().0.to_blob();
+            blob.iter().map(|b| format!("{b:02X}")).collect::<String>()
+        };
+        // The encodings sqlite3 makes.
+        assert_eq!(hex(r#"{"a":[1,2.5,"x",true,null]}"#), "CC0D1761AB133135322E3517780100");
+        assert_eq!(hex("0x1F"), "4430783146");
+        assert_eq!(hex(r#""a\tb""#), "48615C7462");
+        assert_eq!(
+            hex("[NaN, Infinity, -Infinity, .5, 5., +1, 0x1f, -0x10, 1e5, 1E+5]"),
+            "CB2A00553965393939652D3965393939262E3526352E13314430783166542D30783130353165354531452B35"
+        );
+        let blob = parse(r#"{"a":[1,2.5,"x\n",true,null]}"#).unwrap().0.to_blob();
+        assert_eq!(decode(&blob).unwrap().to_text(), r#"{"a":[1,2.5,"x\n",true,null]}"#);
+        assert!(decode(&blob[..blob.len() - 1]).is_none());
+        assert!(decode(&[0x13]).is_none());
+        assert_eq!(decode(&[0x2b, 0x13, 0x31]).unwrap().to_text(), "[1]");
+    }
+
+    #[test]
+    fn paths() {
+        use Step::*;
+        assert_eq!(
+            parse_path(r#"$.a[2]."b c"[#-1][#]"#).unwrap(),
+            [Key("a".into()), Index(2), Key("b c".into()), FromEnd(1), FromEnd(0)]
+        );
+        for bad in ["a", "$a", "$[x]", "$.", "$[1", "$[#1]"] {
+            assert_eq!(
+                parse_path(bad).unwrap_err().to_string(),
+                format!("bad JSON path: '{bad}'")
+            );
+        }
+        let doc = text(r#"{"a":{"b":[1,2,3]},"c":"x"}"#);
+        assert_eq!(
+            extract(&[doc.clone(), text("$.a.b[1]"), text("$.a")], false).unwrap(),
+            text(r#"[2,{"b":[1,2,3]}]"#)
+        );
+        assert_eq!(extract(&[doc.clone(), text("$.c")], false).unwrap(), text("x"));
+        assert_eq!(
+            extract(&[doc.clone(), text("$.a.b[#-1]")], false).unwrap(),
+            Value::Int(3)
+        );
+        assert_eq!(extract(&[doc.clone(), text("$.z")], false).unwrap(), Value::Null);
+        assert_eq!(arrow(&doc, &text("c"), false).unwrap(), text("\"x\""));
+        assert_eq!(arrow(&doc, &text("c"), true).unwrap(), text("x"));
+        assert_eq!(arrow(&text("[5,6]"), &Value::Int(-1), true).unwrap(), Value::Int(6));
+    }
+
+    #[test]
+    fn edits() {
+        let call = |f: fn(&[Value], bool) -> Result<Value<'static>>, args: &[&str]| {
+            f(&args.iter().map(|a| text(a)).collect::<Vec<_>>(), false)
+                .unwrap()
+                .to_string()
+        };
+        assert_eq!(
+            call(set, &[r#"{"a":1}"#, "$.b", "2", "$.a", "3"]),
+            r#"{"a":"3","b":"2"}"#
+        );
+        assert_eq!(call(set, &["{}", "$.a.b[#].c", "1"]), r#"{"a":{"b":[{"c":"1"}]}}"#);
+        assert_eq!(call(set, &["[]", "$[1]", "1"]), "[]");
+        assert_eq!(
+            call(insert, &[r#"{"a":1}"#, "$.a", "9", "$.c[#]", "4"]),
+            r#"{"a":1,"c":["4"]}"#
+        );
+        assert_eq!(call(replace, &[r#"{"a":1}"#, "$.a", "x", "$.z", "1"]), r#"{"a":"x"}"#);
+        assert_eq!(call(remove, &["[1,2,3]", "$[0]", "$[#-1]"]), "[2]");
+        assert_eq!(call(remove, &[r#"{"a":1,"a":2}"#, "$.a"]), r#"{"a":2}"#);
+        assert_eq!(call(set, &[r#"{"a":1}"#, "$.a.b", "2"]), r#"{"a":1}"#);
+        assert_eq!(remove(&[text("[1]"), text("$")], false).unwrap(), Value::Null);
+        let err = set(&[text("{}"), text("$.a")], false).unwrap_err();
+        assert_eq!(err.to_string(), "json_set() needs an odd number of arguments");
+    }
+
+    #[test]
+    fn sql_values() {
+        let values = [
+            Value::Float(0.1),
+            Value::Float(1e100),
+            Value::Float(100.0),
+            text("a\"b\u{1}"),
+            Value::Null,
+        ];
+        assert_eq!(
+            array(&values, false).unwrap(),
+            text(r#"[0.1,1.0e+100,100.0,"a\"b\u0001",null]"#)
+        );
+        assert_eq!(
+            array(&[Value::Blob(vec![0].into())], false).unwrap(),
+            text("[null]"),
+            "blobs are JSONB"
+        );
+        let err = array(&[Value::Blob(vec![0xff].into())], false).unwrap_err();
+        assert_eq!(err.to_string(), "JSON cannot hold BLOB values");
+        let err = object(&[Value::Int(1), Value::Int(1)], false).unwrap_err();
+        assert_eq!(err.to_string(), "json_object() labels must be TEXT");
+        let json = text(r#"[0x1F, 1e400, 99999999999999999999, .5, "aA\n", -0x10, true]"#);
+        let values: Vec<_> = (0..7).map(|i| arrow(&json, &Value::Int(i), true).unwrap()).collect();
+        assert_eq!(
+            values,
+            [
+                Value::Int(31),
+                Value::Float(f64::INFINITY),
+                Value::Float(1e20),
+                Value::Float(0.5),
+                text("aA\n"),
+                Value::Int(-16),
+                Value::Int(1)
+            ]
+        );
+    }
+
+    #[test]
+    fn json_arguments() {
+        let marked = |sql| {
+            let Expr::Function { name, mut args, .. } = parser::sql::expr(sql).unwrap() else {
+                unreachable!()
+            };
+            mark_json_arguments(name, &mut args);
+            args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>()
+        };
+        assert_eq!(
+            marked("json_array(json('[1]'), '[2]', json_extract(x, '$.a'), x -> 'a', x ->> 'a')"),
+            [
+                "jsonb(json('[1]'))",
+                "'[2]'",
+                "jsonb_extract(x, '$.a')",
+                "jsonb(x -> 'a')",
+                "x ->> 'a'"
+            ]
+        );
+        assert_eq!(
+            marked("jsonb_set(x, '$.a', json_object())"),
+            ["x", "'$.a'", "jsonb(json_object())"]
+        );
+        assert_eq!(marked("length(json('[1]'))"), ["json('[1]')"]);
+    }
+
+    #[test]
+    fn table_rows() {
+        let rows = tree(&[text(r#"{"a":1,"b":[2,3],"c":{"d":"x"}}"#)]).unwrap();
+        let rows: Vec<_> = rows
+            .iter()
+            .map(|row| row.iter().map(|v| v.to_string()).collect::<Vec<_>>().join("|"))
+            .collect();
+        // The rows sqlite3 gives.
+        assert_eq!(
+            rows,
+            [
+                r#"|{"a":1,"b":[2,3],"c":{"d":"x"}}|object||0||$|$"#,
+                "a|1|integer|1|2|0|$.a|$",
+                "b|[2,3]|array||6|0|$.b|$",
+                "0|2|integer|2|9|6|$.b[0]|$.b",
+                "1|3|integer|3|11|6|$.b[1]|$.b",
+                r#"c|{"d":"x"}|object||13|0|$.c|$"#,
+                "d|x|text|x|16|13|$.c.d|$.c",
+            ]
+        );
+        let rows = each(&[text(r#"{"a":{"b":[1,2]}}"#), text("$.a.b")]).unwrap();
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[1][6], text("$.a.b[1]"));
+        assert_eq!(rows[1][7], text("$.a.b"));
+        assert!(each(&[text("x")]).is_err());
+        assert!(each(&[Value::Null]).unwrap().is_empty());
+    }
+}
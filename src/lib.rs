@@ -0,0 +1,42 @@
+//! A reader of SQLite databases.
+//!
+//! A [`Connection`] opens a database and prepares statements, which run as many times as needed with the values bound
+//! to their parameters. The rows of a [`Statement`] are read one at a time from its [`Rows`], and the values of a
+//! [`Row`] as any type implementing [`FromSql`].
+
+mod aggregate;
+mod analyze;
+mod btree;
+mod codegen;
+mod connection;
+mod datetime;
+mod expr;
+mod functions;
+mod json;
+mod pager;
+mod planner;
+mod query_plan;
+mod record;
+mod row;
+mod sorter;
+mod spill;
+mod statement;
+mod varint;
+mod vm;
+mod window;
+
+use btree::Database;
+use btree::Entry;
+use record::parse_record;
+
+pub use aggregate::Aggregate;
+pub use connection::Connection;
+pub use functions::FunctionFlags;
+pub use parser::Value;
+pub use record::Schema;
+pub use row::FromSql;
+pub use row::Row;
+pub use row::RowIndex;
+pub use statement::Rows;
+pub use statement::Statement;
+pub use vm::Config;
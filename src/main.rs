@@ -1,44 +1,48 @@
-use std::fs::File;
-
 use anyhow::Context;
 use anyhow::Ok;
 use anyhow::Result;
 use clap::Parser;
+use rusqlite::Config;
+use rusqlite::Connection;
 
-mod btree;
 mod cli;
-mod record;
-mod varint;
-mod vm;
 
-use btree::Database;
-use btree::Entry;
 use cli::Args;
 use cli::Cmd;
-use record::Schema;
-use record::SerialType;
-use varint::read_varint;
 
 fn main() -> Result<()> {
-    let Args { cmd, db_path, query } = Args::parse();
+    let Args {
+        cmd,
+        db_path,
+        query,
+        memory_limit,
+    } = Args::parse();
 
-    let file = File::open(&db_path)?;
-    let db = Database::open(&file)?;
+    let conn = Connection::open_with_config(&db_path, Config { memory_limit })?;
 
     match cmd {
         Some(Cmd::DatabaseInfo) => {
-            println!("database page size: {}", db.page_size);
-            println!("number of tables: {}", db.get_page(1).entries().count());
+            println!("database page size: {}", conn.page_size());
+            println!("number of tables: {}", conn.schema().len());
         }
         Some(Cmd::Tables) => {
-            for cell in db.get_page(1).entries() {
-                print!("{} ", Schema::new(cell.payload).tbl_name);
+            for schema in conn.schema() {
+                print!("{} ", schema.tbl_name);
             }
             println!()
         }
         None => {
             let query = query.context("no command or query provided")?;
-            vm::handle_query(&db, &query)?;
+            let mut statement = conn.prepare(&query)?;
+            if let Some(listing) = statement.explain() {
+                print!("{listing}");
+                return Ok(());
+            }
+            let mut rows = statement.query(&[])?;
+            while let Some(row) = rows.next()? {
+                let values: Vec<_> = row.values().iter().map(|v| v.to_string()).collect();
+                println!("{}", values.join("|"));
+            }
         }
     }
     Ok(())
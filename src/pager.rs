@@ -0,0 +1,606 @@
+//! Writing to the database file.
+//!
+//! Changes are made to copies of the pages in memory and written out all at once when the transaction commits, see
+//! <https://sqlite.org/fileformat2.html> for the layout of the pages and of the header at the start of the file. The
+//! file is locked the way SQLite locks it, see <https://sqlite.org/lockingv3.html>, and the pages about to be
+//! overwritten go to a rollback journal first, which SQLite copies back if the commit doesn't finish. Reading the
+//! database takes no lock.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::hash::BuildHasher;
+use std::hash::RandomState;
+use std::io;
+use std::io::Read;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+
+use anyhow::Context;
+use anyhow::Result;
+use anyhow::bail;
+
+use crate::Database;
+use crate::btree::PageNumber;
+use crate::varint::write_varint;
+
+const PT_INTERIOR_TABLE: u8 = 0x05;
+const PT_LEAF_TABLE: u8 = 0x0d;
+/// Size of the database header, which page 1 starts with.
+const HEADER_SIZE: usize = 100;
+
+/// Offset of the byte locked for a PENDING lock. The page it's on is never used, so the locks can be taken on the
+/// bytes around it.
+const PENDING_BYTE: u64 = 0x4000_0000;
+/// Offset of the byte locked for a RESERVED lock.
+const RESERVED_BYTE: u64 = PENDING_BYTE + 1;
+/// The range of bytes locked for SHARED and EXCLUSIVE locks.
+const SHARED_FIRST: u64 = PENDING_BYTE + 2;
+const SHARED_SIZE: u64 = 510;
+
+/// The bytes a rollback journal starts with.
+const JOURNAL_MAGIC: [u8; 8] = [0xd9, 0xd5, 0x05, 0xf9, 0x20, 0xa1, 0x63, 0xd7];
+/// Size of the header of the journal, which takes a sector.
+const SECTOR_SIZE: usize = 512;
+
+/// Changes to the database being made. The database has to be mapped again to see them once they are committed, which
+/// the connection whose statement made them does.
+pub struct Transaction {
+    /// The database file opened for writing, which the locks are taken on. Closing a file releases all the locks the
+    /// process has on it, so they're released when the transaction is dropped, and not before.
+    file: File,
+    /// The database as it is once it's locked.
+    db: Database,
+    journal: PathBuf,
+    /// The pages changed so far.
+    pages: BTreeMap<PageNumber, Vec<u8>>,
+    page_count: PageNumber,
+    /// Pages no b-tree uses anymore, they are handed out again before the file grows and the rest go to the freelist.
+    freed: Vec<PageNumber>,
+    schema_changed: bool,
+}
+
+/// A page of a b-tree under construction, with the largest rowid it holds.
+struct Child {
+    page: PageNumber,
+    key: i64,
+}
+
+impl Transaction {
+    /// Starts changing the database at `path`. It takes a RESERVED lock, which other connections can go on reading
+    /// with but not start writing.
+    pub fn new(path: &Path) -> Result<Self> {
+        let file = OpenOptions::new().read(true).write(true).open(path);
+        let file = file.context("attempt to write a readonly database")?;
+        // A SHARED lock is taken while holding the PENDING byte, which a writer waiting for the readers to finish
+        // holds.
+        lock(&file, Lock::Read, PENDING_BYTE, 1)?;
+        let shared = lock(&file, Lock::Read, SHARED_FIRST, SHARED_SIZE);
+        lock(&file, Lock::Unlock, PENDING_BYTE, 1)?;
+        shared?;
+        lock(&file, Lock::Write, RESERVED_BYTE, 1)?;
+        // The journal of a commit that didn't finish holds the pages to put back, which SQLite does the next time it
+        // opens the database. Nobody else holds the RESERVED lock, so nobody is writing it.
+        let mut journal = path.as_os_str().to_owned();
+        journal.push("-journal");
+        let journal = PathBuf::from(journal);
+        if is_hot(&journal)? {
+            bail!("cannot write to a database with a hot journal, which SQLite rolls back when it opens it");
+        }
+        let db = Database::open(&file)?;
+        // In WAL mode the latest version of the pages may be in the write-ahead log rather than in the file.
+        if db.mmap[18] == 2 || db.mmap[19] == 2 {
+            bail!("cannot write to a database in WAL mode");
+        }
+        // Auto-vacuum databases have pointer map pages telling the parent of each page, which aren't kept up to date.
+        if db.mmap[52..56] != [0; 4] {
+            bail!("cannot write to a database in auto-vacuum mode");
+        }
+        Ok(Self {
+            file,
+            page_count: db.page_count,
+            db,
+            journal,
+            pages: BTreeMap::new(),
+            freed: vec![],
+            schema_changed: false,
+        })
+    }
+
+    /// The database the changes are made to.
+    pub fn database(&self) -> &Database {
+        &self.db
+    }
+
+    /// Records that `sqlite_schema` changed, which tells the connections that read it to read it again.
+    pub fn schema_changed(&mut self) {
+        self.schema_changed = true;
+    }
+
+    /// Frees the pages of a b-tree, all but its root when `keep_root` is set.
+    pub fn free_tree(&mut self, root: PageNumber, keep_root: bool) {
+        let pages = self.db.tree_pages(root);
+        self.freed.extend(pages.into_iter().skip(keep_root as usize));
+    }
+
+    /// A page to write to, a freed one if there is some.
+    pub fn allocate(&mut self) -> PageNumber {
+        match self.freed.pop() {
+            Some(page) => page,
+            None => {
+                self.page_count += 1;
+                // The page the locks are taken on is left out of the b-trees.
+                if self.page_count as u64 == PENDING_BYTE / self.db.page_size as u64 + 1 {
+                    self.page_count += 1;
+                }
+                self.page_count
+            }
+        }
+    }
+
+    /// Writes a table b-tree holding `rows`, record payloads sorted by rowid, over the pages of the one rooted at
+    /// `root`, which has to be freed beforehand but for the root.
+    pub fn write_table(&mut self, root: PageNumber, rows: &[(i64, Vec<u8>)]) {
+        let cells: Vec<_> = rows
+            .iter()
+            .map(|(rowid, payload)| (*rowid, self.leaf_cell(*rowid, payload)))
+            .collect();
+        let cells: Vec<_> = cells.iter().map(|(rowid, cell)| (*rowid, cell.as_slice())).collect();
+        if self.fits(root, PT_LEAF_TABLE, &cells) {
+            self.write_page(root, PT_LEAF_TABLE, &cells, None);
+            return;
+        }
+        let mut level = self.pack(PT_LEAF_TABLE, &cells);
+        // Interior pages point to the pages below them, up to a level that fits in the root.
+        loop {
+            let interior: Vec<_> = level[..level.len() - 1].iter().map(interior_cell).collect();
+            let interior: Vec<_> = level
+                .iter()
+                .zip(&interior)
+                .map(|(c, cell)| (c.key, cell.as_slice()))
+                .collect();
+            let right = level.last().unwrap();
+            if self.fits(root, PT_INTERIOR_TABLE, &interior) {
+                self.write_page(root, PT_INTERIOR_TABLE, &interior, Some(right.page));
+                return;
+            }
+            level = self.pack_interior(&level);
+        }
+    }
+
+    /// Writes the commit to the file.
+    pub fn commit(mut self) -> Result<()> {
+        self.write()?;
+        // Deleting the journal is what commits the changes, they're rolled back while it's there.
+        fs::remove_file(&self.journal)?;
+        Ok(())
+    }
+
+    /// Writes the changed pages to the file, after the journal has the pages they overwrite.
+    fn write(&mut self) -> Result<()> {
+        self.free_pages();
+        let header = self.page(1);
+        let change_counter = u32::from_be_bytes(header[24..28].try_into().unwrap()).wrapping_add(1);
+        header[24..28].copy_from_slice(&change_counter.to_be_bytes());
+        header[92..96].copy_from_slice(&change_counter.to_be_bytes());
+        let page_count = self.page_count;
+        self.page(1)[28..32].copy_from_slice(&page_count.to_be_bytes());
+        if self.schema_changed {
+            let header = self.page(1);
+            let cookie = u32::from_be_bytes(header[40..44].try_into().unwrap()).wrapping_add(1);
+            header[40..44].copy_from_slice(&cookie.to_be_bytes());
+        }
+        // The PENDING lock keeps new readers out while waiting for the others to finish, which they have once the
+        // EXCLUSIVE lock is taken. They aren't waited for, the lock is refused while there are some.
+        lock(&self.file, Lock::Write, PENDING_BYTE, 1)?;
+        lock(&self.file, Lock::Write, SHARED_FIRST, SHARED_SIZE)?;
+        if let Err(err) = self.write_journal() {
+            _ = fs::remove_file(&self.journal);
+            return Err(err);
+        }
+        let page_size = self.db.page_size as u64;
+        for (number, data) in &self.pages {
+            write_at(&self.file, data, (*number as u64 - 1) * page_size)?;
+        }
+        self.file.sync_all()?;
+        Ok(())
+    }
+
+    /// Writes the pages the commit overwrites to the journal, see
+    /// <https://sqlite.org/fileformat2.html#the_rollback_journal>. The pages past the end of the database aren't in
+    /// it, rolling back cuts the file back to the size the header of the journal gives.
+    fn write_journal(&self) -> Result<()> {
+        let page_size = self.db.page_size as usize;
+        let pages: Vec<_> = self.pages.keys().filter(|n| **n <= self.db.page_count).collect();
+        let nonce = RandomState::new().hash_one(&self.journal) as u32;
+        let mut journal = Vec::with_capacity(SECTOR_SIZE + pages.len() * (page_size + 8));
+        journal.extend(JOURNAL_MAGIC);
+        journal.extend((pages.len() as u32).to_be_bytes());
+        journal.extend(nonce.to_be_bytes());
+        journal.extend(self.db.page_count.to_be_bytes());
+        journal.extend((SECTOR_SIZE as u32).to_be_bytes());
+        journal.extend((page_size as u32).to_be_bytes());
+        journal.resize(SECTOR_SIZE, 0);
+        for number in pages {
+            let data = self.db.page_data(*number);
+            journal.extend(number.to_be_bytes());
+            journal.extend(data);
+            journal.extend(checksum(nonce, data).to_be_bytes());
+        }
+        let mut file = File::create(&self.journal)?;
+        file.write_all(&journal)?;
+        file.sync_all()?;
+        // The directory is synced too, for the journal to be found after a crash.
+        #[cfg(unix)]
+        if let Some(dir) = self.journal.parent() {
+            File::open(dir)?.sync_all()?;
+        }
+        Ok(())
+    }
+
+    /// Puts the freed pages that weren't used again on the freelist. Each trunk page of the freelist lists freed pages,
+    /// and points to the next trunk page.
+    fn free_pages(&mut self) {
+        let per_trunk = self.db.usable_size as usize / 4 - 8;
+        let mut freed = std::mem::take(&mut self.freed);
+        freed.sort_unstable();
+        for chunk in freed.chunks(per_trunk + 1) {
+            let header = self.page(1);
+            let first_trunk: [u8; 4] = header[32..36].try_into().unwrap();
+            let count = u32::from_be_bytes(header[36..40].try_into().unwrap()) + chunk.len() as u32;
+            header[32..36].copy_from_slice(&chunk[0].to_be_bytes());
+            header[36..40].copy_from_slice(&count.to_be_bytes());
+            let trunk = self.page(chunk[0]);
+            trunk.fill(0);
+            trunk[0..4].copy_from_slice(&first_trunk);
+            trunk[4..8].copy_from_slice(&(chunk.len() as u32 - 1).to_be_bytes());
+            for (i, leaf) in chunk[1..].iter().enumerate() {
+                trunk[8 + i * 4..12 + i * 4].copy_from_slice(&leaf.to_be_bytes());
+            }
+        }
+    }
+
+    /// The copy of a page to change, read from the file the first time.
+    fn page(&mut self, number: PageNumber) -> &mut Vec<u8> {
+        let db = &self.db;
+        self.pages
+            .entry(number)
+            .or_insert_with(|| match number <= db.page_count {
+                true => db.page_data(number).to_vec(),
+                false => vec![0; db.page_size as usize],
+            })
+    }
+
+    /// Cell of a table leaf page, the payload spilling to overflow pages when it's too large.
+    fn leaf_cell(&mut self, rowid: i64, payload: &[u8]) -> Vec<u8> {
+        let mut cell = vec![];
+        write_varint(&mut cell, payload.len() as u64);
+        write_varint(&mut cell, rowid as u64);
+        let local = self.db.local_payload_size(payload.len() as u64, false);
+        cell.extend_from_slice(&payload[..local]);
+        if local < payload.len() {
+            let chunks: Vec<_> = payload[local..].chunks(self.db.usable_size as usize - 4).collect();
+            let pages: Vec<_> = chunks.iter().map(|_| self.allocate()).collect();
+            cell.extend_from_slice(&pages[0].to_be_bytes());
+            for (i, chunk) in chunks.iter().enumerate() {
+                let next = pages.get(i + 1).copied().unwrap_or(0);
+                let page = self.page(pages[i]);
+                page.fill(0);
+                page[..4].copy_from_slice(&next.to_be_bytes());
+                page[4..4 + chunk.len()].copy_from_slice(chunk);
+            }
+        }
+        cell
+    }
+
+    /// Bytes available for cells and their pointers on a page.
+    fn capacity(&self, number: PageNumber, page_type: u8) -> usize {
+        let start = if number == 1 { HEADER_SIZE } else { 0 };
+        let header = if page_type == PT_INTERIOR_TABLE { 12 } else { 8 };
+        self.db.usable_size as usize - start - header
+    }
+
+    fn fits(&self, number: PageNumber, page_type: u8, cells: &[(i64, &[u8])]) -> bool {
+        cells.iter().map(|(_, cell)| cell.len() + 2).sum::<usize>() <= self.capacity(number, page_type)
+    }
+
+    /// Spreads leaf cells over as many new pages as they need.
+    fn pack(&mut self, page_type: u8, cells: &[(i64, &[u8])]) -> Vec<Child> {
+        let capacity = self.capacity(2, page_type);
+        let mut children = vec![];
+        let mut start = 0;
+        while start < cells.len() {
+            let mut end = start;
+            let mut used = 0;
+            while end < cells.len() && used + cells[end].1.len() + 2 <= capacity {
+                used += cells[end].1.len() + 2;
+                end += 1;
+            }
+            let page = self.allocate();
+            self.write_page(page, page_type, &cells[start..end], None);
+            children.push(Child {
+                page,
+                key: cells[end - 1].0,
+            });
+            start = end;
+        }
+        children
+    }
+
+    /// Makes a level of interior pages over the pages of the level below. An interior page has a cell for each child
+    /// but the last one, its right child.
+    fn pack_interior(&mut self, level: &[Child]) -> Vec<Child> {
+        let capacity = self.capacity(2, PT_INTERIOR_TABLE);
+        let mut parents = vec![];
+        let mut start = 0;
+        while start < level.len() {
+            let mut end = start + 1;
+            let mut used = 0;
+            // The child at `end - 1` becomes a cell once a child comes after it.
+            while end < level.len() && used + interior_cell(&level[end - 1]).len() + 2 <= capacity {
+                used += interior_cell(&level[end - 1]).len() + 2;
+                end += 1;
+            }
+            // An interior page without cells isn't valid, the last child can't be left alone.
+            if level.len() - end == 1 && end - start > 2 {
+                end -= 1;
+            }
+            let cells: Vec<_> = level[start..end - 1].iter().map(interior_cell).collect();
+            let cells: Vec<_> = level[start..]
+                .iter()
+                .zip(&cells)
+                .map(|(c, cell)| (c.key, cell.as_slice()))
+                .collect();
+            let page = self.allocate();
+            self.write_page(page, PT_INTERIOR_TABLE, &cells, Some(level[end - 1].page));
+            parents.push(Child {
+                page,
+                key: level[end - 1].key,
+            });
+            start = end;
+        }
+        parents
+    }
+
+    /// Writes a b-tree page holding `cells`, their content packed at the end of the page.
+    fn write_page(&mut self, number: PageNumber, page_type: u8, cells: &[(i64, &[u8])], right: Option<PageNumber>) {
+        let usable = self.db.usable_size as usize;
+        let start = if number == 1 { HEADER_SIZE } else { 0 };
+        let page = self.page(number);
+        page[start..].fill(0);
+        page[start] = page_type;
+        page[start + 3..start + 5].copy_from_slice(&(cells.len() as u16).to_be_bytes());
+        let mut pointer = start + 8;
+        if let Some(right) = right {
+            page[start + 8..start + 12].copy_from_slice(&right.to_be_bytes());
+            pointer += 4;
+        }
+        let mut content = usable;
+        for (_, cell) in cells {
+            content -= cell.len();
+            page[content..content + cell.len()].copy_from_slice(cell);
+            page[pointer..pointer + 2].copy_from_slice(&(content as u16).to_be_bytes());
+            pointer += 2;
+        }
+        // A cell content area starting at 65536 is written as 0.
+        page[start + 5..start + 7].copy_from_slice(&(content as u16).to_be_bytes());
+    }
+}
+
+/// Checksum of a page in the journal, which SQLite checks before putting the page back. It adds one byte in 200 to the
+/// nonce of the journal, from the end of the page.
+fn checksum(nonce: u32, data: &[u8]) -> u32 {
+    (1..)
+        .map(|i| i * 200)
+        .take_while(|back| *back < data.len())
+        .fold(nonce, |sum, back| sum.wrapping_add(data[data.len() - back] as u32))
+}
+
+/// Whether the journal was left by a commit that didn't finish, rather than being missing or emptied.
+fn is_hot(journal: &Path) -> Result<bool> {
+    let mut first = [0];
+    match File::open(journal) {
+        Ok(mut file) => Ok(file.read(&mut first)? == 1 && first[0] != 0),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(err) => Err(err.into()),
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Lock {
+    Read,
+    Write,
+    Unlock,
+}
+
+/// Locks `len` bytes of the file from `start`, failing rather than waiting when another process holds a lock on them
+/// it conflicts with.
+fn lock(file: &File, lock: Lock, start: u64, len: u64) -> Result<()> {
+    match set_lock(file, lock, start, len) {
+        Err(err) if err.kind() == io::ErrorKind::WouldBlock => bail!("database is locked"),
+        result => result.context("cannot lock the database"),
+    }
+}
+
+#[cfg(unix)]
+fn set_lock(file: &File, lock: Lock, start: u64, len: u64) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+    // SAFETY: All zeroes is a valid `flock`, whose fields are integers.
+    let mut flock: libc::flock = unsafe { std::mem::zeroed() };
+    flock.l_type = match lock {
+        Lock::Read => libc::F_RDLCK,
+        Lock::Write => libc::F_WRLCK,
+        Lock::Unlock => libc::F_UNLCK,
+    } as _;
+    flock.l_whence = libc::SEEK_SET as _;
+    flock.l_start = start as _;
+    flock.l_len = len as _;
+    // SAFETY: The file descriptor is open for as long as the file is borrowed.
+    if unsafe { libc::fcntl(file.as_raw_fd(), libc::F_SETLK, &flock) } == 0 {
+        return Ok(());
+    }
+    // Some systems tell a conflicting lock by EACCES rather than EAGAIN.
+    match io::Error::last_os_error() {
+        err if err.raw_os_error() == Some(libc::EACCES) => Err(io::ErrorKind::WouldBlock.into()),
+        err => Err(err),
+    }
+}
+
+/// Locking the file the way SQLite does takes `LockFileEx()` on Windows, which isn't supported.
+#[cfg(not(unix))]
+fn set_lock(_: &File, _: Lock, _: u64, _: u64) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+/// Writes all of `data` at `offset` in the file.
+#[cfg(unix)]
+fn write_at(file: &File, data: &[u8], offset: u64) -> io::Result<()> {
+    std::os::unix::fs::FileExt::write_all_at(file, data, offset)
+}
+
+/// Writes all of `data` at `offset` in the file, which `seek_write()` may take a few calls to.
+#[cfg(windows)]
+fn write_at(file: &File, mut data: &[u8], mut offset: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !data.is_empty() {
+        match file.seek_write(data, offset)? {
+            0 => return Err(io::ErrorKind::WriteZero.into()),
+            n => {
+                data = &data[n..];
+                offset += n as u64;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Cell of a table interior page, pointing to a child and holding the largest rowid below it.
+fn interior_cell(child: &Child) -> Vec<u8> {
+    let mut cell = child.page.to_be_bytes().to_vec();
+    write_varint(&mut cell, child.key as u64);
+    cell
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::BufRead;
+    use std::io::BufReader;
+    use std::process::Command;
+    use std::process::Stdio;
+
+    use super::*;
+    use crate::Schema;
+
+    /// A copy of sample.db to write to, with its journal.
+    struct Copy(PathBuf);
+
+    impl Copy {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!("rusqlite-{}-pager-{name}.db", std::process::id()));
+            fs::copy(concat!(env!("CARGO_MANIFEST_DIR"), "/sample.db"), &path).unwrap();
+            Self(path)
+        }
+
+        fn journal(&self) -> PathBuf {
+            PathBuf::from(format!("{}-journal", self.0.display()))
+        }
+    }
+
+    impl Drop for Copy {
+        fn drop(&mut self) {
+            _ = fs::remove_file(&self.0);
+            _ = fs::remove_file(self.journal());
+        }
+    }
+
+    /// Runs the sqlite3 shell, `None` when it isn't installed.
+    fn sqlite3(path: &Path, sql: &str) -> Option<String> {
+        let output = Command::new("sqlite3").arg(path).arg(sql).output().ok()?;
+        Some(String::from_utf8(output.stdout).unwrap().trim_end().to_string())
+    }
+
+    /// Empties the table `apples`.
+    fn empty_apples(tx: &mut Transaction) {
+        let root = tx
+            .database()
+            .get_page(1)
+            .entries()
+            .map(|e| Schema::new(e.payload))
+            .find(|s| s.name == "apples")
+            .unwrap()
+            .rootpage;
+        tx.free_tree(root, true);
+        tx.write_table(root, &[]);
+    }
+
+    #[test]
+    fn commit() {
+        let copy = Copy::new("commit");
+        let mut tx = Transaction::new(&copy.0).unwrap();
+        empty_apples(&mut tx);
+        tx.commit().unwrap();
+        assert!(!copy.journal().exists());
+        assert!(sqlite3(&copy.0, "SELECT count(*) FROM apples").is_none_or(|count| count == "0"));
+    }
+
+    #[test]
+    fn rollback() {
+        let copy = Copy::new("rollback");
+        let mut tx = Transaction::new(&copy.0).unwrap();
+        empty_apples(&mut tx);
+        // The commit stops before deleting the journal, as if it crashed.
+        tx.write().unwrap();
+        drop(tx);
+        let err = Transaction::new(&copy.0).err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "cannot write to a database with a hot journal, which SQLite rolls back when it opens it"
+        );
+        // SQLite puts the pages in the journal back, which needs their checksums to match.
+        if let Some(count) = sqlite3(&copy.0, "SELECT count(*) FROM apples") {
+            assert_eq!(count, "4");
+            assert!(!copy.journal().exists());
+            assert_eq!(sqlite3(&copy.0, "PRAGMA integrity_check").unwrap(), "ok");
+            Transaction::new(&copy.0).unwrap();
+        }
+    }
+
+    #[test]
+    fn locked() {
+        let copy = Copy::new("locked");
+        // The shell holds a RESERVED lock from BEGIN IMMEDIATE until its input ends.
+        let Ok(mut shell) = Command::new("sqlite3")
+            .arg(&copy.0)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+        else {
+            return;
+        };
+        let mut stdin = shell.stdin.take().unwrap();
+        stdin.write_all(b"BEGIN IMMEDIATE;\nSELECT 'begun';\n").unwrap();
+        let mut line = String::new();
+        BufReader::new(shell.stdout.take().unwrap())
+            .read_line(&mut line)
+            .unwrap();
+        assert_eq!(line, "begun\n");
+        let err = Transaction::new(&copy.0).err().unwrap();
+        assert_eq!(err.to_string(), "database is locked");
+        drop(stdin);
+        shell.wait().unwrap();
+        Transaction::new(&copy.0).unwrap();
+    }
+
+    #[test]
+    fn journal_checksum() {
+        let mut page = vec![0; 1024];
+        page[1024 - 200] = 1;
+        page[1024 - 400] = 2;
+        page[1024 - 1000] = 3;
+        page[1024 - 1001] = 100;
+        assert_eq!(checksum(10, &page), 16);
+        assert_eq!(checksum(u32::MAX, &page), 5);
+    }
+}
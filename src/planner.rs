@@ -0,0 +1,2568 @@
+//! Planning of SELECT statements.
+//!
+//! A SELECT is turned into a [`Query`]: names are resolved against the tables it reads, `*` is expanded, the WHERE
+//! conjuncts are attached to the tables they need and the way each table is read is picked. Code generation then turns
+//! the query into a program for the virtual machine.
+
+use std::borrow::Cow;
+use std::cell::Cell;
+use std::cell::RefCell;
+use std::collections::HashSet;
+
+use anyhow::Context;
+use anyhow::Result;
+use anyhow::bail;
+use parser::BinaryOp;
+use parser::Collation;
+use parser::ColumnDef;
+use parser::CompoundOp;
+use parser::CreateIndex;
+use parser::CreateTable;
+use parser::Cte;
+use parser::Expr;
+use parser::FrameBound;
+use parser::FrameExclude;
+use parser::FrameUnits;
+use parser::IndexedColumn;
+use parser::JoinConstraint;
+use parser::Limit;
+use parser::OrderingTerm;
+use parser::ResultColumn;
+use parser::Select;
+use parser::SqlType;
+use parser::TableSource;
+use parser::Value;
+use parser::WindowDef;
+use parser::sql;
+
+use crate::Database;
+use crate::Schema;
+use crate::analyze::Stat;
+use crate::analyze::read_stats;
+use crate::btree::PageNumber;
+use crate::expr::collation;
+use crate::expr::comparison_affinity;
+use crate::expr::comparison_collation;
+use crate::functions::Functions;
+use crate::functions::TableFunction;
+use crate::functions::find_table_function;
+use crate::json::mark_json_arguments;
+use crate::sorter::SortOrder;
+use crate::window::Bound;
+use crate::window::Frame;
+use crate::window::WindowCall;
+use crate::window::WindowFunction;
+use crate::window::WindowPass;
+use crate::window::WindowSpec;
+use crate::window::find_window_function;
+
+/// A SELECT with its clauses resolved against the tables it reads. Column references are qualified with the name of
+/// their table.
+///
+/// Queries form a tree: the subqueries of the FROM clause and of expressions are compiled into queries of their own,
+/// which run as part of the query containing them.
+pub struct Query<'a> {
+    pub tables: Vec<Table<'a>>,
+    /// The WHERE clause of a select without a FROM clause, the conjuncts go to the tables otherwise.
+    pub filter: Vec<Expr<'a>>,
+    /// Whether repeated result rows are left out, like SELECT DISTINCT does.
+    pub distinct: bool,
+    pub columns: Vec<OutputColumn<'a>>,
+    pub group_by: Vec<Expr<'a>>,
+    pub having: Option<Expr<'a>>,
+    pub order_by: Vec<Term<'a>>,
+    pub order: Vec<SortOrder>,
+    /// Distinct aggregate function calls of the result columns, HAVING and ORDER BY.
+    pub calls: Vec<Expr<'a>>,
+    pub limit: Option<Limit<'a>>,
+    pub subqueries: Vec<Subquery<'a>>,
+    /// Columns of the enclosing queries the query refers to, their values are given to it when it runs.
+    pub outer: Vec<(&'a str, &'a str)>,
+}
+
+/// A result column.
+pub struct OutputColumn<'a> {
+    pub expr: Expr<'a>,
+    pub alias: Option<&'a str>,
+    /// The alias, or the name of the column the expression is, or else the expression as written.
+    pub name: &'a str,
+    pub affinity: Option<SqlType>,
+}
+
+/// A subquery of an expression.
+pub struct Subquery<'a> {
+    /// The `(SELECT ...)`, `EXISTS (SELECT ...)` or `x IN (SELECT ...)` expression, evaluating it runs the subquery.
+    pub expr: Expr<'a>,
+    pub query: Query<'a>,
+    /// Affinity of the first result column.
+    pub affinity: Option<SqlType>,
+}
+
+/// A table of the FROM clause, along with how its rows are joined to the ones of the tables before it.
+pub struct Table<'a> {
+    /// Name qualified column references use, the alias if the table has one.
+    pub name: &'a str,
+    pub ct: CreateTable<'a>,
+    pub source: Source<'a>,
+    pub indexes: Vec<(PageNumber, CreateIndex<'a>)>,
+    /// Whether it's the right side of a LEFT JOIN, which adds a row of NULLs when none of its rows match.
+    pub left: bool,
+    /// Conjuncts of the ON clause of a LEFT JOIN, the ones of inner joins go to `filter` like WHERE conjuncts do.
+    pub on: Vec<Expr<'a>>,
+    /// Conjuncts of the WHERE clause that can be checked once the rows of this table and the ones before are known.
+    pub filter: Vec<Expr<'a>>,
+    pub access: Access<'a>,
+    /// Estimated number of rows.
+    rows: u64,
+    /// Estimated number of rows sharing a value of the first column of each of the indexes.
+    key_rows: Vec<u64>,
+}
+
+pub enum Source<'a> {
+    /// A table of the database, the page is the root of its b-tree.
+    Btree(PageNumber),
+    /// A subquery of the FROM clause or a common table expression. Its rows are produced as they are read when it's
+    /// the first table, and are kept in memory while the query runs otherwise.
+    Subquery(Box<Query<'a>>),
+    /// A recursive common table expression, its rows are produced like the ones of a subquery.
+    Recursive(Box<RecursiveQuery<'a>>),
+    /// The selects of a compound select, the query reading it sorts and limits its rows.
+    Compound(Box<CompoundQuery<'a>>),
+    /// A recursive common table expression read by one of its recursive selects, its one row is the row the work queue
+    /// is at.
+    Current,
+    /// A table-valued function, called for every row of the tables before it since its arguments can refer to them.
+    Function {
+        function: TableFunction,
+        name: &'a str,
+        args: Vec<Expr<'a>>,
+    },
+    /// The rows of a select calling window functions, with the results of the calls. The query reading it computes
+    /// the result columns from them, and sorts and limits its rows.
+    Window(Box<WindowQuery<'a>>),
+}
+
+/// A recursive common table expression. Its rows are the ones of the initial selects, followed by the ones the
+/// recursive selects produce from each row added, which wait their turn in a work queue.
+pub struct RecursiveQuery<'a> {
+    pub initial: Vec<Query<'a>>,
+    pub recursive: Vec<Query<'a>>,
+    /// Whether rows that were added already are left out, like UNION does and UNION ALL doesn't.
+    pub distinct: bool,
+    /// The columns ordering the work queue, rows come out first in first out otherwise.
+    pub order_by: Vec<usize>,
+    pub order: Vec<SortOrder>,
+    /// Number of rows taken out of the queue, the recursion stops once it's reached.
+    pub limit: Option<Limit<'a>>,
+    /// Subqueries of the LIMIT.
+    pub subqueries: Vec<Subquery<'a>>,
+    pub outer: Vec<(&'a str, &'a str)>,
+}
+
+/// The rows of selects combined by UNION, UNION ALL, INTERSECT and EXCEPT, from left to right. Like SQLite, the
+/// distinct rows the operators other than UNION ALL leave come out sorted.
+pub struct CompoundQuery<'a> {
+    pub parts: Vec<Query<'a>>,
+    /// The operator combining the rows of each select after the first with the ones of the selects before it.
+    pub ops: Vec<CompoundOp>,
+    pub outer: Vec<(&'a str, &'a str)>,
+}
+
+/// The rows of a select calling window functions, which computes the values the calls and the rest of the result
+/// columns need. The window adds the results of the calls to them once it has read all of them.
+pub struct WindowQuery<'a> {
+    pub query: Query<'a>,
+    pub spec: WindowSpec,
+    /// The names of the tables the passes of the window stand for in query plans, from the last pass to the first.
+    pub names: Vec<&'a str>,
+}
+
+impl Query<'_> {
+    /// Whether the rows are grouped, which a GROUP BY, a HAVING or an aggregate function call does.
+    pub fn is_aggregate(&self) -> bool {
+        !self.calls.is_empty() || !self.group_by.is_empty() || self.having.is_some()
+    }
+}
+
+impl<'a> Table<'a> {
+    fn new(
+        name: &'a str,
+        ct: CreateTable<'a>,
+        source: Source<'a>,
+        indexes: Vec<(PageNumber, CreateIndex<'a>)>,
+    ) -> Self {
+        // Without statistics, the guesses are the ones SQLite makes: a million rows, and ten for each value of an
+        // index unless its values are unique.
+        let key_rows = indexes
+            .iter()
+            .map(|(_, index)| {
+                if index.unique && index.columns.len() == 1 {
+                    1
+                } else {
+                    10
+                }
+            })
+            .collect();
+        let rows = if matches!(source, Source::Current) {
+            1
+        } else {
+            DEFAULT_ROWS
+        };
+        Self {
+            name,
+            ct,
+            source,
+            indexes,
+            left: false,
+            on: vec![],
+            filter: vec![],
+            access: Access::Scan,
+            rows,
+            key_rows,
+        }
+    }
+
+    /// Columns of the enclosing queries a FROM subquery refers to.
+    pub fn outer(&self) -> &[(&'a str, &'a str)] {
+        match &self.source {
+            Source::Subquery(query) => &query.outer,
+            Source::Recursive(query) => &query.outer,
+            Source::Compound(query) => &query.outer,
+            Source::Window(query) => &query.query.outer,
+            Source::Btree(_) | Source::Current | Source::Function { .. } => &[],
+        }
+    }
+}
+
+/// How the rows of a joined table matching a row of the tables before it are found.
+pub enum Access<'a> {
+    /// Reads the whole table for every row of the tables before it.
+    Scan,
+    /// Looks up the row whose rowid is the value of the expression.
+    Rowid(Expr<'a>),
+    /// Walks the entries of an index whose first column is equal to the value of `key`.
+    Index {
+        root: PageNumber,
+        desc: bool,
+        key: Expr<'a>,
+        affinity: Option<SqlType>,
+    },
+    /// Walks the rows whose rowid is between the bounds, from the lower one.
+    RowidRange {
+        lower: Option<KeyBound<'a>>,
+        upper: Option<KeyBound<'a>>,
+    },
+    /// Walks the entries of an index whose first column is between the bounds, from the lower one.
+    IndexRange {
+        root: PageNumber,
+        lower: Option<KeyBound<'a>>,
+        upper: Option<KeyBound<'a>>,
+    },
+    /// Looks up the rows whose `column` is equal to the value of `key` in a hash table of the whole table, which is
+    /// spilled to a temporary file sorted by `column` when it doesn't fit in memory.
+    Hash {
+        column: Expr<'a>,
+        key: Expr<'a>,
+        affinity: Option<SqlType>,
+    },
+}
+
+/// A bound of the keys of a range.
+#[derive(Clone)]
+pub struct KeyBound<'a> {
+    pub key: Expr<'a>,
+    /// Whether the keys equal to it are in the range.
+    pub inclusive: bool,
+    /// The affinity the key is compared with.
+    pub affinity: Option<SqlType>,
+}
+
+/// A comparison bounding the values a column of a table has in the rows matching a row of the tables before it.
+struct Constraint<'a> {
+    /// The column, `None` for the rowid.
+    column: Option<&'a str>,
+    /// Whether the values are above the bound rather than below.
+    lower: bool,
+    bound: KeyBound<'a>,
+}
+
+/// Number of rows of a table assumed when ANALYZE didn't count them.
+const DEFAULT_ROWS: u64 = 1 << 20;
+
+/// What the planner reads from the database, kept for as long as the queries compiled against it so the schema is read
+/// once for all of them.
+pub struct Catalog {
+    /// The rows of `sqlite_schema`.
+    schema: Vec<Schema>,
+    /// The rows of `sqlite_stat1`, which estimate the cost of reading the tables.
+    stats: Vec<Stat>,
+    names: Names,
+}
+
+impl Catalog {
+    pub fn new(db: &Database) -> Self {
+        Self {
+            schema: db.get_page(1).entries().map(|e| Schema::new(e.payload)).collect(),
+            stats: read_stats(db),
+            names: Names::default(),
+        }
+    }
+
+    /// The rows of `sqlite_schema`.
+    pub fn schema(&self) -> &[Schema] {
+        &self.schema
+    }
+}
+
+/// Turns SELECT statements into queries.
+pub struct Planner<'a> {
+    functions: &'a Functions,
+    catalog: &'a Catalog,
+    /// Number of queries compiled so far, FROM subqueries without an alias are numbered after it like SQLite does.
+    compiled: Cell<usize>,
+    /// The common table expressions in scope, the innermost WITH clause last.
+    ctes: RefCell<Vec<CteScope<'a>>>,
+    /// Number of selects being compiled, the one that's compiled and the ones it's a subquery of.
+    depth: Cell<usize>,
+}
+
+/// A common table expression in scope.
+struct CteScope<'a> {
+    cte: Cte<'a>,
+    /// Number of tables in scope once the ones of its WITH clause are, the ones its select can read.
+    visible: usize,
+    state: CteState<'a>,
+}
+
+enum CteState<'a> {
+    Idle,
+    /// Its select is being compiled, reading the table there is a circular reference.
+    Compiling,
+    /// One of its recursive selects is being compiled, reading the table there reads the row the work queue is at.
+    Recursive {
+        ct: CreateTable<'a>,
+        /// The depth of the recursive select, which can't read the table from its subqueries.
+        depth: usize,
+        references: usize,
+    },
+}
+
+impl<'a> Planner<'a> {
+    pub fn new(catalog: &'a Catalog, functions: &'a Functions) -> Self {
+        Self {
+            functions,
+            catalog,
+            compiled: Cell::new(0),
+            ctes: RefCell::new(vec![]),
+            depth: Cell::new(0),
+        }
+    }
+
+    /// Plans a SELECT statement.
+    pub fn plan(&self, select: &Select<'a>) -> Result<Query<'a>> {
+        self.compile(select, None)
+    }
+
+    /// Compiles a SELECT. `outer` is the scope of the query it's a subquery of, where the columns its own tables don't
+    /// have are looked up.
+    fn compile(&self, select: &Select<'a>, outer: Option<&Scope<'_, 'a>>) -> Result<Query<'a>> {
+        let in_scope = self.enter_with(&select.with)?;
+        // The selects of a compound are compiled as selects of their own.
+        let query = if select.compound.is_empty() {
+            self.depth.set(self.depth.get() + 1);
+            let query = self.compile_select(select, outer);
+            self.depth.set(self.depth.get() - 1);
+            query
+        } else {
+            self.compile_compound(select, outer)
+        };
+        self.ctes.borrow_mut().truncate(in_scope);
+        query
+    }
+
+    /// Compiles a compound select into a query reading the rows of its selects as its one table, which applies the
+    /// ORDER BY and LIMIT of the compound.
+    fn compile_compound(&self, select: &Select<'a>, outer: Option<&Scope<'_, 'a>>) -> Result<Query<'a>> {
+        let first = compound_first(select);
+        let mut parts = vec![self.compile(&first, outer)?];
+        for (op, part) in &select.compound {
+            parts.push(self.compile_part(part, Some(*op), parts.first(), outer)?);
+        }
+        let name = self.catalog.names.add(format!("(compound-{})", self.compiled.get()));
+        let ct = self.result_table(name, &parts[0].columns, &[])?;
+        // The result columns are named after the ones of the first select.
+        let columns: Vec<_> = parts[0]
+            .columns
+            .iter()
+            .zip(&ct.columns)
+            .map(|(c, def)| OutputColumn {
+                expr: Expr::Column {
+                    table: Some(name),
+                    name: def.name,
+                },
+                alias: c.alias,
+                name: c.name,
+                affinity: c.affinity,
+            })
+            .collect();
+        let order_by = compound_order(&select.order_by, &parts.iter().collect::<Vec<_>>())?;
+        let (limit, subqueries) = self.compound_limit(&select.limit, outer)?;
+        let mut outer_columns: Vec<_> = parts.iter().flat_map(|q| q.outer.clone()).collect();
+        let compound = CompoundQuery {
+            parts,
+            ops: select.compound.iter().map(|(op, _)| *op).collect(),
+            outer: dedup(outer_columns.clone()),
+        };
+        for expr in limit.iter().flat_map(|l| std::iter::once(&l.count).chain(&l.offset)) {
+            outer_columns.extend(referenced_columns(expr, &subqueries));
+        }
+        Ok(Query {
+            tables: vec![Table::new(name, ct, Source::Compound(Box::new(compound)), vec![])],
+            filter: vec![],
+            distinct: false,
+            columns,
+            group_by: vec![],
+            having: None,
+            order_by: order_by.into_iter().map(Term::Column).collect(),
+            order: select
+                .order_by
+                .iter()
+                .map(|t| SortOrder {
+                    desc: t.desc,
+                    nulls_first: t.nulls_first,
+                    // The terms are result columns, only an explicit COLLATE gives them a collation.
+                    collation: collation(&t.expr, |_, _| Collation::Binary),
+                })
+                .collect(),
+            calls: vec![],
+            limit,
+            subqueries,
+            outer: dedup(outer_columns),
+        })
+    }
+
+    /// Brings the tables of a WITH clause in scope, returns the number of tables in scope before them.
+    fn enter_with(&self, with: &[Cte<'a>]) -> Result<usize> {
+        let in_scope = self.ctes.borrow().len();
+        for (i, cte) in with.iter().enumerate() {
+            if with[..i].iter().any(|c| c.name.eq_ignore_ascii_case(cte.name)) {
+                bail!("duplicate WITH table name: {}", cte.name);
+            }
+        }
+        // The tables of a WITH clause can read each other, whatever their order.
+        let visible = in_scope + with.len();
+        self.ctes.borrow_mut().extend(with.iter().map(|cte| CteScope {
+            cte: cte.clone(),
+            visible,
+            state: CteState::Idle,
+        }));
+        Ok(in_scope)
+    }
+
+    fn compile_select(&self, select: &Select<'a>, outer: Option<&Scope<'_, 'a>>) -> Result<Query<'a>> {
+        let select = &named_windows(select)?;
+        let mut refs: Vec<_> = select.from.iter().map(|t| (t, false, None)).collect();
+        refs.extend(select.joins.iter().map(|j| (&j.table, j.left, j.constraint.as_ref())));
+        let mut tables = vec![];
+        let mut subqueries = vec![];
+        for (t, left, _) in &refs {
+            let mut table = match &t.source {
+                TableSource::Table(name) => match self.cte_table(name, t.alias, outer)? {
+                    Some(table) => table,
+                    None => self.table(name, t.alias)?,
+                },
+                // Subqueries of the FROM clause can't refer to the tables next to them, only to the enclosing queries.
+                TableSource::Subquery(select) => self.derived_table(select, t.alias, outer)?,
+                // The arguments of a table-valued function can refer to the tables before it.
+                TableSource::Function { name, args } => {
+                    let scope = Scope::new(self.functions, &tables, &[], outer);
+                    let mut args = args.clone();
+                    for arg in &mut args {
+                        scope.qualify(arg)?;
+                        check_no_windows(arg)?;
+                        self.compile_subqueries(arg, &scope, &mut subqueries)?;
+                    }
+                    self.function_table(name, args, t.alias)?
+                }
+            };
+            table.left = *left;
+            tables.push(table);
+        }
+
+        // The conjuncts of ON and WHERE, with the table they are attached to and whether they come from the ON clause
+        // of a LEFT JOIN.
+        let mut conjuncts = vec![];
+        let mut hidden = vec![];
+        for (i, (_, left, constraint)) in refs.iter().enumerate() {
+            match constraint {
+                Some(JoinConstraint::On(on)) => {
+                    for mut expr in split_conjuncts(on) {
+                        let scope = Scope::new(self.functions, &tables, &hidden, outer);
+                        scope.qualify(&mut expr)?;
+                        check_no_windows(&expr)?;
+                        self.compile_subqueries(&expr, &scope, &mut subqueries)?;
+                        let last = last_table(&tables, &subqueries, &expr);
+                        if *left && last > i {
+                            bail!("ON clause references tables to its right");
+                        }
+                        conjuncts.push((if *left { i } else { last }, *left, expr));
+                    }
+                }
+                Some(JoinConstraint::Using(names)) => {
+                    for name in names {
+                        let scope = Scope::new(self.functions, &tables[..i], &hidden, None);
+                        let Some(outer) = scope
+                            .table_of(None, name)
+                            .ok()
+                            .filter(|_| has_column(&tables[i].ct, name))
+                        else {
+                            bail!("cannot join using column {name} - column not present in both tables");
+                        };
+                        let column = |table: &Table<'a>| Expr::Column {
+                            table: Some(table.name),
+                            name: declared_name(&table.ct, name),
+                        };
+                        let expr = Expr::binary(column(&tables[outer]), BinaryOp::Eq, column(&tables[i]));
+                        conjuncts.push((i, *left, expr));
+                        hidden.push((i, declared_name(&tables[i].ct, name)));
+                    }
+                }
+                None => {}
+            }
+        }
+
+        let scope = Scope::new(self.functions, &tables, &hidden, outer);
+        let mut columns = expand_columns(&scope, &select.columns)?;
+        for column in &mut columns {
+            scope.qualify(&mut column.expr)?;
+            column.affinity = scope.affinity(&column.expr);
+        }
+        let order_by = resolve_terms(
+            select.order_by.iter().map(|t| &t.expr),
+            &scope,
+            &columns,
+            Clause::OrderBy,
+        )?;
+        let mut group_by = vec![];
+        for term in resolve_terms(select.group_by.iter(), &scope, &columns, Clause::GroupBy)? {
+            let expr = match term {
+                Term::Column(i) => columns[i].expr.clone(),
+                Term::Expr(expr) => expr,
+            };
+            if !aggregate_calls(self.functions, [&expr]).is_empty() {
+                bail!("aggregate functions are not allowed in the GROUP BY clause");
+            }
+            check_no_windows(&expr)?;
+            group_by.push(expr);
+        }
+        let having = select
+            .having
+            .as_ref()
+            .map(|e| scope.resolve(e, &columns, false))
+            .transpose()?;
+        having.iter().try_for_each(check_no_windows)?;
+        let exprs = columns
+            .iter()
+            .map(|c| &c.expr)
+            .chain(order_by.iter().filter_map(Term::expr))
+            .chain(&group_by)
+            .chain(&having);
+        for expr in exprs {
+            self.compile_subqueries(expr, &scope, &mut subqueries)?;
+        }
+        if let Some(expr) = &select.expr {
+            let expr = scope.resolve(expr, &columns, false)?;
+            check_no_windows(&expr)?;
+            for expr in split_conjuncts(&expr) {
+                self.compile_subqueries(&expr, &scope, &mut subqueries)?;
+                conjuncts.push((last_table(&tables, &subqueries, &expr), false, expr));
+            }
+        }
+        // LIMIT and OFFSET can't refer to the tables of the query, only to the enclosing queries.
+        let mut limit = select.limit.clone();
+        let limit_scope = Scope::new(self.functions, &[], &[], outer);
+        for expr in limit
+            .iter_mut()
+            .flat_map(|l| std::iter::once(&mut l.count).chain(&mut l.offset))
+        {
+            limit_scope.qualify(expr)?;
+            check_no_windows(expr)?;
+            self.compile_subqueries(expr, &limit_scope, &mut subqueries)?;
+        }
+        let calls = aggregate_calls(
+            self.functions,
+            columns
+                .iter()
+                .map(|c| &c.expr)
+                .chain(&having)
+                .chain(order_by.iter().filter_map(Term::expr)),
+        );
+
+        // The tables of inner joins can be read in any order, the cheapest one is picked. Table-valued functions stay
+        // after the tables their arguments refer to.
+        let functions = tables.iter().any(|t| matches!(t.source, Source::Function { .. }));
+        if tables.len() > 1 && !tables.iter().any(|t| t.left) && !functions {
+            let scope = Scope::new(self.functions, &tables, &hidden, outer);
+            let exprs: Vec<_> = conjuncts.iter().map(|(_, _, expr)| expr).collect();
+            let order = join_order(&scope, &subqueries, &exprs);
+            if order.iter().enumerate().any(|(i, &t)| i != t) {
+                let mut from: Vec<_> = tables.into_iter().map(Some).collect();
+                tables = order.iter().map(|&t| from[t].take().unwrap()).collect();
+                for (t, _) in &mut hidden {
+                    *t = order.iter().position(|o| o == t).unwrap();
+                }
+                for (t, _, expr) in &mut conjuncts {
+                    *t = last_table(&tables, &subqueries, expr);
+                }
+            }
+        }
+        let mut filter = vec![];
+        for (i, on, expr) in conjuncts {
+            match tables.get_mut(i) {
+                None => filter.push(expr),
+                Some(table) if on => table.on.push(expr),
+                Some(table) => table.filter.push(expr),
+            }
+        }
+        // The first table can be looked up too when it's matched against the columns of an enclosing query.
+        let scope = Scope::new(self.functions, &tables, &hidden, outer);
+        let access: Vec<_> = (0..tables.len())
+            .map(|i| {
+                let table = &tables[i];
+                let terms = table.on.iter().chain(table.filter.iter().filter(|_| !table.left));
+                plan_join(&scope, &subqueries, i, terms, |t| t < i, i == 0).access
+            })
+            .collect();
+        for (table, access) in tables.iter_mut().zip(access) {
+            table.access = access;
+        }
+
+        // The columns that aren't the query's own come from the enclosing queries, like all the ones of LIMIT and of
+        // FROM subqueries.
+        let mut outer_columns = vec![];
+        let exprs = columns
+            .iter()
+            .map(|c| &c.expr)
+            .chain(order_by.iter().filter_map(Term::expr))
+            .chain(&group_by)
+            .chain(&having)
+            .chain(&filter)
+            .chain(tables.iter().flat_map(|t| t.on.iter().chain(&t.filter)));
+        for expr in exprs {
+            let columns = referenced_columns(expr, &subqueries);
+            outer_columns.extend(columns.into_iter().filter(|&c| table_index(&tables, c).is_none()));
+        }
+        for expr in limit.iter().flat_map(|l| std::iter::once(&l.count).chain(&l.offset)) {
+            outer_columns.extend(referenced_columns(expr, &subqueries));
+        }
+        for table in &tables {
+            outer_columns.extend(table.outer());
+            if let Source::Function { args, .. } = &table.source {
+                let columns = args.iter().flat_map(|arg| referenced_columns(arg, &subqueries));
+                outer_columns.extend(columns.filter(|&c| table_index(&tables, c).is_none()));
+            }
+        }
+
+        self.compiled.set(self.compiled.get() + 1);
+        let order = select
+            .order_by
+            .iter()
+            .zip(&order_by)
+            .map(|(t, term)| SortOrder {
+                desc: t.desc,
+                nulls_first: t.nulls_first,
+                collation: term_collation(&tables, &columns, term),
+            })
+            .collect();
+        let query = Query {
+            tables,
+            filter,
+            distinct: select.distinct,
+            group_by,
+            having,
+            order_by,
+            order,
+            calls,
+            columns,
+            limit,
+            subqueries,
+            outer: dedup(outer_columns),
+        };
+        let exprs = query.columns.iter().map(|c| &c.expr);
+        match exprs
+            .chain(query.order_by.iter().filter_map(Term::expr))
+            .any(|e| window_call(e).is_some())
+        {
+            true => self.window_query(query, outer),
+            false => Ok(query),
+        }
+    }
+
+    /// Splits a select calling window functions in two. The first query computes the values the calls need and the
+    /// parts of the result columns and ORDER BY terms outside of the calls, the window adds the results of the calls to
+    /// its rows, and the query reading them computes the result columns and sorts, dedups and limits the rows.
+    fn window_query(&self, query: Query<'a>, outer: Option<&Scope<'_, 'a>>) -> Result<Query<'a>> {
+        let mut calls = vec![];
+        let mut slots = vec![];
+        let exprs = query.columns.iter().map(|c| &c.expr);
+        for expr in exprs.chain(query.order_by.iter().filter_map(Term::expr)) {
+            window_slots(expr, &mut calls, &mut slots);
+        }
+        let slot = |expr: &Expr<'a>| {
+            slots
+                .iter()
+                .position(|s| s == expr)
+                .expect("value missing from the slots")
+        };
+
+        // The calls whose windows have the same PARTITION BY and ORDER BY are computed in the same pass. Like SQLite,
+        // the rows come out in the order of the window of the first call, which the last pass sorts them by.
+        let mut windows: Vec<(&WindowDef, Vec<&Expr>)> = vec![];
+        for call in &calls {
+            let Expr::Window { window, .. } = call else {
+                unreachable!()
+            };
+            let same = |w: &WindowDef| w.partition_by == window.partition_by && w.order_by == window.order_by;
+            match windows.iter_mut().find(|(w, _)| same(w)) {
+                Some((_, calls)) => calls.push(call),
+                None => windows.push((window, vec![call])),
+            }
+        }
+        let mut passes = vec![];
+        // The results of the calls come after the slots, in the order the passes add them.
+        let mut results = vec![];
+        for (window, calls) in windows.into_iter().rev() {
+            let mut pass = WindowPass {
+                partition: window.partition_by.iter().map(slot).collect(),
+                order: window.order_by.iter().map(|t| slot(&t.expr)).collect(),
+                sort: window
+                    .order_by
+                    .iter()
+                    .map(|t| SortOrder {
+                        desc: t.desc,
+                        nulls_first: t.nulls_first,
+                        collation: Collation::Binary,
+                    })
+                    .collect(),
+                calls: vec![],
+            };
+            for call in calls {
+                let Expr::Window { name, args, window, .. } = call else {
+                    unreachable!()
+                };
+                let function = match self.functions.aggregate(name, args.len()) {
+                    Some(new) => WindowFunction::Aggregate(new),
+                    None => {
+                        WindowFunction::Builtin(find_window_function(name, args.len())?.expect("not a window function"))
+                    }
+                };
+                pass.calls.push(WindowCall {
+                    function,
+                    args: args.iter().map(slot).collect(),
+                    frame: window_frame(window, slot),
+                });
+                results.push(call);
+            }
+            passes.push(pass);
+        }
+
+        let base = self.compiled.get() + 1;
+        let names: Vec<_> = (0..passes.len())
+            .map(|i| self.catalog.names.add(format!("(subquery-{})", base + i)))
+            .collect();
+        let name = names[0];
+        let columns: Vec<_> = (0..slots.len() + results.len())
+            .map(|i| self.catalog.names.add(format!("c{i}")))
+            .collect();
+        let column = |i: usize| Expr::Column {
+            table: Some(name),
+            name: columns[i],
+        };
+        let rewrite = |expr: &mut Expr<'a>| {
+            expr.walk_mut(&mut |e| {
+                let i = match results.iter().position(|c| *c == e) {
+                    Some(i) => slots.len() + i,
+                    None => match slots.iter().position(|s| s == e) {
+                        Some(i) => i,
+                        None => return true,
+                    },
+                };
+                *e = column(i);
+                false
+            })
+        };
+        let mut output = vec![];
+        for c in &query.columns {
+            let mut expr = c.expr.clone();
+            rewrite(&mut expr);
+            output.push(OutputColumn { expr, ..*c });
+        }
+        let mut order_by = vec![];
+        for term in &query.order_by {
+            order_by.push(match term {
+                Term::Column(i) => Term::Column(*i),
+                Term::Expr(expr) => {
+                    let mut expr = expr.clone();
+                    rewrite(&mut expr);
+                    Term::Expr(expr)
+                }
+            });
+        }
+
+        let scope = Scope::new(self.functions, &query.tables, &[], outer);
+        let affinities: Vec<_> = slots.iter().map(|e| scope.affinity(e)).collect();
+        let ct = CreateTable {
+            table_name: name,
+            columns: columns
+                .iter()
+                .enumerate()
+                .map(|(i, name)| ColumnDef {
+                    sql_type: affinities.get(i).copied().flatten().unwrap_or(SqlType::Blob),
+                    decl_type: "",
+                    name,
+                    primary_key: false,
+                    default: None,
+                    collation: None,
+                    generated: None,
+                })
+                .collect(),
+            primary_key: vec![],
+            key_columns: vec![],
+            rowid_alias: None,
+            without_rowid: true,
+        };
+        // The subqueries of LIMIT and OFFSET run where they are.
+        let limit = query.limit;
+        let (outer_subqueries, subqueries) = query.subqueries.into_iter().partition(|s| {
+            let mut exprs = limit.iter().flat_map(|l| std::iter::once(&l.count).chain(&l.offset));
+            exprs.any(|e| contains(e, &s.expr))
+        });
+        let asc = SortOrder {
+            desc: false,
+            nulls_first: true,
+            collation: Collation::Binary,
+        };
+        let first = &passes[0];
+        let mut inner = Query {
+            tables: query.tables,
+            filter: query.filter,
+            distinct: false,
+            columns: slots
+                .into_iter()
+                .zip(affinities)
+                .zip(&columns)
+                .map(|((expr, affinity), name)| OutputColumn {
+                    expr,
+                    alias: None,
+                    name,
+                    affinity,
+                })
+                .collect(),
+            group_by: query.group_by,
+            having: query.having,
+            // The rows are read in the order the first pass sorts them by when an index gives it.
+            order_by: first
+                .partition
+                .iter()
+                .chain(&first.order)
+                .map(|&i| Term::Column(i))
+                .collect(),
+            order: [vec![asc; first.partition.len()], first.sort.clone()].concat(),
+            calls: query.calls,
+            limit: None,
+            subqueries,
+            outer: query.outer.clone(),
+        };
+        if plan_strategy(&inner).sort {
+            inner.order_by.clear();
+            inner.order.clear();
+        }
+        let window = WindowQuery {
+            query: inner,
+            spec: WindowSpec { passes },
+            names,
+        };
+        Ok(Query {
+            tables: vec![Table::new(name, ct, Source::Window(Box::new(window)), vec![])],
+            filter: vec![],
+            distinct: query.distinct,
+            columns: output,
+            group_by: vec![],
+            having: None,
+            order_by,
+            order: query.order,
+            calls: vec![],
+            limit,
+            subqueries: outer_subqueries,
+            outer: query.outer,
+        })
+    }
+
+    /// A table of the database, named `alias` in the query if it has one.
+    fn table(&self, name: &'a str, alias: Option<&'a str>) -> Result<Table<'a>> {
+        let schema = self
+            .catalog
+            .schema
+            .iter()
+            .find(|s| s.ty == "table" && s.tbl_name.eq_ignore_ascii_case(name))
+            .with_context(|| format!("no such table: {name}"))?;
+        // The indexes SQLite makes for UNIQUE and PRIMARY KEY constraints have no sql to read their definition from.
+        let indexes = self
+            .catalog
+            .schema
+            .iter()
+            .filter(|s| s.ty == "index" && s.tbl_name.eq_ignore_ascii_case(name) && !s.sql.is_empty())
+            .filter_map(|s| sql::create_index(&s.sql).ok().map(|ci| (s.rootpage, ci)))
+            .collect();
+        let ct = sql::create_table(&schema.sql).expect("corrupt table");
+        let mut table = Table::new(alias.unwrap_or(name), ct, Source::Btree(schema.rootpage), indexes);
+        // The first number of the statistics of a table is its number of rows, whichever index they are about.
+        let stats: Vec<_> = self
+            .catalog
+            .stats
+            .iter()
+            .filter(|s| s.tbl.eq_ignore_ascii_case(name))
+            .collect();
+        if let Some(rows) = stats.iter().find_map(|s| s.counts().first().copied()) {
+            table.rows = rows;
+        }
+        for ((_, index), key_rows) in table.indexes.iter().zip(&mut table.key_rows) {
+            let stat = stats.iter().find(|s| {
+                s.idx
+                    .as_ref()
+                    .is_some_and(|idx| idx.eq_ignore_ascii_case(index.index_name))
+            });
+            if let Some(rows) = stat.and_then(|s| s.counts().get(1).copied()) {
+                *key_rows = rows;
+            }
+        }
+        Ok(table)
+    }
+
+    /// A subquery of the FROM clause, the columns of the table it stands for are its result columns.
+    fn derived_table(
+        &self,
+        select: &Select<'a>,
+        alias: Option<&'a str>,
+        outer: Option<&Scope<'_, 'a>>,
+    ) -> Result<Table<'a>> {
+        let query = self.compile(select, outer)?;
+        let name = match alias {
+            Some(alias) => alias,
+            None => self.catalog.names.add(format!("(subquery-{})", self.compiled.get())),
+        };
+        let ct = self.result_table(name, &query.columns, &[])?;
+        Ok(Table::new(name, ct, Source::Subquery(Box::new(query)), vec![]))
+    }
+
+    /// The table of a call to a table-valued function in the FROM clause.
+    fn function_table(&self, name: &'a str, args: Vec<Expr<'a>>, alias: Option<&'a str>) -> Result<Table<'a>> {
+        let Some((function, names)) = find_table_function(name, args.len())? else {
+            bail!("no such table: {name}");
+        };
+        let columns = names
+            .iter()
+            .map(|name| ColumnDef {
+                sql_type: SqlType::Blob,
+                decl_type: "",
+                name,
+                primary_key: false,
+                default: None,
+                collation: None,
+                generated: None,
+            })
+            .collect();
+        let ct = CreateTable {
+            table_name: name,
+            columns,
+            primary_key: vec![],
+            key_columns: vec![],
+            rowid_alias: None,
+            without_rowid: true,
+        };
+        let source = Source::Function { function, name, args };
+        Ok(Table::new(alias.unwrap_or(name), ct, source, vec![]))
+    }
+
+    /// The definition of a table whose rows are result rows, its columns are named `names`, or after the result
+    /// columns when there are none.
+    fn result_table(&self, name: &'a str, result: &[OutputColumn<'a>], names: &[&'a str]) -> Result<CreateTable<'a>> {
+        if !names.is_empty() && names.len() != result.len() {
+            bail!("table {name} has {} values for {} columns", result.len(), names.len());
+        }
+        let mut columns: Vec<ColumnDef> = vec![];
+        for (i, column) in result.iter().enumerate() {
+            let taken = |name: &str| columns.iter().any(|c| c.name.eq_ignore_ascii_case(name));
+            let name = names.get(i).copied().unwrap_or(column.name);
+            // Like SQLite, a name that's taken already gets a number, `x:1` for the second `x`.
+            let name = match taken(name) {
+                true => {
+                    let unique = (1..).map(|n| format!("{name}:{n}")).find(|n| !taken(n));
+                    self.catalog.names.add(unique.unwrap())
+                }
+                false => name,
+            };
+            columns.push(ColumnDef {
+                sql_type: column.affinity.unwrap_or(SqlType::Blob),
+                decl_type: "",
+                name,
+                primary_key: false,
+                default: None,
+                collation: None,
+                generated: None,
+            });
+        }
+        // The rows have no rowid, like the ones of WITHOUT ROWID tables.
+        Ok(CreateTable {
+            table_name: name,
+            columns,
+            primary_key: vec![],
+            key_columns: vec![],
+            rowid_alias: None,
+            without_rowid: true,
+        })
+    }
+
+    /// The table of the common table expression named `name`, if one is in scope.
+    fn cte_table(
+        &self,
+        name: &'a str,
+        alias: Option<&'a str>,
+        outer: Option<&Scope<'_, 'a>>,
+    ) -> Result<Option<Table<'a>>> {
+        let ctes = self.ctes.borrow();
+        let Some(i) = ctes.iter().rposition(|c| c.cte.name.eq_ignore_ascii_case(name)) else {
+            return Ok(None);
+        };
+        drop(ctes);
+        let alias = alias.unwrap_or(name);
+        match &mut self.ctes.borrow_mut()[i].state {
+            CteState::Idle => {}
+            CteState::Compiling => bail!("circular reference: {name}"),
+            CteState::Recursive { ct, depth, references } => {
+                if *depth != self.depth.get() {
+                    bail!("recursive reference in a subquery: {name}");
+                }
+                if *references > 0 {
+                    bail!("multiple references to recursive table: {name}");
+                }
+                *references += 1;
+                return Ok(Some(Table::new(alias, ct.clone(), Source::Current, vec![])));
+            }
+        }
+        // The select only sees the tables of its WITH clause and of the enclosing ones, not the ones in scope where
+        // it's read.
+        let (cte, visible) = {
+            let ctes = self.ctes.borrow();
+            (ctes[i].cte.clone(), ctes[i].visible)
+        };
+        let hidden = self.ctes.borrow_mut().split_off(visible);
+        let table = self.compile_cte(i, &cte, outer);
+        self.ctes.borrow_mut().extend(hidden);
+        let (ct, source) = table?;
+        Ok(Some(Table::new(alias, ct, source, vec![])))
+    }
+
+    fn set_cte_state(&self, i: usize, state: CteState<'a>) {
+        self.ctes.borrow_mut()[i].state = state;
+    }
+
+    /// Compiles the select of the common table expression at index `i` of the ones in scope.
+    fn compile_cte(
+        &self,
+        i: usize,
+        cte: &Cte<'a>,
+        outer: Option<&Scope<'_, 'a>>,
+    ) -> Result<(CreateTable<'a>, Source<'a>)> {
+        let select = &cte.select;
+        let first = compound_first(select);
+        let parts: Vec<_> = std::iter::once((None, &first))
+            .chain(select.compound.iter().map(|(op, s)| (Some(*op), s)))
+            .collect();
+        // The selects at the end of a compound of UNIONs that read the table in their FROM clause are the recursive
+        // ones, the selects before them are the initial ones.
+        let recursive = parts
+            .iter()
+            .rev()
+            .take_while(|(op, s)| {
+                matches!(op, Some(CompoundOp::Union | CompoundOp::UnionAll)) && reads_table(s, cte.name)
+            })
+            .count();
+        self.set_cte_state(i, CteState::Compiling);
+        if recursive == 0 {
+            let query = self.compile(select, outer)?;
+            self.set_cte_state(i, CteState::Idle);
+            let ct = self.result_table(cte.name, &query.columns, &cte.columns)?;
+            return Ok((ct, Source::Subquery(Box::new(query))));
+        }
+
+        // The tables of the WITH clause of the compound are in scope for all of its selects.
+        let in_scope = self.enter_with(&select.with)?;
+        let (initial_parts, recursive_parts) = parts.split_at(parts.len() - recursive);
+        let mut initial = vec![];
+        for (op, part) in initial_parts {
+            initial.push(self.compile_part(part, *op, initial.first(), outer)?);
+        }
+        let ct = self.result_table(cte.name, &initial[0].columns, &cte.columns)?;
+        let mut recursive = vec![];
+        for (op, part) in recursive_parts {
+            let state = CteState::Recursive {
+                ct: ct.clone(),
+                depth: self.depth.get() + 1,
+                references: 0,
+            };
+            self.set_cte_state(i, state);
+            let query = self.compile_part(part, *op, initial.first(), outer)?;
+            if !query.calls.is_empty() || !query.group_by.is_empty() {
+                bail!("recursive aggregate queries not supported");
+            }
+            recursive.push(query);
+        }
+        self.set_cte_state(i, CteState::Idle);
+        self.ctes.borrow_mut().truncate(in_scope);
+
+        let order_by = compound_order(&select.order_by, &initial.iter().chain(&recursive).collect::<Vec<_>>())?;
+        let (limit, subqueries) = self.compound_limit(&select.limit, outer)?;
+        let mut outer_columns: Vec<_> = initial.iter().chain(&recursive).flat_map(|q| q.outer.clone()).collect();
+        for expr in limit.iter().flat_map(|l| std::iter::once(&l.count).chain(&l.offset)) {
+            outer_columns.extend(referenced_columns(expr, &subqueries));
+        }
+        let query = RecursiveQuery {
+            initial,
+            recursive,
+            distinct: parts.iter().any(|(op, _)| *op == Some(CompoundOp::Union)),
+            order_by,
+            order: select
+                .order_by
+                .iter()
+                .map(|t| SortOrder {
+                    desc: t.desc,
+                    nulls_first: t.nulls_first,
+                    // The terms are result columns, only an explicit COLLATE gives them a collation.
+                    collation: collation(&t.expr, |_, _| Collation::Binary),
+                })
+                .collect(),
+            limit,
+            subqueries,
+            outer: dedup(outer_columns),
+        };
+        Ok((ct, Source::Recursive(Box::new(query))))
+    }
+
+    /// Compiles a select of a compound, which has to have as many result columns as the `first` one.
+    fn compile_part(
+        &self,
+        select: &Select<'a>,
+        op: Option<CompoundOp>,
+        first: Option<&Query<'a>>,
+        outer: Option<&Scope<'_, 'a>>,
+    ) -> Result<Query<'a>> {
+        let query = self.compile(select, outer)?;
+        if let (Some(op), Some(first)) = (op, first)
+            && query.columns.len() != first.columns.len()
+        {
+            bail!(
+                "SELECTs to the left and right of {} do not have the same number of result columns",
+                compound_keyword(op)
+            );
+        }
+        Ok(query)
+    }
+
+    /// The LIMIT of a compound, along with the subqueries of its expressions.
+    fn compound_limit(
+        &self,
+        limit: &Option<Limit<'a>>,
+        outer: Option<&Scope<'_, 'a>>,
+    ) -> Result<(Option<Limit<'a>>, Vec<Subquery<'a>>)> {
+        let mut limit = limit.clone();
+        let mut subqueries = vec![];
+        let scope = Scope::new(self.functions, &[], &[], outer);
+        for expr in limit
+            .iter_mut()
+            .flat_map(|l| std::iter::once(&mut l.count).chain(&mut l.offset))
+        {
+            scope.qualify(expr)?;
+            check_no_windows(expr)?;
+            self.compile_subqueries(expr, &scope, &mut subqueries)?;
+        }
+        Ok((limit, subqueries))
+    }
+
+    /// Compiles the subqueries of a qualified expression, the ones that are already compiled are left alone.
+    fn compile_subqueries(
+        &self,
+        expr: &Expr<'a>,
+        scope: &Scope<'_, 'a>,
+        subqueries: &mut Vec<Subquery<'a>>,
+    ) -> Result<()> {
+        let mut found = vec![];
+        expr.walk(&mut |e| {
+            if matches!(e, Expr::Subquery(_) | Expr::Exists(_) | Expr::InSelect { .. }) {
+                found.push(e);
+            }
+            true
+        });
+        for expr in found {
+            if subqueries.iter().any(|s| s.expr == *expr) {
+                continue;
+            }
+            let (Expr::Subquery(select) | Expr::Exists(select) | Expr::InSelect { select, .. }) = expr else {
+                unreachable!()
+            };
+            let query = self.compile(select, Some(scope))?;
+            if !matches!(expr, Expr::Exists(_)) && query.columns.len() != 1 {
+                bail!("sub-select returns {} columns - expected 1", query.columns.len());
+            }
+            subqueries.push(Subquery {
+                expr: expr.clone(),
+                affinity: query.columns[0].affinity,
+                query,
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Names compiling makes up, like `x:1` for the second column named `x` of a FROM subquery, kept for as long as the
+/// queries using them. Each is kept once, and none is ever removed.
+#[derive(Default)]
+struct Names(RefCell<HashSet<Box<str>>>);
+
+impl Names {
+    fn add(&self, name: String) -> &str {
+        let mut names = self.0.borrow_mut();
+        let name: *const str = match names.get(name.as_str()) {
+            Some(name) => &**name,
+            None => {
+                let name = name.into_boxed_str();
+                let text: *const str = &*name;
+                names.insert(name);
+                text
+            }
+        };
+        // SAFETY: The text of a name is on the heap, where it stays when the set grows, and it's only freed with the
+        // set since names are never removed.
+        unsafe { &*name }
+    }
+}
+
+/// The first select of a compound, without the clauses that belong to the whole compound.
+fn compound_first<'a>(select: &Select<'a>) -> Select<'a> {
+    Select {
+        with: vec![],
+        compound: vec![],
+        order_by: vec![],
+        limit: None,
+        ..select.clone()
+    }
+}
+
+/// Whether the FROM clause of the select reads the table.
+fn reads_table(select: &Select, name: &str) -> bool {
+    let tables = select.from.iter().chain(select.joins.iter().map(|j| &j.table));
+    tables
+        .into_iter()
+        .any(|t| matches!(t.source, TableSource::Table(n) if n.eq_ignore_ascii_case(name)))
+}
+
+pub fn compound_keyword(op: CompoundOp) -> &'static str {
+    match op {
+        CompoundOp::Union => "UNION",
+        CompoundOp::UnionAll => "UNION ALL",
+        CompoundOp::Intersect => "INTERSECT",
+        CompoundOp::Except => "EXCEPT",
+    }
+}
+
+/// Resolves the ORDER BY of a compound to the result columns its terms are, picked by position, by name, or by being
+/// the column a result column of one of the selects is.
+fn compound_order(terms: &[OrderingTerm], parts: &[&Query]) -> Result<Vec<usize>> {
+    let columns = &parts[0].columns;
+    let is_column = |expr: &Expr, table: Option<&str>, name: &str| matches!(expr, Expr::Column { table: t, name: n } if n.eq_ignore_ascii_case(name) && table.is_none_or(|table| t.is_some_and(|t| t.eq_ignore_ascii_case(table))));
+    let mut order_by = vec![];
+    for (i, term) in terms.iter().enumerate() {
+        let column = match &term.expr {
+            Expr::Literal(Value::Int(n)) => match usize::try_from(*n) {
+                Ok(n) if (1..=columns.len()).contains(&n) => n - 1,
+                _ => bail!(
+                    "{} ORDER BY term out of range - should be between 1 and {}",
+                    ordinal(i + 1),
+                    columns.len()
+                ),
+            },
+            Expr::Column { table: None, name }
+                if let Some(c) = columns.iter().position(|c| c.name.eq_ignore_ascii_case(name)) =>
+            {
+                c
+            }
+            Expr::Column { table, name }
+                if let Some(c) = parts
+                    .iter()
+                    .find_map(|q| q.columns.iter().position(|c| is_column(&c.expr, *table, name))) =>
+            {
+                c
+            }
+            _ => bail!(
+                "{} ORDER BY term does not match any column in the result set",
+                ordinal(i + 1)
+            ),
+        };
+        order_by.push(column);
+    }
+    Ok(order_by)
+}
+
+/// The columns without the repeated ones.
+fn dedup<'a>(columns: Vec<(&'a str, &'a str)>) -> Vec<(&'a str, &'a str)> {
+    let mut unique = vec![];
+    for column in columns {
+        if !unique.contains(&column) {
+            unique.push(column);
+        }
+    }
+    unique
+}
+
+/// The distinct aggregate function calls made by the expressions, not counting the ones nested in another.
+fn aggregate_calls<'e, 'a: 'e>(functions: &Functions, exprs: impl IntoIterator<Item = &'e Expr<'a>>) -> Vec<Expr<'a>> {
+    let mut calls = vec![];
+    for expr in exprs {
+        expr.walk(&mut |e| match e {
+            Expr::Function { name, args, .. } if functions.is_aggregate(name, args.len()) => {
+                if !calls.contains(e) {
+                    calls.push(e.clone());
+                }
+                false
+            }
+            _ => true,
+        });
+    }
+    calls
+}
+
+/// The select with the windows of the WINDOW clause that window function calls name replaced by their definition.
+fn named_windows<'s, 'a>(select: &'s Select<'a>) -> Result<Cow<'s, Select<'a>>> {
+    let named = |expr: &Expr| {
+        let mut named = false;
+        expr.walk(&mut |e| {
+            named |= matches!(e, Expr::Window { window, .. } if window.base.is_some());
+            !named
+        });
+        named
+    };
+    let exprs = select.columns.iter().filter_map(|c| match c {
+        ResultColumn::Expr { expr, .. } => Some(expr),
+        _ => None,
+    });
+    if !exprs.chain(select.order_by.iter().map(|t| &t.expr)).any(named) {
+        return Ok(Cow::Borrowed(select));
+    }
+    let mut select = select.clone();
+    let Select {
+        columns,
+        order_by,
+        windows,
+        ..
+    } = &mut select;
+    let exprs = columns.iter_mut().filter_map(|c| match c {
+        ResultColumn::Expr { expr, .. } => Some(expr),
+        _ => None,
+    });
+    let mut result = Ok(());
+    for expr in exprs.chain(order_by.iter_mut().map(|t| &mut t.expr)) {
+        expr.walk_mut(&mut |e| {
+            if let Expr::Window { window, .. } = e
+                && result.is_ok()
+            {
+                match window_def(windows, window) {
+                    Ok(def) => **window = def,
+                    Err(err) => result = Err(err),
+                }
+            }
+            true
+        });
+    }
+    result?;
+    Ok(Cow::Owned(select))
+}
+
+/// The window of a call, which can name a window of the WINDOW clause and add an ORDER BY and a frame to it. The
+/// windows of the clause can name the ones before them.
+fn window_def<'a>(windows: &[(&'a str, WindowDef<'a>)], call: &WindowDef<'a>) -> Result<WindowDef<'a>> {
+    let Some(name) = call.base else {
+        return Ok(call.clone());
+    };
+    let Some(i) = windows.iter().rposition(|(n, _)| n.eq_ignore_ascii_case(name)) else {
+        bail!("no such window: {name}");
+    };
+    let base = window_def(&windows[..i], &windows[i].1)?;
+    if !call.partition_by.is_empty() {
+        bail!("cannot override PARTITION clause of window: {name}");
+    }
+    if !base.order_by.is_empty() && !call.order_by.is_empty() {
+        bail!("cannot override ORDER BY clause of window: {name}");
+    }
+    if base.frame.is_some() && (call.frame.is_some() || !call.order_by.is_empty()) {
+        bail!("cannot override frame specification of window: {name}");
+    }
+    Ok(WindowDef {
+        base: None,
+        partition_by: base.partition_by,
+        order_by: match call.order_by.is_empty() {
+            true => base.order_by,
+            false => call.order_by.clone(),
+        },
+        frame: call.frame.clone().or(base.frame),
+    })
+}
+
+/// The name of the first window function the expression calls, if it calls one.
+fn window_call<'a>(expr: &Expr<'a>) -> Option<&'a str> {
+    let mut call = None;
+    expr.walk(&mut |e| {
+        if let Expr::Window { name, .. } = e {
+            call = Some(*name);
+        }
+        call.is_none()
+    });
+    call
+}
+
+/// Fails if the expression calls a window function, which only the result columns and the ORDER BY can.
+fn check_no_windows(expr: &Expr) -> Result<()> {
+    match window_call(expr) {
+        Some(name) => bail!("misuse of window function {name}()"),
+        None => Ok(()),
+    }
+}
+
+/// Checks the frame of a window: its start can't come after its end, and RANGE offsets are differences of the value
+/// of a single ORDER BY term.
+fn check_frame(window: &WindowDef) -> Result<()> {
+    let Some(frame) = &window.frame else {
+        return Ok(());
+    };
+    let rank = |bound: &FrameBound| match bound {
+        FrameBound::UnboundedPreceding => 0,
+        FrameBound::Preceding(_) => 1,
+        FrameBound::CurrentRow => 2,
+        FrameBound::Following(_) => 3,
+        FrameBound::UnboundedFollowing => 4,
+    };
+    let (start, end) = (rank(&frame.start), rank(&frame.end));
+    if start > end || start == 4 || end == 0 {
+        bail!("unsupported frame specification");
+    }
+    let offset = |bound: &FrameBound| matches!(bound, FrameBound::Preceding(_) | FrameBound::Following(_));
+    if frame.units == FrameUnits::Range && (offset(&frame.start) || offset(&frame.end)) && window.order_by.len() != 1 {
+        bail!("RANGE with offset PRECEDING/FOLLOWING requires one ORDER BY expression");
+    }
+    Ok(())
+}
+
+/// The offset of a frame bound. Like SQLite, offsets that aren't constants are NULL, which isn't a valid offset.
+fn frame_offset<'a>(expr: &Expr<'a>) -> Expr<'a> {
+    let mut constant = true;
+    expr.walk(&mut |e| {
+        constant &= !matches!(
+            e,
+            Expr::Column { .. } | Expr::Subquery(_) | Expr::Exists(_) | Expr::InSelect { .. }
+        );
+        constant
+    });
+    match constant {
+        true => expr.clone(),
+        false => Expr::Literal(Value::Null),
+    }
+}
+
+/// The values the window of a call needs: its PARTITION BY and ORDER BY terms and its frame offsets.
+fn window_values<'w, 'a>(window: &'w WindowDef<'a>) -> impl Iterator<Item = Expr<'a>> + 'w {
+    let bounds = window.frame.iter().flat_map(|f| [&f.start, &f.end]);
+    let offsets = bounds.filter_map(|bound| match bound {
+        FrameBound::Preceding(e) | FrameBound::Following(e) => Some(frame_offset(e)),
+        _ => None,
+    });
+    let terms = window
+        .partition_by
+        .iter()
+        .chain(window.order_by.iter().map(|t| &t.expr));
+    terms.cloned().chain(offsets)
+}
+
+/// Collects the distinct window function calls of an expression, and the values a window computes them from or the
+/// expression uses outside of them: the arguments and the values of the windows of the calls, and the largest parts
+/// of the expression not calling a window function. Literals stay where they are.
+fn window_slots<'a>(expr: &Expr<'a>, calls: &mut Vec<Expr<'a>>, slots: &mut Vec<Expr<'a>>) {
+    let add = |slots: &mut Vec<Expr<'a>>, expr: Expr<'a>| {
+        if !slots.contains(&expr) {
+            slots.push(expr);
+        }
+    };
+    expr.walk(&mut |e| match e {
+        Expr::Window { args, window, .. } => {
+            if !calls.contains(e) {
+                calls.push(e.clone());
+            }
+            for value in args.iter().cloned().chain(window_values(window)) {
+                add(slots, value);
+            }
+            false
+        }
+        Expr::Literal(_) => false,
+        e if window_call(e).is_none() => {
+            add(slots, e.clone());
+            false
+        }
+        _ => true,
+    });
+}
+
+/// The frame of the window of a call, `slot` giving the columns of the offsets. Without one, the frame is the rows up
+/// to the last one sorting like the current row.
+fn window_frame<'a>(window: &WindowDef<'a>, slot: impl Fn(&Expr<'a>) -> usize) -> Frame {
+    let Some(frame) = &window.frame else {
+        return Frame {
+            units: FrameUnits::Range,
+            start: Bound::UnboundedPreceding,
+            end: Bound::CurrentRow,
+            exclude: FrameExclude::NoOthers,
+        };
+    };
+    let bound = |bound: &FrameBound<'a>| match bound {
+        FrameBound::UnboundedPreceding => Bound::UnboundedPreceding,
+        FrameBound::Preceding(e) => Bound::Preceding(slot(&frame_offset(e))),
+        FrameBound::CurrentRow => Bound::CurrentRow,
+        FrameBound::Following(e) => Bound::Following(slot(&frame_offset(e))),
+        FrameBound::UnboundedFollowing => Bound::UnboundedFollowing,
+    };
+    Frame {
+        units: frame.units,
+        start: bound(&frame.start),
+        end: bound(&frame.end),
+        exclude: frame.exclude,
+    }
+}
+
+/// Whether the expression is `part` or has it among its parts.
+fn contains(expr: &Expr, part: &Expr) -> bool {
+    let mut found = false;
+    expr.walk(&mut |e| {
+        found |= e == part;
+        !found
+    });
+    found
+}
+
+/// Collects the columns the expression uses outside of aggregate function calls, the ones its subqueries take from
+/// the enclosing queries included.
+pub fn collect_bare_columns<'a>(
+    functions: &Functions,
+    expr: &Expr<'a>,
+    subqueries: &[Subquery<'a>],
+    columns: &mut Vec<(Option<&'a str>, &'a str)>,
+) {
+    let mut add = |column| {
+        if !columns.contains(&column) {
+            columns.push(column);
+        }
+    };
+    expr.walk(&mut |e| match e {
+        Expr::Function { name, args, .. } if functions.is_aggregate(name, args.len()) => false,
+        Expr::Column { table, name } => {
+            add((*table, *name));
+            false
+        }
+        Expr::Subquery(_) | Expr::Exists(_) | Expr::InSelect { .. } => {
+            for (table, name) in &subquery(subqueries, e).query.outer {
+                add((Some(*table), *name));
+            }
+            true
+        }
+        _ => true,
+    });
+}
+
+/// How the rows of a select are read and put in order.
+pub struct Strategy {
+    pub scan: Scan,
+    /// Whether the result rows go through a sorter, because they don't come in the order of the ORDER BY or because
+    /// the groups aren't read in key order.
+    pub sort: bool,
+    /// Whether the repeated rows of a SELECT DISTINCT come next to each other, so only the last one has to be kept to
+    /// leave them out.
+    pub adjacent: bool,
+}
+
+/// Picks how to read the first table of a select, which decides whether its rows have to be sorted.
+pub fn plan_strategy(q: &Query) -> Strategy {
+    let asc = SortOrder {
+        desc: false,
+        nulls_first: true,
+        collation: Collation::Binary,
+    };
+    if q.is_aggregate() {
+        // Rows that come ordered by the group key can be grouped as they are read, which keeps the groups in key
+        // order. Like SQLite, groups come out in key order when there's no ORDER BY.
+        let group_terms: Vec<_> = q.group_by.iter().cloned().map(Term::Expr).collect();
+        let order: Vec<_> = group_terms
+            .iter()
+            .map(|t| SortOrder {
+                collation: term_collation(&q.tables, &q.columns, t),
+                ..asc
+            })
+            .collect();
+        let scan = plan_scan(q, &group_terms, &order);
+        let sort = !q.order_by.is_empty() || !scan.sorted;
+        return Strategy {
+            scan,
+            sort,
+            adjacent: false,
+        };
+    }
+    let (scan, adjacent) = match q.distinct && q.order_by.is_empty() {
+        true => match distinct_scan(q) {
+            Some(scan) => (scan, true),
+            None => (plan_scan(q, &[], &[]), false),
+        },
+        false => (plan_scan(q, &q.order_by, &q.order), false),
+    };
+    Strategy {
+        sort: !scan.sorted,
+        scan,
+        adjacent,
+    }
+}
+
+/// Picks a way to read the first table of a SELECT DISTINCT without ORDER BY that puts the repeated rows next to
+/// each other, an index of the result columns walked either way, so only the last row has to be kept to leave them out.
+fn distinct_scan(q: &Query) -> Option<Scan> {
+    let terms: Vec<_> = (0..q.columns.len()).map(Term::Column).collect();
+    let order: Vec<_> = terms
+        .iter()
+        .map(|t| SortOrder {
+            desc: false,
+            nulls_first: true,
+            collation: term_collation(&q.tables, &q.columns, t),
+        })
+        .collect();
+    Some(plan_scan(q, &terms, &order)).filter(|scan| scan.sorted)
+}
+
+/// The result columns with `*` expanded to the columns of the tables.
+fn expand_columns<'a>(scope: &Scope<'_, 'a>, list: &[ResultColumn<'a>]) -> Result<Vec<OutputColumn<'a>>> {
+    let table_columns = |i: usize, all: bool| {
+        let table = &scope.tables[i];
+        table
+            .ct
+            .columns
+            .iter()
+            .filter(move |c| all || !scope.hidden.contains(&(i, c.name)))
+            .map(|c| {
+                let expr = Expr::Column {
+                    table: Some(table.name),
+                    name: c.name,
+                };
+                OutputColumn {
+                    expr,
+                    alias: None,
+                    name: c.name,
+                    affinity: None,
+                }
+            })
+    };
+    let mut columns = vec![];
+    for column in list {
+        match column {
+            ResultColumn::All if scope.tables.is_empty() => bail!("no tables specified"),
+            ResultColumn::All => columns.extend((0..scope.tables.len()).flat_map(|i| table_columns(i, false))),
+            ResultColumn::TableAll(name) => {
+                let i = scope
+                    .tables
+                    .iter()
+                    .position(|t| t.name.eq_ignore_ascii_case(name))
+                    .with_context(|| format!("no such table: {name}"))?;
+                columns.extend(table_columns(i, true));
+            }
+            ResultColumn::Expr { expr, alias, text } => {
+                let name = match expr {
+                    Expr::Column { name, .. } => name,
+                    _ => text,
+                };
+                columns.push(OutputColumn {
+                    expr: expr.clone(),
+                    alias: *alias,
+                    name: alias.unwrap_or(name),
+                    affinity: None,
+                });
+            }
+        }
+    }
+    Ok(columns)
+}
+
+fn alias_position(columns: &[OutputColumn], name: &str) -> Option<usize> {
+    columns
+        .iter()
+        .position(|c| c.alias.is_some_and(|a| a.eq_ignore_ascii_case(name)))
+}
+
+fn has_column(ct: &CreateTable, name: &str) -> bool {
+    column_affinity(ct, name).is_some()
+}
+
+/// Name of a column as the table declares it, identifiers are case-insensitive. The rowid and the columns the table
+/// doesn't have keep the name they're written with.
+fn declared_name<'a>(ct: &CreateTable<'a>, name: &'a str) -> &'a str {
+    ct.columns
+        .iter()
+        .find(|c| c.name.eq_ignore_ascii_case(name))
+        .map_or(name, |c| c.name)
+}
+
+/// Affinity of a column of the table, if it has one by that name.
+pub fn column_affinity(ct: &CreateTable, name: &str) -> Option<SqlType> {
+    match ct.columns.iter().find(|c| c.name.eq_ignore_ascii_case(name)) {
+        Some(c) => Some(c.sql_type),
+        None => (!ct.without_rowid && is_rowid_name(name)).then_some(SqlType::Integer),
+    }
+}
+
+/// Collation of a qualified column of the tables, BINARY for the columns of the enclosing queries. Unknown collations
+/// are reported when the column is loaded.
+fn table_collation(tables: &[Table], table: &str, name: &str) -> Collation {
+    match table_index(tables, (table, name)) {
+        Some(i) => column_collation(&tables[i].ct, name).unwrap_or_default(),
+        None => Collation::Binary,
+    }
+}
+
+/// Collation of a qualified expression about the tables.
+pub fn expr_collation(tables: &[Table], expr: &Expr) -> Collation {
+    collation(expr, |t, n| table_collation(tables, t, n))
+}
+
+/// Collation of an ORDER BY or GROUP BY term.
+fn term_collation(tables: &[Table], columns: &[OutputColumn], term: &Term) -> Collation {
+    match term {
+        Term::Column(i) => expr_collation(tables, &columns[*i].expr),
+        Term::Expr(expr) => expr_collation(tables, expr),
+    }
+}
+
+/// Collation of a column of the table, BINARY for the rowid and the columns without one.
+pub fn column_collation(ct: &CreateTable, name: &str) -> Result<Collation> {
+    match ct
+        .columns
+        .iter()
+        .find(|c| c.name.eq_ignore_ascii_case(name))
+        .and_then(|c| c.collation)
+    {
+        Some(name) => collation_named(name),
+        None => Ok(Collation::Binary),
+    }
+}
+
+/// Collation of an indexed column, the one of the table column when the index doesn't give one. `None` for the ones
+/// that don't exist.
+fn index_collation(ct: &CreateTable, column: &IndexedColumn) -> Option<Collation> {
+    match column.collation {
+        Some(name) => Collation::from_name(name),
+        None => column_collation(ct, column.name).ok(),
+    }
+}
+
+fn collation_named(name: &str) -> Result<Collation> {
+    match Collation::from_name(name) {
+        Some(collation) => Ok(collation),
+        None => bail!("no such collation sequence: {name}"),
+    }
+}
+
+/// The tables column references can refer to.
+struct Scope<'s, 'a> {
+    functions: &'s Functions,
+    tables: &'s [Table<'a>],
+    /// Columns of the right table of a USING join, which unqualified references and `*` leave out.
+    hidden: &'s [(usize, &'a str)],
+    /// Scope of the enclosing query, for the columns none of the tables have.
+    outer: Option<&'s Scope<'s, 'a>>,
+}
+
+impl<'s, 'a> Scope<'s, 'a> {
+    fn new(
+        functions: &'s Functions,
+        tables: &'s [Table<'a>],
+        hidden: &'s [(usize, &'a str)],
+        outer: Option<&'s Scope<'s, 'a>>,
+    ) -> Self {
+        Self {
+            functions,
+            tables,
+            hidden,
+            outer,
+        }
+    }
+
+    /// Indexes of the tables a column reference could refer to.
+    fn candidates(&self, table: Option<&str>, name: &str) -> Vec<usize> {
+        (0..self.tables.len())
+            .filter(|&i| {
+                let t = &self.tables[i];
+                has_column(&t.ct, name)
+                    && match table {
+                        Some(table) => t.name.eq_ignore_ascii_case(table),
+                        None => !self.hidden.iter().any(|&(t, n)| t == i && n.eq_ignore_ascii_case(name)),
+                    }
+            })
+            .collect()
+    }
+
+    /// Index of the table a column reference refers to, leaving the enclosing queries out.
+    fn table_of(&self, table: Option<&str>, name: &str) -> Result<usize> {
+        let qualified = table.map_or(name.to_string(), |t| format!("{t}.{name}"));
+        match self.candidates(table, name)[..] {
+            [i] => Ok(i),
+            [] => bail!("no such column: {qualified}"),
+            _ => bail!("ambiguous column name: {qualified}"),
+        }
+    }
+
+    /// Names of the table a column reference refers to and of the column as the table declares it, the innermost
+    /// query with a table that has the column wins.
+    fn qualified(&self, table: Option<&str>, name: &'a str) -> Result<(&'a str, &'a str)> {
+        if let Some(outer) = self.outer
+            && self.candidates(table, name).is_empty()
+        {
+            return outer.qualified(table, name);
+        }
+        let table = &self.tables[self.table_of(table, name)?];
+        Ok((table.name, declared_name(&table.ct, name)))
+    }
+
+    /// Affinity of a qualified expression, the one of the column it is or the type it's cast to.
+    fn affinity(&self, expr: &Expr) -> Option<SqlType> {
+        match expr {
+            Expr::Column { table: Some(t), name } => match table_index(self.tables, (t, name)) {
+                Some(i) => column_affinity(&self.tables[i].ct, name),
+                None => self.outer.and_then(|outer| outer.affinity(expr)),
+            },
+            Expr::Cast(_, ty) => Some(*ty),
+            _ => None,
+        }
+    }
+
+    /// Collation of a qualified column of the tables or of the enclosing queries.
+    fn column_collation(&self, table: &str, name: &str) -> Collation {
+        match table_index(self.tables, (table, name)) {
+            Some(_) => table_collation(self.tables, table, name),
+            None => self
+                .outer
+                .map_or(Collation::Binary, |o| o.column_collation(table, name)),
+        }
+    }
+
+    /// Qualifies the column references of the expression with the name of their table.
+    fn qualify(&self, expr: &mut Expr<'a>) -> Result<()> {
+        let mut result = Ok(());
+        expr.walk_mut(&mut |e| {
+            if result.is_err() {
+                return false;
+            }
+            match e {
+                Expr::Column { table, name } => match self.qualified(*table, name) {
+                    Ok((t, n)) => (*table, *name) = (Some(t), n),
+                    Err(err) => result = Err(err),
+                },
+                Expr::Collate(_, name) => result = collation_named(name).map(|_| ()),
+                Expr::Function { name, args, .. } => {
+                    mark_json_arguments(name, args);
+                    result = self.functions.check_call(name, args);
+                    // The arguments of aggregates are computed before the window functions are.
+                    if result.is_ok() && self.functions.is_aggregate(name, args.len()) {
+                        result = args.iter().try_for_each(check_no_windows);
+                    }
+                }
+                Expr::Window {
+                    name,
+                    args,
+                    distinct,
+                    window,
+                } => {
+                    mark_json_arguments(name, args);
+                    result = self.check_window(name, args, *distinct, window);
+                }
+                _ => {}
+            }
+            true
+        });
+        result?;
+        self.fold_constants(expr);
+        Ok(())
+    }
+
+    /// Checks a window function call, which can't be DISTINCT nor call window functions in its arguments and its
+    /// window.
+    fn check_window(&self, name: &str, args: &[Expr], distinct: bool, window: &WindowDef) -> Result<()> {
+        if distinct {
+            bail!("DISTINCT is not supported for window functions");
+        }
+        self.functions.check_window_call(name, args)?;
+        let terms = window
+            .partition_by
+            .iter()
+            .chain(window.order_by.iter().map(|t| &t.expr));
+        args.iter().chain(terms).try_for_each(check_no_windows)?;
+        check_frame(window)
+    }
+
+    /// Replaces the calls to deterministic functions whose arguments are constants with their result.
+    fn fold_constants(&self, expr: &mut Expr<'a>) {
+        expr.walk_mut(&mut |e| {
+            let Expr::Function { name, args, .. } = e else {
+                return true;
+            };
+            args.iter_mut().for_each(|arg| self.fold_constants(arg));
+            if let Some(value) = self.functions.constant_call(name, args) {
+                *e = Expr::Literal(value);
+            }
+            false
+        });
+    }
+
+    /// Replaces references to result column aliases with the aliased expression, table columns take precedence, then
+    /// qualifies the column references. Only the ORDER BY can refer to result columns calling window functions, which
+    /// `windows` tells.
+    fn resolve(&self, expr: &Expr<'a>, columns: &[OutputColumn<'a>], windows: bool) -> Result<Expr<'a>> {
+        let mut expr = expr.clone();
+        let mut result = Ok(());
+        expr.walk_mut(&mut |e| {
+            if let Expr::Column { table: None, name } = e
+                && !self.tables.iter().any(|t| has_column(&t.ct, name))
+                && let Some(i) = alias_position(columns, name)
+            {
+                if !windows && window_call(&columns[i].expr).is_some() && result.is_ok() {
+                    result = Err(anyhow::anyhow!("misuse of aliased window function {name}"));
+                }
+                *e = columns[i].expr.clone();
+                return false;
+            }
+            true
+        });
+        result?;
+        self.qualify(&mut expr)?;
+        Ok(expr)
+    }
+}
+
+/// The subquery of a `(SELECT ...)`, `EXISTS (SELECT ...)` or `IN (SELECT ...)` expression.
+pub fn subquery<'s, 'a>(subqueries: &'s [Subquery<'a>], expr: &Expr<'a>) -> &'s Subquery<'a> {
+    subqueries
+        .iter()
+        .find(|s| s.expr == *expr)
+        .expect("subquery missing from the query")
+}
+
+/// Splits an expression into the terms that are ANDed together.
+fn split_conjuncts<'a>(expr: &Expr<'a>) -> Vec<Expr<'a>> {
+    match expr {
+        Expr::Binary(l, BinaryOp::And, r) => [split_conjuncts(l), split_conjuncts(r)].concat(),
+        expr => vec![expr.clone()],
+    }
+}
+
+/// Columns a qualified expression refers to, the ones its subqueries take from the enclosing queries included.
+pub fn referenced_columns<'a>(expr: &Expr<'a>, subqueries: &[Subquery<'a>]) -> Vec<(&'a str, &'a str)> {
+    let mut columns = vec![];
+    expr.walk(&mut |e| {
+        match e {
+            Expr::Column { table: Some(t), name } => columns.push((*t, *name)),
+            Expr::Subquery(_) | Expr::Exists(_) | Expr::InSelect { .. } => {
+                columns.extend(&subquery(subqueries, e).query.outer);
+            }
+            _ => {}
+        }
+        true
+    });
+    columns
+}
+
+/// The columns of each table the query uses, the ones its subqueries take included, and the columns the virtual
+/// generated ones are computed from ahead of them.
+pub fn used_columns<'a>(q: &Query<'a>) -> Vec<(&'a str, &'a str)> {
+    let mut used = vec![];
+    let exprs = q
+        .columns
+        .iter()
+        .map(|c| &c.expr)
+        .chain(&q.filter)
+        .chain(&q.group_by)
+        .chain(&q.having)
+        .chain(q.order_by.iter().filter_map(|t| match t {
+            Term::Expr(expr) => Some(expr),
+            Term::Column(_) => None,
+        }))
+        .chain(q.tables.iter().flat_map(|t| {
+            let mut access = match &t.access {
+                Access::Scan => vec![],
+                Access::Rowid(key) | Access::Index { key, .. } => vec![key],
+                Access::RowidRange { lower, upper } | Access::IndexRange { lower, upper, .. } => {
+                    lower.iter().chain(upper).map(|b| &b.key).collect()
+                }
+                Access::Hash { column, key, .. } => vec![column, key],
+            };
+            if let Source::Function { args, .. } = &t.source {
+                access.extend(args);
+            }
+            t.on.iter().chain(&t.filter).chain(access)
+        }));
+    for expr in exprs {
+        for column in referenced_columns(expr, &q.subqueries) {
+            if !used.contains(&column) {
+                used.push(column);
+            }
+        }
+    }
+    generated_dependencies(&q.tables, used)
+}
+
+/// Adds the columns the virtual generated columns of `used` are computed from, ahead of them.
+fn generated_dependencies<'a>(tables: &[Table<'a>], used: Vec<(&'a str, &'a str)>) -> Vec<(&'a str, &'a str)> {
+    fn add<'a>(tables: &[Table<'a>], column: (&'a str, &'a str), columns: &mut Vec<(&'a str, &'a str)>) {
+        if columns.contains(&column) {
+            return;
+        }
+        let generated = table_index(tables, column)
+            .and_then(|i| {
+                tables[i]
+                    .ct
+                    .columns
+                    .iter()
+                    .find(|c| c.name.eq_ignore_ascii_case(column.1))
+            })
+            .filter(|c| c.is_virtual())
+            .and_then(|c| c.generated.as_ref());
+        if let Some(generated) = generated {
+            generated.expr.walk(&mut |e| {
+                if let Expr::Column { name, .. } = e {
+                    add(tables, (column.0, name), columns);
+                }
+                true
+            });
+        }
+        columns.push(column);
+    }
+    let mut columns = vec![];
+    for column in used {
+        add(tables, column, &mut columns);
+    }
+    columns
+}
+
+/// Index of the table a qualified column reference refers to, `None` for the columns of the enclosing queries.
+pub fn table_index(tables: &[Table], (table, name): (&str, &str)) -> Option<usize> {
+    tables.iter().position(|t| t.name == table && has_column(&t.ct, name))
+}
+
+/// Index of the last table a qualified expression refers to, 0 if it doesn't refer to any.
+fn last_table(tables: &[Table], subqueries: &[Subquery], expr: &Expr) -> usize {
+    let columns = referenced_columns(expr, subqueries);
+    columns
+        .into_iter()
+        .filter_map(|c| table_index(tables, c))
+        .max()
+        .unwrap_or(0)
+}
+
+/// Tables a qualified expression refers to.
+fn used_tables(tables: &[Table], subqueries: &[Subquery], expr: &Expr) -> Vec<usize> {
+    let mut used = vec![];
+    for i in referenced_columns(expr, subqueries)
+        .into_iter()
+        .filter_map(|c| table_index(tables, c))
+    {
+        if !used.contains(&i) {
+            used.push(i);
+        }
+    }
+    used
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Clause {
+    OrderBy,
+    GroupBy,
+}
+
+/// An ORDER BY or GROUP BY term.
+pub enum Term<'a> {
+    /// A result column, picked by its position or alias.
+    Column(usize),
+    Expr(Expr<'a>),
+}
+
+impl<'a> Term<'a> {
+    fn expr(&self) -> Option<&Expr<'a>> {
+        match self {
+            Self::Column(_) => None,
+            Self::Expr(expr) => Some(expr),
+        }
+    }
+}
+
+/// Resolves terms that refer to result columns. Integers are positions, and a bare ORDER BY identifier is an alias
+/// before being a table column.
+fn resolve_terms<'e, 'a: 'e>(
+    terms: impl Iterator<Item = &'e Expr<'a>>,
+    scope: &Scope<'_, 'a>,
+    columns: &[OutputColumn<'a>],
+    clause: Clause,
+) -> Result<Vec<Term<'a>>> {
+    terms
+        .enumerate()
+        .map(|(i, expr)| match expr {
+            Expr::Literal(Value::Int(n)) => match usize::try_from(*n) {
+                Ok(n) if (1..=columns.len()).contains(&n) => Ok(Term::Column(n - 1)),
+                _ => bail!(
+                    "{} {} BY term out of range - should be between 1 and {}",
+                    ordinal(i + 1),
+                    if clause == Clause::OrderBy { "ORDER" } else { "GROUP" },
+                    columns.len()
+                ),
+            },
+            Expr::Column { table: None, name }
+                if clause == Clause::OrderBy
+                    && let Some(i) = alias_position(columns, name) =>
+            {
+                Ok(Term::Column(i))
+            }
+            expr => Ok(Term::Expr(scope.resolve(expr, columns, clause == Clause::OrderBy)?)),
+        })
+        .collect()
+}
+
+fn ordinal(n: usize) -> String {
+    let suffix = match (n % 10, n % 100) {
+        (_, 11..=13) => "th",
+        (1, _) => "st",
+        (2, _) => "nd",
+        (3, _) => "rd",
+        _ => "th",
+    };
+    format!("{n}{suffix}")
+}
+
+/// How the rows of the first table are read.
+#[derive(Clone, Copy)]
+pub struct Scan {
+    /// Index b-tree walked instead of the table, its entries point to the table rows by rowid.
+    pub index: Option<PageNumber>,
+    /// Whether the index holds every column of the table the query uses, which are read from its entries then
+    /// without looking up the rows of the table.
+    pub covering: bool,
+    /// Whether the b-tree is walked from its last entry back to the first.
+    pub reverse: bool,
+    /// Whether the rows come out in the requested order.
+    pub sorted: bool,
+}
+
+impl Scan {
+    const UNSORTED: Self = Self {
+        index: None,
+        covering: false,
+        reverse: false,
+        sorted: false,
+    };
+    const SORTED: Self = Self {
+        index: None,
+        covering: false,
+        reverse: false,
+        sorted: true,
+    };
+}
+
+#[derive(PartialEq)]
+enum TableColumn {
+    Rowid,
+    Column(usize),
+}
+
+/// The column of the first table a term refers to, if it is a plain column.
+fn term_column(q: &Query, term: &Term) -> Option<TableColumn> {
+    let expr = match term {
+        Term::Column(i) => &q.columns[*i].expr,
+        Term::Expr(expr) => expr,
+    };
+    let table = &q.tables[0];
+    let Expr::Column { table: Some(t), name } = expr else {
+        return None;
+    };
+    if *t != table.name {
+        return None;
+    }
+    match table.ct.columns.iter().position(|c| c.name.eq_ignore_ascii_case(name)) {
+        Some(i) if table.ct.rowid_alias == Some(i) => Some(TableColumn::Rowid),
+        Some(i) => Some(TableColumn::Column(i)),
+        None if !table.ct.without_rowid && is_rowid_name(name) => Some(TableColumn::Rowid),
+        None => None,
+    }
+}
+
+/// Picks a way to read the first table that yields rows in the order of the terms, so they don't have to be sorted.
+/// Joined tables are read in the order of the first one, which only keeps the rows sorted if the terms are all about
+/// the first table.
+fn plan_scan(q: &Query, terms: &[Term], order: &[SortOrder]) -> Scan {
+    let Some(first) = order.first() else {
+        return Scan::SORTED;
+    };
+    // Without a FROM clause there's a single row, which is as sorted as can be.
+    let Some(table) = q.tables.first() else {
+        return Scan::SORTED;
+    };
+    let columns: Vec<_> = terms.iter().map(|t| term_column(q, t)).collect();
+    // Table b-trees are walked in rowid order, or backwards, and rowids are unique so the terms after it don't matter,
+    // unless other tables are joined and repeat the rowid.
+    let rowid_order = columns[0] == Some(TableColumn::Rowid) && (q.tables.len() == 1 || order.len() == 1);
+    // The entries of a b-tree sorted by the `key` columns, then by rowid for the indexes of rowid tables. Returns
+    // whether it's walked backwards to yield the rows in order, which also puts the NULLs at the other end.
+    let direction = |key: &[IndexedColumn], rowid: bool| {
+        let reverse = first.desc != key.first()?.desc;
+        let matches = order
+            .iter()
+            .zip(&columns)
+            .enumerate()
+            .all(|(i, (o, column))| match (key.get(i), column) {
+                (Some(ic), Some(TableColumn::Column(c))) => {
+                    table.ct.columns[*c].name.eq_ignore_ascii_case(ic.name)
+                        && (o.desc != ic.desc) == reverse
+                        && o.nulls_first == (ic.desc == reverse)
+                        && index_collation(&table.ct, ic) == Some(o.collation)
+                }
+                (None, Some(TableColumn::Rowid)) => rowid && o.desc == reverse,
+                _ => false,
+            });
+        (matches && order.len() <= key.len() + rowid as usize).then_some(reverse)
+    };
+    match &table.access {
+        Access::Scan => {}
+        // Ranges are walked forwards.
+        Access::RowidRange { .. } if rowid_order && !first.desc => return Scan::SORTED,
+        Access::IndexRange { root, .. } => {
+            let (_, index) = table.indexes.iter().find(|(r, _)| r == root).unwrap();
+            return match direction(&index.columns, true) {
+                Some(false) => Scan::SORTED,
+                _ => Scan::UNSORTED,
+            };
+        }
+        _ => return Scan::UNSORTED,
+    }
+    if rowid_order {
+        return Scan {
+            reverse: first.desc,
+            ..Scan::SORTED
+        };
+    }
+    // A WITHOUT ROWID table is an index b-tree keyed by its primary key.
+    if table.ct.without_rowid {
+        return match direction(&table.ct.key_columns, false) {
+            Some(reverse) => Scan {
+                reverse,
+                ..Scan::SORTED
+            },
+            None => Scan::UNSORTED,
+        };
+    }
+    let index = table
+        .indexes
+        .iter()
+        .filter(|(_, index)| index.partial.is_none())
+        .find_map(|(root, index)| Some((*root, direction(&index.columns, true)?)));
+    match index {
+        Some((root, reverse)) => Scan {
+            index: Some(root),
+            covering: covers(q, root),
+            reverse,
+            sorted: true,
+        },
+        None => Scan::UNSORTED,
+    }
+}
+
+/// Whether an index of the first table holds every column of it the query uses, the rowid being in every entry.
+fn covers(q: &Query, root: PageNumber) -> bool {
+    let table = &q.tables[0];
+    let (_, index) = table.indexes.iter().find(|(r, _)| *r == root).unwrap();
+    used_columns(q)
+        .into_iter()
+        .filter(|&c| table_index(&q.tables, c) == Some(0))
+        .all(
+            |(_, name)| match table.ct.columns.iter().position(|c| c.name.eq_ignore_ascii_case(name)) {
+                Some(i) if table.ct.columns[i].is_virtual() => true,
+                Some(i) if table.ct.rowid_alias == Some(i) => true,
+                Some(_) => index.columns.iter().any(|ic| ic.name.eq_ignore_ascii_case(name)),
+                None => true,
+            },
+        )
+}
+
+/// How the rows of a table are read, with what it costs.
+struct JoinPlan<'a> {
+    access: Access<'a>,
+    /// Cost of the work done once, building the hash table.
+    setup: f64,
+    /// Cost of finding the rows matching a row of the tables before it.
+    probe: f64,
+    /// Estimated number of rows matching a row of the tables before it.
+    rows: f64,
+}
+
+/// Picks how to find the rows of table `i` that match a row of the tables before it, from the equalities of `terms`
+/// between one of its columns and an expression of the tables before it or of the enclosing queries. When several
+/// indexes can be searched, the one with the fewest rows per value is. The first table is only looked up by rowid or
+/// index, a hash table of it would be built every time the query runs. Without an equality, a range of the rowid or of
+/// an index bounded by the comparisons of `terms` is walked when it costs less than reading the whole table.
+fn plan_join<'e, 'a: 'e>(
+    scope: &Scope<'_, 'a>,
+    subqueries: &[Subquery<'a>],
+    i: usize,
+    terms: impl Iterator<Item = &'e Expr<'a>>,
+    before: impl Fn(usize) -> bool,
+    first: bool,
+) -> JoinPlan<'a> {
+    let tables = scope.tables;
+    let table = &tables[i];
+    let rows = table.rows as f64;
+    // The rows of a table-valued function depend on the rows before it, they are computed again for each one.
+    if matches!(table.source, Source::Function { .. }) {
+        return JoinPlan {
+            access: Access::Scan,
+            setup: 0.0,
+            probe: rows,
+            rows,
+        };
+    }
+    // Finding a row by key walks down a b-tree.
+    let seek = (rows + 1.0).log2();
+    let mut plan = JoinPlan {
+        access: Access::Scan,
+        setup: 0.0,
+        probe: rows,
+        rows,
+    };
+    let mut constraints = vec![];
+    for term in terms {
+        let Expr::Binary(l, op, r) = term else {
+            continue;
+        };
+        let flipped = match op {
+            BinaryOp::Eq => BinaryOp::Eq,
+            BinaryOp::Lt => BinaryOp::Gt,
+            BinaryOp::Le => BinaryOp::Ge,
+            BinaryOp::Gt => BinaryOp::Lt,
+            BinaryOp::Ge => BinaryOp::Le,
+            _ => continue,
+        };
+        // Keys are looked up byte for byte.
+        if comparison_collation(l, r, |t, n| scope.column_collation(t, n)) != Collation::Binary {
+            continue;
+        }
+        // The column is on the left, the comparison is turned around when it's on the right.
+        for (column, key, op) in [(l, r, op), (r, l, &flipped)] {
+            if used_tables(tables, subqueries, column) != [i]
+                || used_tables(tables, subqueries, key).iter().any(|&t| !before(t))
+            {
+                continue;
+            }
+            let affinity = comparison_affinity(scope.affinity(column), scope.affinity(key));
+            let name = match &**column {
+                Expr::Column { name, .. } => Some(*name),
+                _ => None,
+            };
+            let is_rowid = name.is_some_and(|name| {
+                match table.ct.columns.iter().position(|c| c.name.eq_ignore_ascii_case(name)) {
+                    Some(c) => table.ct.rowid_alias == Some(c),
+                    None => !table.ct.without_rowid && is_rowid_name(name),
+                }
+            });
+            // The index can only be searched if the comparison doesn't convert the values of the column.
+            let column_affinity = name.and_then(|name| column_affinity(&table.ct, name));
+            let keeps_values = match column_affinity {
+                Some(a) if a.is_numeric() => affinity == Some(SqlType::Numeric),
+                Some(SqlType::Text) => affinity != Some(SqlType::Numeric),
+                _ => affinity.is_none(),
+            };
+            if *op != BinaryOp::Eq {
+                if is_rowid || keeps_values {
+                    constraints.push(Constraint {
+                        column: name.filter(|_| !is_rowid),
+                        lower: matches!(op, BinaryOp::Gt | BinaryOp::Ge),
+                        bound: KeyBound {
+                            key: (**key).clone(),
+                            inclusive: matches!(op, BinaryOp::Le | BinaryOp::Ge),
+                            // Rowids are integers, the key is compared as a number.
+                            affinity: if is_rowid { Some(SqlType::Numeric) } else { affinity },
+                        },
+                    });
+                }
+                continue;
+            }
+            if is_rowid {
+                return JoinPlan {
+                    access: Access::Rowid((**key).clone()),
+                    setup: 0.0,
+                    probe: seek,
+                    rows: 1.0,
+                };
+            }
+            let index = table
+                .indexes
+                .iter()
+                .zip(&table.key_rows)
+                .filter(|((_, index), _)| {
+                    let first = &index.columns[0];
+                    name.is_some_and(|name| first.name.eq_ignore_ascii_case(name))
+                        && index.partial.is_none()
+                        && index_collation(&table.ct, first) == Some(Collation::Binary)
+                })
+                .min_by_key(|(_, key_rows)| **key_rows);
+            match index {
+                Some(((root, index), &key_rows)) if keeps_values && !table.ct.without_rowid => {
+                    // Each entry of the index leads to a row of the table, found by rowid.
+                    let probe = seek * (1.0 + key_rows as f64);
+                    if !matches!(plan.access, Access::Index { .. }) || probe < plan.probe {
+                        plan = JoinPlan {
+                            access: Access::Index {
+                                root: *root,
+                                desc: index.columns[0].desc,
+                                key: (**key).clone(),
+                                affinity,
+                            },
+                            setup: 0.0,
+                            probe,
+                            rows: key_rows as f64,
+                        };
+                    }
+                }
+                _ if matches!(plan.access, Access::Scan) && !first => {
+                    let matching = rows.min(10.0);
+                    plan = JoinPlan {
+                        access: Access::Hash {
+                            column: (**column).clone(),
+                            key: (**key).clone(),
+                            affinity,
+                        },
+                        setup: rows,
+                        probe: matching,
+                        rows: matching,
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+    if table.ct.without_rowid {
+        return plan;
+    }
+    // Ranges of the rowid, or of the first column of an index, are walked from their lower bound to their upper one.
+    // Like SQLite without finer statistics, each bound is guessed to leave out three rows in four.
+    let bounds = |column: Option<&str>| {
+        let bound = |lower: bool| {
+            let constraint = constraints.iter().find(|c| {
+                c.lower == lower
+                    && match (c.column, column) {
+                        (Some(c), Some(name)) => c.eq_ignore_ascii_case(name),
+                        (c, name) => c.is_none() && name.is_none(),
+                    }
+            });
+            constraint.map(|c| c.bound.clone())
+        };
+        let (lower, upper) = (bound(true), bound(false));
+        let matching = rows / 4f64.powi(lower.is_some() as i32 + upper.is_some() as i32);
+        (lower, upper, matching)
+    };
+    let (lower, upper, matching) = bounds(None);
+    if (lower.is_some() || upper.is_some()) && seek + matching < plan.probe {
+        plan = JoinPlan {
+            access: Access::RowidRange { lower, upper },
+            setup: 0.0,
+            probe: seek + matching,
+            rows: matching,
+        };
+    }
+    let indexes = table.indexes.iter().filter(|(_, index)| {
+        let first = &index.columns[0];
+        index.partial.is_none() && !first.desc && index_collation(&table.ct, first) == Some(Collation::Binary)
+    });
+    for (root, index) in indexes {
+        let (lower, upper, matching) = bounds(Some(index.columns[0].name));
+        // Each entry leads to a row of the table, whose lookup costs about a row of a scan since the pages on the way
+        // down to it were mostly read for the entry before.
+        let probe = seek + matching * 2.0;
+        if (lower.is_some() || upper.is_some()) && probe < plan.probe {
+            plan = JoinPlan {
+                access: Access::IndexRange {
+                    root: *root,
+                    lower,
+                    upper,
+                },
+                setup: 0.0,
+                probe,
+                rows: matching,
+            };
+        }
+    }
+    plan
+}
+
+/// Largest number of tables whose join orders are all weighed, the ones of larger joins are read in the order of the
+/// FROM clause.
+const MAX_ORDERED_TABLES: usize = 8;
+
+/// Picks the order to read the tables of inner joins in, the one costing the least going by the estimated number of
+/// rows of the tables. Ties keep the order of the FROM clause.
+fn join_order(scope: &Scope, subqueries: &[Subquery], conjuncts: &[&Expr]) -> Vec<usize> {
+    let n = scope.tables.len();
+    let mut best = (f64::INFINITY, (0..n).collect());
+    if n <= MAX_ORDERED_TABLES {
+        order_search(scope, subqueries, conjuncts, &mut vec![], 1.0, 0.0, &mut best);
+    }
+    best.1
+}
+
+/// Tries the orders starting with the tables of `order`, which find `rows` rows at the given cost.
+fn order_search(
+    scope: &Scope,
+    subqueries: &[Subquery],
+    conjuncts: &[&Expr],
+    order: &mut Vec<usize>,
+    rows: f64,
+    cost: f64,
+    best: &mut (f64, Vec<usize>),
+) {
+    if order.len() == scope.tables.len() {
+        // Orders costing about the same as an earlier one don't replace it.
+        if cost < best.0 * (1.0 - 1e-9) {
+            *best = (cost, order.clone());
+        }
+        return;
+    }
+    for i in 0..scope.tables.len() {
+        if order.contains(&i) {
+            continue;
+        }
+        let terms = conjuncts.iter().copied();
+        let plan = plan_join(scope, subqueries, i, terms, |t| order.contains(&t), order.is_empty());
+        let cost = cost + plan.setup + rows * plan.probe;
+        if cost >= best.0 {
+            continue;
+        }
+        order.push(i);
+        order_search(scope, subqueries, conjuncts, order, rows * plan.rows, cost, best);
+        order.pop();
+    }
+}
+
+pub fn is_rowid_name(name: &str) -> bool {
+    ["rowid", "_rowid_", "oid"].iter().any(|r| r.eq_ignore_ascii_case(name))
+}
@@ -0,0 +1,353 @@
+//! EXPLAIN QUERY PLAN, a tree describing how a query reads its tables, the way sqlite3 prints it.
+//!
+//! The tree is made from the decisions of the planner, the same ones code generation follows.
+
+use std::fmt::Display;
+
+use parser::CompoundOp;
+use parser::Expr;
+
+use crate::planner::Access;
+use crate::planner::CompoundQuery;
+use crate::planner::Query;
+use crate::planner::RecursiveQuery;
+use crate::planner::Scan;
+use crate::planner::Source;
+use crate::planner::Subquery;
+use crate::planner::Table;
+use crate::planner::WindowQuery;
+use crate::planner::compound_keyword;
+use crate::planner::plan_strategy;
+
+/// The plan of a query.
+pub struct QueryPlan {
+    nodes: Vec<PlanNode>,
+}
+
+struct PlanNode {
+    detail: String,
+    children: Vec<PlanNode>,
+}
+
+impl PlanNode {
+    fn new(detail: impl Into<String>, children: Vec<PlanNode>) -> Self {
+        Self {
+            detail: detail.into(),
+            children,
+        }
+    }
+}
+
+impl QueryPlan {
+    pub fn new(q: &Query) -> Self {
+        let mut subqueries = 0;
+        Self {
+            nodes: select(q, &mut subqueries),
+        }
+    }
+}
+
+/// The steps of a select: reading its tables, the temporary b-trees it fills and the subqueries of its expressions.
+/// `subqueries` counts the subqueries of expressions seen so far, which are numbered.
+fn select(q: &Query, subqueries: &mut usize) -> Vec<PlanNode> {
+    let strategy = plan_strategy(q);
+    let mut nodes = vec![];
+    if q.tables.is_empty() {
+        nodes.push(PlanNode::new("SCAN CONSTANT ROW", vec![]));
+    }
+    for (i, table) in q.tables.iter().enumerate() {
+        // The rows of the first FROM subquery are produced as they are read, the ones of the others are kept.
+        let kind = if i == 0 { "CO-ROUTINE" } else { "MATERIALIZE" };
+        let source = match &table.source {
+            Source::Subquery(query) => Some(select(query, subqueries)),
+            Source::Recursive(query) => Some(recursive(query, subqueries)),
+            Source::Window(query) => Some(window(query, subqueries)),
+            // A compound is the one table of a query of its own, which only reads its rows.
+            Source::Compound(query) => {
+                nodes.push(compound(query, subqueries));
+                continue;
+            }
+            Source::Btree(_) | Source::Current | Source::Function { .. } => None,
+        };
+        if let Some(children) = source {
+            nodes.push(PlanNode::new(format!("{kind} {}", table.name), children));
+        }
+        let scan = Some(strategy.scan).filter(|_| i == 0);
+        nodes.push(PlanNode::new(table_detail(table, scan), vec![]));
+    }
+    if q.is_aggregate() && !strategy.scan.sorted {
+        nodes.push(PlanNode::new("USE TEMP B-TREE FOR GROUP BY", vec![]));
+    }
+    if q.distinct && !strategy.adjacent {
+        nodes.push(PlanNode::new("USE TEMP B-TREE FOR DISTINCT", vec![]));
+    }
+    if strategy.sort && !q.order_by.is_empty() {
+        nodes.push(PlanNode::new("USE TEMP B-TREE FOR ORDER BY", vec![]));
+    }
+    nodes.extend(expression_subqueries(&q.subqueries, subqueries));
+    nodes
+}
+
+/// How the rows of a table are read, `scan` being how the first table is.
+fn table_detail(table: &Table, scan: Option<Scan>) -> String {
+    let index_name = |root| {
+        let (_, index) = table.indexes.iter().find(|(r, _)| *r == root).unwrap();
+        index.index_name
+    };
+    let mut detail = match &table.access {
+        Access::Scan if matches!(table.source, Source::Function { .. }) => {
+            format!("SCAN {} VIRTUAL TABLE INDEX 1:", table.name)
+        }
+        Access::Scan => match scan.and_then(|s| Some((s.index?, s.covering))) {
+            Some((root, true)) => format!("SCAN {} USING COVERING INDEX {}", table.name, index_name(root)),
+            Some((root, false)) => format!("SCAN {} USING INDEX {}", table.name, index_name(root)),
+            None => format!("SCAN {}", table.name),
+        },
+        Access::Rowid(_) => format!("SEARCH {} USING INTEGER PRIMARY KEY (rowid=?)", table.name),
+        Access::Index { root, .. } => {
+            let (_, index) = table.indexes.iter().find(|(r, _)| r == root).unwrap();
+            let column = index.columns[0].name;
+            format!("SEARCH {} USING INDEX {} ({column}=?)", table.name, index.index_name)
+        }
+        Access::RowidRange { lower, upper } => {
+            let range = range("rowid", lower.is_some(), upper.is_some());
+            format!("SEARCH {} USING INTEGER PRIMARY KEY ({range})", table.name)
+        }
+        Access::IndexRange { root, lower, upper } => {
+            let (_, index) = table.indexes.iter().find(|(r, _)| r == root).unwrap();
+            let range = range(index.columns[0].name, lower.is_some(), upper.is_some());
+            format!("SEARCH {} USING INDEX {} ({range})", table.name, index.index_name)
+        }
+        Access::Hash { column, .. } => {
+            let column = match column {
+                Expr::Column { name, .. } => name.to_string(),
+                column => column.to_string(),
+            };
+            format!("SEARCH {} USING HASH TABLE ({column}=?)", table.name)
+        }
+    };
+    if table.left {
+        detail.push_str(" LEFT-JOIN");
+    }
+    detail
+}
+
+/// The bounds of a range of `column`, which SQLite shows the same whether they're inclusive or not.
+fn range(column: &str, lower: bool, upper: bool) -> String {
+    let lower = lower.then(|| format!("{column}>?"));
+    let upper = upper.then(|| format!("{column}<?"));
+    lower.into_iter().chain(upper).collect::<Vec<_>>().join(" AND ")
+}
+
+fn expression_subqueries(subs: &[Subquery], subqueries: &mut usize) -> Vec<PlanNode> {
+    subs.iter()
+        .map(|sub| {
+            *subqueries += 1;
+            let kind = match sub.expr {
+                Expr::InSelect { .. } => "LIST",
+                _ => "SCALAR",
+            };
+            // A subquery referring to the columns of the enclosing queries runs again for each of their rows.
+            let correlated = if sub.query.outer.is_empty() { "" } else { "CORRELATED " };
+            PlanNode::new(
+                format!("{correlated}{kind} SUBQUERY {subqueries}"),
+                select(&sub.query, subqueries),
+            )
+        })
+        .collect()
+}
+
+fn recursive(rq: &RecursiveQuery, subqueries: &mut usize) -> Vec<PlanNode> {
+    let parts = |parts: &[Query], subqueries: &mut usize| parts.iter().flat_map(|q| select(q, subqueries)).collect();
+    let mut nodes = vec![
+        PlanNode::new("SETUP", parts(&rq.initial, subqueries)),
+        PlanNode::new("RECURSIVE STEP", parts(&rq.recursive, subqueries)),
+    ];
+    nodes.extend(expression_subqueries(&rq.subqueries, subqueries));
+    nodes
+}
+
+/// The passes of a window, each reading the rows of the one before it and sorting them by its PARTITION BY and ORDER
+/// BY, unless the select reads them in the order of the first one.
+fn window(wq: &WindowQuery, subqueries: &mut usize) -> Vec<PlanNode> {
+    let mut nodes = select(&wq.query, subqueries);
+    let passes = &wq.spec.passes;
+    for (i, pass) in passes.iter().enumerate() {
+        let sorted = i == 0 && !wq.query.order_by.is_empty();
+        if !sorted && (!pass.partition.is_empty() || !pass.order.is_empty()) {
+            nodes.push(PlanNode::new("USE TEMP B-TREE FOR ORDER BY", vec![]));
+        }
+        if i + 1 < passes.len() {
+            let name = wq.names[passes.len() - 1 - i];
+            nodes = vec![
+                PlanNode::new(format!("CO-ROUTINE {name}"), nodes),
+                PlanNode::new(format!("SCAN {name}"), vec![]),
+            ];
+        }
+    }
+    nodes
+}
+
+/// The selects of a compound. The rows of the selects up to the last operator that isn't UNION ALL go through
+/// temporary b-trees, which make them distinct and sorted.
+fn compound(cq: &CompoundQuery, subqueries: &mut usize) -> PlanNode {
+    let sorted = cq
+        .ops
+        .iter()
+        .rposition(|op| *op != CompoundOp::UnionAll)
+        .map_or(0, |last| last + 1);
+    let mut children = vec![PlanNode::new("LEFT-MOST SUBQUERY", select(&cq.parts[0], subqueries))];
+    for (i, (op, q)) in cq.ops.iter().zip(&cq.parts[1..]).enumerate() {
+        let detail = match i < sorted {
+            true => format!("{} USING TEMP B-TREE", compound_keyword(*op)),
+            false => compound_keyword(*op).to_string(),
+        };
+        children.push(PlanNode::new(detail, select(q, subqueries)));
+    }
+    PlanNode::new("COMPOUND QUERY", children)
+}
+
+impl Display for QueryPlan {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        fn tree(f: &mut std::fmt::Formatter<'_>, nodes: &[PlanNode], indent: &str) -> std::fmt::Result {
+            for (i, node) in nodes.iter().enumerate() {
+                let last = i == nodes.len() - 1;
+                writeln!(f, "{indent}{}{}", if last { "`--" } else { "|--" }, node.detail)?;
+                tree(
+                    f,
+                    &node.children,
+                    &format!("{indent}{}", if last { "   " } else { "|  " }),
+                )?;
+            }
+            Ok(())
+        }
+        writeln!(f, "QUERY PLAN")?;
+        tree(f, &self.nodes, "")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Connection;
+
+    fn plan(query: &str) -> String {
+        let conn = Connection::open(concat!(env!("CARGO_MANIFEST_DIR"), "/sample.db")).unwrap();
+        let statement = conn.prepare(&format!("EXPLAIN QUERY PLAN {query}")).unwrap();
+        statement.explain().unwrap().to_string()
+    }
+
+    #[test]
+    fn scan_and_search() {
+        assert_eq!(plan("SELECT * FROM apples"), "QUERY PLAN\n`--SCAN apples\n");
+        assert_eq!(
+            plan("SELECT name FROM apples WHERE id = 3"),
+            "QUERY PLAN\n`--SEARCH apples USING INTEGER PRIMARY KEY (rowid=?)\n"
+        );
+        assert_eq!(plan("SELECT 1"), "QUERY PLAN\n`--SCAN CONSTANT ROW\n");
+    }
+
+    #[test]
+    fn temp_b_trees() {
+        // Rows come in rowid order, only other orders need sorting.
+        assert_eq!(plan("SELECT * FROM apples ORDER BY id"), "QUERY PLAN\n`--SCAN apples\n");
+        assert_eq!(
+            plan("SELECT * FROM apples ORDER BY id DESC"),
+            "QUERY PLAN\n`--SCAN apples\n"
+        );
+        assert_eq!(
+            plan("SELECT * FROM apples ORDER BY name"),
+            "QUERY PLAN\n|--SCAN apples\n`--USE TEMP B-TREE FOR ORDER BY\n"
+        );
+        assert_eq!(
+            plan("SELECT color, count(*) FROM apples GROUP BY color ORDER BY 2"),
+            "QUERY PLAN\n|--SCAN apples\n|--USE TEMP B-TREE FOR GROUP BY\n`--USE TEMP B-TREE FOR ORDER BY\n"
+        );
+        assert_eq!(
+            plan("SELECT DISTINCT color FROM apples"),
+            "QUERY PLAN\n|--SCAN apples\n`--USE TEMP B-TREE FOR DISTINCT\n"
+        );
+    }
+
+    #[test]
+    fn joins() {
+        assert_eq!(
+            plan("SELECT * FROM apples a LEFT JOIN oranges o ON o.name = a.name"),
+            "QUERY PLAN\n|--SCAN a\n`--SEARCH o USING HASH TABLE (name=?) LEFT-JOIN\n"
+        );
+        assert_eq!(
+            plan("SELECT * FROM apples a, oranges o WHERE o.id = a.id"),
+            "QUERY PLAN\n|--SCAN a\n`--SEARCH o USING INTEGER PRIMARY KEY (rowid=?)\n"
+        );
+    }
+
+    #[test]
+    fn ranges() {
+        assert_eq!(
+            plan("SELECT * FROM apples WHERE id > 3 AND id < 10"),
+            "QUERY PLAN\n`--SEARCH apples USING INTEGER PRIMARY KEY (rowid>? AND rowid<?)\n"
+        );
+        assert_eq!(
+            plan("SELECT * FROM apples WHERE 3 <= id"),
+            "QUERY PLAN\n`--SEARCH apples USING INTEGER PRIMARY KEY (rowid>?)\n"
+        );
+        assert_eq!(
+            plan("SELECT * FROM apples a, oranges o WHERE o.id > a.id"),
+            "QUERY PLAN\n|--SCAN a\n`--SEARCH o USING INTEGER PRIMARY KEY (rowid>?)\n"
+        );
+    }
+
+    #[test]
+    fn subqueries() {
+        assert_eq!(
+            plan("SELECT name, (SELECT count(*) FROM oranges o WHERE o.id < a.id) FROM apples a"),
+            "QUERY PLAN\n|--SCAN a\n`--CORRELATED SCALAR SUBQUERY 1\n   `--SEARCH o USING INTEGER PRIMARY KEY (rowid<?)\n"
+        );
+        assert_eq!(
+            plan("SELECT * FROM (SELECT name FROM apples UNION SELECT name FROM oranges) t"),
+            "QUERY PLAN
+|--CO-ROUTINE t
+|  `--COMPOUND QUERY
+|     |--LEFT-MOST SUBQUERY
+|     |  `--SCAN apples
+|     `--UNION USING TEMP B-TREE
+|        `--SCAN oranges
+`--SCAN t
+"
+        );
+        assert_eq!(
+            plan("WITH RECURSIVE c(x) AS (SELECT 1 UNION ALL SELECT x + 1 FROM c WHERE x < 3) SELECT * FROM c"),
+            "QUERY PLAN
+|--CO-ROUTINE c
+|  |--SETUP
+|  |  `--SCAN CONSTANT ROW
+|  `--RECURSIVE STEP
+|     `--SCAN c
+`--SCAN c
+"
+        );
+    }
+
+    #[test]
+    fn windows() {
+        // Each pass reads the rows of the one before it, sorted by its window unless they come in that order.
+        assert_eq!(
+            plan(
+                "SELECT id, row_number() OVER (ORDER BY name), rank() OVER (PARTITION BY color) FROM apples ORDER BY id"
+            ),
+            "QUERY PLAN
+|--CO-ROUTINE (subquery-2)
+|  |--CO-ROUTINE (subquery-3)
+|  |  |--SCAN apples
+|  |  `--USE TEMP B-TREE FOR ORDER BY
+|  |--SCAN (subquery-3)
+|  `--USE TEMP B-TREE FOR ORDER BY
+|--SCAN (subquery-2)
+`--USE TEMP B-TREE FOR ORDER BY
+"
+        );
+        assert_eq!(
+            plan("SELECT sum(id) OVER (ORDER BY id) FROM apples"),
+            "QUERY PLAN\n|--CO-ROUTINE (subquery-2)\n|  `--SCAN apples\n`--SCAN (subquery-2)\n"
+        );
+    }
+}
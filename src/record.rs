@@ -2,6 +2,7 @@ use parser::{SqlType, Value};
 
 use crate::btree::PageNumber;
 use crate::varint::read_varint;
+use crate::varint::write_varint;
 
 #[derive(Debug, Clone, Copy)]
 pub enum SerialType {
@@ -28,32 +29,11 @@ impl SerialType {
         sts.iter().filter_map(move |st| {
             Some(match st {
                 T::Null => V::Null,
-                T::Int8 => {
-                    let val = i64::from_be_bytes([0, 0, 0, 0, 0, 0, 0, cursor[0]]);
-                    cursor = &cursor[1..];
-                    V::Int(val)
-                }
-                T::Int16 => {
-                    let val = i64::from_be_bytes([0, 0, 0, 0, 0, 0, cursor[0], cursor[1]]);
-                    cursor = &cursor[2..];
-                    V::Int(val)
-                }
-                T::Int24 => {
-                    let val = i64::from_be_bytes([0, 0, 0, 0, 0, cursor[0], cursor[1], cursor[2]]);
-                    cursor = &cursor[3..];
-                    V::Int(val)
-                }
-                T::Int32 => {
-                    let val = i64::from_be_bytes([0, 0, 0, 0, cursor[0], cursor[1], cursor[2], cursor[3]]);
-                    cursor = &cursor[4..];
-                    V::Int(val)
-                }
-                T::Int48 => {
-                    let val =
-                        i64::from_be_bytes([0, 0, cursor[0], cursor[1], cursor[2], cursor[3], cursor[4], cursor[5]]);
-                    cursor = &cursor[6..];
-                    V::Int(val)
-                }
+                T::Int8 => V::Int(read_int(&mut cursor, 1)),
+                T::Int16 => V::Int(read_int(&mut cursor, 2)),
+                T::Int24 => V::Int(read_int(&mut cursor, 3)),
+                T::Int32 => V::Int(read_int(&mut cursor, 4)),
+                T::Int48 => V::Int(read_int(&mut cursor, 6)),
                 T::Int64 => {
                     let val = i64::from_be_bytes([
                         cursor[0], cursor[1], cursor[2], cursor[3], cursor[4], cursor[5], cursor[6], cursor[7],
@@ -71,8 +51,12 @@ impl SerialType {
                 T::Zero => V::Int(0),
                 T::One => V::Int(1),
                 T::Internal => None?,
-                T::Blob { .. } => None?,
-                T::Text { size } => V::String(next_utf8(&mut cursor, *size as usize)),
+                T::Blob { size } => {
+                    let (val, rest) = cursor.split_at(*size as usize);
+                    cursor = rest;
+                    V::Blob(val.into())
+                }
+                T::Text { size } => V::String(next_utf8(&mut cursor, *size as usize).into()),
             })
         })
     }
@@ -132,9 +116,9 @@ impl From<SerialType> for Option<SqlType> {
             St::Int32 => T::Integer,
             St::Int48 => T::Integer,
             St::Int64 => T::Integer,
-            St::Float => T::Integer,
-            St::Zero => T::Numeric,
-            St::One => T::Numeric,
+            St::Float => T::Real,
+            St::Zero => T::Integer,
+            St::One => T::Integer,
             St::Internal => None?,
             St::Blob { .. } => T::Blob,
             St::Text { .. } => T::Text,
@@ -154,33 +138,25 @@ pub struct Schema {
 
 impl Schema {
     pub fn new(payload: Vec<u8>) -> Self {
-        use SerialType::*;
-
-        let mut cursor = payload.as_slice();
-
-        read_varint(&mut cursor); // header size
-        let Text { size: ty_size } = SerialType::from(read_varint(&mut cursor).0 as u64) else {
-            panic!("invalid serial type for schema type")
+        let mut values = parse_record(&payload).into_iter();
+        let mut next_text = |field: &str| match values.next() {
+            Some(Value::String(s)) => s.into_owned(),
+            _ => panic!("invalid serial type for schema {field}"),
         };
-        let Text { size: name_size } = SerialType::from(read_varint(&mut cursor).0 as u64) else {
-            panic!("invalid serial type for schema name")
+        let ty = next_text("type");
+        let name = next_text("name");
+        let tbl_name = next_text("tbl_name");
+        let Some(Value::Int(rootpage)) = values.next() else {
+            panic!("invalid serial type for schema rootpage")
         };
-        let Text { size: tbl_size } = SerialType::from(read_varint(&mut cursor).0 as u64) else {
-            panic!("invalid serial type for schema tbl_name")
-        };
-        match read_varint(&mut cursor).0 as u64 {
-            1..=6 | 8 | 9 => {}
-            _ => panic!("invalid serial type for schema rootpage"),
-        };
-        let Text { size: sql_size } = SerialType::from(read_varint(&mut cursor).0 as u64) else {
-            panic!("invalid serial type for schema sql")
-        };
-        let ty = next_utf8(&mut cursor, ty_size as usize).to_owned();
-        let name = next_utf8(&mut cursor, name_size as usize).to_owned();
-        let tbl_name = next_utf8(&mut cursor, tbl_size as usize).to_owned();
         // If the rootpage is negative or doesn't fit... 💥
-        let rootpage = read_varint(&mut cursor).0 as u32;
-        let sql = next_utf8(&mut cursor, sql_size as usize).to_owned();
+        let rootpage = rootpage as u32;
+        // Internal indexes, like the ones created for UNIQUE constraints, have no sql.
+        let sql = values.next().map_or(String::new(), |v| match v {
+            Value::String(s) => s.into_owned(),
+            Value::Null => String::new(),
+            _ => panic!("invalid serial type for schema sql"),
+        });
 
         Self {
             ty,
@@ -192,9 +168,103 @@ impl Schema {
     }
 }
 
+/// Decodes the values of a record, see "Record Format" in <https://sqlite.org/fileformat2.html>.
+pub fn parse_record(payload: &[u8]) -> Vec<Value<'_>> {
+    let (header_size, header_int_size) = read_varint(&mut &payload[..]);
+    let mut header = &payload[header_int_size as usize..header_size as usize];
+    // HACK: Can we do this without allocation? By implementing a iterator on serial types.
+    let mut sts = vec![];
+    while !header.is_empty() {
+        let st = SerialType::from(read_varint(&mut header).0 as u64);
+        sts.push(st);
+    }
+    SerialType::parse_payload(&sts, &payload[header_size as usize..]).collect()
+}
+
+/// Encodes values into a record, the inverse of [`parse_record`].
+pub fn make_record(values: &[Value]) -> Vec<u8> {
+    let mut header = vec![];
+    let mut body = vec![];
+    for value in values {
+        let st = match value {
+            Value::Null => SerialType::Null,
+            Value::Int(0) => SerialType::Zero,
+            Value::Int(1) => SerialType::One,
+            Value::Int(i) => {
+                let (st, size) = match i {
+                    -0x80..0x80 => (SerialType::Int8, 1),
+                    -0x8000..0x8000 => (SerialType::Int16, 2),
+                    -0x80_0000..0x80_0000 => (SerialType::Int24, 3),
+                    -0x8000_0000..0x8000_0000 => (SerialType::Int32, 4),
+                    -0x8000_0000_0000..0x8000_0000_0000 => (SerialType::Int48, 6),
+                    _ => (SerialType::Int64, 8),
+                };
+                body.extend_from_slice(&i.to_be_bytes()[8 - size..]);
+                st
+            }
+            Value::Float(f) => {
+                body.extend_from_slice(&f.to_be_bytes());
+                SerialType::Float
+            }
+            Value::String(s) => {
+                body.extend_from_slice(s.as_bytes());
+                SerialType::Text { size: s.len() as u64 }
+            }
+            Value::Blob(b) => {
+                body.extend_from_slice(b);
+                SerialType::Blob { size: b.len() as u64 }
+            }
+        };
+        write_varint(&mut header, st.into());
+    }
+    // The header size counts its own varint, which may need a second byte once the header grows.
+    let mut header_size = header.len() + 1;
+    let mut record = vec![];
+    loop {
+        write_varint(&mut record, header_size as u64);
+        if record.len() + header.len() == header_size {
+            break;
+        }
+        header_size = record.len() + header.len();
+        record.clear();
+    }
+    record.extend_from_slice(&header);
+    record.extend_from_slice(&body);
+    record
+}
+
+/// Reads a big-endian two's complement integer of `size` bytes, sign-extending it to 64 bits.
+fn read_int(v: &mut &[u8], size: usize) -> i64 {
+    let mut bytes = if v[0] & 0x80 != 0 { [0xff; 8] } else { [0; 8] };
+    bytes[8 - size..].copy_from_slice(&v[..size]);
+    *v = &v[size..];
+    i64::from_be_bytes(bytes)
+}
+
 fn next_utf8<'a>(v: &mut &'a [u8], size: usize) -> &'a str {
     assert!(size <= v.len());
     let buf = &v[..size];
     *v = &v[size..];
     std::str::from_utf8(buf).expect("invalid utf8 string")
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn negative_integers_round_trip() {
+        let ints = [-1, -0x80, -200, -0x8000, -70_000, -(1 << 31), -(1 << 40), i64::MIN];
+        let values: Vec<Value> = ints.into_iter().map(Value::Int).collect();
+        assert_eq!(parse_record(&make_record(&values)), values);
+    }
+
+    #[test]
+    fn integer_serial_types_are_signed() {
+        // A REAL column holding -1.0 is stored by SQLite as the one byte integer 0xff.
+        let record = [2, 1, 0xff];
+        assert_eq!(parse_record(&record), [Value::Int(-1)]);
+        let record = [2, 3, 0xfe, 0xee, 0x90];
+        assert_eq!(parse_record(&record), [Value::Int(-70_000)]);
+    }
+}
@@ -0,0 +1,156 @@
+//! Rows of the result of a statement, and reading their values as Rust types.
+
+use anyhow::Result;
+use anyhow::bail;
+use parser::Value;
+
+/// A row of the result of a statement.
+pub struct Row<'r> {
+    pub(crate) values: &'r [Value<'static>],
+    pub(crate) columns: &'r [String],
+}
+
+impl<'r> Row<'r> {
+    /// Reads the value of a column, picked by its position from 0 or by its name.
+    pub fn get<T: FromSql<'r>>(&self, index: impl RowIndex) -> Result<T> {
+        let i = index.index(self.columns)?;
+        T::from_sql(&self.values[i]).map_err(|e| anyhow::anyhow!("column {}: {e}", self.columns[i]))
+    }
+
+    /// The values of the columns, in order.
+    pub fn values(&self) -> &'r [Value<'static>] {
+        self.values
+    }
+}
+
+/// A way to pick a column of a row.
+pub trait RowIndex {
+    /// The position of the column among the ones named `columns`.
+    fn index(&self, columns: &[String]) -> Result<usize>;
+}
+
+impl RowIndex for usize {
+    fn index(&self, columns: &[String]) -> Result<usize> {
+        match *self < columns.len() {
+            true => Ok(*self),
+            false => bail!("column index out of range"),
+        }
+    }
+}
+
+/// Names are compared ignoring case, the first column with the name is the one picked.
+impl RowIndex for &str {
+    fn index(&self, columns: &[String]) -> Result<usize> {
+        match columns.iter().position(|c| c.eq_ignore_ascii_case(self)) {
+            Some(i) => Ok(i),
+            None => bail!("no such column: {self}"),
+        }
+    }
+}
+
+/// Types the values of a row can be read as.
+pub trait FromSql<'v>: Sized {
+    fn from_sql(value: &'v Value<'static>) -> Result<Self>;
+}
+
+fn mismatch<T>(value: &Value, ty: &str) -> Result<T> {
+    bail!("{} value read as {ty}", value.type_name())
+}
+
+impl FromSql<'_> for i64 {
+    fn from_sql(value: &Value<'static>) -> Result<Self> {
+        match value {
+            Value::Int(i) => Ok(*i),
+            v => mismatch(v, "an integer"),
+        }
+    }
+}
+
+/// Integers are read as reals too.
+impl FromSql<'_> for f64 {
+    fn from_sql(value: &Value<'static>) -> Result<Self> {
+        match value {
+            Value::Float(f) => Ok(*f),
+            Value::Int(i) => Ok(*i as f64),
+            v => mismatch(v, "a real"),
+        }
+    }
+}
+
+impl FromSql<'_> for String {
+    fn from_sql(value: &Value<'static>) -> Result<Self> {
+        <&str>::from_sql(value).map(str::to_string)
+    }
+}
+
+impl<'v> FromSql<'v> for &'v str {
+    fn from_sql(value: &'v Value<'static>) -> Result<Self> {
+        match value {
+            Value::String(s) => Ok(s),
+            v => mismatch(v, "text"),
+        }
+    }
+}
+
+impl FromSql<'_> for Vec<u8> {
+    fn from_sql(value: &Value<'static>) -> Result<Self> {
+        match value {
+            Value::Blob(b) => Ok(b.to_vec()),
+            v => mismatch(v, "a blob"),
+        }
+    }
+}
+
+/// Integers are true unless they're 0.
+impl FromSql<'_> for bool {
+    fn from_sql(value: &Value<'static>) -> Result<Self> {
+        i64::from_sql(value).map(|i| i != 0)
+    }
+}
+
+/// NULL is read as `None`.
+impl<'v, T: FromSql<'v>> FromSql<'v> for Option<T> {
+    fn from_sql(value: &'v Value<'static>) -> Result<Self> {
+        match value {
+            Value::Null => Ok(None),
+            v => T::from_sql(v).map(Some),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn from_sql() {
+        let columns = ["i", "f", "s", "b", "n"].map(String::from);
+        let values = [
+            Value::Int(3),
+            Value::Float(2.5),
+            Value::String("text".into()),
+            Value::Blob(vec![1, 2].into()),
+            Value::Null,
+        ];
+        let row = Row {
+            values: &values,
+            columns: &columns,
+        };
+        assert_eq!(row.get::<i64>(0).unwrap(), 3);
+        assert_eq!(row.get::<f64>("I").unwrap(), 3.0);
+        assert_eq!(row.get::<f64>("f").unwrap(), 2.5);
+        assert!(row.get::<bool>("i").unwrap());
+        assert_eq!(row.get::<&str>(2).unwrap(), "text");
+        assert_eq!(row.get::<String>("s").unwrap(), "text");
+        assert_eq!(row.get::<Vec<u8>>("b").unwrap(), [1, 2]);
+        assert_eq!(row.get::<Option<i64>>("n").unwrap(), None);
+        assert_eq!(row.get::<Option<&str>>("s").unwrap(), Some("text"));
+        assert_eq!(
+            row.get::<i64>("f").unwrap_err().to_string(),
+            "column f: real value read as an integer"
+        );
+        assert!(row.get::<String>("n").is_err());
+        assert_eq!(row.get::<i64>(5).unwrap_err().to_string(), "column index out of range");
+        assert_eq!(row.get::<i64>("x").unwrap_err().to_string(), "no such column: x");
+    }
+}
@@ -0,0 +1,439 @@
+//! External merge sort for query results.
+//!
+//! Rows are sorted in memory until they take more than the configured amount of memory, then each batch is written
+//! to a temp file as a sorted run and the runs are merged back together when the rows are read. When only the first
+//! rows are wanted, like with a LIMIT, the sorter keeps just those in a heap instead.
+
+use std::cmp::Ordering;
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::rc::Rc;
+
+use anyhow::Result;
+use parser::Collation;
+use parser::Value;
+
+use crate::spill::RunReader;
+use crate::spill::RunWriter;
+use crate::spill::TempFile;
+use crate::spill::row_size;
+
+pub type SortRow = Vec<Value<'static>>;
+
+/// Most runs merged at once, more than that are merged into bigger runs first to keep the open files in check.
+const MERGE_FAN_IN: usize = 64;
+
+/// How one of the leading values of a row is ordered.
+#[derive(Debug, Clone, Copy)]
+pub struct SortOrder {
+    pub desc: bool,
+    pub nulls_first: bool,
+    pub collation: Collation,
+}
+
+/// Compares rows by their leading values, one per sort order.
+pub fn compare_rows(order: &[SortOrder], a: &[Value], b: &[Value]) -> Ordering {
+    for (o, (a, b)) in order.iter().zip(a.iter().zip(b)) {
+        let ord = match (a, b) {
+            (Value::Null, Value::Null) => Ordering::Equal,
+            (Value::Null, _) if o.nulls_first => Ordering::Less,
+            (Value::Null, _) => Ordering::Greater,
+            (_, Value::Null) if o.nulls_first => Ordering::Greater,
+            (_, Value::Null) => Ordering::Less,
+            (a, b) if o.desc => o.collation.compare(b, a),
+            (a, b) => o.collation.compare(a, b),
+        };
+        if ord.is_ne() {
+            return ord;
+        }
+    }
+    Ordering::Equal
+}
+
+pub struct Sorter {
+    order: Rc<[SortOrder]>,
+    rows: Vec<SortRow>,
+    /// Estimated size of `rows`, or of `top`.
+    memory: usize,
+    memory_limit: usize,
+    runs: Vec<TempFile>,
+    /// Number of rows wanted, if not all of them.
+    limit: Option<usize>,
+    /// The first `limit` rows pushed so far, the last of them in sort order on top.
+    top: BinaryHeap<Reverse<Head>>,
+    pushed: usize,
+}
+
+impl Sorter {
+    /// With a `limit` only the first rows in sort order are returned.
+    pub fn new(order: Vec<SortOrder>, memory_limit: usize, limit: Option<usize>) -> Self {
+        Self {
+            order: order.into(),
+            rows: vec![],
+            memory: 0,
+            memory_limit,
+            runs: vec![],
+            limit,
+            top: BinaryHeap::new(),
+            pushed: 0,
+        }
+    }
+
+    pub fn push(&mut self, row: SortRow) -> Result<()> {
+        if let Some(limit) = self.limit {
+            self.push_top(row, limit);
+            return Ok(());
+        }
+        self.memory += row_size(&row);
+        self.rows.push(row);
+        if self.memory > self.memory_limit {
+            self.spill()?;
+        }
+        Ok(())
+    }
+
+    fn push_top(&mut self, row: SortRow, limit: usize) {
+        self.memory += row_size(&row);
+        self.top.push(Reverse(Head {
+            order: self.order.clone(),
+            row,
+            source: self.pushed,
+        }));
+        self.pushed += 1;
+        if self.top.len() > limit
+            && let Some(Reverse(last)) = self.top.pop()
+        {
+            self.memory -= row_size(&last.row);
+        }
+        // A limit too large to keep the rows in memory is no better than a full sort, which can spill them.
+        if self.memory > self.memory_limit {
+            self.limit = None;
+            self.rows = std::mem::take(&mut self.top)
+                .into_sorted_vec()
+                .into_iter()
+                .map(|r| r.0.row)
+                .collect();
+        }
+    }
+
+    /// Writes the rows in memory to a new sorted run.
+    fn spill(&mut self) -> Result<()> {
+        let mut rows = std::mem::take(&mut self.rows);
+        rows.sort_by(|a, b| compare_rows(&self.order, a, b));
+        let run = TempFile::create()?;
+        let mut writer = RunWriter::new(&run)?;
+        for row in &rows {
+            writer.write(row)?;
+        }
+        writer.finish()?;
+        self.runs.push(run);
+        self.memory = 0;
+        Ok(())
+    }
+
+    /// Returns the rows in order, rows that compare equal keep the order they were pushed in.
+    pub fn finish(mut self) -> Result<SortedRows> {
+        if self.limit.is_some() {
+            let rows = self
+                .top
+                .into_sorted_vec()
+                .into_iter()
+                .map(|r| r.0.row)
+                .collect::<Vec<_>>();
+            return Ok(SortedRows::Memory(rows.into_iter()));
+        }
+        self.rows.sort_by(|a, b| compare_rows(&self.order, a, b));
+        if self.runs.is_empty() {
+            return Ok(SortedRows::Memory(self.rows.into_iter()));
+        }
+        // Merging neighbouring runs keeps them in the order they were written, for the sort to stay stable.
+        while self.runs.len() > MERGE_FAN_IN {
+            let mut runs = std::mem::take(&mut self.runs).into_iter().peekable();
+            while runs.peek().is_some() {
+                let merged = TempFile::create()?;
+                let mut writer = RunWriter::new(&merged)?;
+                for row in Merge::new(self.order.clone(), runs.by_ref().take(MERGE_FAN_IN).collect(), vec![])? {
+                    writer.write(&row?)?;
+                }
+                writer.finish()?;
+                self.runs.push(merged);
+            }
+        }
+        Ok(SortedRows::Merge(Merge::new(self.order, self.runs, self.rows)?))
+    }
+}
+
+pub enum SortedRows {
+    Memory(std::vec::IntoIter<SortRow>),
+    Merge(Merge),
+}
+
+impl Iterator for SortedRows {
+    type Item = Result<SortRow>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self {
+            Self::Memory(rows) => rows.next().map(Ok),
+            Self::Merge(merge) => merge.next(),
+        }
+    }
+}
+
+/// Sorted rows without the repeated ones, rows being equal when their leading values compare equal. Like SQLite, the
+/// last of the rows that are equal is the one kept, so `1` and `1.0` make `1.0`.
+pub struct Distinct {
+    rows: SortedRows,
+    order: Vec<SortOrder>,
+    /// The row read ahead, to see whether the rows after it are equal to it.
+    next: Option<SortRow>,
+}
+
+impl Distinct {
+    pub fn new(rows: SortedRows, order: &[SortOrder]) -> Self {
+        Self {
+            rows,
+            order: order.to_vec(),
+            next: None,
+        }
+    }
+}
+
+impl Iterator for Distinct {
+    type Item = Result<SortRow>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut row = match self.next.take() {
+            Some(row) => row,
+            None => match self.rows.next()? {
+                Ok(row) => row,
+                Err(e) => return Some(Err(e)),
+            },
+        };
+        for next in self.rows.by_ref() {
+            let next = match next {
+                Ok(next) => next,
+                Err(e) => return Some(Err(e)),
+            };
+            if !compare_rows(&self.order, &row, &next).is_eq() {
+                self.next = Some(next);
+                break;
+            }
+            row = next;
+        }
+        Some(Ok(row))
+    }
+}
+
+/// K-way merge of sorted runs, plus the rows that were still in memory.
+pub struct Merge {
+    order: Rc<[SortOrder]>,
+    readers: Vec<RunReader>,
+    memory: std::vec::IntoIter<SortRow>,
+    heap: BinaryHeap<Head>,
+}
+
+/// The smallest row not yet returned from one of the merged sources.
+struct Head {
+    order: Rc<[SortOrder]>,
+    row: SortRow,
+    /// Where the row comes from, ties go to the smaller source. When merging it is the index of the row's run, the
+    /// rows in memory coming after every run, and when keeping the first rows it is the row's position.
+    source: usize,
+}
+
+impl Ord for Head {
+    fn cmp(&self, other: &Self) -> Ordering {
+        // `BinaryHeap` pops the greatest element, so the comparison is reversed. Ties go to the earlier source, which
+        // keeps the sort stable.
+        compare_rows(&self.order, &other.row, &self.row).then(other.source.cmp(&self.source))
+    }
+}
+
+impl PartialOrd for Head {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Head {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other).is_eq()
+    }
+}
+
+impl Eq for Head {}
+
+impl Merge {
+    fn new(order: Rc<[SortOrder]>, runs: Vec<TempFile>, memory: Vec<SortRow>) -> Result<Self> {
+        let mut merge = Self {
+            order,
+            readers: runs.into_iter().map(RunReader::open).collect::<Result<_>>()?,
+            memory: memory.into_iter(),
+            heap: BinaryHeap::new(),
+        };
+        for source in 0..=merge.readers.len() {
+            merge.refill(source)?;
+        }
+        Ok(merge)
+    }
+
+    /// Pushes the next row of the source onto the heap.
+    fn refill(&mut self, source: usize) -> Result<()> {
+        let row = match self.readers.get_mut(source) {
+            Some(reader) => reader.read()?,
+            None => self.memory.next(),
+        };
+        if let Some(row) = row {
+            self.heap.push(Head {
+                order: self.order.clone(),
+                row,
+                source,
+            });
+        }
+        Ok(())
+    }
+}
+
+impl Iterator for Merge {
+    type Item = Result<SortRow>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let Head { row, source, .. } = self.heap.pop()?;
+        Some(self.refill(source).map(|_| row))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sort(rows: Vec<SortRow>, order: Vec<SortOrder>, memory_limit: usize) -> Vec<SortRow> {
+        sort_limit(rows, order, memory_limit, None)
+    }
+
+    fn sort_limit(
+        rows: Vec<SortRow>,
+        order: Vec<SortOrder>,
+        memory_limit: usize,
+        limit: Option<usize>,
+    ) -> Vec<SortRow> {
+        let mut sorter = Sorter::new(order, memory_limit, limit);
+        for row in rows {
+            sorter.push(row).unwrap();
+        }
+        sorter.finish().unwrap().map(Result::unwrap).collect()
+    }
+
+    #[test]
+    fn nulls_and_directions() {
+        let rows = vec![
+            vec![Value::Int(2), Value::String("b".into())],
+            vec![Value::Null, Value::String("a".into())],
+            vec![Value::Int(1), Value::Null],
+            vec![Value::Int(2), Value::Float(0.5)],
+        ];
+        let order = vec![
+            SortOrder {
+                desc: true,
+                nulls_first: false,
+                collation: Collation::Binary,
+            },
+            SortOrder {
+                desc: false,
+                nulls_first: false,
+                collation: Collation::Binary,
+            },
+        ];
+        assert_eq!(
+            sort(rows, order, usize::MAX),
+            vec![
+                vec![Value::Int(2), Value::Float(0.5)],
+                vec![Value::Int(2), Value::String("b".into())],
+                vec![Value::Int(1), Value::Null],
+                vec![Value::Null, Value::String("a".into())],
+            ]
+        );
+    }
+
+    #[test]
+    fn spills_to_runs() {
+        // Enough rows and a small enough limit to need more runs than are merged at once, the many ties check that
+        // the merge keeps the sort stable.
+        let rows: Vec<SortRow> = (0..5000i64)
+            .map(|i| {
+                vec![
+                    Value::Int(i * 7919 % 13),
+                    Value::Int(i),
+                    Value::String(format!("row {i}").into()),
+                ]
+            })
+            .collect();
+        let order = vec![SortOrder {
+            desc: false,
+            nulls_first: true,
+            collation: Collation::Binary,
+        }];
+        let mut expected = rows.clone();
+        expected.sort_by(|a, b| compare_rows(&order, a, b));
+        let sorted = sort(rows, order, 1000);
+        assert!(
+            sorted
+                .iter()
+                .zip(&expected)
+                .all(|(a, b)| matches!((&a[1], &b[1]), (Value::Int(a), Value::Int(b)) if a == b))
+        );
+        assert_eq!(sorted, expected);
+    }
+
+    #[test]
+    fn first_rows() {
+        let rows: Vec<SortRow> = (0..1000i64)
+            .map(|i| vec![Value::Int(i * 7919 % 13), Value::Int(i)])
+            .collect();
+        let order = vec![SortOrder {
+            desc: true,
+            nulls_first: false,
+            collation: Collation::Binary,
+        }];
+        let mut expected = rows.clone();
+        expected.sort_by(|a, b| compare_rows(&order, a, b));
+        expected.truncate(100);
+        assert_eq!(sort_limit(rows.clone(), order.clone(), usize::MAX, Some(100)), expected);
+        // Past the memory limit it falls back to a full sort.
+        assert_eq!(
+            sort_limit(rows.clone(), order.clone(), 1000, Some(100))[..100],
+            expected
+        );
+        assert!(sort_limit(rows, order, usize::MAX, Some(0)).is_empty());
+    }
+
+    #[test]
+    fn distinct_keeps_last() {
+        let order = vec![SortOrder {
+            desc: false,
+            nulls_first: true,
+            collation: Collation::Binary,
+        }];
+        let mut sorter = Sorter::new(order.clone(), usize::MAX, None);
+        for row in [
+            vec![Value::Int(2), Value::Int(0)],
+            vec![Value::Null, Value::Int(1)],
+            vec![Value::Float(2.0), Value::Int(2)],
+            vec![Value::Null, Value::Int(3)],
+            vec![Value::Int(1), Value::Int(4)],
+        ] {
+            sorter.push(row).unwrap();
+        }
+        let rows: Vec<_> = Distinct::new(sorter.finish().unwrap(), &order)
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(
+            rows,
+            vec![
+                vec![Value::Null, Value::Int(3)],
+                vec![Value::Int(1), Value::Int(4)],
+                vec![Value::Float(2.0), Value::Int(2)],
+            ]
+        );
+    }
+}
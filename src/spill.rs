@@ -0,0 +1,129 @@
+//! Temp files for rows that don't fit in memory.
+
+use std::fs::File;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::BufWriter;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+
+use anyhow::Context;
+use anyhow::Result;
+use parser::Value;
+
+use crate::record::make_record;
+use crate::record::parse_record;
+use crate::varint::read_varint;
+use crate::varint::write_varint;
+
+/// Estimate of the memory taken by a row.
+pub fn row_size(row: &[Value]) -> usize {
+    let values: usize = row
+        .iter()
+        .map(|v| match v {
+            Value::String(s) => s.len(),
+            Value::Blob(b) => b.len(),
+            _ => 0,
+        })
+        .sum();
+    size_of::<Vec<Value>>() + size_of_val(row) + values
+}
+
+/// A file in the system temp directory, removed once dropped.
+pub struct TempFile {
+    path: PathBuf,
+}
+
+impl TempFile {
+    pub fn create() -> Result<Self> {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let n = NEXT.fetch_add(1, Ordering::Relaxed);
+        let path = std::env::temp_dir().join(format!("rusqlite-{}-{n}", std::process::id()));
+        File::create_new(&path).with_context(|| format!("creating temp file {}", path.display()))?;
+        Ok(Self { path })
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Writes a run of rows: a sequence of records, each one prefixed by its length.
+pub struct RunWriter {
+    file: BufWriter<File>,
+    buf: Vec<u8>,
+    written: u64,
+}
+
+impl RunWriter {
+    pub fn new(run: &TempFile) -> Result<Self> {
+        Ok(Self {
+            file: BufWriter::new(File::create(&run.path)?),
+            buf: vec![],
+            written: 0,
+        })
+    }
+
+    pub fn write(&mut self, row: &[Value]) -> Result<()> {
+        let record = make_record(row);
+        self.buf.clear();
+        write_varint(&mut self.buf, record.len() as u64);
+        self.buf.extend_from_slice(&record);
+        self.file.write_all(&self.buf)?;
+        self.written += self.buf.len() as u64;
+        Ok(())
+    }
+
+    /// Offset of the next row in the run, for a reader to seek to.
+    pub fn position(&self) -> u64 {
+        self.written
+    }
+
+    pub fn finish(mut self) -> Result<()> {
+        self.file.flush()?;
+        Ok(())
+    }
+}
+
+/// Reads back the rows of a run, in the order they were written.
+pub struct RunReader {
+    file: BufReader<File>,
+    buf: Vec<u8>,
+    /// Keeps the file around until the run has been read.
+    _run: TempFile,
+}
+
+impl RunReader {
+    pub fn open(run: TempFile) -> Result<Self> {
+        Ok(Self {
+            file: BufReader::new(File::open(&run.path)?),
+            buf: vec![],
+            _run: run,
+        })
+    }
+
+    /// Moves to the row written at `offset`.
+    pub fn seek(&mut self, offset: u64) -> Result<()> {
+        self.file.seek(SeekFrom::Start(offset))?;
+        Ok(())
+    }
+
+    pub fn read(&mut self) -> Result<Option<Vec<Value<'static>>>> {
+        if self.file.fill_buf()?.is_empty() {
+            return Ok(None);
+        }
+        let (len, _) = read_varint(&mut self.file);
+        self.buf.resize(len as usize, 0);
+        self.file.read_exact(&mut self.buf)?;
+        Ok(Some(
+            parse_record(&self.buf).into_iter().map(Value::into_owned).collect(),
+        ))
+    }
+}
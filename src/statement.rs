@@ -0,0 +1,304 @@
+//! Prepared statements, compiled once and run as many times as needed with the values bound to their parameters.
+//!
+//! Parameters are numbered like SQLite does: `?` takes the number after the largest one so far, `?NNN` takes NNN, and
+//! `:name`, `@name` and `$name` take the number after the largest one the first time the name is used and the same one
+//! after that.
+
+use std::rc::Rc;
+
+use anyhow::Result;
+use anyhow::bail;
+use parser::Value;
+use parser::sql;
+
+use crate::Connection;
+use crate::Database;
+use crate::analyze;
+use crate::codegen;
+use crate::connection::Running;
+use crate::functions::Functions;
+use crate::planner::Catalog;
+use crate::planner::Planner;
+use crate::query_plan::QueryPlan;
+use crate::row::Row;
+use crate::vm::Context;
+use crate::vm::Program;
+use crate::vm::Vm;
+
+/// The largest number of a parameter, SQLite's default limit.
+const MAX_PARAMETERS: usize = 32766;
+
+/// The parameters of a statement and the values bound to them.
+pub struct Parameters<'a> {
+    /// The offset in the text of each placeholder and the number of the parameter it stands for, in the order they're
+    /// written.
+    placeholders: Vec<(usize, usize)>,
+    /// The name of each parameter, none for the ones only `?` stands for.
+    names: Vec<Option<&'a str>>,
+    /// The value bound to each parameter, NULL until one is.
+    values: Vec<Value<'static>>,
+}
+
+impl<'a> Parameters<'a> {
+    /// Numbers the parameters of the text of a statement.
+    pub fn new(sql: &'a str) -> Result<Self> {
+        let mut placeholders = vec![];
+        let mut names: Vec<Option<&str>> = vec![];
+        for (offset, name) in sql::parameters(sql)? {
+            let number = match name.strip_prefix('?') {
+                Some("") => names.len() + 1,
+                Some(number) => match number.parse() {
+                    Ok(n @ 1..=MAX_PARAMETERS) => n,
+                    _ => bail!("variable number must be between ?1 and ?{MAX_PARAMETERS}"),
+                },
+                None => match names.iter().position(|n| *n == Some(name)) {
+                    Some(i) => i + 1,
+                    None => names.len() + 1,
+                },
+            };
+            if number > MAX_PARAMETERS {
+                bail!("too many SQL variables");
+            }
+            if number > names.len() {
+                names.resize(number, None);
+            }
+            // A parameter is named after the first placeholder naming it.
+            if name != "?" && names[number - 1].is_none() {
+                names[number - 1] = Some(name);
+            }
+            placeholders.push((offset, number));
+        }
+        Ok(Self {
+            placeholders,
+            values: vec![Value::Null; names.len()],
+            names,
+        })
+    }
+
+    /// The value bound to the parameter of the placeholder at `offset`.
+    pub fn value(&self, offset: usize) -> &Value<'static> {
+        let i = self
+            .placeholders
+            .binary_search_by_key(&offset, |(offset, _)| *offset)
+            .expect("placeholder missing from the statement");
+        &self.values[self.placeholders[i].1 - 1]
+    }
+}
+
+/// A statement compiled once, whose program runs again each time it's queried. It's compiled again when the schema
+/// or the statistics of the database changed since.
+pub struct Statement<'c> {
+    conn: &'c Connection,
+    /// The database the program reads, as it was when the statement was compiled.
+    db: Rc<Database>,
+    kind: Kind<'c>,
+    /// Names of the result columns.
+    columns: Vec<String>,
+    parameters: Parameters<'c>,
+    /// The catalog the program was compiled against, which it refers to.
+    catalog: Rc<Catalog>,
+    /// The text of the statement, which the program and the parameters refer to. It and the catalog are dropped after
+    /// them since they come last.
+    sql: Rc<str>,
+}
+
+enum Kind<'c> {
+    Select(Program<'c>),
+    /// `EXPLAIN` or `EXPLAIN QUERY PLAN`, which give a listing instead of rows.
+    Explain(String),
+    Analyze(Option<&'c str>),
+}
+
+impl<'c> Statement<'c> {
+    /// Compiles a statement, keeping a copy of its text for as long as the program refers to it.
+    pub(crate) fn new(conn: &'c Connection, sql: &str) -> Result<Self> {
+        let sql: Rc<str> = sql.into();
+        // SAFETY: The statement keeps the text on the heap, where it stays when the statement moves.
+        let text = unsafe { kept(&*sql) };
+        let catalog = conn.catalog();
+        // SAFETY: Likewise for the catalog.
+        let (kind, columns) = compile(text, unsafe { kept(&*catalog) }, conn.functions())?;
+        Ok(Self {
+            conn,
+            db: conn.database(),
+            kind,
+            columns,
+            parameters: Parameters::new(text)?,
+            catalog,
+            sql,
+        })
+    }
+
+    /// Compiles the statement again if the connection read the database again since it was.
+    fn recompile(&mut self) -> Result<()> {
+        let catalog = self.conn.catalog();
+        if Rc::ptr_eq(&catalog, &self.catalog) {
+            return Ok(());
+        }
+        // SAFETY: As in `new`, the program being replaced before the catalog it refers to.
+        let (kind, columns) = compile(
+            unsafe { kept(&*self.sql) },
+            unsafe { kept(&*catalog) },
+            self.conn.functions(),
+        )?;
+        self.kind = kind;
+        self.columns = columns;
+        self.catalog = catalog;
+        self.db = self.conn.database();
+        Ok(())
+    }
+
+    /// The text the statement was compiled from.
+    pub fn sql(&self) -> &str {
+        &self.sql
+    }
+
+    /// Names of the result columns: their alias, or the name of the column they are, or else the expression as
+    /// written.
+    pub fn column_names(&self) -> &[String] {
+        &self.columns
+    }
+
+    /// The listing of an `EXPLAIN` or `EXPLAIN QUERY PLAN` statement, which has no rows.
+    pub fn explain(&self) -> Option<&str> {
+        match &self.kind {
+            Kind::Explain(listing) => Some(listing),
+            _ => None,
+        }
+    }
+
+    /// Number of parameters, which is the largest number of one.
+    pub fn parameter_count(&self) -> usize {
+        self.parameters.names.len()
+    }
+
+    /// The number of the parameter with the name, written with its `:`, `@` or `$` prefix.
+    pub fn parameter_index(&self, name: &str) -> Option<usize> {
+        let names = &self.parameters.names;
+        names.iter().position(|n| *n == Some(name)).map(|i| i + 1)
+    }
+
+    /// Binds a value to the parameter numbered `index`, counting from 1. It stays bound for the next runs of the
+    /// statement until another one is.
+    pub fn bind(&mut self, index: usize, value: Value) -> Result<()> {
+        match self.parameters.values.get_mut(index.wrapping_sub(1)) {
+            Some(bound) => *bound = value.into_owned(),
+            None => bail!("column index out of range"),
+        }
+        Ok(())
+    }
+
+    /// Binds a value to the parameter with the name.
+    pub fn bind_named(&mut self, name: &str, value: Value) -> Result<()> {
+        match self.parameter_index(name) {
+            Some(index) => self.bind(index, value),
+            None => bail!("no such parameter: {name}"),
+        }
+    }
+
+    /// Runs the statement, its rows being produced as they're read. The values of `params` are bound to the
+    /// parameters in order first, there has to be one for each of them unless there's none, which runs with the values
+    /// bound before.
+    pub fn query(&mut self, params: &[Value]) -> Result<Rows<'_>> {
+        self.recompile()?;
+        if !params.is_empty() {
+            if params.len() != self.parameter_count() {
+                bail!("{} values for {} parameters", params.len(), self.parameter_count());
+            }
+            for (i, value) in params.iter().enumerate() {
+                self.bind(i + 1, value.clone())?;
+            }
+        }
+        let vm = match &self.kind {
+            Kind::Select(program) => {
+                let ctx = Context::new(
+                    program,
+                    &self.db,
+                    self.conn.config(),
+                    self.conn.functions(),
+                    &self.parameters,
+                );
+                Some(Vm::new(Rc::new(ctx), 0, vec![]))
+            }
+            Kind::Explain(_) => None,
+            Kind::Analyze(name) => {
+                // The database is mapped shared, the rows being read would see the pages change under them.
+                if self.conn.is_running() {
+                    bail!("cannot ANALYZE - SQL statements in progress");
+                }
+                analyze::analyze(self.conn.path(), *name)?;
+                self.conn.reload()?;
+                None
+            }
+        };
+        Ok(Rows {
+            running: vm.as_ref().map(|_| self.conn.run()),
+            vm,
+            columns: &self.columns,
+        })
+    }
+
+    /// Runs the statement to the end, leaving out its rows.
+    pub fn execute(&mut self, params: &[Value]) -> Result<()> {
+        let mut rows = self.query(params)?;
+        while rows.next()?.is_some() {}
+        Ok(())
+    }
+}
+
+/// Extends a borrow of what a statement keeps to the lifetime of the connection, for the fields of the statement
+/// referring to it.
+///
+/// # Safety
+///
+/// The statement has to keep it where it is until the fields referring to it are dropped. It's kept in an `Rc`, which
+/// unlike a `Box` doesn't claim to be the only way to reach it.
+unsafe fn kept<'c, T: ?Sized>(value: &T) -> &'c T {
+    unsafe { &*(value as *const T) }
+}
+
+/// Compiles the text of a statement against the catalog, giving the names of its result columns along.
+fn compile<'c>(sql: &'c str, catalog: &'c Catalog, functions: &'c Functions) -> Result<(Kind<'c>, Vec<String>)> {
+    let planner = Planner::new(catalog, functions);
+    let mut columns = vec![];
+    let kind = match parser::parse_statement(sql).map_err(anyhow::Error::msg)? {
+        parser::Statement::Select(select) => {
+            let query = planner.plan(&select)?;
+            columns = query.columns.iter().map(|c| c.name.to_string()).collect();
+            Kind::Select(codegen::compile(&query, functions)?)
+        }
+        parser::Statement::Explain(select) => {
+            Kind::Explain(codegen::compile(&planner.plan(&select)?, functions)?.to_string())
+        }
+        parser::Statement::ExplainQueryPlan(select) => {
+            Kind::Explain(QueryPlan::new(&planner.plan(&select)?).to_string())
+        }
+        parser::Statement::Analyze(name) => Kind::Analyze(name),
+    };
+    Ok((kind, columns))
+}
+
+/// The rows of a statement, each read when asked for.
+pub struct Rows<'s> {
+    /// The machine running the program of the statement, none for the statements without rows.
+    vm: Option<Vm<'s>>,
+    columns: &'s [String],
+    /// Counts the statement as running until its rows are all read.
+    running: Option<Running<'s>>,
+}
+
+impl Rows<'_> {
+    /// The next row, `None` once there are no more.
+    #[allow(clippy::should_implement_trait)]
+    pub fn next(&mut self) -> Result<Option<Row<'_>>> {
+        let Some(vm) = &mut self.vm else {
+            return Ok(None);
+        };
+        let columns = self.columns;
+        let row = vm.step()?;
+        if row.is_none() {
+            self.running = None;
+        }
+        Ok(row.map(|values| Row { values, columns }))
+    }
+}
@@ -34,6 +34,11 @@ pub fn read_varint(buf: &mut impl Read) -> (i64, u8) {
             .unwrap_or_else(|e| panic!("error while reading buffer: {e:?}"));
         i += 1;
 
+        // The ninth byte uses all of its bits.
+        if i == 9 {
+            ret = ret << 8 | b[0] as i64;
+            break;
+        }
         ret = ret << 7 | (b[0] & !(1 << 7)) as i64;
         if b[0] >> 7 == 0 {
             break;
@@ -42,3 +47,44 @@ pub fn read_varint(buf: &mut impl Read) -> (i64, u8) {
 
     (ret, i)
 }
+
+/// Appends the varint encoding of the given number to the buffer.
+pub fn write_varint(buf: &mut Vec<u8>, value: u64) {
+    // Nine byte varints use all the bits of their last byte.
+    if value >> 56 != 0 {
+        let mut bytes = [0; 9];
+        bytes[8] = value as u8;
+        let mut rest = value >> 8;
+        for b in bytes[..8].iter_mut().rev() {
+            *b = (rest & 0x7f) as u8 | 0x80;
+            rest >>= 7;
+        }
+        buf.extend_from_slice(&bytes);
+        return;
+    }
+    let start = buf.len();
+    let mut rest = value;
+    loop {
+        buf.push((rest & 0x7f) as u8 | 0x80);
+        rest >>= 7;
+        if rest == 0 {
+            break;
+        }
+    }
+    buf[start] &= 0x7f;
+    buf[start..].reverse();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        for value in [0, 127, 128, 16384, 1 << 56, i64::MAX, -1, -5, i64::MIN] {
+            let mut buf = vec![];
+            write_varint(&mut buf, value as u64);
+            assert_eq!(read_varint(&mut &buf[..]), (value, buf.len() as u8));
+        }
+    }
+}
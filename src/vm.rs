@@ -1,140 +1,1815 @@
-use anyhow::Context;
-use anyhow::Result;
+//! The virtual machine running statements.
+//!
+//! Like SQLite's VDBE, a statement is compiled into a program for a register machine: opcodes move cursors over
+//! b-trees and temporary tables, load column values into registers, evaluate expressions and jump around, and
+//! `ResultRow` hands a row of registers to the caller. The subqueries of a statement are subprograms of the same
+//! program, run by a machine of their own with the values they need from the enclosing query as arguments.
+//!
+//! `EXPLAIN` lists the program instead of running it.
+
+use std::cell::OnceCell;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::collections::VecDeque;
+use std::fmt::Display;
+use std::rc::Rc;
 
-use anyhow::anyhow;
-use parser::ColumnDef;
-use parser::CreateTable;
-use parser::SelectColStmt;
-use parser::SelectCols;
+use anyhow::Result;
+use anyhow::bail;
+use parser::Collation;
+use parser::Expr;
 use parser::SqlType;
 use parser::Value;
-use parser::WhereExpr;
-use parser::sql;
 
 use crate::Database;
 use crate::Entry;
-use crate::Schema;
-use crate::SerialType;
-use crate::read_varint;
-
-pub fn handle_query(db: &Database, query: &str) -> Result<()> {
-    let select = sql::select(query)?;
-    let schema = get_tbl_schema(db, select.table)?;
-    let ct = sql::create_table(&schema.sql).expect("corrupt table");
-
-    match select.columns {
-        SelectColStmt::List(list) => {
-            let selected = match list {
-                SelectCols::List(cols) => &cols
-                    .iter()
-                    .flat_map(|col| ct.columns.iter().copied().find(|c| c.name == *col))
-                    .collect(),
-                SelectCols::All => &ct.columns,
-            };
-            for e in db.get_page(schema.rootpage).entries() {
-                let pe = parse_entry(&ct, &e);
-                if let Some(expr) = &select.expr
-                    && !where_matches(expr, &pe)?
-                {
-                    continue;
-                }
-                print_row(selected, pe);
-            }
-        }
-        SelectColStmt::Count(_) => {
-            println!("{}", db.get_page(schema.rootpage).entries().count())
-        }
-        SelectColStmt::Avg(col) => {
-            let ct = sql::create_table(&schema.sql).expect("corrupt create table statement");
-
-            let mut sum: f64 = 0.;
-            let mut count: usize = 0;
-
-            for entry in db.get_page(schema.rootpage).entries() {
-                let cloned = entry.clone();
-                let parsed_row = parse_entry(&ct, &cloned);
-
-                for (c, val) in parsed_row {
-                    if c == col {
-                        count += 1;
-                        match val {
-                            Value::Int(i) => sum += i as f64,
-                            Value::Float(i) => sum += i,
-                            Value::Null => {}
-                            Value::String(s) => sum += s.parse::<f64>().unwrap(),
+use crate::aggregate::AggregateSpec;
+use crate::aggregate::Group;
+use crate::aggregate::GroupKey;
+use crate::aggregate::HashAggregate;
+use crate::aggregate::HashGroups;
+use crate::aggregate::StreamAggregate;
+use crate::btree::EntryIter;
+use crate::btree::PageNumber;
+use crate::btree::compare_key;
+use crate::expr::Row;
+use crate::expr::SubqueryValues;
+use crate::expr::eval;
+use crate::expr::truth;
+use crate::functions::Functions;
+use crate::functions::TableFunction;
+use crate::parse_record;
+use crate::sorter::Distinct;
+use crate::sorter::SortOrder;
+use crate::sorter::SortRow;
+use crate::sorter::Sorter;
+use crate::sorter::compare_rows;
+use crate::spill::RunReader;
+use crate::spill::RunWriter;
+use crate::spill::TempFile;
+use crate::spill::row_size;
+use crate::statement::Parameters;
+use crate::window::Window;
+use crate::window::WindowSpec;
+
+/// Settings that tune query execution.
+pub struct Config {
+    /// Bytes of rows that sorting, grouping and hash joins keep in memory before spilling them to temp files.
+    pub memory_limit: usize,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self { memory_limit: 64 << 20 }
+    }
+}
+
+/// Index of a register of a subprogram.
+pub type Reg = usize;
+/// Index of a cursor of a subprogram.
+pub type CursorId = usize;
+/// Position of an opcode in the program.
+pub type Addr = usize;
+
+/// A compiled statement.
+pub struct Program<'a> {
+    /// The opcodes of the main program, followed by the ones of each subprogram.
+    pub ops: Vec<Op<'a>>,
+    /// A note on each opcode for EXPLAIN, empty for most of them.
+    pub comments: Vec<String>,
+    /// The main program first, then the queries it runs as subqueries.
+    pub subprograms: Vec<Subprogram<'a>>,
+}
+
+/// A part of a program that runs with registers and cursors of its own.
+pub struct Subprogram<'a> {
+    /// Address of its first opcode.
+    pub start: Addr,
+    /// Columns of the enclosing queries it refers to. Their values are given to it in its first registers when it
+    /// starts, followed by the row the work queue is at for the recursive selects of a common table expression.
+    pub params: Vec<(&'a str, &'a str)>,
+    pub registers: usize,
+    pub cursors: usize,
+}
+
+/// An expression `Eval` computes, along with where the values it reads are.
+pub struct Expression<'a> {
+    pub expr: Expr<'a>,
+    pub columns: Vec<ColumnRegister<'a>>,
+    /// The registers holding the results of the aggregate function calls it makes.
+    pub aggregates: Vec<(Expr<'a>, Reg)>,
+    pub subqueries: Vec<SubqueryProgram<'a>>,
+}
+
+/// The register holding the value of a column.
+#[derive(Clone)]
+pub struct ColumnRegister<'a> {
+    pub table: &'a str,
+    pub name: &'a str,
+    pub affinity: SqlType,
+    pub collation: Collation,
+    pub reg: Reg,
+}
+
+/// The subprogram running the subquery of a `(SELECT ...)`, `EXISTS (SELECT ...)` or `IN (SELECT ...)` expression.
+#[derive(Clone)]
+pub struct SubqueryProgram<'a> {
+    pub expr: Expr<'a>,
+    pub program: usize,
+    /// Affinity of the first result column.
+    pub affinity: Option<SqlType>,
+}
+
+/// An instruction of the virtual machine. Jump targets are addresses of opcodes.
+pub enum Op<'a> {
+    Goto {
+        target: Addr,
+    },
+    /// Stores the address of the next opcode in `ret` and jumps to the subroutine at `target`.
+    Gosub {
+        ret: Reg,
+        target: Addr,
+    },
+    /// Jumps back to the address stored by `Gosub`.
+    Return {
+        ret: Reg,
+    },
+    /// Ends the subprogram, no more rows come out of it.
+    Halt,
+    /// Jumps unless the value is true, NULL included.
+    IfNot {
+        reg: Reg,
+        target: Addr,
+    },
+    /// Jumps if the value is a positive integer, taking `decrement` from it.
+    IfPos {
+        reg: Reg,
+        target: Addr,
+        decrement: i64,
+    },
+    IsNull {
+        reg: Reg,
+        target: Addr,
+    },
+    /// Takes one from the integer and jumps if it reaches zero.
+    DecrJumpZero {
+        reg: Reg,
+        target: Addr,
+    },
+    /// Compares two rows of `n` registers, for the `Jump` that follows.
+    Compare {
+        left: Reg,
+        right: Reg,
+        n: usize,
+    },
+    /// Jumps to one of the targets, depending on whether the left rows of the last `Compare` was smaller, equal or
+    /// larger.
+    Jump {
+        lt: Addr,
+        eq: Addr,
+        gt: Addr,
+    },
+    Integer {
+        value: i64,
+        dest: Reg,
+    },
+    Copy {
+        src: Reg,
+        dest: Reg,
+        n: usize,
+    },
+    /// Converts the value to an integer, fails with a datatype mismatch if it isn't one.
+    MustBeInt {
+        reg: Reg,
+    },
+    Affinity {
+        reg: Reg,
+        affinity: SqlType,
+    },
+    /// Applies the affinity of a REAL column, SQLite may store reals without a fractional part as integers.
+    RealAffinity {
+        reg: Reg,
+    },
+    /// Stores the number of rows to produce for a LIMIT with an OFFSET, -1 for no limit.
+    OffsetLimit {
+        limit: Reg,
+        offset: Option<Reg>,
+        dest: Reg,
+    },
+    Eval {
+        expr: Box<Expression<'a>>,
+        dest: Reg,
+    },
+    /// Hands a row of `n` registers to the caller.
+    ResultRow {
+        start: Reg,
+        n: usize,
+    },
+    /// Opens a cursor on the b-tree of a table or an index.
+    OpenRead {
+        cursor: CursorId,
+        root: PageNumber,
+    },
+    /// Opens a cursor on an empty temporary table.
+    OpenEphemeral {
+        cursor: CursorId,
+    },
+    /// Adds a row of `n` registers to a temporary table.
+    Insert {
+        cursor: CursorId,
+        start: Reg,
+        n: usize,
+    },
+    /// Opens a cursor on a table whose one row is the `n` registers.
+    OpenPseudo {
+        cursor: CursorId,
+        start: Reg,
+        n: usize,
+    },
+    /// Opens a cursor on the rows of a subprogram, started with the `n` registers as arguments. Rows are produced as
+    /// the cursor moves.
+    OpenSubquery {
+        cursor: CursorId,
+        program: usize,
+        args: Reg,
+        n: usize,
+    },
+    /// Opens a cursor on the rows of a table-valued function called with the `n` registers as arguments.
+    OpenFunction {
+        cursor: CursorId,
+        function: TableFunction,
+        name: &'a str,
+        args: Reg,
+        n: usize,
+    },
+    /// Moves to the first row, jumps if there's none.
+    Rewind {
+        cursor: CursorId,
+        target: Addr,
+    },
+    /// Moves to the next row, jumps to `target` if there's one.
+    Next {
+        cursor: CursorId,
+        target: Addr,
+    },
+    /// Moves to the last row of a b-tree, jumps if there's none.
+    Last {
+        cursor: CursorId,
+        target: Addr,
+    },
+    /// Moves to the previous row of a b-tree walked from its last one, jumps to `target` if there's one.
+    Prev {
+        cursor: CursorId,
+        target: Addr,
+    },
+    /// Loads the value at `column` in the record of the row, or `default` when the record is too short to have it.
+    Column {
+        cursor: CursorId,
+        column: usize,
+        dest: Reg,
+        default: Option<Value<'a>>,
+    },
+    Rowid {
+        cursor: CursorId,
+        dest: Reg,
+    },
+    /// Moves to a row of NULLs, which has no row after it.
+    NullRow {
+        cursor: CursorId,
+    },
+    /// Moves a table cursor to the row with the rowid in `key`, jumps if there's none.
+    SeekRowid {
+        cursor: CursorId,
+        key: Reg,
+        target: Addr,
+    },
+    /// Moves a cursor to the first entry whose first column, or rowid for a table, is not below `key`, jumps if
+    /// there's none.
+    SeekGE {
+        cursor: CursorId,
+        key: Reg,
+        desc: bool,
+        target: Addr,
+    },
+    /// Moves a cursor to the first entry whose first column, or rowid for a table, is above `key`, jumps if there's
+    /// none.
+    SeekGT {
+        cursor: CursorId,
+        key: Reg,
+        desc: bool,
+        target: Addr,
+    },
+    /// Jumps if the first column of the entry is past `key`, or if there's no entry.
+    IdxGT {
+        cursor: CursorId,
+        key: Reg,
+        desc: bool,
+        target: Addr,
+    },
+    /// Jumps if the first column of the entry is `key` or past it, or if there's no entry.
+    IdxGE {
+        cursor: CursorId,
+        key: Reg,
+        desc: bool,
+        target: Addr,
+    },
+    /// Loads the rowid an index entry points to.
+    IdxRowid {
+        cursor: CursorId,
+        dest: Reg,
+    },
+    /// Opens an empty hash table of the rows of `source`.
+    OpenHash {
+        cursor: CursorId,
+        source: CursorId,
+    },
+    /// Adds the row `source` is at by the value of `key`. Once the rows take more than the memory limit they are
+    /// sorted by key into a temp file instead.
+    HashInsert {
+        cursor: CursorId,
+        key: Reg,
+    },
+    /// Moves to the first row whose key is equal to `key`, jumps if there's none.
+    HashSeek {
+        cursor: CursorId,
+        key: Reg,
+        target: Addr,
+    },
+    /// Opens a sorter, keeping only the first rows when `limit` holds a number that isn't negative. A `distinct`
+    /// sorter leaves out the rows equal to the one after them.
+    SorterOpen {
+        cursor: CursorId,
+        order: Vec<SortOrder>,
+        limit: Option<Reg>,
+        distinct: bool,
+    },
+    SorterInsert {
+        cursor: CursorId,
+        start: Reg,
+        n: usize,
+    },
+    /// Sorts the rows and moves to the first one, jumps if there's none.
+    SorterSort {
+        cursor: CursorId,
+        target: Addr,
+    },
+    /// Loads `n` values of the row, from `column` on.
+    SorterData {
+        cursor: CursorId,
+        column: usize,
+        n: usize,
+        dest: Reg,
+    },
+    SorterNext {
+        cursor: CursorId,
+        target: Addr,
+    },
+    /// Opens an empty set of rows, whose values are compared with the collations. The rows of an `adjacent` set come
+    /// with the equal ones next to each other, so only the last one is kept.
+    OpenSet {
+        cursor: CursorId,
+        adjacent: bool,
+        collations: Vec<Collation>,
+    },
+    /// Adds a row of `n` registers, jumps if the set has it already.
+    SetInsert {
+        cursor: CursorId,
+        start: Reg,
+        n: usize,
+        target: Addr,
+    },
+    /// Opens an aggregation. Rows given to a `sorted` one come ordered by their group key, so groups are finished as
+    /// soon as the key changes.
+    AggOpen {
+        cursor: CursorId,
+        spec: Box<AggregateSpec>,
+        sorted: bool,
+    },
+    /// Adds a row laid out as the spec says: the group key, the arguments of the calls and the bare columns.
+    AggStep {
+        cursor: CursorId,
+        start: Reg,
+        n: usize,
+    },
+    /// Finishes the groups, after the last row.
+    AggFinal {
+        cursor: CursorId,
+    },
+    /// Loads the next finished group, its key followed by its bare columns and its results, jumps if there's none.
+    AggGroup {
+        cursor: CursorId,
+        dest: Reg,
+        target: Addr,
+    },
+    /// Opens the work queue of a recursive common table expression, ordered by the `order_by` columns of the rows or
+    /// first in first out. A `distinct` queue leaves out the rows that were added before.
+    OpenQueue {
+        cursor: CursorId,
+        order_by: Vec<usize>,
+        order: Vec<SortOrder>,
+        distinct: bool,
+    },
+    QueueInsert {
+        cursor: CursorId,
+        start: Reg,
+        n: usize,
+    },
+    /// Takes the first row of the queue out, jumps if it's empty.
+    QueuePop {
+        cursor: CursorId,
+        dest: Reg,
+        target: Addr,
+    },
+    /// Opens a window, which computes the window function calls of the spec over the rows added to it.
+    WindowOpen {
+        cursor: CursorId,
+        spec: Box<WindowSpec>,
+    },
+    WindowInsert {
+        cursor: CursorId,
+        start: Reg,
+        n: usize,
+    },
+    /// Loads the next row of the window followed by the results of the calls, jumps if there's none. The calls are
+    /// computed when the first row is read, once they were all added.
+    WindowNext {
+        cursor: CursorId,
+        dest: Reg,
+        target: Addr,
+    },
+}
+
+impl Op<'_> {
+    /// The jump targets of the opcode.
+    pub fn targets_mut(&mut self) -> Vec<&mut Addr> {
+        match self {
+            Self::Jump { lt, eq, gt } => vec![lt, eq, gt],
+            Self::Goto { target }
+            | Self::Gosub { target, .. }
+            | Self::IfNot { target, .. }
+            | Self::IfPos { target, .. }
+            | Self::IsNull { target, .. }
+            | Self::DecrJumpZero { target, .. }
+            | Self::Rewind { target, .. }
+            | Self::Next { target, .. }
+            | Self::Last { target, .. }
+            | Self::Prev { target, .. }
+            | Self::SeekRowid { target, .. }
+            | Self::SeekGE { target, .. }
+            | Self::SeekGT { target, .. }
+            | Self::IdxGT { target, .. }
+            | Self::IdxGE { target, .. }
+            | Self::HashSeek { target, .. }
+            | Self::SorterSort { target, .. }
+            | Self::SorterNext { target, .. }
+            | Self::SetInsert { target, .. }
+            | Self::AggGroup { target, .. }
+            | Self::QueuePop { target, .. }
+            | Self::WindowNext { target, .. } => vec![target],
+            _ => vec![],
+        }
+    }
+
+    /// The name of the opcode and its operands the way EXPLAIN lists them, P1 to P3 being numbers and P4 anything else.
+    fn describe(&self) -> (&'static str, [Option<i64>; 3], String) {
+        let n = |v: usize| Some(v as i64);
+        let order = |order: &[SortOrder]| {
+            let terms: Vec<_> = order
+                .iter()
+                .map(|o| match (o.desc, o.nulls_first) {
+                    (false, true) => "ASC",
+                    (false, false) => "ASC NULLS LAST",
+                    (true, false) => "DESC",
+                    (true, true) => "DESC NULLS FIRST",
+                })
+                .collect();
+            terms.join(", ")
+        };
+        let none = String::new();
+        match self {
+            Self::Goto { target } => ("Goto", [None, n(*target), None], none),
+            Self::Gosub { ret, target } => ("Gosub", [n(*ret), n(*target), None], none),
+            Self::Return { ret } => ("Return", [n(*ret), None, None], none),
+            Self::Halt => ("Halt", [None; 3], none),
+            Self::IfNot { reg, target } => ("IfNot", [n(*reg), n(*target), None], none),
+            Self::IfPos { reg, target, decrement } => ("IfPos", [n(*reg), n(*target), Some(*decrement)], none),
+            Self::IsNull { reg, target } => ("IsNull", [n(*reg), n(*target), None], none),
+            Self::DecrJumpZero { reg, target } => ("DecrJumpZero", [n(*reg), n(*target), None], none),
+            Self::Compare { left, right, n: count } => ("Compare", [n(*left), n(*right), n(*count)], none),
+            Self::Jump { lt, eq, gt } => ("Jump", [n(*lt), n(*eq), n(*gt)], none),
+            Self::Integer { value, dest } => ("Integer", [Some(*value), n(*dest), None], none),
+            Self::Copy { src, dest, n: count } => ("Copy", [n(*src), n(*dest), n(*count)], none),
+            Self::MustBeInt { reg } => ("MustBeInt", [n(*reg), None, None], none),
+            Self::Affinity { reg, affinity } => ("Affinity", [n(*reg), None, None], format!("{affinity:?}")),
+            Self::RealAffinity { reg } => ("RealAffinity", [n(*reg), None, None], none),
+            Self::OffsetLimit { limit, offset, dest } => {
+                ("OffsetLimit", [n(*limit), n(*dest), offset.map(|o| o as i64)], none)
+            }
+            Self::Eval { expr, dest } => ("Eval", [None, n(*dest), None], expr.expr.to_string()),
+            Self::ResultRow { start, n: count } => ("ResultRow", [n(*start), n(*count), None], none),
+            Self::OpenRead { cursor, root } => ("OpenRead", [n(*cursor), Some(*root as i64), None], none),
+            Self::OpenEphemeral { cursor } => ("OpenEphemeral", [n(*cursor), None, None], none),
+            Self::Insert {
+                cursor,
+                start,
+                n: count,
+            } => ("Insert", [n(*cursor), n(*start), n(*count)], none),
+            Self::OpenPseudo {
+                cursor,
+                start,
+                n: count,
+            } => ("OpenPseudo", [n(*cursor), n(*start), n(*count)], none),
+            Self::OpenSubquery {
+                cursor,
+                program,
+                args,
+                n: count,
+            } => (
+                "OpenSubquery",
+                [n(*cursor), n(*args), n(*count)],
+                format!("subprogram {program}"),
+            ),
+            Self::OpenFunction {
+                cursor,
+                name,
+                args,
+                n: count,
+                ..
+            } => ("OpenFunction", [n(*cursor), n(*args), n(*count)], name.to_string()),
+            Self::Rewind { cursor, target } => ("Rewind", [n(*cursor), n(*target), None], none),
+            Self::Next { cursor, target } => ("Next", [n(*cursor), n(*target), None], none),
+            Self::Last { cursor, target } => ("Last", [n(*cursor), n(*target), None], none),
+            Self::Prev { cursor, target } => ("Prev", [n(*cursor), n(*target), None], none),
+            Self::Column {
+                cursor,
+                column,
+                dest,
+                default,
+            } => (
+                "Column",
+                [n(*cursor), n(*column), n(*dest)],
+                default.as_ref().map_or(none, |d| Expr::Literal(d.clone()).to_string()),
+            ),
+            Self::Rowid { cursor, dest } => ("Rowid", [n(*cursor), n(*dest), None], none),
+            Self::NullRow { cursor } => ("NullRow", [n(*cursor), None, None], none),
+            Self::SeekRowid { cursor, key, target } => ("SeekRowid", [n(*cursor), n(*target), n(*key)], none),
+            Self::SeekGE {
+                cursor,
+                key,
+                desc,
+                target,
+            } => ("SeekGE", [n(*cursor), n(*target), n(*key)], order(&[direction(*desc)])),
+            Self::SeekGT {
+                cursor,
+                key,
+                desc,
+                target,
+            } => ("SeekGT", [n(*cursor), n(*target), n(*key)], order(&[direction(*desc)])),
+            Self::IdxGT {
+                cursor,
+                key,
+                desc,
+                target,
+            } => ("IdxGT", [n(*cursor), n(*target), n(*key)], order(&[direction(*desc)])),
+            Self::IdxGE {
+                cursor,
+                key,
+                desc,
+                target,
+            } => ("IdxGE", [n(*cursor), n(*target), n(*key)], order(&[direction(*desc)])),
+            Self::IdxRowid { cursor, dest } => ("IdxRowid", [n(*cursor), n(*dest), None], none),
+            Self::OpenHash { cursor, source } => ("OpenHash", [n(*cursor), n(*source), None], none),
+            Self::HashInsert { cursor, key } => ("HashInsert", [n(*cursor), None, n(*key)], none),
+            Self::HashSeek { cursor, key, target } => ("HashSeek", [n(*cursor), n(*target), n(*key)], none),
+            Self::SorterOpen {
+                cursor,
+                order: o,
+                limit,
+                distinct,
+            } => (
+                "SorterOpen",
+                [n(*cursor), limit.map(|l| l as i64), None],
+                if *distinct {
+                    format!("DISTINCT {}", order(o))
+                } else {
+                    order(o)
+                },
+            ),
+            Self::SorterInsert {
+                cursor,
+                start,
+                n: count,
+            } => ("SorterInsert", [n(*cursor), n(*start), n(*count)], none),
+            Self::SorterSort { cursor, target } => ("SorterSort", [n(*cursor), n(*target), None], none),
+            Self::SorterData {
+                cursor,
+                column,
+                n: count,
+                dest,
+            } => (
+                "SorterData",
+                [n(*cursor), n(*dest), n(*count)],
+                format!("from column {column}"),
+            ),
+            Self::SorterNext { cursor, target } => ("SorterNext", [n(*cursor), n(*target), None], none),
+            Self::OpenSet { cursor, adjacent, .. } => (
+                "OpenSet",
+                [n(*cursor), None, None],
+                if *adjacent { "adjacent".into() } else { none },
+            ),
+            Self::SetInsert {
+                cursor,
+                start,
+                n: count,
+                target,
+            } => (
+                "SetInsert",
+                [n(*cursor), n(*target), n(*start)],
+                format!("{count} values"),
+            ),
+            Self::AggOpen { cursor, spec, sorted } => (
+                "AggOpen",
+                [n(*cursor), n(spec.keys.len()), n(spec.calls.len())],
+                if *sorted { "sorted".into() } else { "hash".into() },
+            ),
+            Self::AggStep {
+                cursor,
+                start,
+                n: count,
+            } => ("AggStep", [n(*cursor), n(*start), n(*count)], none),
+            Self::AggFinal { cursor } => ("AggFinal", [n(*cursor), None, None], none),
+            Self::AggGroup { cursor, dest, target } => ("AggGroup", [n(*cursor), n(*target), n(*dest)], none),
+            Self::OpenQueue {
+                cursor,
+                order_by,
+                order: o,
+                distinct,
+            } => {
+                let columns: Vec<_> = order_by.iter().map(|c| c.to_string()).collect();
+                let mut p4 = if columns.is_empty() {
+                    "FIFO".to_string()
+                } else {
+                    format!("columns {} {}", columns.join(","), order(o))
+                };
+                if *distinct {
+                    p4.insert_str(0, "DISTINCT ");
+                }
+                ("OpenQueue", [n(*cursor), None, None], p4)
+            }
+            Self::QueueInsert {
+                cursor,
+                start,
+                n: count,
+            } => ("QueueInsert", [n(*cursor), n(*start), n(*count)], none),
+            Self::QueuePop { cursor, dest, target } => ("QueuePop", [n(*cursor), n(*target), n(*dest)], none),
+            Self::WindowOpen { cursor, spec } => (
+                "WindowOpen",
+                [n(*cursor), n(spec.passes.len()), None],
+                format!("{} calls", spec.passes.iter().map(|p| p.calls.len()).sum::<usize>()),
+            ),
+            Self::WindowInsert {
+                cursor,
+                start,
+                n: count,
+            } => ("WindowInsert", [n(*cursor), n(*start), n(*count)], none),
+            Self::WindowNext { cursor, dest, target } => ("WindowNext", [n(*cursor), n(*target), n(*dest)], none),
+        }
+    }
+}
+
+fn direction(desc: bool) -> SortOrder {
+    SortOrder {
+        desc,
+        nulls_first: !desc,
+        collation: Collation::Binary,
+    }
+}
+
+/// The listing of EXPLAIN, one opcode per line.
+impl Display for Program<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let header = ["addr", "opcode", "p1", "p2", "p3", "p4", "comment"].map(String::from);
+        let mut lines = vec![header];
+        for (addr, (op, comment)) in self.ops.iter().zip(&self.comments).enumerate() {
+            let (name, p, p4) = op.describe();
+            let p = p.map(|p| p.map_or(String::new(), |p| p.to_string()));
+            let [p1, p2, p3] = p;
+            lines.push([addr.to_string(), name.into(), p1, p2, p3, p4, comment.clone()]);
+        }
+        let mut widths = [4, 13, 4, 4, 4, 13, 0];
+        for line in &lines {
+            for (width, field) in widths.iter_mut().zip(line) {
+                *width = (*width).max(field.chars().count());
+            }
+        }
+        let separator = widths.map(|w| "-".repeat(w.max(4)));
+        lines.insert(1, separator);
+        for line in lines {
+            let fields: Vec<_> = line.iter().zip(widths).map(|(s, w)| format!("{s:w$}")).collect();
+            writeln!(f, "{}", fields.join("  ").trim_end())?;
+        }
+        Ok(())
+    }
+}
+
+/// What the subprograms of a running program share.
+pub struct Context<'p> {
+    program: &'p Program<'p>,
+    db: &'p Database,
+    config: &'p Config,
+    functions: &'p Functions,
+    /// The values bound to the parameters of the statement.
+    parameters: &'p Parameters<'p>,
+    /// Results of the subqueries without parameters, which are the same every time they run.
+    results: Vec<OnceCell<Rc<SubqueryValues>>>,
+}
+
+impl<'p> Context<'p> {
+    pub fn new(
+        program: &'p Program<'p>,
+        db: &'p Database,
+        config: &'p Config,
+        functions: &'p Functions,
+        parameters: &'p Parameters<'p>,
+    ) -> Self {
+        Self {
+            program,
+            db,
+            config,
+            functions,
+            parameters,
+            results: program.subprograms.iter().map(|_| OnceCell::new()).collect(),
+        }
+    }
+
+    /// Runs the subquery of an expression, with the values of the row for the columns it refers to.
+    fn subquery(self: &Rc<Self>, sub: &SubqueryProgram<'p>, row: &impl Row<'p>) -> Result<Rc<SubqueryValues>> {
+        let params = &self.program.subprograms[sub.program].params;
+        if let Some(values) = self.results[sub.program].get() {
+            return Ok(values.clone());
+        }
+        let mut args = vec![];
+        for (table, name) in params {
+            args.push(row.column(Some(table), name)?.1.into_owned());
+        }
+        // Only the first row of scalar and EXISTS subqueries matters.
+        let all = matches!(sub.expr, Expr::InSelect { .. });
+        let mut vm = Vm::new(self.clone(), sub.program, args);
+        let mut values = vec![];
+        while let Some(row) = vm.step()? {
+            values.push(row[0].clone());
+            if !all {
+                break;
+            }
+        }
+        let values = Rc::new(SubqueryValues::new(values, sub.affinity));
+        if params.is_empty() {
+            let _ = self.results[sub.program].set(values.clone());
+        }
+        Ok(values)
+    }
+}
+
+/// A running subprogram.
+pub struct Vm<'p> {
+    ctx: Rc<Context<'p>>,
+    pc: Addr,
+    registers: Vec<Value<'static>>,
+    cursors: Vec<Option<Cursor<'p>>>,
+    /// Whether each cursor is at the row of NULLs a LEFT JOIN adds when none of the rows of its table match.
+    null_rows: Vec<bool>,
+    /// Result of the last `Compare`.
+    comparison: Ordering,
+}
+
+impl<'p> Vm<'p> {
+    /// Starts a subprogram, `args` being its parameters.
+    pub fn new(ctx: Rc<Context<'p>>, program: usize, args: Vec<Value<'static>>) -> Self {
+        let sub = &ctx.program.subprograms[program];
+        let mut registers = args;
+        registers.resize(sub.registers.max(registers.len()), Value::Null);
+        Self {
+            ctx,
+            pc: sub.start,
+            registers,
+            cursors: (0..sub.cursors).map(|_| None).collect(),
+            null_rows: vec![false; sub.cursors],
+            comparison: Ordering::Equal,
+        }
+    }
+
+    /// Runs until the next result row, `None` once the subprogram halted.
+    pub fn step(&mut self) -> Result<Option<&[Value<'static>]>> {
+        let ctx = self.ctx.clone();
+        loop {
+            let pc = self.pc;
+            self.pc += 1;
+            match &ctx.program.ops[pc] {
+                Op::Goto { target } => self.pc = *target,
+                Op::Gosub { ret, target } => {
+                    self.registers[*ret] = Value::Int(self.pc as i64);
+                    self.pc = *target;
+                }
+                Op::Return { ret } => {
+                    let Value::Int(addr) = self.registers[*ret] else {
+                        unreachable!("return without gosub")
+                    };
+                    self.pc = addr as Addr;
+                }
+                Op::Halt => {
+                    self.pc = pc;
+                    return Ok(None);
+                }
+                Op::IfNot { reg, target } => {
+                    if truth(&self.registers[*reg]) != Some(true) {
+                        self.pc = *target;
+                    }
+                }
+                Op::IfPos { reg, target, decrement } => {
+                    if let Value::Int(n) = &mut self.registers[*reg]
+                        && *n > 0
+                    {
+                        *n -= decrement;
+                        self.pc = *target;
+                    }
+                }
+                Op::IsNull { reg, target } => {
+                    if matches!(self.registers[*reg], Value::Null) {
+                        self.pc = *target;
+                    }
+                }
+                Op::DecrJumpZero { reg, target } => {
+                    if let Value::Int(n) = &mut self.registers[*reg] {
+                        *n = n.saturating_sub(1);
+                        if *n == 0 {
+                            self.pc = *target;
                         }
                     }
                 }
+                Op::Compare { left, right, n } => {
+                    let order = vec![direction(false); *n];
+                    let (left, right) = (&self.registers[*left..left + n], &self.registers[*right..right + n]);
+                    self.comparison = compare_rows(&order, left, right);
+                }
+                Op::Jump { lt, eq, gt } => {
+                    self.pc = match self.comparison {
+                        Ordering::Less => *lt,
+                        Ordering::Equal => *eq,
+                        Ordering::Greater => *gt,
+                    }
+                }
+                Op::Integer { value, dest } => self.registers[*dest] = Value::Int(*value),
+                Op::Copy { src, dest, n } => {
+                    for i in 0..*n {
+                        self.registers[dest + i] = self.registers[src + i].clone();
+                    }
+                }
+                Op::MustBeInt { reg } => {
+                    let value = std::mem::replace(&mut self.registers[*reg], Value::Null);
+                    match value.with_affinity(SqlType::Integer) {
+                        value @ Value::Int(_) => self.registers[*reg] = value,
+                        _ => bail!("datatype mismatch"),
+                    }
+                }
+                Op::Affinity { reg, affinity } => {
+                    let value = std::mem::replace(&mut self.registers[*reg], Value::Null);
+                    self.registers[*reg] = value.with_affinity(*affinity);
+                }
+                Op::RealAffinity { reg } => {
+                    let value = std::mem::replace(&mut self.registers[*reg], Value::Null);
+                    self.registers[*reg] = value.with_affinity(SqlType::Real);
+                }
+                Op::OffsetLimit { limit, offset, dest } => {
+                    let int = |reg: Reg| match self.registers[reg] {
+                        Value::Int(n) => n,
+                        _ => unreachable!("LIMIT and OFFSET are integers"),
+                    };
+                    // A negative LIMIT means no limit at all, and a negative OFFSET is the same as none.
+                    let offset = offset.map_or(0, |offset| int(offset).max(0));
+                    let count = match int(*limit) {
+                        limit if limit < 0 => -1,
+                        limit => limit.saturating_add(offset),
+                    };
+                    self.registers[*dest] = Value::Int(count);
+                }
+                Op::Eval { expr, dest } => {
+                    let row = RegisterRow {
+                        ctx: &ctx,
+                        expression: expr,
+                        registers: &self.registers,
+                    };
+                    let value = eval(&expr.expr, &row)?.into_owned();
+                    self.registers[*dest] = value;
+                }
+                Op::ResultRow { start, n } => return Ok(Some(&self.registers[*start..start + n])),
+                Op::OpenRead { cursor, root } => {
+                    self.open(*cursor, Cursor::Btree(Box::new(BtreeCursor::new(ctx.db, *root))))
+                }
+                Op::OpenEphemeral { cursor } => self.open(*cursor, Cursor::rows(vec![])),
+                Op::Insert { cursor, start, n } => {
+                    let row = self.registers[*start..start + n].to_vec();
+                    let Cursor::Rows { rows, .. } = self.cursor(*cursor) else {
+                        unreachable!()
+                    };
+                    rows.push(row);
+                }
+                Op::OpenPseudo { cursor, start, n } => {
+                    let row = self.registers[*start..start + n].to_vec();
+                    self.open(*cursor, Cursor::rows(vec![row]));
+                }
+                Op::OpenSubquery {
+                    cursor,
+                    program,
+                    args,
+                    n,
+                } => {
+                    let vm = Vm::new(ctx.clone(), *program, self.registers[*args..args + n].to_vec());
+                    self.open(
+                        *cursor,
+                        Cursor::Subquery {
+                            vm: Box::new(vm),
+                            row: None,
+                        },
+                    );
+                }
+                Op::OpenFunction {
+                    cursor,
+                    function,
+                    args,
+                    n,
+                    ..
+                } => {
+                    let rows = function(&self.registers[*args..args + n])?;
+                    self.open(*cursor, Cursor::rows(rows));
+                }
+                Op::Rewind { cursor, target } => {
+                    self.null_rows[*cursor] = false;
+                    if !self.cursor(*cursor).rewind()? {
+                        self.pc = *target;
+                    }
+                }
+                Op::Next { cursor, target } => {
+                    if !self.null_rows[*cursor] && self.cursor(*cursor).next()? {
+                        self.pc = *target;
+                    }
+                }
+                Op::Last { cursor, target } => {
+                    self.null_rows[*cursor] = false;
+                    let Cursor::Btree(btree) = self.cursor(*cursor) else {
+                        unreachable!("only b-trees are walked backwards")
+                    };
+                    let mut entries = btree.db.get_page(btree.root).entries_rev();
+                    if !btree.set(entries.next(), Some(entries)) {
+                        self.pc = *target;
+                    }
+                }
+                // The entries left to a cursor moved by `Last` come in reverse order.
+                Op::Prev { cursor, target } => {
+                    if !self.null_rows[*cursor] && self.cursor(*cursor).next()? {
+                        self.pc = *target;
+                    }
+                }
+                Op::Column {
+                    cursor,
+                    column,
+                    dest,
+                    default,
+                } => {
+                    let value = match self.null_rows[*cursor] {
+                        true => None,
+                        false => self
+                            .cursor(*cursor)
+                            .column(*column)
+                            .or_else(|| default.clone().map(Value::into_owned)),
+                    };
+                    self.registers[*dest] = value.unwrap_or(Value::Null);
+                }
+                Op::Rowid { cursor, dest } => {
+                    self.registers[*dest] = match self.null_rows[*cursor] {
+                        true => Value::Null,
+                        false => Value::Int(self.cursor(*cursor).rowid()),
+                    };
+                }
+                Op::NullRow { cursor } => self.null_rows[*cursor] = true,
+                Op::SeekRowid { cursor, key, target } => {
+                    self.null_rows[*cursor] = false;
+                    let key = self.registers[*key].clone().with_affinity(SqlType::Numeric);
+                    let Cursor::Btree(btree) = self.cursor(*cursor) else {
+                        unreachable!("only b-trees have rowids")
+                    };
+                    let found = match key {
+                        Value::Int(rowid) => btree.set(ctx.db.seek_rowid(btree.root, rowid), None),
+                        _ => btree.set(None, None),
+                    };
+                    if !found {
+                        self.pc = *target;
+                    }
+                }
+                op @ (Op::SeekGE {
+                    cursor,
+                    key,
+                    desc,
+                    target,
+                }
+                | Op::SeekGT {
+                    cursor,
+                    key,
+                    desc,
+                    target,
+                }) => {
+                    self.null_rows[*cursor] = false;
+                    let key = [self.registers[*key].clone()];
+                    let Cursor::Btree(btree) = self.cursor(*cursor) else {
+                        unreachable!("only b-trees are seeked")
+                    };
+                    let after = matches!(op, Op::SeekGT { .. });
+                    let mut entries = ctx.db.seek(btree.root, &key, &[*desc], after);
+                    if !btree.set(entries.next(), Some(entries)) {
+                        self.pc = *target;
+                    }
+                }
+                op @ (Op::IdxGT {
+                    cursor,
+                    key,
+                    desc,
+                    target,
+                }
+                | Op::IdxGE {
+                    cursor,
+                    key,
+                    desc,
+                    target,
+                }) => {
+                    let key = [self.registers[*key].clone()];
+                    let Cursor::Btree(btree) = self.cursor(*cursor) else {
+                        unreachable!("only b-trees have indexes")
+                    };
+                    let past = match op {
+                        Op::IdxGT { .. } => Ordering::is_gt,
+                        _ => Ordering::is_ge,
+                    };
+                    if btree.entry.is_none() || past(compare_key(btree.values(), &key, &[*desc])) {
+                        self.pc = *target;
+                    }
+                }
+                Op::IdxRowid { cursor, dest } => {
+                    let Cursor::Btree(btree) = self.cursor(*cursor) else {
+                        unreachable!("only b-trees have indexes")
+                    };
+                    let Some(&Value::Int(rowid)) = btree.values().last() else {
+                        panic!("corrupt index, entry without a rowid")
+                    };
+                    self.registers[*dest] = Value::Int(rowid);
+                }
+                Op::OpenHash { cursor, source } => self.open(*cursor, Cursor::Hash(HashTable::new(*source))),
+                Op::HashInsert { cursor, key } => {
+                    let key = GroupKey(vec![self.registers[*key].clone()]);
+                    let Cursor::Hash(hash) = self.cursor(*cursor) else {
+                        unreachable!()
+                    };
+                    let source = hash.source;
+                    let row = self.cursor(source).row();
+                    let Cursor::Hash(hash) = self.cursor(*cursor) else {
+                        unreachable!()
+                    };
+                    hash.insert(key, row, ctx.config.memory_limit)?;
+                }
+                Op::HashSeek { cursor, key, target } => {
+                    self.null_rows[*cursor] = false;
+                    let key = GroupKey(vec![self.registers[*key].clone()]);
+                    let Cursor::Hash(hash) = self.cursor(*cursor) else {
+                        unreachable!()
+                    };
+                    if !hash.seek(&key)? {
+                        self.pc = *target;
+                    }
+                }
+                Op::SorterOpen {
+                    cursor,
+                    order,
+                    limit,
+                    distinct,
+                } => {
+                    let limit = limit.and_then(|limit| match self.registers[limit] {
+                        Value::Int(n) => n.try_into().ok(),
+                        _ => None,
+                    });
+                    let sorter = Sorter::new(order.clone(), ctx.config.memory_limit, limit);
+                    self.open(*cursor, Cursor::Sorter(SorterCursor::Filling(sorter, order, *distinct)));
+                }
+                Op::SorterInsert { cursor, start, n } => {
+                    let row = self.registers[*start..start + n].to_vec();
+                    let Cursor::Sorter(SorterCursor::Filling(sorter, ..)) = self.cursor(*cursor) else {
+                        unreachable!("sorter sorted already")
+                    };
+                    sorter.push(row)?;
+                }
+                Op::SorterSort { cursor, target } => {
+                    let Some(Cursor::Sorter(sorter)) = self.cursors[*cursor].take() else {
+                        unreachable!()
+                    };
+                    let SorterCursor::Filling(sorter, order, distinct) = sorter else {
+                        unreachable!("sorter sorted already")
+                    };
+                    let rows = sorter.finish()?;
+                    let mut rows: Box<dyn Iterator<Item = Result<SortRow>>> = match distinct {
+                        true => Box::new(Distinct::new(rows, order)),
+                        false => Box::new(rows),
+                    };
+                    let row = rows.next().transpose()?;
+                    if row.is_none() {
+                        self.pc = *target;
+                    }
+                    self.open(*cursor, Cursor::Sorter(SorterCursor::Sorted { rows, row }));
+                }
+                Op::SorterData {
+                    cursor,
+                    column,
+                    n,
+                    dest,
+                } => {
+                    let Cursor::Sorter(SorterCursor::Sorted { row: Some(row), .. }) = self.cursor(*cursor) else {
+                        unreachable!("sorter not at a row")
+                    };
+                    let values = row[*column..column + n].to_vec();
+                    self.registers[*dest..dest + n].clone_from_slice(&values);
+                }
+                Op::SorterNext { cursor, target } => {
+                    let Cursor::Sorter(SorterCursor::Sorted { rows, row }) = self.cursor(*cursor) else {
+                        unreachable!("sorter not sorted")
+                    };
+                    *row = rows.next().transpose()?;
+                    if row.is_some() {
+                        self.pc = *target;
+                    }
+                }
+                Op::OpenSet {
+                    cursor,
+                    adjacent,
+                    collations,
+                } => {
+                    let rows = match adjacent {
+                        true => Seen::Last(None),
+                        false => Seen::Hash(HashSet::new()),
+                    };
+                    let set = Cursor::Set {
+                        rows,
+                        collations: collations.clone(),
+                    };
+                    self.open(*cursor, set);
+                }
+                Op::SetInsert {
+                    cursor,
+                    start,
+                    n,
+                    target,
+                } => {
+                    let values = &self.registers[*start..start + n];
+                    let Some(Cursor::Set { rows, collations }) = &mut self.cursors[*cursor] else {
+                        unreachable!()
+                    };
+                    let row = values.iter().zip(collations.iter()).map(|(v, c)| c.fold(v.clone()));
+                    if !rows.insert(GroupKey(row.collect())) {
+                        self.pc = *target;
+                    }
+                }
+                Op::AggOpen { cursor, spec, sorted } => {
+                    let aggregation = match sorted {
+                        true => Aggregation::Stream {
+                            aggregate: Some(StreamAggregate::new(spec)),
+                            group: None,
+                        },
+                        false => Aggregation::Hash {
+                            aggregate: Some(HashAggregate::new(spec, ctx.config.memory_limit)),
+                            groups: None,
+                        },
+                    };
+                    self.open(*cursor, Cursor::Aggregate(aggregation));
+                }
+                Op::AggStep { cursor, start, n } => {
+                    let row = self.registers[*start..start + n].to_vec();
+                    match self.cursor(*cursor) {
+                        Cursor::Aggregate(Aggregation::Stream {
+                            aggregate: Some(aggregate),
+                            group,
+                        }) => {
+                            if let Some(done) = aggregate.push(row)? {
+                                *group = Some(done);
+                            }
+                        }
+                        Cursor::Aggregate(Aggregation::Hash {
+                            aggregate: Some(aggregate),
+                            ..
+                        }) => aggregate.push(row)?,
+                        _ => unreachable!("aggregation finished already"),
+                    }
+                }
+                Op::AggFinal { cursor } => match self.cursor(*cursor) {
+                    Cursor::Aggregate(Aggregation::Stream { aggregate, group }) => {
+                        *group = aggregate.take().and_then(StreamAggregate::finish);
+                    }
+                    Cursor::Aggregate(Aggregation::Hash { aggregate, groups }) => {
+                        *groups = aggregate.take().map(HashAggregate::finish).transpose()?;
+                    }
+                    _ => unreachable!(),
+                },
+                Op::AggGroup { cursor, dest, target } => {
+                    let group = match self.cursor(*cursor) {
+                        Cursor::Aggregate(Aggregation::Stream { group, .. }) => group.take(),
+                        Cursor::Aggregate(Aggregation::Hash { groups, .. }) => {
+                            groups.as_mut().and_then(Iterator::next).transpose()?
+                        }
+                        _ => unreachable!(),
+                    };
+                    match group {
+                        Some(group) => {
+                            let results = group.results()?;
+                            let values = group.key.into_iter().chain(group.bare).chain(results);
+                            for (i, value) in values.enumerate() {
+                                self.registers[dest + i] = value;
+                            }
+                        }
+                        None => self.pc = *target,
+                    }
+                }
+                Op::OpenQueue {
+                    cursor,
+                    order_by,
+                    order,
+                    distinct,
+                } => {
+                    let queue = WorkQueue {
+                        rows: VecDeque::new(),
+                        seen: distinct.then(HashSet::new),
+                        order_by,
+                        order,
+                    };
+                    self.open(*cursor, Cursor::Queue(queue));
+                }
+                Op::QueueInsert { cursor, start, n } => {
+                    let row = self.registers[*start..start + n].to_vec();
+                    let Cursor::Queue(queue) = self.cursor(*cursor) else {
+                        unreachable!()
+                    };
+                    queue.push(row);
+                }
+                Op::QueuePop { cursor, dest, target } => {
+                    let Cursor::Queue(queue) = self.cursor(*cursor) else {
+                        unreachable!()
+                    };
+                    match queue.rows.pop_front() {
+                        Some((_, row)) => {
+                            for (i, value) in row.into_iter().enumerate() {
+                                self.registers[dest + i] = value;
+                            }
+                        }
+                        None => self.pc = *target,
+                    }
+                }
+                Op::WindowOpen { cursor, spec } => {
+                    let window = Window::new(spec, ctx.config.memory_limit);
+                    self.open(*cursor, Cursor::Window(window));
+                }
+                Op::WindowInsert { cursor, start, n } => {
+                    let row = self.registers[*start..start + n].to_vec();
+                    let Cursor::Window(window) = self.cursor(*cursor) else {
+                        unreachable!()
+                    };
+                    window.push(row)?;
+                }
+                Op::WindowNext { cursor, dest, target } => {
+                    let Cursor::Window(window) = self.cursor(*cursor) else {
+                        unreachable!()
+                    };
+                    match window.next()? {
+                        Some(row) => {
+                            for (i, value) in row.into_iter().enumerate() {
+                                self.registers[dest + i] = value;
+                            }
+                        }
+                        None => self.pc = *target,
+                    }
+                }
             }
+        }
+    }
+
+    fn open(&mut self, cursor: CursorId, c: Cursor<'p>) {
+        self.cursors[cursor] = Some(c);
+        self.null_rows[cursor] = false;
+    }
+
+    fn cursor(&mut self, cursor: CursorId) -> &mut Cursor<'p> {
+        self.cursors[cursor].as_mut().expect("cursor not open")
+    }
+}
+
+/// Registers of a machine, for an expression to read the values it refers to.
+struct RegisterRow<'r, 'p> {
+    ctx: &'r Rc<Context<'p>>,
+    expression: &'r Expression<'p>,
+    registers: &'r [Value<'static>],
+}
+
+impl<'p> Row<'p> for RegisterRow<'_, 'p> {
+    fn column(&self, table: Option<&str>, name: &str) -> Result<(SqlType, Value<'p>)> {
+        // Column references are qualified before the query runs, so the table and the column exist.
+        let table = table.expect("unqualified column reference");
+        let column = self
+            .expression
+            .columns
+            .iter()
+            .find(|c| c.table == table && c.name == name)
+            .expect("column missing from the expression");
+        Ok((column.affinity, self.registers[column.reg].clone()))
+    }
+
+    fn collation(&self, table: &str, name: &str) -> Collation {
+        let columns = &self.expression.columns;
+        columns
+            .iter()
+            .find(|c| c.table == table && c.name == name)
+            .map_or(Collation::Binary, |c| c.collation)
+    }
 
-            println!("{}", sum / count as f64);
+    fn aggregate(&self, call: &Expr<'p>) -> Result<Value<'p>> {
+        match self.expression.aggregates.iter().find(|(c, _)| c == call) {
+            Some((_, reg)) => Ok(self.registers[*reg].clone()),
+            None => {
+                let Expr::Function { name, args, .. } = call else {
+                    unreachable!()
+                };
+                // The calls left unbound are to aggregates, or to functions that don't exist.
+                let functions = self.ctx.functions;
+                match functions.scalar(name, args.len()) {
+                    Err(err) if !functions.is_aggregate(name, args.len()) => Err(err),
+                    _ => bail!("misuse of aggregate function {name}()"),
+                }
+            }
         }
     }
-    Ok(())
+
+    fn subquery(&self, expr: &Expr<'p>) -> Result<Rc<SubqueryValues>> {
+        let sub = self
+            .expression
+            .subqueries
+            .iter()
+            .find(|s| s.expr == *expr)
+            .expect("subquery missing from the expression");
+        self.ctx.subquery(sub, self)
+    }
+
+    fn functions(&self) -> &Functions {
+        self.ctx.functions
+    }
+
+    fn parameter(&self, offset: usize) -> Value<'p> {
+        self.ctx.parameters.value(offset).clone()
+    }
 }
 
-type ParsedEntry<'a> = Vec<(&'a str, Value<'a>)>;
+enum Cursor<'p> {
+    Btree(Box<BtreeCursor<'p>>),
+    /// Rows kept in memory, the ones of a FROM subquery or the one row of a pseudo-table.
+    Rows {
+        rows: Vec<SortRow>,
+        position: usize,
+    },
+    /// The rows of a subprogram, the current one being the last it produced.
+    Subquery {
+        vm: Box<Vm<'p>>,
+        row: Option<SortRow>,
+    },
+    Hash(HashTable),
+    Sorter(SorterCursor<'p>),
+    /// The rows seen so far, with the collation of each of their values.
+    Set {
+        rows: Seen,
+        collations: Vec<Collation>,
+    },
+    Aggregate(Aggregation<'p>),
+    Queue(WorkQueue<'p>),
+    Window(Window<'p>),
+}
 
-fn where_matches<'a>(we: &WhereExpr<'a>, pe: &'a ParsedEntry<'a>) -> Result<bool> {
-    use WhereExpr::*;
-    let get_col_value = |colname: &'a str, pe: &'a ParsedEntry<'a>| {
-        pe.iter()
-            .find_map(|(name, val)| (*name == colname).then_some(val))
-            .ok_or(anyhow!("invalid column name '{colname}' in where expression"))
-    };
+impl Cursor<'_> {
+    fn rows(rows: Vec<SortRow>) -> Self {
+        Self::Rows { rows, position: 0 }
+    }
+
+    /// Moves to the first row, returns whether there is one.
+    fn rewind(&mut self) -> Result<bool> {
+        match self {
+            Self::Btree(btree) => {
+                let mut entries = btree.db.get_page(btree.root).entries();
+                Ok(btree.set(entries.next(), Some(entries)))
+            }
+            Self::Rows { rows, position } => {
+                *position = 0;
+                Ok(!rows.is_empty())
+            }
+            // A subprogram runs once, its first row is the next one it produces.
+            Self::Subquery { .. } => self.next(),
+            _ => unreachable!("not a table cursor"),
+        }
+    }
 
-    Ok(match we {
-        Neq(c, v) => get_col_value(c, pe)? != v,
-        Eq(c, v) => get_col_value(c, pe)? == v,
-        Leq(c, v) => get_col_value(c, pe)? <= v,
-        Geq(c, v) => get_col_value(c, pe)? >= v,
-        Le(c, v) => get_col_value(c, pe)? < v,
-        Ge(c, v) => get_col_value(c, pe)? > v,
-        And(l, r) => where_matches(l, pe)? && where_matches(r, pe)?,
-        Or(l, r) => where_matches(l, pe)? || where_matches(r, pe)?,
-    })
-}
-
-fn parse_entry<'a>(ct: &'a CreateTable<'a>, entry: &'a Entry) -> ParsedEntry<'a> {
-    let (header_size, header_int_size) = read_varint(&mut entry.payload.as_slice());
-    let mut header = &entry.payload[header_int_size as usize..header_size as usize];
-    // HACK: Can we do this without allocation? By implementing a iterator on serial types.
-    let mut sts = vec![];
-    while !header.is_empty() {
-        let st = SerialType::from(read_varint(&mut header).0 as u64);
-        sts.push(st);
-    }
-    let record_payload = &entry.payload[header_size as usize..];
-    let values = SerialType::parse_payload(&sts, record_payload);
-    values
-        .zip(&ct.columns)
-        .map(|(v, c)| {
-            if c.primary_key && c.sql_type == SqlType::Integer {
-                (c.name, Value::Int(entry.key))
-            } else {
-                (c.name, v)
+    /// Moves to the next row, returns whether there is one.
+    fn next(&mut self) -> Result<bool> {
+        match self {
+            Self::Btree(btree) => {
+                let entry = btree.entries.as_mut().and_then(Iterator::next);
+                let entries = btree.entries.take();
+                Ok(btree.set(entry, entries))
             }
+            Self::Rows { rows, position } => {
+                *position += 1;
+                Ok(*position < rows.len())
+            }
+            Self::Subquery { vm, row } => {
+                *row = vm.step()?.map(<[_]>::to_vec);
+                Ok(row.is_some())
+            }
+            Self::Hash(hash) => {
+                hash.position += 1;
+                Ok(hash.position < hash.matches.len())
+            }
+            _ => unreachable!("not a table cursor"),
+        }
+    }
+
+    /// The value at a position of the record of the row, `None` if the record is too short to have it.
+    fn column(&mut self, column: usize) -> Option<Value<'static>> {
+        match self {
+            Self::Btree(btree) => btree.values().get(column).cloned(),
+            Self::Rows { rows, position } => rows[*position].get(column).cloned(),
+            Self::Subquery { row, .. } => row.as_ref().expect("subquery not at a row").get(column).cloned(),
+            Self::Hash(hash) => hash.row().1.get(column).cloned(),
+            _ => unreachable!("not a table cursor"),
+        }
+    }
+
+    fn rowid(&self) -> i64 {
+        match self {
+            Self::Btree(btree) => btree.entry.as_ref().expect("cursor not at a row").key,
+            Self::Hash(hash) => hash.row().0,
+            _ => unreachable!("the rows of a subquery have no rowid"),
+        }
+    }
+
+    /// The rowid and the values of the row, for a hash table to keep.
+    fn row(&mut self) -> (i64, SortRow) {
+        match self {
+            Self::Btree(btree) => {
+                let rowid = btree.entry.as_ref().expect("cursor not at a row").key;
+                (rowid, btree.values().to_vec())
+            }
+            Self::Rows { rows, position } => (0, rows[*position].clone()),
+            Self::Subquery { row, .. } => (0, row.clone().expect("subquery not at a row")),
+            _ => unreachable!("not a table cursor"),
+        }
+    }
+}
+
+/// A cursor over a table or index b-tree.
+struct BtreeCursor<'p> {
+    db: &'p Database,
+    root: PageNumber,
+    /// The entries after the current one, `None` when the cursor was moved to a single row.
+    entries: Option<EntryIter<'p>>,
+    entry: Option<Entry>,
+    /// The values of the record of the entry, parsed when they are first read.
+    values: Option<SortRow>,
+}
+
+impl<'p> BtreeCursor<'p> {
+    fn new(db: &'p Database, root: PageNumber) -> Self {
+        Self {
+            db,
+            root,
+            entries: None,
+            entry: None,
+            values: None,
+        }
+    }
+
+    /// Moves to an entry, returns whether there is one.
+    fn set(&mut self, entry: Option<Entry>, entries: Option<EntryIter<'p>>) -> bool {
+        self.entry = entry;
+        self.entries = entries;
+        self.values = None;
+        self.entry.is_some()
+    }
+
+    fn values(&mut self) -> &[Value<'static>] {
+        let entry = self.entry.as_ref().expect("cursor not at a row");
+        self.values.get_or_insert_with(|| {
+            parse_record(&entry.payload)
+                .into_iter()
+                .map(Value::into_owned)
+                .collect()
         })
-        .collect()
+    }
+}
+
+/// The rows of a table by the value of the column of a hash join.
+struct HashTable {
+    /// The cursor of the table the rows are read from.
+    source: CursorId,
+    rows: Vec<(i64, SortRow)>,
+    index: HashMap<GroupKey, Vec<usize>>,
+    memory: usize,
+    /// The rows being sorted by key, once they took more than the memory limit, until the first lookup.
+    sorter: Option<Sorter>,
+    /// The rows sorted by key, the ones matching a key are read into `rows` as it's looked up.
+    spilled: Option<SpilledRows>,
+    /// The rows whose key is the one looked up.
+    matches: Vec<usize>,
+    position: usize,
 }
 
-fn print_row<'a>(selected: &[ColumnDef], pe: ParsedEntry<'a>) {
-    let mut iter = selected
-        .iter()
-        .flat_map(|s| pe.iter().find_map(|(c, v)| (s.name == *c).then_some(v)));
-    iter.next().inspect(|v| print!("{v}"));
-    for v in iter {
-        print!("|{v}")
+impl HashTable {
+    fn new(source: CursorId) -> Self {
+        Self {
+            source,
+            rows: vec![],
+            index: HashMap::new(),
+            memory: 0,
+            sorter: None,
+            spilled: None,
+            matches: vec![],
+            position: 0,
+        }
+    }
+
+    /// Adds a row. Once the rows don't fit in memory anymore, they all go to a sorter, which spills them to temp
+    /// files.
+    fn insert(&mut self, key: GroupKey, row: (i64, SortRow), memory_limit: usize) -> Result<()> {
+        if let Some(sorter) = &mut self.sorter {
+            return sorter.push(SpilledRows::row(key.0, row));
+        }
+        self.memory += row_size(&row.1) + row_size(&key.0);
+        self.index.entry(key).or_default().push(self.rows.len());
+        self.rows.push(row);
+        if self.memory > memory_limit {
+            let mut sorter = Sorter::new(vec![SpilledRows::ORDER], memory_limit, None);
+            let mut rows: Vec<_> = std::mem::take(&mut self.rows).into_iter().map(Some).collect();
+            for (key, positions) in std::mem::take(&mut self.index) {
+                for i in positions {
+                    sorter.push(SpilledRows::row(key.0.clone(), rows[i].take().unwrap()))?;
+                }
+            }
+            self.sorter = Some(sorter);
+        }
+        Ok(())
+    }
+
+    fn seek(&mut self, key: &GroupKey) -> Result<bool> {
+        if let Some(sorter) = self.sorter.take() {
+            self.spilled = Some(SpilledRows::new(sorter)?);
+        }
+        self.matches = match &mut self.spilled {
+            Some(spilled) => {
+                self.rows = spilled.find(&key.0[0])?;
+                (0..self.rows.len()).collect()
+            }
+            None => self.index.get(key).cloned().unwrap_or_default(),
+        };
+        self.position = 0;
+        Ok(!self.matches.is_empty())
     }
-    println!()
+
+    fn row(&self) -> &(i64, SortRow) {
+        &self.rows[self.matches[self.position]]
+    }
+}
+
+/// Number of rows of a spilled hash table per key kept in memory.
+const SPILL_BLOCK: usize = 64;
+
+/// The rows of a hash table that didn't fit in memory, sorted by key in a temp file. The key of the first row of each
+/// block of rows is kept in memory along with where the block starts, so the rows of a key are found reading about a
+/// block.
+struct SpilledRows {
+    reader: RunReader,
+    /// The first key of each block and its offset in the file.
+    blocks: Vec<(Value<'static>, u64)>,
 }
 
-fn get_tbl_schema(db: &Database, tbl_name: &str) -> Result<Schema> {
-    db.get_page(1)
-        .entries()
-        .find_map(|e| {
-            let s = Schema::new(e.payload);
-            if s.tbl_name == tbl_name { Some(s) } else { None }
+impl SpilledRows {
+    const ORDER: SortOrder = SortOrder {
+        desc: false,
+        nulls_first: true,
+        collation: Collation::Binary,
+    };
+
+    /// The row of the file: the key, the rowid and then the values of the row.
+    fn row(mut key: SortRow, (rowid, values): (i64, SortRow)) -> SortRow {
+        key.push(Value::Int(rowid));
+        key.extend(values);
+        key
+    }
+
+    fn new(sorter: Sorter) -> Result<Self> {
+        let run = TempFile::create()?;
+        let mut writer = RunWriter::new(&run)?;
+        let mut blocks = vec![];
+        for (i, row) in sorter.finish()?.enumerate() {
+            let row = row?;
+            if i % SPILL_BLOCK == 0 {
+                blocks.push((row[0].clone(), writer.position()));
+            }
+            writer.write(&row)?;
+        }
+        writer.finish()?;
+        Ok(Self {
+            reader: RunReader::open(run)?,
+            blocks,
         })
-        .with_context(|| format!("no such table: {tbl_name}"))
+    }
+
+    /// The rowids and values of the rows whose key is equal to `key`.
+    fn find(&mut self, key: &Value) -> Result<Vec<(i64, SortRow)>> {
+        // The rows of the key start in the last block starting with a smaller key, if there's one.
+        let block = self.blocks.partition_point(|(first, _)| first.sql_cmp(key).is_lt());
+        let Some(&(_, offset)) = self.blocks.get(block.saturating_sub(1)) else {
+            return Ok(vec![]);
+        };
+        self.reader.seek(offset)?;
+        let mut rows = vec![];
+        while let Some(mut row) = self.reader.read()? {
+            match row[0].sql_cmp(key) {
+                Ordering::Less => continue,
+                Ordering::Greater => break,
+                Ordering::Equal => {
+                    let values = row.split_off(2);
+                    let Value::Int(rowid) = row[1] else {
+                        unreachable!("spilled rows start with their key and rowid")
+                    };
+                    rows.push((rowid, values));
+                }
+            }
+        }
+        Ok(rows)
+    }
+}
+
+enum SorterCursor<'p> {
+    /// Taking rows, sorted in the given order.
+    Filling(Sorter, &'p [SortOrder], bool),
+    /// The sorted rows, `row` being the one the cursor is at.
+    Sorted {
+        rows: Box<dyn Iterator<Item = Result<SortRow>>>,
+        row: Option<SortRow>,
+    },
+}
+
+/// The rows added to a set, to leave out the repeated ones. Like for GROUP BY, NULLs are equal to each other.
+enum Seen {
+    /// All the rows.
+    Hash(HashSet<GroupKey>),
+    /// The last row, for rows that come with the repeated ones next to each other.
+    Last(Option<GroupKey>),
+}
+
+impl Seen {
+    /// Adds a row, returns whether it wasn't seen before.
+    fn insert(&mut self, row: GroupKey) -> bool {
+        match self {
+            Self::Hash(rows) => rows.insert(row),
+            Self::Last(last) if last.as_ref() == Some(&row) => false,
+            Self::Last(last) => {
+                *last = Some(row);
+                true
+            }
+        }
+    }
+}
+
+enum Aggregation<'p> {
+    /// Rows come ordered by their group key, `group` is the last group finished if it wasn't read yet.
+    Stream {
+        aggregate: Option<StreamAggregate<'p>>,
+        group: Option<Group>,
+    },
+    /// Groups are read once all the rows were added.
+    Hash {
+        aggregate: Option<HashAggregate<'p>>,
+        groups: Option<HashGroups<'p>>,
+    },
+}
+
+/// The rows of a recursive table that didn't run the recursive selects yet.
+struct WorkQueue<'p> {
+    /// The rows along with their ORDER BY keys, in the order they come out.
+    rows: VecDeque<(SortRow, SortRow)>,
+    /// The rows added so far, to leave out the repeated ones of a UNION.
+    seen: Option<HashSet<GroupKey>>,
+    order_by: &'p [usize],
+    order: &'p [SortOrder],
+}
+
+impl WorkQueue<'_> {
+    fn push(&mut self, row: SortRow) {
+        if let Some(seen) = &mut self.seen
+            && !seen.insert(GroupKey(row.clone()))
+        {
+            return;
+        }
+        let keys: Vec<_> = self.order_by.iter().map(|&i| row[i].clone()).collect();
+        // Rows with the same keys come out in the order they were added.
+        let at = self
+            .rows
+            .partition_point(|(k, _)| compare_rows(self.order, k, &keys).is_le());
+        self.rows.insert(at, (keys, row));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Connection;
+
+    /// Runs a query on the fixture of the end-to-end tests, returns the listing of its program and the rows it
+    /// produced.
+    fn run(query: &str) -> (String, Vec<SortRow>) {
+        let conn = Connection::open(concat!(env!("CARGO_MANIFEST_DIR"), "/testdata/tests.db")).unwrap();
+        let explain = conn.prepare(&format!("EXPLAIN {query}")).unwrap();
+        let mut statement = conn.prepare(query).unwrap();
+        let mut rows = statement.query(&[]).unwrap();
+        let mut values = vec![];
+        while let Some(row) = rows.next().unwrap() {
+            values.push(row.values().to_vec());
+        }
+        (explain.explain().unwrap().to_string(), values)
+    }
+
+    fn text(s: &str) -> Value<'static> {
+        Value::String(s.to_string().into())
+    }
+
+    #[test]
+    fn index_seek() {
+        let (listing, rows) = run("SELECT id, b FROM t WHERE a = 3");
+        assert_eq!(rows, [vec![Value::Int(1), text("x")], vec![Value::Int(4), text("X")]]);
+        // The key is looked up in the index, whose entries point to the rows of the table.
+        assert_eq!(
+            listing,
+            "\
+addr  opcode         p1    p2    p3    p4             comment
+----  -------------  ----  ----  ----  -------------  ---------
+0     OpenRead       0     2                          t
+1     OpenRead       1     3                          ta
+2     Eval                 3           3
+3     IsNull         3     18
+4     Affinity       3                 Numeric
+5     SeekGE         1     18    3     ASC
+6     IdxGT          1     18    3     ASC
+7     IdxRowid       1     4
+8     SeekRowid      0     17    4
+9     Rowid          0     0                          r[0]=t.id
+10    Column         0     2     1                    r[1]=t.b
+11    Column         0     1     2                    r[2]=t.a
+12    Eval                 5           t.a = 3
+13    IfNot          5     17
+14    Copy           0     6     1
+15    Copy           1     7     1
+16    ResultRow      6     2
+17    Next           1     6
+18    Halt
+"
+        );
+    }
+
+    #[test]
+    fn rowid_seek() {
+        let (listing, rows) = run("SELECT b FROM t WHERE id = 5");
+        assert_eq!(rows, [vec![text("z")]]);
+        assert!(listing.contains("SeekRowid"));
+        assert!(!listing.contains("Rewind"));
+        let (_, rows) = run("SELECT b FROM t WHERE id = 7");
+        assert!(rows.is_empty());
+    }
+
+    #[test]
+    fn scans_sorts_and_groups() {
+        let (_, rows) = run("SELECT id FROM t WHERE c < 0 ORDER BY c");
+        assert_eq!(rows, [vec![Value::Int(4)], vec![Value::Int(2)]]);
+        let (listing, rows) = run("SELECT a, count(*) FROM t GROUP BY a");
+        let expected = [
+            (Value::Null, 1),
+            (Value::Int(-200), 1),
+            (Value::Int(1), 1),
+            (Value::Int(2), 1),
+            (Value::Int(3), 2),
+        ];
+        let expected: Vec<_> = expected.into_iter().map(|(a, n)| vec![a, Value::Int(n)]).collect();
+        assert_eq!(rows, expected);
+        // The index walk gives the groups one after the other.
+        assert!(!listing.contains("SorterOpen"));
+        let (listing, rows) = run("SELECT id FROM big ORDER BY id DESC LIMIT 2");
+        assert_eq!(rows, [vec![Value::Int(1000)], vec![Value::Int(999)]]);
+        assert!(listing.contains("Last") && listing.contains("Prev"));
+    }
 }
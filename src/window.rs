@@ -0,0 +1,626 @@
+//! Window functions, computed for each row over the rows of its partition.
+//!
+//! The rows given to a window are flat lists of values: the ones the calls read, their arguments, PARTITION BY and
+//! ORDER BY terms and frame offsets. The calls whose windows share their PARTITION BY and ORDER BY are computed in one
+//! pass, which sorts the rows by them and reads them a partition at a time. Each pass adds the results of its calls
+//! after the values of the rows, for the next pass to sort them again.
+
+use std::collections::VecDeque;
+
+use anyhow::Result;
+use anyhow::bail;
+use parser::Collation;
+use parser::FrameExclude;
+use parser::FrameUnits;
+use parser::SqlType;
+use parser::Value;
+
+use crate::aggregate::AggregateConstructor;
+use crate::sorter::SortOrder;
+use crate::sorter::SortRow;
+use crate::sorter::SortedRows;
+use crate::sorter::Sorter;
+use crate::sorter::compare_rows;
+
+/// The passes of a window, in the order they run. Rows come out in the order the last one sorts them.
+#[derive(Clone)]
+pub struct WindowSpec {
+    pub passes: Vec<WindowPass>,
+}
+
+/// The calls computed over the rows sorted one way.
+#[derive(Clone)]
+pub struct WindowPass {
+    /// The columns of the PARTITION BY terms.
+    pub partition: Vec<usize>,
+    /// The columns of the ORDER BY terms, and how each is ordered.
+    pub order: Vec<usize>,
+    pub sort: Vec<SortOrder>,
+    pub calls: Vec<WindowCall>,
+}
+
+#[derive(Clone)]
+pub struct WindowCall {
+    pub function: WindowFunction,
+    /// The columns of the arguments.
+    pub args: Vec<usize>,
+    pub frame: Frame,
+}
+
+#[derive(Clone)]
+pub enum WindowFunction {
+    Builtin(Builtin),
+    /// An aggregate function, computed over the rows of the frame.
+    Aggregate(AggregateConstructor),
+}
+
+/// The built-in window functions. Only the value functions look at the frame, the others at the whole partition.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Builtin {
+    RowNumber,
+    Rank,
+    DenseRank,
+    PercentRank,
+    CumeDist,
+    Ntile,
+    Lag,
+    Lead,
+    FirstValue,
+    LastValue,
+    NthValue,
+}
+
+/// Built-in window functions, with the numbers of arguments they take.
+const BUILTINS: &[(&str, &[usize], Builtin)] = &[
+    ("row_number", &[0], Builtin::RowNumber),
+    ("rank", &[0], Builtin::Rank),
+    ("dense_rank", &[0], Builtin::DenseRank),
+    ("percent_rank", &[0], Builtin::PercentRank),
+    ("cume_dist", &[0], Builtin::CumeDist),
+    ("ntile", &[1], Builtin::Ntile),
+    ("lag", &[1, 2, 3], Builtin::Lag),
+    ("lead", &[1, 2, 3], Builtin::Lead),
+    ("first_value", &[1], Builtin::FirstValue),
+    ("last_value", &[1], Builtin::LastValue),
+    ("nth_value", &[2], Builtin::NthValue),
+];
+
+/// The built-in window function with that name, if there is one. Fails if it doesn't take that many arguments.
+pub fn find_window_function(name: &str, args: usize) -> Result<Option<Builtin>> {
+    match BUILTINS.iter().find(|(n, ..)| n.eq_ignore_ascii_case(name)) {
+        Some((_, arities, _)) if !arities.contains(&args) => bail!("wrong number of arguments to function {name}()"),
+        Some((_, _, builtin)) => Ok(Some(*builtin)),
+        None => Ok(None),
+    }
+}
+
+/// The rows a call is computed over for each row, `start` to `end` less the excluded ones.
+#[derive(Clone)]
+pub struct Frame {
+    pub units: FrameUnits,
+    pub start: Bound,
+    pub end: Bound,
+    pub exclude: FrameExclude,
+}
+
+/// A bound of a frame, the offsets being the columns holding them.
+#[derive(Clone, PartialEq, Eq)]
+pub enum Bound {
+    UnboundedPreceding,
+    Preceding(usize),
+    CurrentRow,
+    Following(usize),
+    UnboundedFollowing,
+}
+
+/// Ascending order, NULLs first, the order of PARTITION BY.
+const ASC: SortOrder = SortOrder {
+    desc: false,
+    nulls_first: true,
+    collation: Collation::Binary,
+};
+
+impl WindowPass {
+    /// The order of the rows given to the sorter, which start with their PARTITION BY and ORDER BY values.
+    fn sort_order(&self) -> Vec<SortOrder> {
+        [&vec![ASC; self.partition.len()][..], &self.sort].concat()
+    }
+
+    /// The row along with the values sorting it.
+    fn keyed(&self, row: SortRow) -> SortRow {
+        let mut keyed: SortRow = self
+            .partition
+            .iter()
+            .chain(&self.order)
+            .map(|&c| row[c].clone())
+            .collect();
+        keyed.extend(row);
+        keyed
+    }
+
+    /// Whether two rows of a partition sort alike, which makes them peers.
+    fn peers(&self, a: &SortRow, b: &SortRow) -> bool {
+        self.order.iter().all(|&c| a[c].sql_cmp(&b[c]).is_eq())
+    }
+
+    /// The results of each call for each row of a partition.
+    fn compute(&self, rows: &[SortRow]) -> Result<Vec<Vec<Value<'static>>>> {
+        let mut group = Vec::with_capacity(rows.len());
+        let mut starts = vec![];
+        for (i, row) in rows.iter().enumerate() {
+            if i == 0 || !self.peers(&rows[i - 1], row) {
+                starts.push(i);
+            }
+            group.push(starts.len() - 1);
+        }
+        starts.push(rows.len());
+        let partition = Partition {
+            pass: self,
+            rows,
+            group,
+            starts,
+        };
+        self.calls.iter().map(|call| call.compute(&partition)).collect()
+    }
+}
+
+/// The rows of a partition, along with their groups of peers.
+struct Partition<'p> {
+    pass: &'p WindowPass,
+    rows: &'p [SortRow],
+    /// The group of peers of each row.
+    group: Vec<usize>,
+    /// Where each group starts, followed by the number of rows.
+    starts: Vec<usize>,
+}
+
+impl Partition<'_> {
+    /// The rows that are peers of a row, itself included.
+    fn peers(&self, i: usize) -> (usize, usize) {
+        let group = self.group[i];
+        (self.starts[group], self.starts[group + 1])
+    }
+}
+
+impl WindowCall {
+    fn compute(&self, p: &Partition) -> Result<Vec<Value<'static>>> {
+        let n = p.rows.len();
+        let arg = |i: usize, k: usize| &p.rows[i][self.args[k]];
+        let builtin = match &self.function {
+            WindowFunction::Builtin(builtin) => *builtin,
+            WindowFunction::Aggregate(new) => return self.aggregate(p, new),
+        };
+        let mut results = Vec::with_capacity(n);
+        for i in 0..n {
+            let (peers, peers_end) = p.peers(i);
+            let value = match builtin {
+                Builtin::RowNumber => Value::Int(i as i64 + 1),
+                Builtin::Rank => Value::Int(peers as i64 + 1),
+                Builtin::DenseRank => Value::Int(p.group[i] as i64 + 1),
+                Builtin::PercentRank => match n {
+                    1 => Value::Float(0.0),
+                    _ => Value::Float(peers as f64 / (n - 1) as f64),
+                },
+                Builtin::CumeDist => Value::Float(peers_end as f64 / n as f64),
+                Builtin::Ntile => {
+                    let buckets = match arg(i, 0).to_number() {
+                        Value::Int(b) if b > 0 => b as usize,
+                        Value::Float(b) if b >= 1.0 => b as usize,
+                        _ => bail!("argument of ntile must be a positive integer"),
+                    };
+                    Value::Int(ntile(i, n, buckets) as i64)
+                }
+                Builtin::Lag | Builtin::Lead => {
+                    // Offsets that aren't integers give NULL.
+                    let offset = match self.args.get(1).map(|_| arg(i, 1)) {
+                        None => Some(1),
+                        Some(offset) => match offset.to_number() {
+                            Value::Int(k) => Some(k),
+                            Value::Float(k) if k.fract() == 0.0 => Some(k as i64),
+                            _ => None,
+                        },
+                    };
+                    let row = offset.map(|k| {
+                        if builtin == Builtin::Lag {
+                            i as i64 - k
+                        } else {
+                            i as i64 + k
+                        }
+                    });
+                    match row {
+                        Some(j) if (0..n as i64).contains(&j) => arg(j as usize, 0).clone(),
+                        Some(_) => self.args.get(2).map_or(Value::Null, |_| arg(i, 2).clone()),
+                        None => Value::Null,
+                    }
+                }
+                Builtin::FirstValue | Builtin::LastValue | Builtin::NthValue => {
+                    let mut frame = self.frame(p, i)?;
+                    let row = match builtin {
+                        Builtin::FirstValue => frame.next(),
+                        Builtin::LastValue => frame.last(),
+                        _ => {
+                            let nth = match arg(i, 1).to_number() {
+                                Value::Int(k) if k > 0 => k as usize,
+                                Value::Float(k) if k >= 1.0 && k.fract() == 0.0 => k as usize,
+                                _ => bail!("second argument to nth_value must be a positive integer"),
+                            };
+                            frame.nth(nth - 1)
+                        }
+                    };
+                    row.map_or(Value::Null, |j| arg(j, 0).clone())
+                }
+            };
+            results.push(value);
+        }
+        Ok(results)
+    }
+
+    /// The results of an aggregate over the frame of each row. When frames start with the partition, each one is the
+    /// one before with the rows after it added.
+    fn aggregate(&self, p: &Partition, new: &AggregateConstructor) -> Result<Vec<Value<'static>>> {
+        let args = |j: usize| -> Vec<Value> { self.args.iter().map(|&c| p.rows[j][c].clone()).collect() };
+        let growing = self.frame.start == Bound::UnboundedPreceding && self.frame.exclude == FrameExclude::NoOthers;
+        let mut aggregate = new();
+        let mut added = 0;
+        let mut results = Vec::with_capacity(p.rows.len());
+        for i in 0..p.rows.len() {
+            if growing {
+                let end = self.bound(p, i, &self.frame.end, false)?;
+                if end < added {
+                    (aggregate, added) = (new(), 0);
+                }
+                for j in added..end {
+                    aggregate.step(&args(j))?;
+                }
+                added = added.max(end);
+            } else {
+                aggregate = new();
+                for j in self.frame(p, i)? {
+                    aggregate.step(&args(j))?;
+                }
+            }
+            results.push(aggregate.finalize()?);
+        }
+        Ok(results)
+    }
+
+    /// The rows of the frame of a row.
+    fn frame<'s>(&'s self, p: &'s Partition, i: usize) -> Result<impl Iterator<Item = usize> + 's> {
+        let start = self.bound(p, i, &self.frame.start, true)?;
+        let end = self.bound(p, i, &self.frame.end, false)?;
+        let (peers, peers_end) = p.peers(i);
+        let excluded = move |j: usize| match self.frame.exclude {
+            FrameExclude::NoOthers => false,
+            FrameExclude::CurrentRow => j == i,
+            FrameExclude::Group => (peers..peers_end).contains(&j),
+            FrameExclude::Ties => j != i && (peers..peers_end).contains(&j),
+        };
+        Ok((start..end.max(start)).filter(move |&j| !excluded(j)))
+    }
+
+    /// Where a bound of the frame of a row is, the first row of the frame for its start and the one after its last row
+    /// for its end.
+    fn bound(&self, p: &Partition, i: usize, bound: &Bound, start: bool) -> Result<usize> {
+        let n = p.rows.len();
+        let (peers, peers_end) = p.peers(i);
+        let (column, preceding) = match bound {
+            Bound::UnboundedPreceding => return Ok(0),
+            Bound::UnboundedFollowing => return Ok(n),
+            Bound::CurrentRow => {
+                return Ok(match (self.frame.units, start) {
+                    (FrameUnits::Rows, true) => i,
+                    (FrameUnits::Rows, false) => i + 1,
+                    (_, true) => peers,
+                    (_, false) => peers_end,
+                });
+            }
+            Bound::Preceding(column) => (*column, true),
+            Bound::Following(column) => (*column, false),
+        };
+        let which = if start { "starting" } else { "ending" };
+        let offset = &p.rows[i][column].clone().with_affinity(SqlType::Numeric);
+        if self.frame.units == FrameUnits::Range {
+            if !matches!(offset, Value::Int(0..) | Value::Float(0.0..)) {
+                bail!("frame {which} offset must be a non-negative number");
+            }
+            return Ok(range_bound(p, i, offset, preceding, start));
+        }
+        let offset = match offset {
+            Value::Int(k) if *k >= 0 => *k,
+            Value::Float(k) if *k >= 0.0 && k.fract() == 0.0 => *k as i64,
+            _ => bail!("frame {which} offset must be a non-negative integer"),
+        };
+        let offset = if preceding { -offset } else { offset };
+        let clamp = |at: i64| at.clamp(0, n as i64) as usize;
+        Ok(match self.frame.units {
+            FrameUnits::Rows => clamp((i as i64).saturating_add(offset).saturating_add(!start as i64)),
+            // The frame starts with the first row of a group and ends with the last one.
+            _ => {
+                let groups = p.starts.len() as i64 - 1;
+                let group = (p.group[i] as i64).saturating_add(offset).saturating_add(!start as i64);
+                p.starts[group.clamp(0, groups) as usize]
+            }
+        })
+    }
+}
+
+/// A bound of a RANGE frame: the rows whose ORDER BY value is within the offset of the one of the current row. Rows
+/// whose value isn't a number only have their peers in their frame.
+fn range_bound(p: &Partition, i: usize, offset: &Value, preceding: bool, start: bool) -> usize {
+    let (column, order) = (p.pass.order[0], p.pass.sort[0]);
+    let value = &p.rows[i][column];
+    // Preceding rows have smaller values in ascending order, larger ones in descending order.
+    let target = match add(value, offset, preceding != order.desc) {
+        Some(target) => target,
+        None if start => return p.peers(i).0,
+        None => return p.peers(i).1,
+    };
+    let cmp = |row: &SortRow| {
+        compare_rows(
+            &[order],
+            std::slice::from_ref(&row[column]),
+            std::slice::from_ref(&target),
+        )
+    };
+    match start {
+        true => p.rows.partition_point(|row| cmp(row).is_lt()),
+        false => p.rows.partition_point(|row| cmp(row).is_le()),
+    }
+}
+
+/// The number plus or minus the offset, `None` if it isn't a number.
+fn add(value: &Value, offset: &Value, minus: bool) -> Option<Value<'static>> {
+    let sign = if minus { -1 } else { 1 };
+    match (value, offset) {
+        (Value::Int(v), Value::Int(k)) => Some(match v.checked_add(sign * k) {
+            Some(sum) => Value::Int(sum),
+            None => Value::Float(*v as f64 + (sign * k) as f64),
+        }),
+        (Value::Int(v), Value::Float(k)) => Some(Value::Float(*v as f64 + sign as f64 * k)),
+        (Value::Float(v), Value::Int(k)) => Some(Value::Float(v + (sign * k) as f64)),
+        (Value::Float(v), Value::Float(k)) => Some(Value::Float(v + sign as f64 * k)),
+        _ => None,
+    }
+}
+
+/// The bucket of the `i`th of `n` rows split into `buckets` buckets as equal as can be, the first ones taking a row
+/// more when they can't be.
+fn ntile(i: usize, n: usize, buckets: usize) -> usize {
+    let (size, extra) = (n / buckets, n % buckets);
+    let large = extra * (size + 1);
+    match i < large {
+        true => i / (size + 1) + 1,
+        false => (i - large) / size + extra + 1,
+    }
+}
+
+/// The rows of a window, sorted and given the results of the calls once they have all been added.
+pub struct Window<'s> {
+    spec: &'s WindowSpec,
+    memory_limit: usize,
+    sorter: Option<Sorter>,
+    rows: Option<Partitions<'s>>,
+}
+
+impl<'s> Window<'s> {
+    pub fn new(spec: &'s WindowSpec, memory_limit: usize) -> Self {
+        let sorter = Sorter::new(spec.passes[0].sort_order(), memory_limit, None);
+        Self {
+            spec,
+            memory_limit,
+            sorter: Some(sorter),
+            rows: None,
+        }
+    }
+
+    pub fn push(&mut self, row: SortRow) -> Result<()> {
+        let sorter = self.sorter.as_mut().expect("window read already");
+        sorter.push(self.spec.passes[0].keyed(row))
+    }
+
+    /// The next row with the results of the calls, the passes run when the first one is read.
+    pub fn next(&mut self) -> Result<Option<SortRow>> {
+        if let Some(sorter) = self.sorter.take() {
+            let passes = &self.spec.passes;
+            let mut rows = Partitions::new(&passes[0], sorter.finish()?);
+            for pass in &passes[1..] {
+                let mut sorter = Sorter::new(pass.sort_order(), self.memory_limit, None);
+                for row in rows {
+                    sorter.push(pass.keyed(row?))?;
+                }
+                rows = Partitions::new(pass, sorter.finish()?);
+            }
+            self.rows = Some(rows);
+        }
+        self.rows.as_mut().and_then(Iterator::next).transpose()
+    }
+}
+
+/// The rows of a pass, with the results of its calls added a partition at a time.
+struct Partitions<'s> {
+    pass: &'s WindowPass,
+    rows: SortedRows,
+    /// The first row of the next partition, read to find the end of the one before.
+    next: Option<SortRow>,
+    /// The rows of the partition computed last that weren't read yet.
+    done: VecDeque<SortRow>,
+}
+
+impl<'s> Partitions<'s> {
+    fn new(pass: &'s WindowPass, rows: SortedRows) -> Self {
+        Self {
+            pass,
+            rows,
+            next: None,
+            done: VecDeque::new(),
+        }
+    }
+
+    /// Reads the next partition and computes the calls over it.
+    fn compute(&mut self) -> Result<()> {
+        let first = match self.next.take() {
+            Some(row) => row,
+            None => match self.rows.next() {
+                Some(row) => row?,
+                None => return Ok(()),
+            },
+        };
+        let (partition, keys) = (self.pass.partition.len(), self.pass.sort_order().len());
+        let order = vec![ASC; partition];
+        let mut rows = vec![first];
+        for row in self.rows.by_ref() {
+            let row = row?;
+            if compare_rows(&order, &rows[0], &row).is_ne() {
+                self.next = Some(row);
+                break;
+            }
+            rows.push(row);
+        }
+        let rows: Vec<_> = rows.into_iter().map(|mut row| row.split_off(keys)).collect();
+        let mut results: Vec<_> = self.pass.compute(&rows)?.into_iter().map(Vec::into_iter).collect();
+        for mut row in rows {
+            row.extend(results.iter_mut().map(|r| r.next().unwrap()));
+            self.done.push_back(row);
+        }
+        Ok(())
+    }
+}
+
+impl Iterator for Partitions<'_> {
+    type Item = Result<SortRow>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done.is_empty()
+            && let Err(e) = self.compute()
+        {
+            return Some(Err(e));
+        }
+        self.done.pop_front().map(Ok)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::aggregate::find_aggregate;
+
+    fn builtin(builtin: Builtin, args: Vec<usize>) -> WindowCall {
+        WindowCall {
+            function: WindowFunction::Builtin(builtin),
+            args,
+            frame: Frame {
+                units: FrameUnits::Range,
+                start: Bound::UnboundedPreceding,
+                end: Bound::CurrentRow,
+                exclude: FrameExclude::NoOthers,
+            },
+        }
+    }
+
+    fn rows(spec: &WindowSpec, input: &[[i64; 2]], memory_limit: usize) -> Vec<Vec<Value<'static>>> {
+        let mut window = Window::new(spec, memory_limit);
+        for row in input {
+            window.push(row.iter().map(|&v| Value::Int(v)).collect()).unwrap();
+        }
+        let mut rows = vec![];
+        while let Some(row) = window.next().unwrap() {
+            rows.push(row);
+        }
+        rows
+    }
+
+    #[test]
+    fn ntile_buckets() {
+        let buckets = |n, b| (0..n).map(|i| ntile(i, n, b)).collect::<Vec<_>>();
+        assert_eq!(buckets(7, 3), [1, 1, 1, 2, 2, 3, 3]);
+        assert_eq!(buckets(2, 4), [1, 2]);
+        assert_eq!(buckets(4, 1), [1, 1, 1, 1]);
+    }
+
+    #[test]
+    fn passes() {
+        // Row numbers within the partitions of the first column, then sums of the second column in descending order
+        // over the rows as far back as the row number says.
+        let sum = WindowCall {
+            function: WindowFunction::Aggregate(Rc::new(find_aggregate("sum", 1).unwrap())),
+            args: vec![1],
+            frame: Frame {
+                units: FrameUnits::Rows,
+                start: Bound::Preceding(2),
+                end: Bound::CurrentRow,
+                exclude: FrameExclude::NoOthers,
+            },
+        };
+        let spec = WindowSpec {
+            passes: vec![
+                WindowPass {
+                    partition: vec![0],
+                    order: vec![],
+                    sort: vec![],
+                    calls: vec![builtin(Builtin::RowNumber, vec![])],
+                },
+                WindowPass {
+                    partition: vec![],
+                    order: vec![1],
+                    sort: vec![SortOrder {
+                        desc: true,
+                        nulls_first: false,
+                        collation: Collation::Binary,
+                    }],
+                    calls: vec![sum, builtin(Builtin::Rank, vec![])],
+                },
+            ],
+        };
+        let input = [[2, 10], [1, 20], [2, 30], [1, 40]];
+        let expected: Vec<Vec<Value>> = [
+            [1, 40, 2, 40, 1],
+            [2, 30, 2, 70, 2],
+            [1, 20, 1, 50, 3],
+            [2, 10, 1, 30, 4],
+        ]
+        .iter()
+        .map(|row| row.iter().map(|&v| Value::Int(v)).collect())
+        .collect();
+        assert_eq!(rows(&spec, &input, usize::MAX), expected);
+        assert_eq!(rows(&spec, &input, 10), expected);
+        assert!(rows(&spec, &[], usize::MAX).is_empty());
+    }
+
+    #[test]
+    fn huge_offsets() {
+        // Offsets as large as an integer goes take in the whole partition rather than overflowing.
+        let sum = |units| WindowCall {
+            function: WindowFunction::Aggregate(Rc::new(find_aggregate("sum", 1).unwrap())),
+            args: vec![1],
+            frame: Frame {
+                units,
+                start: Bound::Preceding(0),
+                end: Bound::Following(0),
+                exclude: FrameExclude::NoOthers,
+            },
+        };
+        let spec = WindowSpec {
+            passes: vec![WindowPass {
+                partition: vec![],
+                order: vec![1],
+                sort: vec![ASC],
+                calls: vec![sum(FrameUnits::Rows), sum(FrameUnits::Groups)],
+            }],
+        };
+        let input = [[i64::MAX, 1], [i64::MAX, 2], [i64::MAX, 3]];
+        let expected: Vec<Vec<Value>> = (1..=3)
+            .map(|v| vec![Value::Int(i64::MAX), Value::Int(v), Value::Int(6), Value::Int(6)])
+            .collect();
+        assert_eq!(rows(&spec, &input, usize::MAX), expected);
+    }
+
+    #[test]
+    fn window_functions() {
+        assert_eq!(find_window_function("LAG", 3).unwrap(), Some(Builtin::Lag));
+        assert_eq!(find_window_function("sum", 1).unwrap(), None);
+        let err = find_window_function("ntile", 0).unwrap_err();
+        assert_eq!(err.to_string(), "wrong number of arguments to function ntile()");
+    }
+}
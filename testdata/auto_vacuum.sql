@@ -0,0 +1,7 @@
+-- Fixture of a database in auto-vacuum mode, rebuild it with:
+--   rm -f testdata/auto_vacuum.db && sqlite3 testdata/auto_vacuum.db < testdata/auto_vacuum.sql
+PRAGMA auto_vacuum = FULL;
+CREATE TABLE t(id INTEGER PRIMARY KEY, v TEXT);
+CREATE INDEX tv ON t(v);
+WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < 200)
+INSERT INTO t SELECT i, printf('%03d of the rows of a few pages', i) FROM n;
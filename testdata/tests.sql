@@ -0,0 +1,26 @@
+-- Fixture for the end-to-end tests, rebuild it with:
+--   rm -f testdata/tests.db && sqlite3 testdata/tests.db < testdata/tests.sql
+CREATE TABLE t(id INTEGER PRIMARY KEY, a INT, b TEXT, c REAL);
+CREATE INDEX ta ON t(a);
+INSERT INTO t VALUES
+    (1, 3, 'x', 1.5),
+    (2, NULL, 'y', -1),
+    (3, 1, NULL, NULL),
+    (4, 3, 'X', -2.5),
+    (5, -200, 'z', 70000),
+    (6, 2, 'y', NULL);
+CREATE TABLE g(a INT, b INT GENERATED ALWAYS AS (a * 2) VIRTUAL, c TEXT, d AS (b + length(c)), e AS (c || '!') STORED);
+INSERT INTO g(a, c) VALUES (3, 'x'), (5, 'yz'), (NULL, 'w');
+CREATE TABLE w(k TEXT, v INT, x, PRIMARY KEY(k, v)) WITHOUT ROWID;
+INSERT INTO w VALUES ('b', 2, 'b2'), ('a', 9, NULL), ('b', 1, 1.5), ('c', 0, x'00ff'), ('a', -3, 'a-3');
+CREATE TABLE o(rowid TEXT, x INT);
+INSERT INTO o(_rowid_, rowid, x) VALUES (10, 'ten', 1), (-5, 'minus five', 2);
+CREATE TABLE n(s TEXT COLLATE NOCASE, r TEXT COLLATE RTRIM);
+INSERT INTO n VALUES ('a', 'a'), ('B', 'b  '), ('A', 'a '), ('b', 'B'), (NULL, 'c');
+CREATE TABLE big(id INTEGER PRIMARY KEY, v TEXT, k INT);
+CREATE INDEX bv ON big(v);
+WITH RECURSIVE c(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM c WHERE i < 1000)
+INSERT INTO big SELECT i, printf('%03d', i * 7919 % 1000) || ' of the rows of a few pages', i % 100 FROM c;
+CREATE TABLE d(x);
+CREATE INDEX dx ON d(x);
+INSERT INTO d VALUES (1), (NULL), (1.0), ('1'), (2), (NULL), (2.5);